@@ -0,0 +1,420 @@
+//! Native decryption of Audible AAX and AAXC files.
+//!
+//! The audio samples are encrypted with AES-128-CBC, each sample on its own starting from the
+//! same IV, and any trailing partial block left in the clear. In AAX files the key and IV are
+//! stored encrypted in the `adrm` box, and are unlocked with the activation bytes of the
+//! account. For AAXC files they come from the voucher of the license instead.
+
+use std::fs::File;
+use std::io::{Read, Seek, SeekFrom, Write};
+use std::path::Path;
+
+use aes::cipher::block_padding::NoPadding;
+use aes::cipher::{BlockDecryptMut, KeyIvInit};
+use anyhow::{anyhow, bail, Result};
+use sha1::{Digest, Sha1};
+
+use crate::frontend::Frontend;
+use crate::mp4::{self, Child};
+
+type Decryptor = cbc::Decryptor<aes::Aes128>;
+
+/// Key used by all Audible players to derive the key that encrypts the file key
+const FIXED_KEY: [u8; 16] = [
+    0x77, 0x21, 0x4d, 0x4b, 0x19, 0x6a, 0x87, 0xcd, 0x52, 0x00, 0x45, 0xfd, 0x20, 0xa5, 0x1d, 0x67,
+];
+
+/// Parse activation bytes given as 8 hex digits, for use as a clap value parser
+pub fn parse_activation_bytes(s: &str) -> Result<[u8; 4], String> {
+    let bytes = parse_hex(s)?;
+
+    bytes
+        .try_into()
+        .map_err(|_| "activation bytes are 8 hex digits, e.g. 1a2b3c4d".to_owned())
+}
+
+/// Parse an AAXC key or IV given as 32 hex digits, for use as a clap value parser
+pub fn parse_key(s: &str) -> Result<[u8; 16], String> {
+    let bytes = parse_hex(s)?;
+
+    bytes
+        .try_into()
+        .map_err(|_| "keys and IVs are 32 hex digits".to_owned())
+}
+
+fn parse_hex(s: &str) -> Result<Vec<u8>, String> {
+    // An odd length leaves half a byte at the end, which `get` refuses
+    (0..s.len())
+        .step_by(2)
+        .map(|i| {
+            s.get(i..i + 2)
+                .and_then(|byte| u8::from_str_radix(byte, 16).ok())
+                .ok_or_else(|| format!("`{}` isn't a hex string", s))
+        })
+        .collect()
+}
+
+fn sha1(parts: &[&[u8]]) -> [u8; 20] {
+    let mut hasher = Sha1::new();
+
+    for part in parts {
+        hasher.update(part);
+    }
+
+    hasher.finalize().into()
+}
+
+/// Key and IV the audio samples are encrypted with
+pub struct Key {
+    key: [u8; 16],
+    iv: [u8; 16],
+}
+
+impl Key {
+    /// Key and IV of an AAXC file, as found in its voucher
+    pub fn new(key: [u8; 16], iv: [u8; 16]) -> Key {
+        Key { key, iv }
+    }
+}
+
+/// Contents of the `adrm` box
+struct Adrm {
+    /// File key and IV seed, encrypted with a key derived from the activation bytes
+    blob: [u8; 48],
+    /// Checksum of the key derived from the activation bytes, which external tools can look up
+    /// the activation bytes from
+    checksum: [u8; 20],
+}
+
+impl Adrm {
+    fn parse(body: &[u8]) -> Result<Adrm> {
+        if body.len() < 88 {
+            bail!("Truncated adrm box");
+        }
+
+        Ok(Adrm {
+            blob: body[8..56].try_into().expect("48 bytes"),
+            checksum: body[68..88].try_into().expect("20 bytes"),
+        })
+    }
+
+    /// Unlock the file key with `activation_bytes`
+    fn key(&self, activation_bytes: [u8; 4]) -> Result<Key> {
+        let intermediate_key = sha1(&[&FIXED_KEY, &activation_bytes]);
+        let intermediate_iv = sha1(&[&FIXED_KEY, &intermediate_key, &activation_bytes]);
+
+        if sha1(&[&intermediate_key[..16], &intermediate_iv[..16]]) != self.checksum {
+            bail!("Wrong activation bytes for this file");
+        }
+
+        let mut blob = self.blob;
+        Decryptor::new(intermediate_key[..16].into(), intermediate_iv[..16].into())
+            .decrypt_padded_mut::<NoPadding>(&mut blob)
+            .map_err(|_| anyhow!("Invalid adrm box"))?;
+
+        // The blob starts with the activation bytes, stored little endian
+        if blob[..4].iter().rev().ne(activation_bytes.iter()) {
+            bail!("Failed to decrypt the file key");
+        }
+
+        let key: [u8; 16] = blob[8..24].try_into().expect("16 bytes");
+        let iv = sha1(&[&blob[26..42], &key, &FIXED_KEY]);
+
+        Ok(Key {
+            key,
+            iv: iv[..16].try_into().expect("16 bytes"),
+        })
+    }
+}
+
+/// An encrypted track
+struct Encrypted {
+    stbl: Child,
+    entry: Child,
+    /// Only AAX files have an adrm box
+    adrm: Option<Child>,
+}
+
+/// Encrypted tracks of the file, an error if there are none
+fn encrypted_tracks(file: &mut File) -> Result<Vec<Encrypted>> {
+    let mut result = Vec::new();
+
+    for track in mp4::tracks(file)? {
+        let mp4::Track {
+            stbl,
+            entry: Some(entry),
+            ..
+        } = track
+        else {
+            continue;
+        };
+
+        if &entry.kind != b"aavd" {
+            continue;
+        }
+
+        // The child boxes follow the 28 bytes of the audio sample entry
+        let adrm = mp4::children(file, entry.body + 28, entry.end)?
+            .into_iter()
+            .find(|child| &child.kind == b"adrm");
+
+        result.push(Encrypted { stbl, entry, adrm });
+    }
+
+    if result.is_empty() {
+        bail!("The file isn't an encrypted AAX or AAXC file");
+    }
+
+    Ok(result)
+}
+
+fn read_adrm(path: &Path) -> Result<Adrm> {
+    let mut file = File::open(path)?;
+    let tracks = encrypted_tracks(&mut file)?;
+
+    let adrm = tracks[0]
+        .adrm
+        .as_ref()
+        .ok_or_else(|| anyhow!("The file has no adrm box, use the key and IV from its voucher"))?;
+
+    Adrm::parse(&mp4::read_body(&mut file, adrm)?)
+}
+
+/// Key of the file at `path`, unlocked with `activation_bytes`
+pub fn key(path: &Path, activation_bytes: [u8; 4]) -> Result<Key> {
+    read_adrm(path)?.key(activation_bytes)
+}
+
+/// Checksum in the adrm box of the file at `path`, as used to look up the activation bytes
+pub fn checksum(path: &Path) -> Result<String> {
+    Ok(read_adrm(path)?
+        .checksum
+        .iter()
+        .map(|byte| format!("{:02x}", byte))
+        .collect())
+}
+
+/// Decrypt the file at `path` in place, turning it into a plain M4B file.
+///
+/// Besides decrypting the samples the sample entries are changed from `aavd` to `mp4a`, any
+/// `adrm` boxes are turned into `free` boxes and the Audible brands are replaced with `M4B `.
+pub fn decrypt(path: &Path, key: &Key, frontend: &dyn Frontend) -> Result<()> {
+    let mut file = File::options().read(true).write(true).open(path)?;
+    let tracks = encrypted_tracks(&mut file)?;
+
+    let mut chunks = Vec::new();
+
+    for track in &tracks {
+        chunks.extend(mp4::chunks(&mut file, &track.stbl)?);
+    }
+
+    let total = chunks
+        .iter()
+        .flat_map(|chunk| &chunk.sample_sizes)
+        .map(|&size| u64::from(size))
+        .sum();
+
+    frontend.set_length(total);
+
+    let mut buf = Vec::new();
+
+    for chunk in chunks {
+        let len = chunk.sample_sizes.iter().map(|&size| size as usize).sum();
+        buf.resize(len, 0);
+
+        file.seek(SeekFrom::Start(chunk.offset))?;
+        file.read_exact(&mut buf)?;
+
+        let mut pos = 0;
+
+        for size in chunk.sample_sizes {
+            let sample = &mut buf[pos..pos + size as usize];
+            let blocks = sample.len() / 16 * 16;
+
+            Decryptor::new(&key.key.into(), &key.iv.into())
+                .decrypt_padded_mut::<NoPadding>(&mut sample[..blocks])
+                .expect("whole blocks");
+
+            pos += size as usize;
+        }
+
+        file.seek(SeekFrom::Start(chunk.offset))?;
+        file.write_all(&buf)?;
+
+        frontend.inc(len as u64);
+    }
+
+    for track in &tracks {
+        rename(&mut file, &track.entry, b"mp4a")?;
+
+        if let Some(adrm) = &track.adrm {
+            rename(&mut file, adrm, b"free")?;
+        }
+    }
+
+    rebrand(&mut file)?;
+
+    file.sync_all()?;
+
+    Ok(())
+}
+
+/// Change the type of the box `child`
+fn rename(file: &mut File, child: &Child, kind: &[u8; 4]) -> Result<()> {
+    file.seek(SeekFrom::Start(child.start + 4))?;
+    file.write_all(kind)?;
+    Ok(())
+}
+
+/// Replace the Audible brands in the ftyp box with `M4B `
+fn rebrand(file: &mut File) -> Result<()> {
+    let header = mp4::BoxHeader::read(file, 0)?;
+
+    let ftyp = match header.size {
+        Some(size) if &header.kind == b"ftyp" => Child {
+            kind: header.kind,
+            start: 0,
+            body: header.header_len,
+            end: size,
+        },
+        _ => bail!("File doesn't start with an MP4 ftyp box"),
+    };
+
+    let mut body = mp4::read_body(file, &ftyp)?;
+
+    // Major brand, minor version and the compatible brands
+    for (i, brand) in body.chunks_mut(4).enumerate() {
+        if i != 1 && (brand == b"aax " || brand == b"aaxc") {
+            brand.copy_from_slice(b"M4B ");
+        }
+    }
+
+    file.seek(SeekFrom::Start(ftyp.body))?;
+    file.write_all(&body)?;
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::frontend::Hidden;
+
+    const ACTIVATION_BYTES: [u8; 4] = [0x1c, 0xeb, 0x00, 0xda];
+
+    /// Body of an adrm box locking the key `000102...0f` with `ACTIVATION_BYTES`
+    const ADRM: &str = "00000000000000000145e1ca5db7e1e436111570be4b1ac6d6dfb8f4a5b7ebe1129c656b86f2d6fbbf40cb20579e6acc0abcd9b95223547c0000000000000000000000007b19e237cd6eef8770b30a93fe165070ab199e54";
+
+    fn hex(s: &str) -> Vec<u8> {
+        parse_hex(s).unwrap()
+    }
+
+    fn boxed(kind: &[u8; 4], body: &[u8]) -> Vec<u8> {
+        let mut result = (body.len() as u32 + 8).to_be_bytes().to_vec();
+        result.extend(kind);
+        result.extend(body);
+        result
+    }
+
+    /// A box with version and flags
+    fn full(kind: &[u8; 4], body: &[u8]) -> Vec<u8> {
+        boxed(kind, &[&[0; 4], body].concat())
+    }
+
+    #[test]
+    fn parses_hex_arguments() {
+        assert_eq!(parse_activation_bytes("1CEB00da"), Ok(ACTIVATION_BYTES));
+        assert!(parse_activation_bytes("1ceb00d").is_err());
+        assert!(parse_activation_bytes("1ceb00dx").is_err());
+        assert!(parse_activation_bytes("1ceb00da00").is_err());
+        assert_eq!(
+            parse_key("000102030405060708090a0b0c0d0e0f").unwrap()[15],
+            0x0f
+        );
+        assert!(parse_key("00010203").is_err());
+    }
+
+    #[test]
+    fn unlocks_aax_key() {
+        let key = Adrm::parse(&hex(ADRM))
+            .unwrap()
+            .key(ACTIVATION_BYTES)
+            .unwrap();
+
+        assert_eq!(key.key.to_vec(), hex("000102030405060708090a0b0c0d0e0f"));
+        assert_eq!(key.iv.to_vec(), hex("098501a105df4fc4418f56be28fa8695"));
+    }
+
+    #[test]
+    fn reads_checksum() {
+        let adrm = Adrm::parse(&hex(ADRM)).unwrap();
+
+        assert_eq!(
+            adrm.checksum.to_vec(),
+            hex("7b19e237cd6eef8770b30a93fe165070ab199e54")
+        );
+    }
+
+    #[test]
+    fn rejects_wrong_activation_bytes() {
+        let adrm = Adrm::parse(&hex(ADRM)).unwrap();
+
+        assert!(adrm.key([0x1c, 0xeb, 0x00, 0xdb]).is_err());
+        assert!(Adrm::parse(&hex(ADRM)[..80]).is_err());
+    }
+
+    #[test]
+    fn decrypts_aaxc_samples() {
+        let key = Key::new(
+            parse_key("00112233445566778899aabbccddeeff").unwrap(),
+            parse_key("0f0e0d0c0b0a09080706050403020100").unwrap(),
+        );
+
+        // Two whole blocks and a partial one, and a sample shorter than a block
+        let encrypted =
+            hex("8fe813763a7e66ffb81785f83e110cef014c408d594a4d770b72b7ca8534ee332021222324252627");
+        let short = b"0123456789".to_vec();
+        let samples = [&encrypted[..], &short].concat();
+
+        let ftyp = boxed(b"ftyp", b"aaxc\0\0\0\x01aaxcM4B isom");
+        let entry = boxed(b"aavd", &[&[0; 28][..], &boxed(b"esds", &[0; 8])].concat());
+
+        let moov = |offset: u32| {
+            let stsd = full(b"stsd", &[&1u32.to_be_bytes()[..], &entry].concat());
+            let stsc = full(b"stsc", &[1u32, 1, 2, 1].map(u32::to_be_bytes).concat());
+            let stsz = full(
+                b"stsz",
+                &[0u32, 2, encrypted.len() as u32, short.len() as u32]
+                    .map(u32::to_be_bytes)
+                    .concat(),
+            );
+            let stco = full(b"stco", &[1u32, offset].map(u32::to_be_bytes).concat());
+            let stbl = boxed(b"stbl", &[stsd, stsc, stsz, stco].concat());
+            let trak = boxed(b"trak", &boxed(b"mdia", &boxed(b"minf", &stbl)));
+            boxed(b"moov", &trak)
+        };
+
+        let offset = (ftyp.len() + moov(0).len() + 8) as u32;
+        let file = [ftyp, moov(offset), boxed(b"mdat", &samples)].concat();
+
+        let path = std::env::temp_dir().join(format!("audible-dl-aaxc-{}", std::process::id()));
+        std::fs::write(&path, &file).unwrap();
+
+        let result = decrypt(&path, &key, &Hidden);
+        let decrypted = std::fs::read(&path).unwrap();
+        let entries = mp4::sample_entries(&mut File::open(&path).unwrap());
+        std::fs::remove_file(&path).unwrap();
+        result.unwrap();
+
+        assert_eq!(entries.unwrap(), vec![*b"mp4a"]);
+
+        let plain: Vec<u8> = (0..40).collect();
+        let mdat = decrypted.len() - samples.len();
+
+        assert_eq!(&decrypted[mdat..mdat + 40], &plain[..]);
+        assert_eq!(&decrypted[mdat + 40..], &short[..]);
+        assert_eq!(&decrypted[8..12], b"M4B ");
+        assert_eq!(&decrypted[16..20], b"M4B ");
+        assert_eq!(decrypted.len(), file.len());
+    }
+}
@@ -0,0 +1,90 @@
+use anyhow::Result;
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+
+use super::Client;
+
+pub const RESPONSE_GROUPS: &str = "product_desc,product_attrs,contributors,series";
+
+#[derive(Deserialize, Serialize, Debug)]
+pub struct Person {
+    pub name: String,
+}
+
+#[derive(Deserialize, Serialize, Debug)]
+pub struct Series {
+    pub title: String,
+    pub sequence: Option<String>,
+}
+
+#[derive(Deserialize, Serialize, Debug)]
+pub struct Product {
+    pub asin: String,
+    pub sku: Option<String>,
+    pub sku_lite: Option<String>,
+    pub title: String,
+    pub subtitle: Option<String>,
+    pub authors: Option<Vec<Person>>,
+    pub narrators: Option<Vec<Person>>,
+    pub series: Option<Vec<Series>>,
+    pub publisher_name: Option<String>,
+    pub release_date: Option<String>,
+    /// When the title becomes available, e.g. `2023-05-02T04:00:00Z`
+    pub publication_datetime: Option<String>,
+    pub runtime_length_min: Option<u32>,
+}
+
+impl Product {
+    /// SKU to pass to the CDS download, the full SKU if known
+    pub fn download_sku(&self) -> Option<&str> {
+        self.sku.as_deref().or(self.sku_lite.as_deref())
+    }
+
+    /// When the title becomes available, if the catalog says
+    pub fn published_at(&self) -> Option<DateTime<Utc>> {
+        let datetime = self.publication_datetime.as_deref()?;
+
+        DateTime::parse_from_rfc3339(datetime)
+            .ok()
+            .map(|datetime| datetime.with_timezone(&Utc))
+    }
+
+    /// Whether the title is preordered and not available yet
+    pub fn is_preorder(&self) -> bool {
+        self.published_at().is_some_and(|at| at > Utc::now())
+    }
+
+    pub fn author_names(&self) -> String {
+        names(&self.authors)
+    }
+
+    pub fn narrator_names(&self) -> String {
+        names(&self.narrators)
+    }
+}
+
+fn names(people: &Option<Vec<Person>>) -> String {
+    people
+        .iter()
+        .flatten()
+        .map(|person| person.name.as_str())
+        .collect::<Vec<_>>()
+        .join(", ")
+}
+
+#[derive(Deserialize)]
+struct ProductResponse {
+    product: Product,
+}
+
+impl Client {
+    /// Catalog details of a single title
+    pub async fn product(&self, asin: &str) -> Result<Product> {
+        let path = format!("/1.0/catalog/products/{}", asin);
+        let res: ProductResponse = self
+            .get(&path, &[("response_groups", RESPONSE_GROUPS)])
+            .await?;
+
+        Ok(res.product)
+    }
+}
@@ -0,0 +1,55 @@
+use anyhow::{anyhow, Result};
+use serde::{Deserialize, Serialize};
+
+use super::Client;
+
+/// A collection of titles curated in the library, e.g. "Kids"
+#[derive(Deserialize, Serialize, Debug)]
+pub struct Collection {
+    pub collection_id: String,
+    pub name: String,
+    pub description: Option<String>,
+}
+
+#[derive(Deserialize)]
+struct CollectionsResponse {
+    #[serde(default)]
+    collections: Vec<Collection>,
+}
+
+#[derive(Deserialize)]
+struct CollectionItem {
+    asin: String,
+}
+
+#[derive(Deserialize)]
+struct CollectionItemsResponse {
+    #[serde(default)]
+    items: Vec<CollectionItem>,
+}
+
+impl Client {
+    /// All collections in the library, including the built in ones like favorites
+    pub async fn collections(&self) -> Result<Vec<Collection>> {
+        let res: CollectionsResponse = self.get("/1.0/collections", &[]).await?;
+
+        Ok(res.collections)
+    }
+
+    /// The collection named `name`, ignoring case
+    pub async fn collection(&self, name: &str) -> Result<Collection> {
+        self.collections()
+            .await?
+            .into_iter()
+            .find(|collection| collection.name.eq_ignore_ascii_case(name))
+            .ok_or_else(|| anyhow!("There is no collection named {:?} in your library", name))
+    }
+
+    /// ASINs of the titles in the collection with `collection_id`
+    pub async fn collection_items(&self, collection_id: &str) -> Result<Vec<String>> {
+        let path = format!("/1.0/collections/{}/items", collection_id);
+        let res: CollectionItemsResponse = self.get(&path, &[]).await?;
+
+        Ok(res.items.into_iter().map(|item| item.asin).collect())
+    }
+}
@@ -0,0 +1,166 @@
+//! Credentials from the cookies of a browser logged in to Audible.
+//!
+//! The cookies are read from a `cookies.txt` file in the Netscape format, as exported by browser
+//! extensions and `yt-dlp --cookies-from-browser`. They can't be refreshed, export them again
+//! once the session has expired.
+
+use std::path::Path;
+
+use anyhow::{anyhow, bail, Context, Result};
+use reqwest::header::HeaderValue;
+
+use super::auth::AuthProvider;
+
+#[derive(Debug, PartialEq, Eq)]
+struct Cookie {
+    /// Domain without the leading dot, e.g. `audible.com`
+    domain: String,
+    /// Whether the cookie is sent to subdomains of `domain` as well
+    subdomains: bool,
+    path: String,
+    secure: bool,
+    /// Expiry as a Unix timestamp, 0 for session cookies
+    expires: i64,
+    name: String,
+    value: String,
+}
+
+impl Cookie {
+    /// Whether the cookie is sent with a request to `url`
+    fn matches(&self, url: &reqwest::Url, now: i64) -> bool {
+        let host = url.host_str().unwrap_or_default();
+
+        let domain = host == self.domain
+            || (self.subdomains
+                && host
+                    .strip_suffix(self.domain.as_str())
+                    .is_some_and(|prefix| prefix.ends_with('.')));
+
+        domain
+            && url.path().starts_with(&self.path)
+            && (!self.secure || url.scheme() == "https")
+            && (self.expires == 0 || self.expires > now)
+    }
+}
+
+/// Cookies of a browser session
+#[derive(Debug)]
+pub struct Cookies {
+    cookies: Vec<Cookie>,
+}
+
+impl Cookies {
+    /// Read the cookies in the `cookies.txt` file at `path`
+    pub fn load(path: &Path) -> Result<Cookies> {
+        let contents = std::fs::read_to_string(path)
+            .with_context(|| format!("Failed to read cookies {}", path.display()))?;
+
+        let cookies =
+            parse(&contents).with_context(|| format!("Invalid cookies file {}", path.display()))?;
+
+        if !cookies
+            .iter()
+            .any(|cookie| cookie.domain.contains("audible."))
+        {
+            bail!(
+                "{} has no cookies for Audible, export them while logged in",
+                path.display()
+            );
+        }
+
+        Ok(Cookies { cookies })
+    }
+}
+
+fn parse(contents: &str) -> Result<Vec<Cookie>> {
+    let mut cookies = Vec::new();
+
+    for (index, line) in contents.lines().enumerate() {
+        // HttpOnly cookies are marked with a prefix that looks like a comment
+        let line = line.strip_prefix("#HttpOnly_").unwrap_or(line);
+
+        if line.trim().is_empty() || line.starts_with('#') {
+            continue;
+        }
+
+        let fields = line.split('\t').collect::<Vec<_>>();
+
+        let [domain, subdomains, path, secure, expires, name, value] = fields[..] else {
+            bail!("Line {} doesn't have the 7 fields of a cookie", index + 1);
+        };
+
+        cookies.push(Cookie {
+            domain: domain.trim_start_matches('.').to_owned(),
+            subdomains: subdomains.eq_ignore_ascii_case("TRUE"),
+            path: path.to_owned(),
+            secure: secure.eq_ignore_ascii_case("TRUE"),
+            expires: expires
+                .parse()
+                .map_err(|_| anyhow!("Invalid expiry on line {}", index + 1))?,
+            name: name.to_owned(),
+            value: value.to_owned(),
+        });
+    }
+
+    Ok(cookies)
+}
+
+impl AuthProvider for Cookies {
+    fn authenticate(&self, request: &mut reqwest::Request) -> Result<()> {
+        let now = chrono::Utc::now().timestamp();
+
+        let header = self
+            .cookies
+            .iter()
+            .filter(|cookie| cookie.matches(request.url(), now))
+            .map(|cookie| format!("{}={}", cookie.name, cookie.value))
+            .collect::<Vec<_>>()
+            .join("; ");
+
+        if header.is_empty() {
+            bail!(
+                "None of the cookies are for {}, or they have expired, export them again",
+                request.url().host_str().unwrap_or_default()
+            );
+        }
+
+        request
+            .headers_mut()
+            .insert("Cookie", HeaderValue::try_from(header)?);
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const COOKIES: &str = "# Netscape HTTP Cookie File\n\
+        .audible.com\tTRUE\t/\tTRUE\t0\tsession-id\t123\n\
+        #HttpOnly_.audible.com\tTRUE\t/\tTRUE\t4102444800\tat-main\tAtza|abc\n\
+        www.audible.com\tFALSE\t/\tFALSE\t4102444800\tubid-main\t456\n\
+        .audible.de\tTRUE\t/\tTRUE\t1\tsession-id\texpired\n";
+
+    #[test]
+    fn matches_cookies() {
+        let cookies = Cookies {
+            cookies: parse(COOKIES).unwrap(),
+        };
+
+        let url = "https://api.audible.com/1.0/library".parse().unwrap();
+        let mut request = reqwest::Request::new(reqwest::Method::GET, url);
+        cookies.authenticate(&mut request).unwrap();
+
+        assert_eq!(
+            request.headers()["Cookie"],
+            "session-id=123; at-main=Atza|abc"
+        );
+
+        let url = "https://api.audible.de/1.0/library".parse().unwrap();
+        let mut request = reqwest::Request::new(reqwest::Method::GET, url);
+        assert!(cookies.authenticate(&mut request).is_err());
+
+        assert!(parse("audible.com\tTRUE\t/").is_err());
+    }
+}
@@ -0,0 +1,108 @@
+//! Credentials printed by a command, so that they can come from a password manager or whatever
+//! other tooling already logs in to Audible.
+//!
+//! The command prints a JSON object with the fields of an audible-cli auth file, at least an
+//! `access_token`, or an `adp_token` and `device_private_key`, and optionally `expires` and
+//! `locale_code`. It's run again once the access token expires, or when the API rejects it.
+//! Nothing is ever written back.
+
+use std::process::Command;
+
+use anyhow::{bail, Context, Result};
+use serde_json::{Map, Value};
+
+use super::auth::{Auth, AuthProvider, Refresh};
+
+/// Credentials from the output of a command
+pub struct ExternalCommand {
+    command: String,
+    auth: Auth,
+    clock_offset: chrono::Duration,
+}
+
+impl ExternalCommand {
+    /// Run `command` with the shell, and use the credentials it prints
+    pub fn run(command: &str) -> Result<ExternalCommand> {
+        Ok(ExternalCommand {
+            command: command.to_owned(),
+            auth: credentials(command)?,
+            clock_offset: chrono::Duration::zero(),
+        })
+    }
+}
+
+fn credentials(command: &str) -> Result<Auth> {
+    let output = if cfg!(windows) {
+        Command::new("cmd").arg("/C").arg(command).output()
+    } else {
+        Command::new("sh").arg("-c").arg(command).output()
+    }
+    .with_context(|| format!("Failed to run auth command {:?}", command))?;
+
+    if !output.status.success() {
+        bail!(
+            "Auth command {:?} failed ({}): {}",
+            command,
+            output.status,
+            String::from_utf8_lossy(&output.stderr).trim()
+        );
+    }
+
+    let data: Map<String, Value> = serde_json::from_slice(&output.stdout).with_context(|| {
+        format!(
+            "Auth command {:?} didn't print a JSON object of credentials",
+            command
+        )
+    })?;
+
+    let mut auth = Auth::from_data(None, data)?;
+    auth.set_ephemeral();
+
+    if !auth.is_signed() && auth.access_token().is_none() {
+        bail!(
+            "Auth command {:?} printed neither an access token nor a device key",
+            command
+        );
+    }
+
+    Ok(auth)
+}
+
+impl AuthProvider for ExternalCommand {
+    fn locale_code(&self) -> Option<&str> {
+        self.auth.locale_code()
+    }
+
+    fn is_signed(&self) -> bool {
+        self.auth.is_signed()
+    }
+
+    fn set_clock_offset(&mut self, offset: chrono::Duration) {
+        self.clock_offset = offset;
+        self.auth.set_clock_offset(offset);
+    }
+
+    fn can_refresh(&self) -> bool {
+        true
+    }
+
+    fn needs_refresh(&self) -> bool {
+        self.auth.expired()
+    }
+
+    fn refresh<'a>(&'a mut self, _http: &'a reqwest::Client, _domain: &'a str) -> Refresh<'a> {
+        Box::pin(async move {
+            let command = self.command.clone();
+            let mut auth = tokio::task::spawn_blocking(move || credentials(&command)).await??;
+
+            auth.set_clock_offset(self.clock_offset);
+            self.auth = auth;
+
+            Ok(())
+        })
+    }
+
+    fn authenticate(&self, request: &mut reqwest::Request) -> Result<()> {
+        self.auth.authenticate(request)
+    }
+}
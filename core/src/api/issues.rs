@@ -0,0 +1,132 @@
+//! Issues of periodicals, such as the podcasts and newspapers of a subscription.
+//!
+//! A periodical is a title of its own in the library, but has nothing to download. Each issue
+//! has its own ASIN and SKU, and is listed as a child in the relationships of the periodical.
+
+use anyhow::{bail, Result};
+use serde::{Deserialize, Serialize};
+
+use super::Client;
+
+/// An issue of a periodical
+#[derive(Deserialize, Serialize, Debug)]
+pub struct Issue {
+    pub asin: String,
+    pub sku: Option<String>,
+    pub sku_lite: Option<String>,
+    pub title: Option<String>,
+    /// Number of the issue, if the publisher numbers them
+    pub sequence: Option<String>,
+    /// Position of the issue in the periodical, the oldest first
+    pub sort: Option<String>,
+}
+
+impl Issue {
+    /// SKU to pass to the CDS download, the full SKU if known
+    pub fn download_sku(&self) -> Option<&str> {
+        self.sku.as_deref().or(self.sku_lite.as_deref())
+    }
+
+    /// Key to order issues by, oldest first
+    fn position(&self) -> (u64, u64) {
+        let number = |value: &Option<String>| {
+            value
+                .as_deref()
+                .and_then(|value| value.parse().ok())
+                .unwrap_or(0)
+        };
+
+        (number(&self.sort), number(&self.sequence))
+    }
+}
+
+#[derive(Deserialize)]
+struct Relationship {
+    relationship_to_product: String,
+    relationship_type: String,
+    #[serde(flatten)]
+    issue: Issue,
+}
+
+#[derive(Deserialize)]
+struct Relationships {
+    #[serde(default)]
+    relationships: Vec<Relationship>,
+}
+
+#[derive(Deserialize)]
+struct RelationshipsResponse {
+    product: Relationships,
+}
+
+/// The issues among `relationships`, oldest first
+fn issues(relationships: Vec<Relationship>) -> Vec<Issue> {
+    let mut issues = relationships
+        .into_iter()
+        .filter(|relationship| {
+            relationship.relationship_to_product == "child"
+                && matches!(relationship.relationship_type.as_str(), "issue" | "episode")
+        })
+        .map(|relationship| relationship.issue)
+        .collect::<Vec<_>>();
+
+    issues.sort_by_key(Issue::position);
+    issues
+}
+
+/// The last `count` of `issues`, all of them without a count
+pub fn latest(mut issues: Vec<Issue>, count: Option<usize>) -> Vec<Issue> {
+    if let Some(count) = count {
+        issues.drain(..issues.len().saturating_sub(count));
+    }
+
+    issues
+}
+
+impl Client {
+    /// Issues of the periodical with `asin`, oldest first
+    pub async fn issues(&self, asin: &str) -> Result<Vec<Issue>> {
+        let path = format!("/1.0/catalog/products/{}", asin);
+        let res: RelationshipsResponse = self
+            .get(&path, &[("response_groups", "relationships")])
+            .await?;
+
+        let issues = issues(res.product.relationships);
+
+        if issues.is_empty() {
+            bail!("{} has no issues, it isn't a periodical", asin);
+        }
+
+        Ok(issues)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn orders_issues() {
+        let res: RelationshipsResponse = serde_json::from_str(
+            r#"{"product": {"relationships": [
+                {"asin": "B0C", "sku_lite": "BK_ADBL_000003", "sort": "3", "relationship_to_product": "child", "relationship_type": "issue"},
+                {"asin": "B0A", "sku": "BK_ADBL_000001_22", "sort": "1", "relationship_to_product": "child", "relationship_type": "issue"},
+                {"asin": "B0S", "sort": "1", "relationship_to_product": "parent", "relationship_type": "series"},
+                {"asin": "B0B", "sort": "2", "relationship_to_product": "child", "relationship_type": "issue"}
+            ]}}"#,
+        )
+        .unwrap();
+
+        let issues = issues(res.product.relationships);
+        let asins = |issues: &[Issue]| {
+            issues
+                .iter()
+                .map(|issue| issue.asin.clone())
+                .collect::<Vec<_>>()
+        };
+
+        assert_eq!(asins(&issues), ["B0A", "B0B", "B0C"]);
+        assert_eq!(issues[2].download_sku(), Some("BK_ADBL_000003"));
+        assert_eq!(asins(&latest(issues, Some(2))), ["B0B", "B0C"]);
+    }
+}
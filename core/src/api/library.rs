@@ -0,0 +1,50 @@
+use anyhow::Result;
+use serde::{Deserialize, Serialize};
+
+use super::catalog::{Product, RESPONSE_GROUPS};
+use super::Client;
+
+const PAGE_SIZE: usize = 1000;
+
+#[derive(Deserialize, Serialize, Debug)]
+pub struct Item {
+    #[serde(flatten)]
+    pub product: Product,
+    pub purchase_date: Option<String>,
+}
+
+#[derive(Deserialize)]
+struct LibraryResponse {
+    items: Vec<Item>,
+}
+
+impl Client {
+    /// All titles in the library, fetched page by page
+    pub async fn library(&self) -> Result<Vec<Item>> {
+        let mut items = Vec::new();
+        let page_size = PAGE_SIZE.to_string();
+
+        for page in 1.. {
+            let page = page.to_string();
+            let res: LibraryResponse = self
+                .get(
+                    "/1.0/library",
+                    &[
+                        ("num_results", &page_size),
+                        ("page", &page),
+                        ("response_groups", RESPONSE_GROUPS),
+                    ],
+                )
+                .await?;
+
+            let len = res.items.len();
+            items.extend(res.items);
+
+            if len < PAGE_SIZE {
+                break;
+            }
+        }
+
+        Ok(items)
+    }
+}
@@ -0,0 +1,67 @@
+use anyhow::{anyhow, Result};
+use serde::{Deserialize, Serialize};
+
+use super::Client;
+
+#[derive(Serialize)]
+struct LicenseRequest {
+    drm_type: &'static str,
+    consumption_type: &'static str,
+    quality: &'static str,
+}
+
+#[derive(Deserialize)]
+struct LicenseResponse {
+    content_license: ContentLicense,
+}
+
+#[derive(Deserialize, Debug)]
+pub struct ContentLicense {
+    pub status_code: String,
+    pub message: Option<String>,
+    pub content_metadata: Option<ContentMetadata>,
+}
+
+#[derive(Deserialize, Debug)]
+pub struct ContentMetadata {
+    pub content_url: ContentUrl,
+}
+
+#[derive(Deserialize, Debug)]
+pub struct ContentUrl {
+    pub offline_url: String,
+}
+
+impl ContentLicense {
+    /// Signed URL the title can be downloaded from
+    pub fn download_url(&self) -> Result<&str> {
+        if self.status_code != "Granted" {
+            return Err(anyhow!(
+                "License was not granted ({}): {}",
+                self.status_code,
+                self.message.as_deref().unwrap_or("no reason given")
+            ));
+        }
+
+        self.content_metadata
+            .as_ref()
+            .map(|metadata| metadata.content_url.offline_url.as_str())
+            .ok_or_else(|| anyhow!("License doesn't contain a download URL"))
+    }
+}
+
+impl Client {
+    /// Request a download license for `asin`
+    pub async fn license(&self, asin: &str) -> Result<ContentLicense> {
+        let path = format!("/1.0/content/{}/licenserequest", asin);
+        let body = LicenseRequest {
+            drm_type: "Adrm",
+            consumption_type: "Download",
+            quality: "High",
+        };
+
+        let res: LicenseResponse = self.post(&path, &body).await?;
+
+        Ok(res.content_license)
+    }
+}
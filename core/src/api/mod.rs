@@ -0,0 +1,277 @@
+//! Client for the official Audible API, as used by the Audible apps.
+//!
+//! All endpoints share the same authentication and error handling, each endpoint lives in its
+//! own module with typed request and response structs.
+
+use std::path::Path;
+use std::sync::atomic::{AtomicBool, Ordering};
+
+use anyhow::{anyhow, Context, Result};
+use reqwest::{Method, RequestBuilder, Response, StatusCode};
+use serde::de::DeserializeOwned;
+use serde::Deserialize;
+use tokio::sync::Mutex;
+
+pub mod auth;
+pub mod catalog;
+pub mod collections;
+pub mod cookies;
+pub mod external;
+pub mod issues;
+pub mod library;
+pub mod license;
+pub mod stats;
+
+use crate::frontend::{Frontend, Hidden};
+use auth::{Auth, AuthProvider};
+
+/// Warn when the clock differs this much from the one of the API servers
+const MAX_CLOCK_SKEW_SECS: i64 = 60;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+#[cfg_attr(feature = "clap", derive(clap::ValueEnum))]
+pub enum Marketplace {
+    Us,
+    Uk,
+    De,
+    Fr,
+    Ca,
+    Au,
+    In,
+    It,
+    Jp,
+    Es,
+    Br,
+}
+
+impl Marketplace {
+    pub fn from_locale_code(code: &str) -> Option<Marketplace> {
+        match code.to_ascii_lowercase().as_str() {
+            "us" => Some(Marketplace::Us),
+            "uk" => Some(Marketplace::Uk),
+            "de" => Some(Marketplace::De),
+            "fr" => Some(Marketplace::Fr),
+            "ca" => Some(Marketplace::Ca),
+            "au" => Some(Marketplace::Au),
+            "in" => Some(Marketplace::In),
+            "it" => Some(Marketplace::It),
+            "jp" => Some(Marketplace::Jp),
+            "es" => Some(Marketplace::Es),
+            "br" => Some(Marketplace::Br),
+            _ => None,
+        }
+    }
+
+    /// Top level domain of the marketplace, e.g. `co.uk`
+    pub fn domain(self) -> &'static str {
+        match self {
+            Marketplace::Us => "com",
+            Marketplace::Uk => "co.uk",
+            Marketplace::De => "de",
+            Marketplace::Fr => "fr",
+            Marketplace::Ca => "ca",
+            Marketplace::Au => "com.au",
+            Marketplace::In => "in",
+            Marketplace::It => "it",
+            Marketplace::Jp => "co.jp",
+            Marketplace::Es => "es",
+            Marketplace::Br => "com.br",
+        }
+    }
+}
+
+/// Error body returned by the API
+#[derive(Deserialize)]
+struct ErrorResponse {
+    message: Option<String>,
+    error_code: Option<String>,
+}
+
+pub struct Client {
+    http: reqwest::Client,
+    auth: Mutex<Box<dyn AuthProvider>>,
+    marketplace: Marketplace,
+    fix_clock_skew: bool,
+    /// Whether the clock skew was already reported
+    skew_reported: AtomicBool,
+    /// Where warnings, such as the one about the clock skew, go
+    frontend: Box<dyn Frontend + Send + Sync>,
+}
+
+impl Client {
+    /// Client using the credentials in `auth_file`, as created by `audible quickstart` from
+    /// audible-cli. The marketplace defaults to the one in the auth file. With `fix_clock_skew`
+    /// requests are signed with the time of the API servers when the local clock is off.
+    pub fn new(
+        http: reqwest::Client,
+        auth_file: &Path,
+        marketplace: Option<Marketplace>,
+        fix_clock_skew: bool,
+    ) -> Result<Client> {
+        let auth = Auth::load(auth_file)?;
+
+        Client::with_auth(http, Box::new(auth), marketplace, fix_clock_skew)
+    }
+
+    /// Client using the credentials of `auth`, e.g. cookies or those printed by a command
+    pub fn with_auth(
+        http: reqwest::Client,
+        auth: Box<dyn AuthProvider>,
+        marketplace: Option<Marketplace>,
+        fix_clock_skew: bool,
+    ) -> Result<Client> {
+        let marketplace = marketplace
+            .or_else(|| auth.locale_code().and_then(Marketplace::from_locale_code))
+            .ok_or_else(|| anyhow!("Unknown marketplace, pass one with --marketplace"))?;
+
+        Ok(Client {
+            http,
+            auth: Mutex::new(auth),
+            marketplace,
+            fix_clock_skew,
+            skew_reported: AtomicBool::new(false),
+            frontend: Box::new(Hidden),
+        })
+    }
+
+    /// Never save refreshed tokens to the auth file, e.g. on a shared machine
+    pub fn ephemeral(mut self) -> Client {
+        self.auth.get_mut().set_ephemeral();
+        self
+    }
+
+    /// Show warnings, e.g. that the local clock is off, with `frontend`
+    pub fn with_frontend(mut self, frontend: impl Frontend + Send + Sync + 'static) -> Client {
+        self.frontend = Box::new(frontend);
+        self
+    }
+
+    pub fn marketplace(&self) -> Marketplace {
+        self.marketplace
+    }
+
+    fn request(&self, method: Method, path: &str) -> RequestBuilder {
+        let url = format!("https://api.audible.{}{}", self.marketplace.domain(), path);
+
+        self.http.request(method, url)
+    }
+
+    pub async fn get<T: DeserializeOwned>(&self, path: &str, query: &[(&str, &str)]) -> Result<T> {
+        self.send(self.request(Method::GET, path).query(query))
+            .await
+    }
+
+    pub async fn post<T: DeserializeOwned>(
+        &self,
+        path: &str,
+        body: &impl serde::Serialize,
+    ) -> Result<T> {
+        self.send(self.request(Method::POST, path).json(body)).await
+    }
+
+    async fn send<T: DeserializeOwned>(&self, request: RequestBuilder) -> Result<T> {
+        let request = request.build()?;
+        let retry = request
+            .try_clone()
+            .expect("API requests have in-memory bodies");
+
+        let mut res = self.execute(request, false).await?;
+        let rejected = matches!(
+            res.status(),
+            StatusCode::UNAUTHORIZED | StatusCode::FORBIDDEN
+        );
+
+        if self.check_clock(&res).await && rejected {
+            // The signature was made with the wrong time, sign it again with the corrected one
+            res = self.execute(retry, false).await?;
+        } else if res.status() == StatusCode::UNAUTHORIZED && self.auth.lock().await.can_refresh() {
+            // The access token might have been revoked or expired early, refresh it and try again
+            res = self.execute(retry, true).await?;
+        }
+
+        let status = res.status();
+        let url = res.url().path().to_owned();
+
+        if !status.is_success() {
+            let body: Option<ErrorResponse> = res.json().await.ok();
+            let message = body
+                .and_then(|body| body.message.or(body.error_code))
+                .unwrap_or_else(|| status.canonical_reason().unwrap_or("").to_owned());
+
+            return Err(anyhow!(
+                "Audible API request to {} failed ({}): {}",
+                url,
+                status,
+                message
+            ));
+        }
+
+        res.json()
+            .await
+            .with_context(|| format!("Invalid response from Audible API for {}", url))
+    }
+
+    /// Warn the frontend, once, when the local clock is off compared to the `Date` of `res`, since signed
+    /// requests are rejected when their time is too far off. With `fix_clock_skew` the
+    /// difference is added when signing from now on, in which case `true` is returned.
+    async fn check_clock(&self, res: &Response) -> bool {
+        let Some(skew) = clock_skew(res) else {
+            return false;
+        };
+
+        if skew.num_seconds().abs() < MAX_CLOCK_SKEW_SECS
+            || self.skew_reported.swap(true, Ordering::Relaxed)
+        {
+            return false;
+        }
+
+        let direction = if skew > chrono::Duration::zero() {
+            "behind"
+        } else {
+            "ahead of"
+        };
+
+        self.frontend.warn(&format!(
+            "The local clock is {} seconds {} the Audible servers, signed requests might be rejected",
+            skew.num_seconds().abs(),
+            direction
+        ));
+
+        let mut auth = self.auth.lock().await;
+
+        if !self.fix_clock_skew || !auth.is_signed() {
+            return false;
+        }
+
+        self.frontend
+            .warn("Signing requests with the time of the Audible servers instead");
+        auth.set_clock_offset(skew);
+
+        true
+    }
+
+    async fn execute(
+        &self,
+        mut request: reqwest::Request,
+        force_refresh: bool,
+    ) -> Result<Response> {
+        let mut auth = self.auth.lock().await;
+
+        if force_refresh || auth.needs_refresh() {
+            auth.refresh(&self.http, self.marketplace.domain()).await?;
+        }
+
+        auth.authenticate(&mut request)?;
+        drop(auth);
+
+        Ok(self.http.execute(request).await?)
+    }
+}
+
+/// How far the `Date` of `res` is ahead of the local clock
+fn clock_skew(res: &Response) -> Option<chrono::Duration> {
+    let date = res.headers().get(reqwest::header::DATE)?.to_str().ok()?;
+    let date = chrono::DateTime::parse_from_rfc2822(date).ok()?;
+
+    Some(date.with_timezone(&chrono::Utc) - chrono::Utc::now())
+}
@@ -0,0 +1,48 @@
+use anyhow::Result;
+use serde::Deserialize;
+use serde_json::Value;
+
+use super::Client;
+
+/// Listening time within one interval, e.g. a month
+#[derive(Deserialize, Debug)]
+pub struct ListeningStat {
+    /// The interval, e.g. `2023-04` for a month
+    pub interval_identifier: String,
+    /// Listening time in milliseconds
+    pub aggregated_sum: u64,
+}
+
+#[derive(Deserialize)]
+struct AggregatesResponse {
+    #[serde(default)]
+    aggregated_monthly_listening_stats: Vec<ListeningStat>,
+}
+
+impl Client {
+    /// Listening time of each month of `year`
+    pub async fn monthly_listening(&self, year: i32) -> Result<Vec<ListeningStat>> {
+        let start = format!("{}-01", year);
+        let res: AggregatesResponse = self
+            .get(
+                "/1.0/stats/aggregates",
+                &[
+                    ("monthly_listening_interval_duration", "12"),
+                    ("monthly_listening_interval_start_date", &start),
+                    ("store", "Audible"),
+                ],
+            )
+            .await?;
+
+        Ok(res.aggregated_monthly_listening_stats)
+    }
+
+    /// Badges earned by the account, as returned by the API
+    pub async fn badges(&self) -> Result<Value> {
+        self.get(
+            "/1.0/badges/progress",
+            &[("response_groups", "brag_message"), ("store", "Audible")],
+        )
+        .await
+    }
+}
@@ -0,0 +1,157 @@
+use std::path::{Path, PathBuf};
+
+use anyhow::{Context, Result};
+use sha2::{Digest, Sha256};
+use tokio::io::{AsyncReadExt, AsyncSeekExt};
+
+/// SHA-256 of a partial download, computed as the data is written.
+///
+/// The bytes already on disk when a download is resumed are hashed once from the file, after
+/// that only new data is hashed, so the whole book doesn't have to be read again at the end.
+#[derive(Debug, Default)]
+pub struct Checksum {
+    hasher: Sha256,
+    /// Number of bytes hashed so far
+    len: u64,
+}
+
+impl Checksum {
+    pub fn new() -> Checksum {
+        Checksum::default()
+    }
+
+    /// Make sure the first `len` bytes of `part` are hashed, reading them from the file as needed
+    pub async fn catch_up(&mut self, part: &Path, len: u64) -> Result<()> {
+        // The partial download was removed or cut off since, start over
+        if self.len > len {
+            *self = Checksum::new();
+        }
+
+        if self.len == len {
+            return Ok(());
+        }
+
+        let mut file = tokio::fs::File::open(part)
+            .await
+            .with_context(|| format!("Failed to open {}", part.display()))?;
+        file.seek(std::io::SeekFrom::Start(self.len)).await?;
+
+        let mut file = file.take(len - self.len);
+        let mut buf = vec![0; 1024 * 1024];
+
+        loop {
+            let read = file.read(&mut buf).await?;
+
+            if read == 0 {
+                break;
+            }
+
+            self.hasher.update(&buf[..read]);
+            self.len += read as u64;
+        }
+
+        Ok(())
+    }
+
+    /// Hash `chunk` written at `offset`, skipping any bytes that were hashed already.
+    ///
+    /// A chunk past what was hashed so far leaves a gap, it's read from the file by
+    /// [`Checksum::catch_up`] instead.
+    pub fn update(&mut self, offset: u64, chunk: &[u8]) {
+        let Some(hashed) = self.len.checked_sub(offset) else {
+            return;
+        };
+
+        if let Some(new) = chunk.get(hashed as usize..) {
+            self.hasher.update(new);
+            self.len += new.len() as u64;
+        }
+    }
+
+    /// Hex encoded checksum of everything hashed
+    pub fn finish(self) -> String {
+        self.hasher
+            .finalize()
+            .iter()
+            .map(|byte| format!("{:02x}", byte))
+            .collect()
+    }
+}
+
+/// Write `checksum` of `output` to `<output>.sha256`, in the format of `sha256sum`
+pub async fn write(output: &Path, checksum: &str) -> Result<PathBuf> {
+    let mut path = output.as_os_str().to_owned();
+    path.push(".sha256");
+    let path = PathBuf::from(path);
+
+    let name = output.file_name().unwrap_or_default().to_string_lossy();
+
+    tokio::fs::write(&path, format!("{}  {}\n", checksum, name))
+        .await
+        .with_context(|| format!("Failed to write {}", path.display()))?;
+
+    Ok(path)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn sha256(data: &[u8]) -> String {
+        Sha256::digest(data)
+            .iter()
+            .map(|byte| format!("{:02x}", byte))
+            .collect()
+    }
+
+    fn block_on<F: std::future::Future>(future: F) -> F::Output {
+        tokio::runtime::Builder::new_current_thread()
+            .build()
+            .unwrap()
+            .block_on(future)
+    }
+
+    #[test]
+    fn hashes_overlapping_chunks_once() {
+        let data: Vec<u8> = (0..=255).collect();
+        let mut checksum = Checksum::new();
+
+        checksum.update(0, &data[..100]);
+        // Retried from an earlier offset, and entirely old
+        checksum.update(50, &data[50..150]);
+        checksum.update(10, &data[10..20]);
+        // Past what was hashed, left for `catch_up`
+        checksum.update(200, &data[200..]);
+        checksum.update(150, &data[150..200]);
+
+        assert_eq!(checksum.len, 200);
+        assert_eq!(checksum.finish(), sha256(&data[..200]));
+    }
+
+    #[test]
+    fn rehashes_prefix_once_across_resumes() {
+        let data: Vec<u8> = (0..=255).cycle().take(10_000).collect();
+        let part = std::env::temp_dir().join(format!("audible-dl-checksum-{}", std::process::id()));
+        std::fs::write(&part, &data[..4_000]).unwrap();
+
+        let checksum = block_on(async {
+            let mut checksum = Checksum::new();
+
+            // Resumed at 3000, then at 4000 after the connection dropped
+            checksum.catch_up(&part, 3_000).await?;
+            checksum.update(3_000, &data[3_000..3_500]);
+            checksum.catch_up(&part, 4_000).await?;
+            checksum.update(3_500, &data[3_500..6_000]);
+            checksum.update(6_000, &data[6_000..]);
+
+            std::fs::write(&part, &data)?;
+            checksum.catch_up(&part, data.len() as u64).await?;
+
+            anyhow::Ok(checksum.finish())
+        });
+
+        std::fs::remove_file(&part).unwrap();
+
+        assert_eq!(checksum.unwrap(), sha256(&data));
+    }
+}
@@ -0,0 +1,96 @@
+use std::fs::File;
+use std::io::Read;
+use std::path::Path;
+
+use anyhow::Result;
+
+use crate::mp4;
+
+/// Container format of a downloaded book
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Format {
+    Aax,
+    Aaxc,
+    M4b,
+    Mp3,
+}
+
+impl Format {
+    pub fn extension(self) -> &'static str {
+        match self {
+            Format::Aax => "aax",
+            Format::Aaxc => "aaxc",
+            Format::M4b => "m4b",
+            Format::Mp3 => "mp3",
+        }
+    }
+
+    pub fn description(self) -> &'static str {
+        match self {
+            Format::Aax => "DRM protected AAX",
+            Format::Aaxc => "DRM protected AAXC",
+            Format::M4b => "DRM free M4B",
+            Format::Mp3 => "DRM free MP3",
+        }
+    }
+
+    /// Whether `path` has an extension that fits this format
+    pub fn matches(self, path: &Path) -> bool {
+        let Some(ext) = path.extension().and_then(|ext| ext.to_str()) else {
+            return false;
+        };
+
+        match self {
+            Format::M4b => ["m4b", "m4a", "mp4"]
+                .iter()
+                .any(|candidate| ext.eq_ignore_ascii_case(candidate)),
+            format => ext.eq_ignore_ascii_case(format.extension()),
+        }
+    }
+
+    pub fn from_content_type(content_type: &str) -> Option<Format> {
+        let mime = content_type.split(';').next()?.trim().to_ascii_lowercase();
+
+        match mime.as_str() {
+            "audio/vnd.audible.aax" | "audio/aax" => Some(Format::Aax),
+            "audio/vnd.audible.aaxc" | "audio/aaxc" => Some(Format::Aaxc),
+            "audio/mp4" | "audio/m4b" | "audio/x-m4b" | "audio/x-m4a" => Some(Format::M4b),
+            "audio/mpeg" | "audio/mp3" => Some(Format::Mp3),
+            _ => None,
+        }
+    }
+
+    /// Detect the format from the contents of `path`.
+    ///
+    /// AAX and AAXC files can't always be told apart by their contents, in that case `hint`
+    /// (usually from the Content-Type header) decides.
+    pub fn detect(path: &Path, hint: Option<Format>) -> Result<Option<Format>> {
+        let mut file = File::open(path)?;
+        let mut magic = [0; 12];
+
+        if let Err(e) = file.read_exact(&mut magic) {
+            return match e.kind() {
+                std::io::ErrorKind::UnexpectedEof => Ok(None),
+                _ => Err(e.into()),
+            };
+        }
+
+        if &magic[..3] == b"ID3" || (magic[0] == 0xff && magic[1] & 0xe0 == 0xe0) {
+            return Ok(Some(Format::Mp3));
+        }
+
+        if &magic[4..8] != b"ftyp" {
+            return Ok(None);
+        }
+
+        Ok(Some(match &magic[8..12] {
+            b"aax " => Format::Aax,
+            b"aaxc" => Format::Aaxc,
+            _ if mp4::sample_entries(&mut file)?.contains(b"aavd") => match hint {
+                Some(Format::Aaxc) => Format::Aaxc,
+                _ => Format::Aax,
+            },
+            _ => Format::M4b,
+        }))
+    }
+}
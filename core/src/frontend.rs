@@ -0,0 +1,54 @@
+use anyhow::Result;
+
+/// What a long running operation is doing, for a frontend to pick how to show its progress
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Stage {
+    /// Waiting or preparing, with nothing to count yet, see the message
+    Waiting,
+    /// Data being downloaded
+    Downloading,
+    /// A file being copied or processed, see the message
+    Working,
+}
+
+/// What long running operations need from the program running them: showing their progress,
+/// and asking the user.
+///
+/// Every method does nothing by default, so a frontend only has to implement what it can show.
+/// Lengths and positions are in bytes.
+pub trait Frontend {
+    fn set_stage(&self, _stage: Stage) {}
+
+    fn set_message(&self, _message: &str) {}
+
+    fn set_length(&self, _len: u64) {}
+
+    /// Move to `position`, starting a new estimate of the time left
+    fn set_position(&self, _position: u64) {}
+
+    fn inc(&self, _delta: u64) {}
+
+    /// Details of what's going on, e.g. errors that are retried
+    fn log(&self, _line: &str) {}
+
+    /// Something the user should know about, that doesn't stop the operation
+    fn warn(&self, _message: &str) {}
+
+    /// A request or transfer failed with `error` and is retried, after `failures` failed
+    /// attempts in a row
+    fn retry(&self, _failures: u32, _error: &str) {}
+
+    /// The transfer rate in bytes per second, sampled every second while data is received
+    fn rate(&self, _position: u64, _total: u64, _rate: u64) {}
+
+    /// Ask the user a yes or no question, answering no when there is no one to ask
+    fn confirm(&self, _question: &str) -> Result<bool> {
+        Ok(false)
+    }
+}
+
+/// A frontend that shows nothing and answers no to every question
+#[derive(Clone, Copy, Debug, Default)]
+pub struct Hidden;
+
+impl Frontend for Hidden {}
@@ -0,0 +1,18 @@
+//! The download engine, Audible API client and AAX tooling of
+//! [audible-dl](https://github.com/LinusU/audible-dl), for programs that want to download or
+//! convert Audible books without its command line interface.
+//!
+//! Progress is reported, and questions are asked, through a [`Frontend`] implemented by the
+//! program.
+
+pub mod aax;
+pub mod api;
+pub mod checksum;
+pub mod format;
+pub mod frontend;
+pub mod mp4;
+pub mod rangedl;
+pub mod sidecar;
+pub mod speed;
+
+pub use frontend::{Frontend, Hidden, Stage};
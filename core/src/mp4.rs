@@ -0,0 +1,533 @@
+use std::fs::File;
+use std::io::{Read, Seek, SeekFrom};
+use std::path::Path;
+use std::time::Duration;
+
+use anyhow::{anyhow, bail, Result};
+
+/// Boxes that contain nothing but other boxes
+const CONTAINERS: [&[u8; 4]; 11] = [
+    b"moov", b"trak", b"mdia", b"minf", b"stbl", b"dinf", b"edts", b"udta", b"mvex", b"moof",
+    b"traf",
+];
+
+pub struct BoxHeader {
+    pub kind: [u8; 4],
+    pub header_len: u64,
+    /// Total size including the header, `None` if the box extends to the end of the file
+    pub size: Option<u64>,
+}
+
+impl BoxHeader {
+    /// Read the header of the box starting at `offset`
+    pub fn read(file: &mut File, offset: u64) -> Result<BoxHeader> {
+        let mut buf = [0; 8];
+        file.seek(SeekFrom::Start(offset))?;
+        file.read_exact(&mut buf)?;
+
+        let kind = [buf[4], buf[5], buf[6], buf[7]];
+
+        if !kind
+            .iter()
+            .all(|&b| b.is_ascii_alphanumeric() || b == b' ' || b == 0xa9)
+        {
+            bail!("Invalid MP4 box type {:02x?} at offset {}", kind, offset);
+        }
+
+        let (header_len, size) = match u32::from_be_bytes([buf[0], buf[1], buf[2], buf[3]]) {
+            0 => (8, None),
+            1 => {
+                file.read_exact(&mut buf)?;
+                (16, Some(u64::from_be_bytes(buf)))
+            }
+            size => (8, Some(u64::from(size))),
+        };
+
+        if size.is_some_and(|size| size < header_len) {
+            bail!("Invalid MP4 box size at offset {}", offset);
+        }
+
+        Ok(BoxHeader {
+            kind,
+            header_len,
+            size,
+        })
+    }
+
+    pub fn kind_str(&self) -> String {
+        String::from_utf8_lossy(&self.kind).into_owned()
+    }
+}
+
+/// Location of a box within a file
+#[derive(Clone)]
+pub struct Child {
+    pub kind: [u8; 4],
+    /// Offset of the box header
+    pub start: u64,
+    /// Offset of the box contents, right after the header
+    pub body: u64,
+    /// Offset right after the end of the box
+    pub end: u64,
+}
+
+/// All boxes directly within `start..end`
+pub fn children(file: &mut File, start: u64, end: u64) -> Result<Vec<Child>> {
+    let mut result = Vec::new();
+    let mut pos = start;
+
+    while end.saturating_sub(pos) >= 8 {
+        let header = BoxHeader::read(file, pos)?;
+        let size = header.size.unwrap_or(end - pos);
+
+        // A corrupt size would otherwise have `read_body` allocate whatever it claims
+        if size > end - pos {
+            bail!(
+                "MP4 box {} at offset {} overflows its parent",
+                header.kind_str(),
+                pos
+            );
+        }
+
+        result.push(Child {
+            kind: header.kind,
+            start: pos,
+            body: pos + header.header_len,
+            end: pos + size,
+        });
+
+        pos += size;
+    }
+
+    Ok(result)
+}
+
+/// First box directly within `parent` of the given kind
+pub fn child(file: &mut File, parent: &Child, kind: &[u8; 4]) -> Result<Option<Child>> {
+    Ok(children(file, parent.body, parent.end)?
+        .into_iter()
+        .find(|child| &child.kind == kind))
+}
+
+/// Read the contents of `child`
+pub fn read_body(file: &mut File, child: &Child) -> Result<Vec<u8>> {
+    if child.body > child.end || child.end > file.metadata()?.len() {
+        bail!(
+            "MP4 box {} at offset {} extends past the end of the file",
+            String::from_utf8_lossy(&child.kind),
+            child.start
+        );
+    }
+
+    let mut buf = vec![0; usize::try_from(child.end - child.body)?];
+    file.seek(SeekFrom::Start(child.body))?;
+    file.read_exact(&mut buf)?;
+    Ok(buf)
+}
+
+/// A track, located by its media and sample table boxes
+pub struct Track {
+    pub mdia: Child,
+    pub stbl: Child,
+    /// First sample entry of the track, e.g. `mp4a`, or `aavd` for encrypted Audible audio
+    pub entry: Option<Child>,
+}
+
+/// All tracks in the file
+pub fn tracks(file: &mut File) -> Result<Vec<Track>> {
+    let len = file.metadata()?.len();
+    let mut result = Vec::new();
+
+    for moov in children(file, 0, len)? {
+        if &moov.kind != b"moov" {
+            continue;
+        }
+
+        for trak in children(file, moov.body, moov.end)? {
+            if &trak.kind != b"trak" {
+                continue;
+            }
+
+            let Some(mdia) = child(file, &trak, b"mdia")? else {
+                continue;
+            };
+
+            let mut current = Some(mdia.clone());
+
+            for kind in [b"minf", b"stbl"] {
+                current = match current {
+                    Some(parent) => child(file, &parent, kind)?,
+                    None => None,
+                };
+            }
+
+            let Some(stbl) = current else {
+                continue;
+            };
+
+            // The sample entries follow the version, flags and entry count of the stsd box
+            let entry = match child(file, &stbl, b"stsd")? {
+                Some(stsd) => children(file, stsd.body + 8, stsd.end)?.into_iter().next(),
+                None => None,
+            };
+
+            result.push(Track { mdia, stbl, entry });
+        }
+    }
+
+    Ok(result)
+}
+
+/// Length of the track with the media box `mdia`, from its mdhd box
+pub fn duration(file: &mut File, mdia: &Child) -> Result<Duration> {
+    let mdhd = child(file, mdia, b"mdhd")?.ok_or_else(|| anyhow!("Missing mdhd box"))?;
+    let body = read_body(file, &mdhd)?;
+
+    // Version 1 has 64 bit creation and modification times, and duration
+    let (timescale, duration) = match body.first() {
+        Some(1) if body.len() >= 32 => (
+            be_u32(&body[20..24]),
+            u64::from_be_bytes(body[24..32].try_into().expect("8 bytes")),
+        ),
+        Some(0) if body.len() >= 20 => (be_u32(&body[12..16]), u64::from(be_u32(&body[16..20]))),
+        _ => bail!("Invalid mdhd box"),
+    };
+
+    if timescale == 0 {
+        bail!("Invalid mdhd box");
+    }
+
+    Ok(Duration::from_secs_f64(
+        duration as f64 / f64::from(timescale),
+    ))
+}
+
+/// Sample rate in the audio sample entry `entry`, e.g. `mp4a` or `aavd`
+pub fn sample_rate(file: &mut File, entry: &Child) -> Result<u32> {
+    let body = read_body(file, entry)?;
+
+    // A 16.16 fixed point number at the end of the 28 bytes of the audio sample entry
+    let rate = body
+        .get(24..28)
+        .map(be_u32)
+        .ok_or_else(|| anyhow!("Truncated audio sample entry"))?;
+
+    Ok(rate >> 16)
+}
+
+/// Sample entry type of each track, e.g. `mp4a`, or `aavd` for encrypted Audible audio
+pub fn sample_entries(file: &mut File) -> Result<Vec<[u8; 4]>> {
+    Ok(tracks(file)?
+        .into_iter()
+        .filter_map(|track| track.entry.map(|entry| entry.kind))
+        .collect())
+}
+
+/// Text of the iTunes style metadata item `kind` in `moov/udta/meta/ilst`, e.g. `CDEK`, which is
+/// the ASIN in Audible files
+pub fn tag(file: &mut File, kind: &[u8; 4]) -> Result<Option<String>> {
+    let len = file.metadata()?.len();
+
+    let Some(moov) = children(file, 0, len)?
+        .into_iter()
+        .find(|child| &child.kind == b"moov")
+    else {
+        return Ok(None);
+    };
+
+    let Some(meta) = child(file, &moov, b"udta")?
+        .map(|udta| child(file, &udta, b"meta"))
+        .transpose()?
+        .flatten()
+    else {
+        return Ok(None);
+    };
+
+    // The items follow the version and flags of the meta box
+    let Some(ilst) = children(file, meta.body + 4, meta.end)?
+        .into_iter()
+        .find(|child| &child.kind == b"ilst")
+    else {
+        return Ok(None);
+    };
+
+    let Some(data) = child(file, &ilst, kind)?
+        .map(|item| child(file, &item, b"data"))
+        .transpose()?
+        .flatten()
+    else {
+        return Ok(None);
+    };
+
+    // The text follows the type and locale of the data box
+    let body = read_body(file, &data)?;
+    let text = body.get(8..).ok_or_else(|| anyhow!("Invalid data box"))?;
+
+    Ok(Some(String::from_utf8_lossy(text).into_owned()))
+}
+
+/// A run of consecutive samples of a track
+pub struct Chunk {
+    pub offset: u64,
+    pub sample_sizes: Vec<u32>,
+}
+
+/// All chunks of the track with the sample table `stbl`, from the stsz, stsc and stco or co64
+/// boxes
+pub fn chunks(file: &mut File, stbl: &Child) -> Result<Vec<Chunk>> {
+    let table = |file: &mut File, kind: &[u8; 4]| -> Result<Option<Vec<u8>>> {
+        match child(file, stbl, kind)? {
+            Some(child) => Ok(Some(read_body(file, &child)?)),
+            None => Ok(None),
+        }
+    };
+
+    let stsz = table(file, b"stsz")?.ok_or_else(|| anyhow!("Missing stsz box"))?;
+    let stsc = table(file, b"stsc")?.ok_or_else(|| anyhow!("Missing stsc box"))?;
+
+    let offsets = match (table(file, b"stco")?, table(file, b"co64")?) {
+        (Some(stco), _) => entries(&stco, 4, 4)?
+            .map(|entry| u64::from(be_u32(entry)))
+            .collect::<Vec<_>>(),
+        (None, Some(co64)) => entries(&co64, 4, 8)?
+            .map(|entry| u64::from_be_bytes(entry.try_into().expect("8 bytes")))
+            .collect(),
+        (None, None) => bail!("Missing stco box"),
+    };
+
+    // Either all samples have the same size, or one size per sample follows
+    if stsz.len() < 12 {
+        bail!("Truncated stsz box");
+    }
+
+    let uniform = be_u32(&stsz[4..8]);
+    let count = be_u32(&stsz[8..12]) as usize;
+
+    let mut sizes: Box<dyn Iterator<Item = u32>> = if uniform != 0 {
+        Box::new(std::iter::repeat_n(uniform, count))
+    } else {
+        Box::new(entries(&stsz[4..], 4, 4)?.map(be_u32))
+    };
+
+    // Each entry applies from its first chunk up to the first chunk of the next entry
+    let runs = entries(&stsc, 4, 12)?
+        .map(|entry| (be_u32(&entry[0..4]), be_u32(&entry[4..8])))
+        .collect::<Vec<_>>();
+
+    let mut result = Vec::with_capacity(offsets.len());
+
+    for (index, offset) in offsets.into_iter().enumerate() {
+        let number = index as u32 + 1;
+        let per_chunk = runs
+            .iter()
+            .rev()
+            .find(|(first, _)| *first <= number)
+            .map_or(0, |(_, per_chunk)| *per_chunk);
+
+        let sample_sizes: Vec<u32> = sizes.by_ref().take(per_chunk as usize).collect();
+
+        if sample_sizes.len() != per_chunk as usize {
+            bail!("The sample table lists more samples than sizes");
+        }
+
+        result.push(Chunk {
+            offset,
+            sample_sizes,
+        });
+    }
+
+    Ok(result)
+}
+
+fn be_u32(bytes: &[u8]) -> u32 {
+    u32::from_be_bytes(bytes[..4].try_into().expect("4 bytes"))
+}
+
+/// Entries of `size` bytes following `skip` bytes of other fields and a 32 bit entry count
+fn entries(body: &[u8], skip: usize, size: usize) -> Result<std::slice::Chunks<'_, u8>> {
+    let count = body
+        .get(skip..skip + 4)
+        .map(be_u32)
+        .ok_or_else(|| anyhow!("Truncated sample table"))? as usize;
+
+    let entries = body
+        .get(skip + 4..skip + 4 + count * size)
+        .ok_or_else(|| anyhow!("Truncated sample table"))?;
+
+    Ok(entries.chunks(size))
+}
+
+/// Incrementally checks that a growing file is a well formed sequence of MP4 boxes.
+///
+/// Only boxes that are completely downloaded are checked, each of them only once.
+#[derive(Debug, Default)]
+pub struct Verifier {
+    next: u64,
+    open_ended: bool,
+}
+
+impl Verifier {
+    pub fn new() -> Verifier {
+        Verifier::default()
+    }
+
+    /// Check the boxes that lie completely within the first `len` bytes of `path`
+    pub fn check(&mut self, path: &Path, len: u64) -> Result<()> {
+        let mut file = File::open(path)?;
+
+        while !self.open_ended && self.next + 16 <= len {
+            let header = BoxHeader::read(&mut file, self.next)?;
+
+            if self.next == 0 && &header.kind != b"ftyp" {
+                bail!("File doesn't start with an MP4 ftyp box");
+            }
+
+            let Some(size) = header.size else {
+                self.open_ended = true;
+                break;
+            };
+
+            let end = self
+                .next
+                .checked_add(size)
+                .ok_or_else(|| anyhow!("Invalid MP4 box size at offset {}", self.next))?;
+
+            if end > len {
+                break;
+            }
+
+            if CONTAINERS.contains(&&header.kind) {
+                check_children(&mut file, self.next + header.header_len, end)?;
+            }
+
+            self.next = end;
+        }
+
+        Ok(())
+    }
+
+    /// Check that the boxes make up exactly the complete `len` bytes of `path`
+    pub fn finish(&mut self, path: &Path, len: u64) -> Result<()> {
+        self.check(path, len)?;
+
+        // Boxes smaller than 16 bytes at the very end are skipped by `check`
+        while !self.open_ended && self.next + 8 <= len {
+            let header = BoxHeader::read(&mut File::open(path)?, self.next)?;
+            let size = header.size.unwrap_or(len - self.next);
+
+            self.next = self
+                .next
+                .checked_add(size)
+                .ok_or_else(|| anyhow!("Invalid MP4 box size at offset {}", self.next))?;
+        }
+
+        if !self.open_ended && self.next != len {
+            bail!(
+                "File ends in the middle of an MP4 box (expected {} bytes, got {})",
+                self.next,
+                len
+            );
+        }
+
+        Ok(())
+    }
+}
+
+fn check_children(file: &mut File, start: u64, end: u64) -> Result<()> {
+    let mut pos = start;
+
+    while pos < end {
+        if end - pos < 8 {
+            bail!("Truncated MP4 box at offset {}", pos);
+        }
+
+        let header = BoxHeader::read(file, pos)?;
+        let size = header
+            .size
+            .ok_or_else(|| anyhow!("Unterminated nested MP4 box at offset {}", pos))?;
+
+        if size > end - pos {
+            bail!(
+                "MP4 box {} at offset {} overflows its parent",
+                header.kind_str(),
+                pos
+            );
+        }
+
+        if CONTAINERS.contains(&&header.kind) {
+            check_children(file, pos + header.header_len, pos + size)?;
+        }
+
+        pos += size;
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Write;
+
+    use super::*;
+
+    #[test]
+    fn rejects_oversized_boxes() {
+        let path = std::env::temp_dir().join(format!("audible-dl-mp4-{}.m4b", std::process::id()));
+        let mut contents = Vec::new();
+
+        contents.extend_from_slice(&16u32.to_be_bytes());
+        contents.extend_from_slice(b"ftypM4B \0\0\0\0");
+        // A `moov` claiming the largest 64 bit size, in a file of 40 bytes
+        contents.extend_from_slice(&1u32.to_be_bytes());
+        contents.extend_from_slice(b"moov");
+        contents.extend_from_slice(&u64::MAX.to_be_bytes());
+        contents.extend_from_slice(&[0; 8]);
+
+        File::create(&path).unwrap().write_all(&contents).unwrap();
+        let len = contents.len() as u64;
+
+        let mut file = File::open(&path).unwrap();
+        assert!(tracks(&mut file).is_err());
+        assert!(Verifier::new().finish(&path, len).is_err());
+
+        let moov = Child {
+            kind: *b"moov",
+            start: 16,
+            body: 32,
+            end: u64::MAX,
+        };
+        assert!(read_body(&mut file, &moov).is_err());
+
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    /// A box of `kind` around `body`
+    fn mp4_box(kind: &[u8; 4], body: &[u8]) -> Vec<u8> {
+        let mut contents = (8 + body.len() as u32).to_be_bytes().to_vec();
+        contents.extend_from_slice(kind);
+        contents.extend_from_slice(body);
+        contents
+    }
+
+    #[test]
+    fn reads_tags() {
+        let path = std::env::temp_dir().join(format!("audible-dl-tag-{}.aax", std::process::id()));
+
+        let data = mp4_box(b"data", b"\0\0\0\x01\0\0\0\0B002V0QK4C");
+        let ilst = mp4_box(b"ilst", &mp4_box(b"CDEK", &data));
+        let meta = mp4_box(b"meta", &[&[0; 4], ilst.as_slice()].concat());
+        let moov = mp4_box(b"moov", &mp4_box(b"udta", &meta));
+        let contents = [mp4_box(b"ftyp", b"aax \0\0\0\0"), moov].concat();
+
+        File::create(&path).unwrap().write_all(&contents).unwrap();
+
+        let mut file = File::open(&path).unwrap();
+        let asin = tag(&mut file, b"CDEK");
+        let title = tag(&mut file, b"\xa9nam");
+
+        std::fs::remove_file(&path).unwrap();
+
+        assert_eq!(asin.unwrap().as_deref(), Some("B002V0QK4C"));
+        assert_eq!(title.unwrap(), None);
+    }
+}
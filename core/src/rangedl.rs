@@ -0,0 +1,1506 @@
+//! The resumable transfer of a download into its partial file.
+//!
+//! Every attempt asks for the rest of the file with a `Range` header, checks the
+//! `Content-Range` of the response against what's already on disk, and writes the data at the
+//! offset the server says it starts at. A server that sends some bytes again overwrites them
+//! rather than duplicating them, and one that skips ahead is refused, so the partial file never
+//! has holes. Failed attempts are retried according to the [`Options`].
+//!
+//! With [`Strategy::Pipelined`] the rest of the file is instead requested in pieces, several of
+//! them at once. The pieces are collected in memory and written in order, so that the partial
+//! file can be resumed the same way.
+
+use std::borrow::Cow;
+use std::cell::{Cell, RefCell};
+use std::collections::VecDeque;
+use std::future::Future;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::time::{Duration, Instant};
+
+use anyhow::{anyhow, bail, Context, Result};
+use bytes::Bytes;
+use reqwest::StatusCode;
+use tokio::io::{AsyncReadExt, AsyncSeekExt, AsyncWriteExt, BufWriter};
+use tokio::task::JoinHandle;
+
+use crate::checksum::{self, Checksum};
+use crate::format::Format;
+use crate::frontend::{Frontend, Stage};
+use crate::mp4;
+use crate::sidecar::{Sidecar, Sidecars};
+use crate::speed::{Meter, Rates, Throttle};
+
+/// Size of the pieces requested by [`Strategy::Pipelined`]
+pub const PIECE_SIZE: u64 = 4 * 1024 * 1024;
+
+/// How many pieces [`Strategy::Pipelined`] requests ahead of the one being written
+const PIPELINE_DEPTH: usize = 4;
+
+/// How often a trickling download makes sure the partial file is on disk, since it runs for
+/// long enough that the machine may go down in the middle of it
+const TRICKLE_SYNC_INTERVAL: Duration = Duration::from_secs(60);
+
+/// How much of the end of a partial download is requested again on resume, to compare it with
+/// what the server has
+const OVERLAP: u64 = 64 * 1024;
+
+/// User-Agent of the Audible download manager, which the CDS expects
+pub const USER_AGENT: &str = "Audible ADM 6.6.0.19;Windows Vista  Build 9200";
+
+/// How to request the data
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+#[cfg_attr(feature = "clap", derive(clap::ValueEnum))]
+pub enum Strategy {
+    /// One request for the rest of the book
+    #[default]
+    Single,
+    /// Requests for 4 MiB pieces, four at a time, over one HTTP/2 connection if the server
+    /// supports it
+    Pipelined,
+}
+
+/// How a transfer is done, and how hard it tries
+#[derive(Clone, Debug)]
+pub struct Options {
+    /// Check that the partial download is a well formed MP4 file every this many bytes, 0 to
+    /// disable
+    pub verify_every: u64,
+    pub strategy: Strategy,
+    /// Download at a steady rate of this many bytes per second
+    pub trickle: Option<u64>,
+    /// Compute the SHA-256 of the book while downloading it
+    pub checksum: bool,
+    /// Collect writes to the partial download in a buffer of this many bytes, since few large
+    /// writes are much faster than many small ones on network shares
+    pub write_buffer: usize,
+    /// How long to wait for the first byte of the response to a request, including the TLS
+    /// handshake, `None` to wait as long as it takes
+    pub response_timeout: Option<Duration>,
+    /// How long to wait for more data before reconnecting, `None` to wait as long as it takes
+    pub stall_timeout: Option<Duration>,
+    /// Whether to keep trying when no connection can be made, e.g. while the network is down
+    pub retry_requests: bool,
+    /// Give up after this many failed attempts in a row, `None` to never give up
+    pub max_retries: Option<u32>,
+    /// Count 403 Forbidden as a failed attempt and retry, instead of failing right away
+    pub retry_forbidden: bool,
+    /// Make a new client for every reconnect, so that no pooled connection from before a
+    /// network change is reused. The first reconnect is then made right away.
+    pub new_client: Option<fn() -> Result<reqwest::Client>>,
+    /// Send the User-Agent of the Audible download manager, unless the client sets its own
+    pub user_agent: bool,
+    /// Give up with [`OutOfTime`] when this is reached, checked before every request and after
+    /// every chunk, leaving the partial file to resume
+    pub stop_at: Option<Instant>,
+    /// Where the total size and ETag of the book are kept, to check a resumed download against
+    pub sidecars: Sidecars,
+}
+
+impl Default for Options {
+    fn default() -> Options {
+        Options {
+            verify_every: 64 * 1024 * 1024,
+            strategy: Strategy::Single,
+            trickle: None,
+            checksum: false,
+            write_buffer: 1024 * 1024,
+            response_timeout: None,
+            stall_timeout: None,
+            retry_requests: false,
+            max_retries: None,
+            retry_forbidden: false,
+            new_client: None,
+            user_agent: true,
+            stop_at: None,
+            sidecars: Sidecars::default(),
+        }
+    }
+}
+
+impl Options {
+    /// Whether `failures` failed attempts in a row are more than allowed
+    fn out_of_retries(&self, failures: u32) -> bool {
+        self.max_retries.is_some_and(|max| failures > max)
+    }
+
+    /// Whether [`Options::stop_at`] has been reached
+    fn out_of_time(&self) -> bool {
+        self.stop_at.is_some_and(|at| Instant::now() >= at)
+    }
+}
+
+/// Context of the error of a transfer that gave up after [`Options::max_retries`], as opposed to
+/// one that failed for good
+#[derive(Debug)]
+pub struct OutOfRetries(pub u32);
+
+impl std::fmt::Display for OutOfRetries {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "Giving up after {} retries", self.0)
+    }
+}
+
+/// Context of the error of a transfer that gave up at [`Options::stop_at`]
+#[derive(Debug)]
+pub struct OutOfTime;
+
+impl std::fmt::Display for OutOfTime {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "Out of time")
+    }
+}
+
+/// Error of a request that got no response within [`Options::response_timeout`], or of a
+/// response that stalled for [`Options::stall_timeout`]
+#[derive(Debug)]
+pub struct TimedOut(pub Duration);
+
+impl std::fmt::Display for TimedOut {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "Nothing received from the server for {} seconds",
+            self.0.as_secs()
+        )
+    }
+}
+
+impl std::error::Error for TimedOut {}
+
+/// Error of a response that is an HTML or XML error page instead of the book. Asking again gets
+/// the same page, so the transfer stops rather than retrying.
+#[derive(Debug)]
+pub struct ErrorPage;
+
+impl std::fmt::Display for ErrorPage {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "Server sent an error page instead of the book")
+    }
+}
+
+impl std::error::Error for ErrorPage {}
+
+/// A completed transfer
+#[derive(Debug)]
+pub struct Completed {
+    /// Where the book was saved, with its extension changed to match its format if asked for
+    pub path: PathBuf,
+    /// Transfer rates, `None` if nothing had to be downloaded
+    pub rates: Option<Rates>,
+    /// SHA-256 of the book, if [`Options::checksum`] was set
+    pub sha256: Option<String>,
+}
+
+/// Set by [`stop`]
+static STOPPED: AtomicBool = AtomicBool::new(false);
+
+/// Make all transfers stop after the chunk they're writing, leaving the partial files to resume
+pub fn stop() {
+    STOPPED.store(true, Ordering::Relaxed);
+}
+
+pub fn stopped() -> bool {
+    STOPPED.load(Ordering::Relaxed)
+}
+
+/// Start of the error pages that are sometimes sent with a `206` status instead of the book
+const ERROR_SIGNATURES: [&[u8]; 3] = [b"<!doctype html", b"<html", b"<?xml"];
+
+/// How much of a suspected error page has to be text to be taken for one
+pub const ERROR_TEXT_LEN: usize = 64;
+
+/// Whether `data` starts with an HTML or XML document rather than audio
+pub fn is_error_document(data: &[u8]) -> bool {
+    let start = data
+        .iter()
+        .position(|byte| !byte.is_ascii_whitespace())
+        .unwrap_or(data.len());
+    let data = &data[start..];
+
+    let signature = ERROR_SIGNATURES.iter().any(|signature| {
+        data.len() >= signature.len() && data[..signature.len()].eq_ignore_ascii_case(signature)
+    });
+
+    // Audio data can happen to contain a signature, but isn't followed by more text
+    signature
+        && data[..data.len().min(ERROR_TEXT_LEN)]
+            .iter()
+            .all(|&byte| byte.is_ascii_graphic() || byte.is_ascii_whitespace())
+}
+
+/// A `Content-Range` header, e.g. `bytes 100-999/1000`
+#[derive(Debug, PartialEq, Eq)]
+pub enum ContentRange {
+    /// The bytes sent in a partial response, `total` is `None` when the server gave it as `*`
+    Range {
+        start: u64,
+        end: u64,
+        total: Option<u64>,
+    },
+    /// `bytes */<total>`, sent with `416 Range Not Satisfiable`
+    Unsatisfied { total: u64 },
+}
+
+impl FromStr for ContentRange {
+    type Err = anyhow::Error;
+
+    /// Parse the header, allowing whitespace around its parts and any case for the unit
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let invalid = || anyhow!("Invalid Content-Range header: {}", s);
+
+        let (unit, rest) = s
+            .trim()
+            .split_once(|c: char| c.is_ascii_whitespace())
+            .ok_or_else(invalid)?;
+
+        if !unit.eq_ignore_ascii_case("bytes") {
+            return Err(invalid());
+        }
+
+        let (range, total) = rest.split_once('/').ok_or_else(invalid)?;
+        let number = |s: &str| s.trim().parse::<u64>().map_err(|_| invalid());
+
+        let total = match total.trim() {
+            "*" => None,
+            total => Some(number(total)?),
+        };
+
+        if range.trim() == "*" {
+            return Ok(Self::Unsatisfied {
+                total: total.ok_or_else(invalid)?,
+            });
+        }
+
+        let (start, end) = range.split_once('-').ok_or_else(invalid)?;
+        let (start, end) = (number(start)?, number(end)?);
+
+        if start > end || total.is_some_and(|total| end >= total) {
+            return Err(invalid());
+        }
+
+        Ok(Self::Range { start, end, total })
+    }
+}
+
+impl ContentRange {
+    /// Size of the whole file, if the server told it
+    pub fn total(&self) -> Option<u64> {
+        match *self {
+            Self::Range { total, .. } => total,
+            Self::Unsatisfied { total } => Some(total),
+        }
+    }
+
+    /// Check that the range continues the partial download at `start`, up to the end of the file
+    /// or at most `limit` bytes, and return where the data starts and the size of the file. The
+    /// data may start before `start`, repeating bytes that are already there.
+    pub fn check(&self, start: u64, limit: Option<u64>) -> Result<(u64, u64)> {
+        let Self::Range {
+            start: first,
+            end,
+            total,
+        } = *self
+        else {
+            bail!("Server returned a Content-Range without a range");
+        };
+
+        let total =
+            total.ok_or_else(|| anyhow!("Server didn't tell the size of the book (`/*`)"))?;
+
+        if first > start {
+            bail!("Server returned invalid start offset");
+        }
+
+        let expected = match limit {
+            Some(limit) => total.min(start + limit),
+            None => total,
+        };
+
+        if end + 1 != expected {
+            bail!("Server returned invalid end offset");
+        }
+
+        Ok((first, total))
+    }
+}
+
+/// Move `from` to `to`, falling back to copy + remove when they are on different filesystems
+async fn move_file(from: &Path, to: &Path, frontend: &dyn Frontend) -> Result<()> {
+    match tokio::fs::rename(from, to).await {
+        Ok(()) => return Ok(()),
+        Err(e) if e.kind() == std::io::ErrorKind::CrossesDevices => {}
+        Err(e) => return Err(e.into()),
+    }
+
+    let mut src = tokio::fs::File::open(from).await?;
+    let mut dst = tokio::fs::File::create(to).await?;
+
+    frontend.set_length(src.metadata().await?.len());
+    frontend.set_position(0);
+
+    let mut buf = vec![0; 1024 * 1024];
+
+    loop {
+        let len = src.read(&mut buf).await?;
+
+        if len == 0 {
+            break;
+        }
+
+        dst.write_all(&buf[..len]).await?;
+        frontend.inc(len as u64);
+    }
+
+    // Make sure everything is on disk before removing the source
+    dst.sync_all().await?;
+    drop(src);
+
+    tokio::fs::remove_file(from).await?;
+
+    Ok(())
+}
+
+fn corrupt_message(part: &Path) -> String {
+    format!(
+        "Downloaded data is corrupt, remove {} to start over",
+        part.display()
+    )
+}
+
+/// Await `future`, giving up after `timeout`
+async fn deadline<T>(
+    timeout: Option<Duration>,
+    future: impl Future<Output = reqwest::Result<T>>,
+) -> Result<T> {
+    let Some(timeout) = timeout else {
+        return Ok(future.await?);
+    };
+
+    match tokio::time::timeout(timeout, future).await {
+        Ok(result) => Ok(result?),
+        Err(_) => Err(TimedOut(timeout).into()),
+    }
+}
+
+/// Wait before retrying after `failures` failed attempts in a row
+async fn backoff(options: &Options, failures: u32) {
+    // After a network change the first reconnect usually works, so don't wait for that one
+    if options.new_client.is_none() || failures > 1 {
+        tokio::time::sleep(Duration::from_secs(1)).await;
+    }
+}
+
+/// Delay asked for by the Retry-After header of `res`, given in seconds or as a date
+fn retry_after(res: &reqwest::Response) -> Option<Duration> {
+    let value = res
+        .headers()
+        .get(reqwest::header::RETRY_AFTER)?
+        .to_str()
+        .ok()?
+        .trim();
+
+    if let Ok(secs) = value.parse() {
+        return Some(Duration::from_secs(secs));
+    }
+
+    let date = chrono::DateTime::parse_from_rfc2822(value).ok()?;
+
+    Some(
+        (date.with_timezone(&chrono::Utc) - chrono::Utc::now())
+            .to_std()
+            .unwrap_or_default(),
+    )
+}
+
+/// Request `range` of `url`, with the User-Agent of the Audible download manager if `user_agent`
+fn request(
+    client: &reqwest::Client,
+    url: &str,
+    range: String,
+    user_agent: bool,
+) -> reqwest::RequestBuilder {
+    let request = client.get(url).header("Range", range);
+
+    if !user_agent {
+        return request;
+    }
+
+    request.header("User-Agent", USER_AGENT)
+}
+
+/// Download `start..end` of `url` with its own request, as part of a book of `total` bytes, and
+/// return it along with its offset
+async fn fetch(
+    client: reqwest::Client,
+    url: String,
+    start: u64,
+    end: u64,
+    total: u64,
+    options: Options,
+) -> Result<(u64, Bytes)> {
+    let range = format!("bytes={}-{}", start, end - 1);
+    let request = request(&client, &url, range, options.user_agent).send();
+
+    let mut res = deadline(options.response_timeout, request).await?;
+
+    if res.status() != StatusCode::PARTIAL_CONTENT {
+        bail!("Invalid status code: {}", res.status());
+    }
+
+    let content_range: ContentRange = res
+        .headers()
+        .get("Content-Range")
+        .ok_or_else(|| anyhow!("Missing Content-Range header"))?
+        .to_str()?
+        .parse()?;
+
+    let (offset, size) = content_range.check(start, Some(end - start))?;
+
+    if size != total {
+        bail!("The book changed on the server during the download");
+    }
+
+    let mut data = Vec::with_capacity((end - offset) as usize);
+
+    while let Some(chunk) = deadline(options.stall_timeout, res.chunk()).await? {
+        data.extend_from_slice(&chunk);
+    }
+
+    if is_error_document(&data) {
+        return Err(ErrorPage.into());
+    }
+
+    if data.len() as u64 != end - offset {
+        bail!(
+            "Server sent {} bytes of a {} byte piece",
+            data.len(),
+            end - offset
+        );
+    }
+
+    Ok((offset, data.into()))
+}
+
+/// A response along with the offset of the next data in it
+struct Stream {
+    res: reqwest::Response,
+    offset: u64,
+    started: bool,
+}
+
+impl Stream {
+    fn new(res: reqwest::Response, offset: u64) -> Stream {
+        Stream {
+            res,
+            offset,
+            started: false,
+        }
+    }
+
+    async fn next(&mut self, stall_timeout: Option<Duration>) -> Result<Option<(u64, Bytes)>> {
+        let Some(chunk) = deadline(stall_timeout, self.res.chunk()).await? else {
+            return Ok(None);
+        };
+
+        if !self.started && is_error_document(&chunk) {
+            return Err(ErrorPage.into());
+        }
+
+        self.started = true;
+
+        let offset = self.offset;
+        self.offset += chunk.len() as u64;
+
+        Ok(Some((offset, chunk)))
+    }
+}
+
+/// Pieces of a book requested ahead, following the response for the first one
+struct Pipeline {
+    first: Option<Stream>,
+    pending: VecDeque<JoinHandle<Result<(u64, Bytes)>>>,
+    /// Start of the next piece to request
+    next: u64,
+    total: u64,
+    client: reqwest::Client,
+    url: String,
+    options: Options,
+}
+
+impl Pipeline {
+    /// Keep [`PIPELINE_DEPTH`] pieces requested
+    fn fill(&mut self) {
+        while self.pending.len() < PIPELINE_DEPTH && self.next < self.total {
+            let end = self.total.min(self.next + PIECE_SIZE);
+
+            self.pending.push_back(tokio::spawn(fetch(
+                self.client.clone(),
+                self.url.clone(),
+                self.next,
+                end,
+                self.total,
+                self.options.clone(),
+            )));
+
+            self.next = end;
+        }
+    }
+}
+
+impl Drop for Pipeline {
+    fn drop(&mut self) {
+        for piece in &self.pending {
+            piece.abort();
+        }
+    }
+}
+
+/// The data following the partial download, in order
+enum Body {
+    Single(Stream),
+    Pipelined(Box<Pipeline>),
+}
+
+impl Body {
+    /// The next bytes of the book and their offset, `None` once all has been received
+    async fn next(&mut self, stall_timeout: Option<Duration>) -> Result<Option<(u64, Bytes)>> {
+        let pipeline = match self {
+            Body::Single(stream) => return stream.next(stall_timeout).await,
+            Body::Pipelined(pipeline) => pipeline,
+        };
+
+        if let Some(stream) = &mut pipeline.first {
+            match stream.next(stall_timeout).await? {
+                Some(chunk) => return Ok(Some(chunk)),
+                None => pipeline.first = None,
+            }
+        }
+
+        pipeline.fill();
+
+        let Some(piece) = pipeline.pending.pop_front() else {
+            return Ok(None);
+        };
+
+        let piece = piece.await??;
+        pipeline.fill();
+
+        Ok(Some(piece))
+    }
+}
+
+/// Size of the book at `url`, from the answer to a request for its first byte
+pub async fn size(client: &reqwest::Client, url: &str, options: &Options) -> Result<u64> {
+    let request = request(client, url, "bytes=0-0".to_owned(), options.user_agent).send();
+    let res = deadline(options.response_timeout, request).await?;
+
+    if res.status() != StatusCode::PARTIAL_CONTENT {
+        bail!("Invalid status code: {}", res.status());
+    }
+
+    let content_range: ContentRange = res
+        .headers()
+        .get("Content-Range")
+        .ok_or_else(|| anyhow!("Missing Content-Range header"))?
+        .to_str()?
+        .parse()?;
+
+    content_range
+        .total()
+        .ok_or_else(|| anyhow!("The server didn't tell the size of the book"))
+}
+
+/// What the server says about resuming a download at some offset, see [`preflight`]
+struct Preflight {
+    total: u64,
+    etag: Option<String>,
+    /// Whether the bytes before the offset are the same as on disk
+    overlap_matches: bool,
+}
+
+/// Check resuming the partial download at `part` from `start` with a request for the end of
+/// what's on disk and the byte at `start`, like [`expect: 100-continue`]. `None` if the server
+/// doesn't accept the offset right now.
+///
+/// [`expect: 100-continue`]: https://www.rfc-editor.org/rfc/rfc9110#name-expect
+async fn preflight(
+    client: &reqwest::Client,
+    url: &str,
+    part: &Path,
+    start: u64,
+    options: &Options,
+) -> Result<Option<Preflight>> {
+    let first = start.saturating_sub(OVERLAP);
+    let range = format!("bytes={}-{}", first, start);
+    let request = request(client, url, range, options.user_agent).send();
+    let res = deadline(options.response_timeout, request).await?;
+
+    if res.status() != StatusCode::PARTIAL_CONTENT {
+        return Ok(None);
+    }
+
+    let range = res
+        .headers()
+        .get("Content-Range")
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.parse::<ContentRange>().ok());
+
+    let (sent, total) = match range {
+        Some(ContentRange::Range {
+            start: sent,
+            total: Some(total),
+            ..
+        }) => (sent, total),
+        _ => return Ok(None),
+    };
+
+    let etag = etag(&res);
+    let body = deadline(options.stall_timeout, res.bytes()).await?;
+
+    // A server that sends something else than asked for can't be compared
+    let len = (start - first) as usize;
+
+    if sent != first || body.len() < len {
+        return Ok(None);
+    }
+
+    let mut file = tokio::fs::File::open(part).await?;
+    file.seek(std::io::SeekFrom::Start(first)).await?;
+
+    let mut on_disk = vec![0; len];
+    file.read_exact(&mut on_disk).await?;
+
+    Ok(Some(Preflight {
+        total,
+        etag,
+        overlap_matches: body[..len] == on_disk[..],
+    }))
+}
+
+/// The ETag of a response, if the server sent one
+fn etag(res: &reqwest::Response) -> Option<String> {
+    res.headers()
+        .get("ETag")
+        .and_then(|value| value.to_str().ok())
+        .map(str::to_owned)
+}
+
+/// How the book on the server differs from the one recorded in `sidecar`, if it does
+fn change(sidecar: &Sidecar, total: u64, etag: Option<&str>) -> Option<String> {
+    match (sidecar.total, sidecar.etag.as_deref(), etag) {
+        (Some(recorded), _, _) if recorded != total => {
+            Some(format!("{} bytes, now {}", recorded, total))
+        }
+        (_, Some(recorded), Some(etag)) if recorded != etag => {
+            Some(format!("ETag {}, now {}", recorded, etag))
+        }
+        _ => None,
+    }
+}
+
+/// Ask whether to start the partial download at `part` over, since the book it's of changed on
+/// the server, and remove it if so
+async fn start_over(part: &Path, change: &str, frontend: &dyn Frontend) -> Result<()> {
+    let question = format!(
+        "The book changed on the server since the download started ({}). Remove the partial download and start over?",
+        change
+    );
+
+    if !frontend.confirm(&question)? {
+        bail!(
+            "The book changed on the server since the download started, remove {} to start over",
+            part.display()
+        );
+    }
+
+    match tokio::fs::remove_file(part).await {
+        Err(e) if e.kind() != std::io::ErrorKind::NotFound => Err(e.into()),
+        _ => Ok(()),
+    }
+}
+
+/// Measure the transfer rate from `url` in bytes per second, by downloading the start of it for
+/// up to `duration`
+pub async fn measure(
+    client: &reqwest::Client,
+    url: &str,
+    options: &Options,
+    duration: Duration,
+) -> Result<u64> {
+    let request = request(client, url, "bytes=0-".to_owned(), options.user_agent).send();
+    let mut res = deadline(options.response_timeout, request).await?;
+
+    if res.status() != StatusCode::PARTIAL_CONTENT {
+        bail!("Invalid status code: {}", res.status());
+    }
+
+    // Start after the response, so that only the transfer is measured
+    let started = Instant::now();
+    let mut bytes = 0;
+
+    while let Ok(chunk) = tokio::time::timeout_at((started + duration).into(), res.chunk()).await {
+        match chunk? {
+            Some(chunk) => bytes += chunk.len() as u64,
+            None => break,
+        }
+    }
+
+    Ok((bytes as f64 / started.elapsed().as_secs_f64()) as u64)
+}
+
+/// Sleep for `delay`, counting down in the message of the progress
+async fn countdown(frontend: &dyn Frontend, delay: Duration) {
+    let end = tokio::time::Instant::now() + delay;
+
+    loop {
+        let left = end.saturating_duration_since(tokio::time::Instant::now());
+
+        if left.is_zero() {
+            break;
+        }
+
+        frontend.set_message(&format!(
+            "Server busy, retrying in {}s...",
+            left.as_secs_f64().ceil()
+        ));
+        tokio::time::sleep(left.min(Duration::from_secs(1))).await;
+    }
+}
+
+/// Download `url` into `part`, resuming what's already there, and move it to `output` once it's
+/// complete.
+///
+/// With `detect_extension` the extension of `output` is replaced to match the delivered format,
+/// otherwise the frontend is warned if they don't match.
+pub async fn transfer(
+    client: &reqwest::Client,
+    url: &str,
+    output: &Path,
+    part: &Path,
+    detect_extension: bool,
+    options: &Options,
+    frontend: &dyn Frontend,
+) -> Result<Completed> {
+    // Pick up a partial download left at the output path by an earlier version
+    if !part.exists() && output.exists() {
+        frontend.set_message("Moving partial download...");
+        frontend.set_stage(Stage::Working);
+        move_file(output, part, frontend).await?;
+    }
+
+    // Format announced by the server, if any
+    let announced = Cell::new(None::<Format>);
+    let meter = RefCell::new(Meter::new());
+    let mut throttle = options.trickle.map(Throttle::new);
+    let hash = RefCell::new(options.checksum.then(Checksum::new));
+
+    let finish = || async {
+        let delivered = Format::detect(part, announced.get())?.or(announced.get());
+
+        let output = match delivered {
+            Some(format) if !format.matches(output) && detect_extension => {
+                output.with_extension(format.extension())
+            }
+            Some(format) if !format.matches(output) => {
+                frontend.warn(&format!(
+                    "The downloaded file is {}, but is saved as {}",
+                    format.description(),
+                    output.display()
+                ));
+                output.to_path_buf()
+            }
+            _ => output.to_path_buf(),
+        };
+
+        if options.verify_every > 0 && delivered != Some(Format::Mp3) {
+            let len = tokio::fs::metadata(part).await?.len();
+            mp4::Verifier::new()
+                .finish(part, len)
+                .with_context(|| corrupt_message(part))?;
+        }
+
+        let sha256 = match hash.take() {
+            Some(mut hash) => {
+                let len = tokio::fs::metadata(part).await?.len();
+                hash.catch_up(part, len).await?;
+                Some(hash.finish())
+            }
+            None => None,
+        };
+
+        frontend.set_message("Moving to output...");
+        frontend.set_stage(Stage::Working);
+        move_file(part, &output, frontend).await?;
+
+        if let Some(sha256) = &sha256 {
+            checksum::write(&output, sha256).await?;
+        }
+
+        options.sidecars.remove(part).await?;
+
+        Ok(Completed {
+            path: output,
+            rates: meter.borrow().summary(),
+            sha256,
+        })
+    };
+
+    let verify_every = options.verify_every;
+    let mut verifier = mp4::Verifier::new();
+    let mut sidecar = options.sidecars.load(part).await?;
+
+    // With `new_client` every reconnect uses a new client
+    let mut client = Cow::Borrowed(client);
+    let mut failures = 0;
+    let mut preflighted = false;
+
+    loop {
+        if stopped() {
+            bail!("Stopped before the download was complete");
+        }
+
+        if options.out_of_time() {
+            let e = anyhow!("Ran out of time before the download was complete");
+            return Err(e.context(OutOfTime));
+        }
+
+        // Get file size of existing file
+        let start = match tokio::fs::metadata(part).await {
+            Ok(metadata) => metadata.len(),
+            // Ignore if file doesn't exist
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => 0,
+            // Propagate all other errors
+            Err(e) => return Err(e.into()),
+        };
+
+        // Nothing on disk to be at odds with the book, e.g. after the partial download was removed
+        if start == 0 {
+            sidecar.total = None;
+            sidecar.etag = None;
+        }
+
+        // Rather than setting up the transfer of the rest of a book that changed, check a resume
+        // by comparing the end of what's on disk with what the server has first
+        if start > 0 && !preflighted {
+            preflighted = true;
+
+            // Any trouble is left to the request for the rest, which knows how to retry
+            let change = match preflight(&client, url, part, start, options).await {
+                Ok(Some(preflight)) if !preflight.overlap_matches => {
+                    Some("the end of the partial download differs from it".to_owned())
+                }
+                Ok(Some(preflight)) => change(&sidecar, preflight.total, preflight.etag.as_deref()),
+                Ok(None) => None,
+                Err(e) => {
+                    frontend.log(&format!("Preflight failed: {:#}", e));
+                    None
+                }
+            };
+
+            if let Some(change) = change {
+                start_over(part, &change, frontend).await?;
+                verifier = mp4::Verifier::new();
+                sidecar.total = None;
+                sidecar.etag = None;
+                continue;
+            }
+        }
+
+        frontend.log(&format!("Downloading from offset {}", start));
+
+        // Send the request with the range header, just for the first piece when pipelining
+        let limit = match options.strategy {
+            Strategy::Single => None,
+            Strategy::Pipelined => Some(PIECE_SIZE),
+        };
+
+        let range = match limit {
+            Some(limit) => format!("bytes={}-{}", start, start + limit - 1),
+            None => format!("bytes={}-", start),
+        };
+
+        let request = request(&client, url, range, options.user_agent).send();
+
+        let res = match deadline(options.response_timeout, request).await {
+            Ok(res) => res,
+            // Keep trying until the connection comes back. A server that hangs, e.g. in the TLS
+            // handshake, is always tried again, it's usually fine on the next connection.
+            Err(e) if options.retry_requests || e.is::<TimedOut>() => {
+                frontend.log(&format!("Error: {:#}", e));
+
+                failures += 1;
+
+                if options.out_of_retries(failures) {
+                    return Err(e.context(OutOfRetries(failures - 1)));
+                }
+
+                frontend.retry(failures, &format!("{:#}", e));
+                frontend.set_message("Reconnecting...");
+                frontend.set_stage(Stage::Waiting);
+
+                if let Some(new_client) = options.new_client {
+                    client = Cow::Owned(new_client()?);
+                }
+
+                backoff(options, failures).await;
+
+                continue;
+            }
+            Err(e) => return Err(e),
+        };
+
+        match res.status() {
+            StatusCode::PARTIAL_CONTENT => {}
+            StatusCode::TOO_MANY_REQUESTS | StatusCode::SERVICE_UNAVAILABLE => {
+                frontend.log(&format!("Error: {}", res.status()));
+                frontend.set_message("Server busy, retrying...");
+                frontend.set_stage(Stage::Waiting);
+
+                failures += 1;
+
+                if options.out_of_retries(failures) {
+                    let e = anyhow!("The server is busy ({})", res.status());
+                    return Err(e.context(OutOfRetries(failures - 1)));
+                }
+
+                frontend.retry(failures, &format!("The server is busy ({})", res.status()));
+
+                match retry_after(&res) {
+                    Some(delay) => countdown(frontend, delay).await,
+                    None => backoff(options, failures).await,
+                }
+
+                continue;
+            }
+            StatusCode::FORBIDDEN if options.retry_forbidden => {
+                frontend.log(&format!("Error: {}", res.status()));
+                frontend.set_message("Access denied, retrying...");
+                frontend.set_stage(Stage::Waiting);
+
+                failures += 1;
+
+                if options.out_of_retries(failures) {
+                    let e = anyhow!("Access denied ({})", res.status());
+                    return Err(e.context(OutOfRetries(failures - 1)));
+                }
+
+                frontend.retry(failures, &format!("Access denied ({})", res.status()));
+
+                backoff(options, failures).await;
+
+                continue;
+            }
+            StatusCode::RANGE_NOT_SATISFIABLE => {
+                // Prefer the size the server gives with `bytes */<total>`
+                let total = res
+                    .headers()
+                    .get("Content-Range")
+                    .and_then(|value| value.to_str().ok())
+                    .and_then(|value| value.parse::<ContentRange>().ok())
+                    .and_then(|range| range.total())
+                    .or(sidecar.total);
+
+                match total {
+                    Some(total) if total < start => bail!(
+                        "{} is larger than the book on the server, remove it to start over",
+                        part.display()
+                    ),
+                    Some(total) if total > start => bail!(
+                        "Server refused to resume the download at {} of {} bytes",
+                        start,
+                        total
+                    ),
+                    _ => return finish().await,
+                }
+            }
+            code => return Err(anyhow!("Invalid status code: {code}")),
+        }
+
+        // Parse Content-Range header
+        let content_range: ContentRange = res
+            .headers()
+            .get("Content-Range")
+            .ok_or_else(|| anyhow!("Missing Content-Range header"))?
+            .to_str()?
+            .parse()?;
+
+        announced.set(
+            res.headers()
+                .get("Content-Type")
+                .and_then(|value| value.to_str().ok())
+                .and_then(Format::from_content_type),
+        );
+
+        let (offset, total) = content_range.check(start, limit)?;
+
+        // Appending to a partial download of a different encode would produce a broken file
+        let etag = etag(&res);
+
+        if let Some(change) = change(&sidecar, total, etag.as_deref()) {
+            start_over(part, &change, frontend).await?;
+            verifier = mp4::Verifier::new();
+            sidecar.total = None;
+            sidecar.etag = None;
+            continue;
+        }
+
+        if sidecar.total.is_none() {
+            sidecar.total = Some(total);
+            sidecar.etag = etag;
+            options.sidecars.save(&sidecar, part).await?;
+        }
+
+        // Hash what's already on disk before adding to it
+        if let Some(mut sum) = hash.take() {
+            sum.catch_up(part, start).await?;
+            hash.replace(Some(sum));
+        }
+
+        frontend.set_stage(Stage::Downloading);
+        frontend.set_length(total);
+        frontend.set_position(start);
+        meter.borrow_mut().restart();
+
+        if let Some(throttle) = &mut throttle {
+            throttle.restart();
+        }
+
+        // Open file for writing at the offsets the server sends
+        let file = tokio::fs::OpenOptions::new()
+            .create(true)
+            .write(true)
+            .truncate(false)
+            .open(part)
+            .await?;
+        let mut file = BufWriter::with_capacity(options.write_buffer, file);
+
+        let mut position = start;
+        let mut synced = Instant::now();
+        let mut cursor = None;
+        let mut next_check = start + verify_every;
+
+        let stream = Stream::new(res, offset);
+
+        let mut body = match limit {
+            Some(limit) => {
+                let mut pipeline = Pipeline {
+                    first: Some(stream),
+                    pending: VecDeque::new(),
+                    next: total.min(start + limit),
+                    total,
+                    client: client.clone().into_owned(),
+                    url: url.to_owned(),
+                    options: options.clone(),
+                };
+
+                // Start on the next pieces while the first one is still coming in
+                pipeline.fill();
+                Body::Pipelined(Box::new(pipeline))
+            }
+            None => Body::Single(stream),
+        };
+
+        // Download data
+        loop {
+            match body.next(options.stall_timeout).await {
+                Ok(Some((offset, chunk))) => {
+                    failures = 0;
+
+                    if offset > position {
+                        bail!(
+                            "Server skipped from offset {} to {} in the middle of the download",
+                            position,
+                            offset
+                        );
+                    }
+
+                    if cursor != Some(offset) {
+                        file.seek(std::io::SeekFrom::Start(offset)).await?;
+                    }
+
+                    file.write_all(&chunk).await?;
+                    cursor = Some(offset + chunk.len() as u64);
+
+                    if let Some(hash) = hash.borrow_mut().as_mut() {
+                        hash.update(offset, &chunk);
+                    }
+
+                    // Only count the bytes that weren't there already
+                    let end = offset + chunk.len() as u64;
+                    let new = end.saturating_sub(position);
+                    position = position.max(end);
+
+                    frontend.inc(new);
+
+                    if let Some(rate) = meter.borrow_mut().record(chunk.len() as u64) {
+                        frontend.rate(position, total, rate);
+                    }
+
+                    if stopped() {
+                        file.shutdown().await?;
+                        bail!("Stopped at {} of {} bytes", position, total);
+                    }
+
+                    if options.out_of_time() {
+                        file.shutdown().await?;
+                        let e = anyhow!("Ran out of time at {} of {} bytes", position, total);
+                        return Err(e.context(OutOfTime));
+                    }
+
+                    if let Some(throttle) = &mut throttle {
+                        tokio::time::sleep(throttle.record(chunk.len() as u64)).await;
+
+                        if synced.elapsed() >= TRICKLE_SYNC_INTERVAL {
+                            file.flush().await?;
+                            file.get_ref().sync_data().await?;
+                            synced = Instant::now();
+                        }
+                    }
+
+                    // Catch corrupted data early rather than after the whole book is downloaded
+                    if verify_every > 0
+                        && position >= next_check
+                        && announced.get() != Some(Format::Mp3)
+                    {
+                        file.flush().await?;
+                        verifier
+                            .check(part, position)
+                            .with_context(|| corrupt_message(part))?;
+                        next_check = position + verify_every;
+                    }
+                }
+                // The entire file has been downloaded
+                Ok(None) => {
+                    file.shutdown().await?;
+                    return finish().await;
+                }
+                // Nothing of the page was written, and trying again would get it again
+                Err(e) if e.is::<ErrorPage>() => {
+                    file.shutdown().await?;
+                    return Err(e);
+                }
+                // Retry on error
+                Err(e) => {
+                    frontend.log(&format!("Error: {:#}", e));
+                    frontend.set_message("Restarting download...");
+                    frontend.set_stage(Stage::Waiting);
+
+                    // Close and flush file
+                    file.shutdown().await?;
+
+                    failures += 1;
+
+                    if options.out_of_retries(failures) {
+                        return Err(e.context(OutOfRetries(failures - 1)));
+                    }
+
+                    frontend.retry(failures, &format!("{:#}", e));
+
+                    if let Some(new_client) = options.new_client {
+                        client = Cow::Owned(new_client()?);
+                    }
+
+                    // Wait a bit before retrying
+                    backoff(options, failures).await;
+
+                    break;
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::{BufRead, BufReader, Write};
+    use std::sync::atomic::AtomicUsize;
+    use std::sync::Arc;
+
+    use super::*;
+
+    fn parse(s: &str) -> Result<ContentRange> {
+        s.parse()
+    }
+
+    #[test]
+    fn parses_content_range() {
+        assert_eq!(
+            parse("bytes 100-999/1000").unwrap(),
+            ContentRange::Range {
+                start: 100,
+                end: 999,
+                total: Some(1000)
+            }
+        );
+        assert_eq!(
+            parse("bytes 0-0/1").unwrap(),
+            ContentRange::Range {
+                start: 0,
+                end: 0,
+                total: Some(1)
+            }
+        );
+    }
+
+    #[test]
+    fn parses_wildcards() {
+        assert_eq!(
+            parse("bytes */12345").unwrap(),
+            ContentRange::Unsatisfied { total: 12345 }
+        );
+        assert_eq!(
+            parse("bytes 0-99/*").unwrap(),
+            ContentRange::Range {
+                start: 0,
+                end: 99,
+                total: None
+            }
+        );
+        assert!(parse("bytes */*").is_err());
+    }
+
+    #[test]
+    fn tolerates_whitespace() {
+        let expected = ContentRange::Range {
+            start: 100,
+            end: 999,
+            total: Some(1000),
+        };
+
+        assert_eq!(parse("  bytes 100-999/1000 ").unwrap(), expected);
+        assert_eq!(parse("bytes  100 - 999 / 1000").unwrap(), expected);
+        assert_eq!(parse("bytes\t100-999/1000").unwrap(), expected);
+        assert_eq!(parse("Bytes 100-999/1000").unwrap(), expected);
+        assert_eq!(
+            parse("bytes * / 12345").unwrap(),
+            ContentRange::Unsatisfied { total: 12345 }
+        );
+    }
+
+    #[test]
+    fn rejects_malformed_content_range() {
+        for header in [
+            "",
+            "bytes",
+            "bytes ",
+            "bytes 100",
+            "bytes 100-999",
+            "bytes 100/1000",
+            "bytes -999/1000",
+            "bytes 100-/1000",
+            "bytes 100-999/",
+            "bytes 100-999/1000/1",
+            "bytes 1-2-3/1000",
+            "bytes a-999/1000",
+            "bytes -1-999/1000",
+            "bytes 1 00-999/1000",
+            "bytes */",
+            "bytes *-99/1000",
+            "bytes 0-*/1000",
+            "bytes100-999/1000",
+            "items 100-999/1000",
+            "100-999/1000",
+            "bytes 18446744073709551616-999/1000",
+        ] {
+            assert!(parse(header).is_err(), "{:?} should be rejected", header);
+        }
+    }
+
+    #[test]
+    fn rejects_impossible_content_range() {
+        assert!(parse("bytes 999-100/1000").is_err());
+        assert!(parse("bytes 100-1000/1000").is_err());
+        assert!(parse("bytes 0-0/0").is_err());
+    }
+
+    #[test]
+    fn checks_continuation() {
+        let range = parse("bytes 100-999/1000").unwrap();
+
+        assert_eq!(range.check(100, None).unwrap(), (100, 1000));
+        assert!(range.check(0, None).is_err());
+        assert!(parse("bytes 100-998/1000")
+            .unwrap()
+            .check(100, None)
+            .is_err());
+        assert!(parse("bytes 100-999/*").unwrap().check(100, None).is_err());
+        assert!(parse("bytes */1000").unwrap().check(1000, None).is_err());
+    }
+
+    #[test]
+    fn checks_pieces() {
+        let range = parse("bytes 100-199/1000").unwrap();
+
+        assert_eq!(range.check(100, Some(100)).unwrap(), (100, 1000));
+        assert!(range.check(100, Some(50)).is_err());
+        assert!(range.check(100, None).is_err());
+
+        // The last piece ends with the file
+        let last = parse("bytes 900-999/1000").unwrap();
+        assert_eq!(last.check(900, Some(400)).unwrap(), (900, 1000));
+    }
+
+    #[test]
+    fn detects_error_documents() {
+        assert!(is_error_document(
+            b"<?xml version=\"1.0\"?><Error><Code>AccessDenied</Code>"
+        ));
+        assert!(is_error_document(
+            b"\r\n<!DOCTYPE html>\n<html><body>Bad Gateway</body>"
+        ));
+        assert!(is_error_document(
+            b"<HTML><HEAD><TITLE>Error</TITLE></HEAD>"
+        ));
+
+        assert!(!is_error_document(b"\0\0\0\x20ftypaax \0\0\0\0"));
+        assert!(!is_error_document(b"<html\xff\x00\x13\x8e"));
+        assert!(!is_error_document(b""));
+    }
+
+    #[test]
+    fn checks_overlap() {
+        // Bytes that are already there may be sent again, but none may be skipped
+        let range = parse("bytes 50-999/1000").unwrap();
+
+        assert_eq!(range.check(100, None).unwrap(), (50, 1000));
+        assert!(range.check(10, None).is_err());
+    }
+
+    #[test]
+    fn reports_total() {
+        assert_eq!(parse("bytes 0-99/100").unwrap().total(), Some(100));
+        assert_eq!(parse("bytes 0-99/*").unwrap().total(), None);
+        assert_eq!(parse("bytes */100").unwrap().total(), Some(100));
+    }
+
+    #[test]
+    fn detects_changed_books() {
+        let sidecar = Sidecar {
+            total: Some(100),
+            etag: Some("\"a\"".to_owned()),
+            codec: None,
+        };
+
+        assert_eq!(change(&sidecar, 100, Some("\"a\"")), None);
+        // Not every server sends an ETag every time
+        assert_eq!(change(&sidecar, 100, None), None);
+        assert!(change(&sidecar, 100, Some("\"b\"")).is_some());
+        assert!(change(&sidecar, 200, Some("\"a\"")).is_some());
+    }
+
+    /// Serve `body` as a 206 response to every request, counting the requests
+    fn serve(body: &'static [u8]) -> (String, Arc<AtomicUsize>) {
+        let listener = std::net::TcpListener::bind(("127.0.0.1", 0)).unwrap();
+        let url = format!("http://{}/book.aax", listener.local_addr().unwrap());
+        let requests = Arc::new(AtomicUsize::new(0));
+        let served = requests.clone();
+
+        std::thread::spawn(move || {
+            for stream in listener.incoming() {
+                let mut stream = stream.unwrap();
+                served.fetch_add(1, Ordering::SeqCst);
+
+                let mut line = String::new();
+                let mut reader = BufReader::new(&stream);
+                while reader.read_line(&mut line).unwrap() > 2 {
+                    line.clear();
+                }
+
+                let head = format!(
+                    "HTTP/1.1 206 Partial Content\r\ncontent-range: bytes 0-{}/{}\r\ncontent-length: {}\r\nconnection: close\r\n\r\n",
+                    body.len() - 1,
+                    body.len(),
+                    body.len()
+                );
+                let _ = stream.write_all(head.as_bytes());
+                let _ = stream.write_all(body);
+            }
+        });
+
+        (url, requests)
+    }
+
+    fn block_on<F: std::future::Future>(future: F) -> F::Output {
+        tokio::runtime::Builder::new_current_thread()
+            .enable_all()
+            .build()
+            .unwrap()
+            .block_on(future)
+    }
+
+    #[test]
+    fn stops_at_error_page() {
+        // Answers every request with the error page, as the rest of the book
+        let (url, requests) =
+            serve(b"<!DOCTYPE html>\n<html><body>Service Unavailable</body></html>\n");
+
+        let dir =
+            std::env::temp_dir().join(format!("audible-dl-error-page-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let (output, part) = (dir.join("book.aax"), dir.join("book.aax.part"));
+
+        // A few retries, so that retrying fails the test rather than hanging it
+        let options = Options {
+            max_retries: Some(2),
+            ..Options::default()
+        };
+
+        let result = block_on(transfer(
+            &reqwest::Client::new(),
+            &url,
+            &output,
+            &part,
+            false,
+            &options,
+            &crate::Hidden,
+        ));
+
+        let written = std::fs::metadata(&part).map_or(0, |metadata| metadata.len());
+        std::fs::remove_dir_all(&dir).unwrap();
+
+        assert!(result.unwrap_err().is::<ErrorPage>());
+        assert_eq!(requests.load(Ordering::SeqCst), 1);
+        assert_eq!(written, 0);
+    }
+
+    #[test]
+    fn starts_over_without_part() {
+        // An `ftyp` and an empty `free` box
+        let (url, _) = serve(b"\0\0\0\x10ftypM4B \0\0\0\0\0\0\0\x08free");
+
+        let dir =
+            std::env::temp_dir().join(format!("audible-dl-missing-part-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let (output, part) = (dir.join("book.aax"), dir.join("book.aax.part"));
+
+        // Left behind by a partial download of another encode, which was then removed
+        let stale = Sidecar {
+            total: Some(1000),
+            etag: Some("\"old\"".to_owned()),
+            codec: None,
+        };
+
+        let result = block_on(async {
+            stale.save(&part).await.unwrap();
+
+            transfer(
+                &reqwest::Client::new(),
+                &url,
+                &output,
+                &part,
+                false,
+                &Options::default(),
+                &crate::Hidden,
+            )
+            .await
+        });
+
+        let written = std::fs::metadata(&output).map(|metadata| metadata.len());
+        std::fs::remove_dir_all(&dir).unwrap();
+
+        result.unwrap();
+        assert_eq!(written.unwrap(), 24);
+    }
+}
@@ -0,0 +1,109 @@
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+
+use anyhow::{Context, Result};
+use serde::{Deserialize, Serialize};
+
+/// Metadata kept next to a partial download, so that resumed downloads can be checked against
+/// what was downloaded before.
+#[derive(Serialize, Deserialize, Default, Debug)]
+#[serde(default)]
+pub struct Sidecar {
+    /// Total size of the book as reported by the server when the download started
+    pub total: Option<u64>,
+    /// ETag of the book as sent by the server when the download started, if it sent one
+    pub etag: Option<String>,
+    /// Codec the book is downloaded in, when it isn't the one the download asked for
+    pub codec: Option<String>,
+}
+
+impl Sidecar {
+    fn path(part: &Path) -> PathBuf {
+        let mut path = part.as_os_str().to_owned();
+        path.push(".json");
+        PathBuf::from(path)
+    }
+
+    /// Load the sidecar of `part`, or an empty one if there is none yet
+    pub async fn load(part: &Path) -> Result<Sidecar> {
+        let path = Sidecar::path(part);
+
+        match tokio::fs::read(&path).await {
+            Ok(contents) => serde_json::from_slice(&contents)
+                .with_context(|| format!("Invalid download metadata in {}", path.display())),
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(Sidecar::default()),
+            Err(e) => Err(e.into()),
+        }
+    }
+
+    pub async fn save(&self, part: &Path) -> Result<()> {
+        Ok(tokio::fs::write(Sidecar::path(part), serde_json::to_vec_pretty(self)?).await?)
+    }
+
+    pub async fn remove(part: &Path) -> Result<()> {
+        match tokio::fs::remove_file(Sidecar::path(part)).await {
+            Err(e) if e.kind() != std::io::ErrorKind::NotFound => Err(e.into()),
+            _ => Ok(()),
+        }
+    }
+}
+
+/// Somewhere else to keep sidecars than in `.json` files, such as a database
+pub trait Store: Send + Sync + std::fmt::Debug {
+    /// The sidecar of `part`, `None` if the store has none
+    fn load(&self, part: &Path) -> Result<Option<Sidecar>>;
+    fn save(&self, part: &Path, sidecar: &Sidecar) -> Result<()>;
+    fn remove(&self, part: &Path) -> Result<()>;
+}
+
+/// Where the sidecars of a transfer are kept: in `.json` files next to the partial downloads,
+/// in a [`Store`], or in both
+#[derive(Clone, Debug)]
+pub struct Sidecars {
+    pub store: Option<Arc<dyn Store>>,
+    /// Also write `.json` files when there is a store
+    pub files: bool,
+}
+
+impl Default for Sidecars {
+    fn default() -> Sidecars {
+        Sidecars {
+            store: None,
+            files: true,
+        }
+    }
+}
+
+impl Sidecars {
+    /// Load the sidecar of `part` from the store, or from its `.json` file, which may have been
+    /// written before there was a store
+    pub async fn load(&self, part: &Path) -> Result<Sidecar> {
+        if let Some(store) = &self.store {
+            if let Some(sidecar) = store.load(part)? {
+                return Ok(sidecar);
+            }
+        }
+
+        Sidecar::load(part).await
+    }
+
+    pub async fn save(&self, sidecar: &Sidecar, part: &Path) -> Result<()> {
+        if let Some(store) = &self.store {
+            store.save(part, sidecar)?;
+        }
+
+        if self.files || self.store.is_none() {
+            sidecar.save(part).await?;
+        }
+
+        Ok(())
+    }
+
+    pub async fn remove(&self, part: &Path) -> Result<()> {
+        if let Some(store) = &self.store {
+            store.remove(part)?;
+        }
+
+        Sidecar::remove(part).await
+    }
+}
@@ -0,0 +1,110 @@
+use std::time::{Duration, Instant};
+
+/// Measure the transfer rate of a download, one sample per second of transfer.
+///
+/// Time spent reconnecting isn't counted, so the rates are those of the connection while it
+/// works.
+#[derive(Debug)]
+pub struct Meter {
+    window_start: Instant,
+    window_bytes: u64,
+    /// Bytes per second of each completed window
+    samples: Vec<u64>,
+    bytes: u64,
+    elapsed: Duration,
+}
+
+/// Minimum, average and maximum transfer rate of a download, in bytes per second
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Rates {
+    pub min: u64,
+    pub avg: u64,
+    pub max: u64,
+}
+
+impl Default for Meter {
+    fn default() -> Meter {
+        Meter::new()
+    }
+}
+
+impl Meter {
+    pub fn new() -> Meter {
+        Meter {
+            window_start: Instant::now(),
+            window_bytes: 0,
+            samples: Vec::new(),
+            bytes: 0,
+            elapsed: Duration::ZERO,
+        }
+    }
+
+    /// Start measuring a new response, dropping the unfinished window of the previous one
+    pub fn restart(&mut self) {
+        self.window_start = Instant::now();
+        self.window_bytes = 0;
+    }
+
+    /// Record that `len` bytes were received, returning the rate once a second is complete
+    pub fn record(&mut self, len: u64) -> Option<u64> {
+        self.window_bytes += len;
+
+        let elapsed = self.window_start.elapsed();
+
+        if elapsed < Duration::from_secs(1) {
+            return None;
+        }
+
+        let rate = (self.window_bytes as f64 / elapsed.as_secs_f64()) as u64;
+        self.samples.push(rate);
+        self.bytes += self.window_bytes;
+        self.elapsed += elapsed;
+        self.restart();
+
+        Some(rate)
+    }
+
+    /// The rates of the download, if there is any sample
+    pub fn summary(&self) -> Option<Rates> {
+        Some(Rates {
+            min: *self.samples.iter().min()?,
+            avg: (self.bytes as f64 / self.elapsed.as_secs_f64()) as u64,
+            max: *self.samples.iter().max()?,
+        })
+    }
+}
+
+/// Keep a transfer at a fixed rate, by waiting whenever it gets ahead.
+///
+/// Reading slower makes the server send slower, so the connection never bursts above the rate.
+#[derive(Debug)]
+pub struct Throttle {
+    /// Bytes per second
+    rate: u64,
+    started: Instant,
+    bytes: u64,
+}
+
+impl Throttle {
+    pub fn new(rate: u64) -> Throttle {
+        Throttle {
+            rate,
+            started: Instant::now(),
+            bytes: 0,
+        }
+    }
+
+    /// Start pacing a new response, so that the time spent reconnecting isn't made up for
+    pub fn restart(&mut self) {
+        self.started = Instant::now();
+        self.bytes = 0;
+    }
+
+    /// Record that `len` bytes were received, and return how long to wait before reading more
+    pub fn record(&mut self, len: u64) -> Duration {
+        self.bytes += len;
+
+        let due = Duration::from_secs_f64(self.bytes as f64 / self.rate as f64);
+        due.saturating_sub(self.started.elapsed())
+    }
+}
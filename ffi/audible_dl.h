@@ -0,0 +1,65 @@
+/*
+ * C API for the resilient downloader of audible-dl.
+ *
+ * A download runs on its own thread. Poll it for progress until it's no longer running, then
+ * free it. Partial downloads are kept as `<output>.part` and resumed by the next download to
+ * the same output.
+ */
+
+#ifndef AUDIBLE_DL_H
+#define AUDIBLE_DL_H
+
+#include <stdint.h>
+
+#ifdef __cplusplus
+extern "C" {
+#endif
+
+typedef struct AudibleDlDownload AudibleDlDownload;
+
+enum {
+    AUDIBLE_DL_RUNNING = 0,
+    AUDIBLE_DL_COMPLETE = 1,
+    AUDIBLE_DL_FAILED = 2,
+    AUDIBLE_DL_CANCELLED = 3,
+};
+
+typedef struct {
+    /* Bytes downloaded, or copied when moving the file */
+    uint64_t position;
+    /* Total bytes, 0 until known */
+    uint64_t length;
+} AudibleDlProgress;
+
+/*
+ * Start downloading `url` to `output`, both UTF-8. Returns NULL if either isn't valid UTF-8 or
+ * the download thread can't be started.
+ */
+AudibleDlDownload *audible_dl_start_download(const char *url, const char *output);
+
+/*
+ * Fill in `progress`, if not NULL, and return the state of the download, one of the
+ * AUDIBLE_DL_* constants.
+ */
+int32_t audible_dl_poll_progress(const AudibleDlDownload *download, AudibleDlProgress *progress);
+
+/*
+ * Why the download failed, or NULL if it didn't. Valid until the download is freed.
+ */
+const char *audible_dl_error(const AudibleDlDownload *download);
+
+/*
+ * Stop the download, keeping the partial file to resume later. Doesn't wait for it to stop.
+ */
+void audible_dl_cancel(const AudibleDlDownload *download);
+
+/*
+ * Cancel the download if it's running, wait for it to stop and free it.
+ */
+void audible_dl_free(AudibleDlDownload *download);
+
+#ifdef __cplusplus
+}
+#endif
+
+#endif
@@ -0,0 +1,236 @@
+//! C API for the resilient downloader of audible-dl, see `audible_dl.h` for the documentation of
+//! each function.
+//!
+//! Every download runs [`rangedl::transfer`] on a thread of its own, with a single threaded
+//! runtime, and reports its progress through atomics that the caller polls.
+
+use std::ffi::{c_char, CStr, CString, OsString};
+use std::path::PathBuf;
+use std::ptr;
+use std::sync::atomic::{AtomicI32, AtomicU64, Ordering};
+use std::sync::{Arc, OnceLock};
+use std::thread::JoinHandle;
+
+use audible_dl_core::rangedl::{self, Options};
+use audible_dl_core::Frontend;
+use tokio::sync::Notify;
+
+pub const AUDIBLE_DL_RUNNING: i32 = 0;
+pub const AUDIBLE_DL_COMPLETE: i32 = 1;
+pub const AUDIBLE_DL_FAILED: i32 = 2;
+pub const AUDIBLE_DL_CANCELLED: i32 = 3;
+
+#[repr(C)]
+#[derive(Clone, Copy, Debug, Default)]
+pub struct AudibleDlProgress {
+    pub position: u64,
+    pub length: u64,
+}
+
+/// State shared between a download thread and the caller
+#[derive(Debug, Default)]
+struct Shared {
+    state: AtomicI32,
+    position: AtomicU64,
+    length: AtomicU64,
+    error: OnceLock<CString>,
+    cancel: Notify,
+}
+
+impl Frontend for Shared {
+    fn set_length(&self, len: u64) {
+        self.length.store(len, Ordering::Relaxed);
+    }
+
+    fn set_position(&self, position: u64) {
+        self.position.store(position, Ordering::Relaxed);
+    }
+
+    fn inc(&self, delta: u64) {
+        self.position.fetch_add(delta, Ordering::Relaxed);
+    }
+}
+
+#[derive(Debug)]
+pub struct AudibleDlDownload {
+    shared: Arc<Shared>,
+    thread: Option<JoinHandle<()>>,
+}
+
+unsafe fn string(ptr: *const c_char) -> Option<String> {
+    if ptr.is_null() {
+        return None;
+    }
+
+    CStr::from_ptr(ptr).to_str().ok().map(str::to_owned)
+}
+
+unsafe fn shared<'a>(download: *const AudibleDlDownload) -> &'a Shared {
+    &(*download).shared
+}
+
+fn run(url: String, output: PathBuf, shared: &Shared) -> i32 {
+    let mut part = OsString::from(output.clone());
+    part.push(".part");
+    let part = PathBuf::from(part);
+
+    let fail = |e: anyhow::Error| {
+        let message = format!("{:#}", e).replace('\0', " ");
+        let _ = shared.error.set(CString::new(message).unwrap_or_default());
+        AUDIBLE_DL_FAILED
+    };
+
+    let runtime = match tokio::runtime::Builder::new_current_thread()
+        .enable_all()
+        .build()
+    {
+        Ok(runtime) => runtime,
+        Err(e) => return fail(e.into()),
+    };
+
+    runtime.block_on(async {
+        let client = reqwest::Client::new();
+        let options = Options::default();
+        let transfer = rangedl::transfer(&client, &url, &output, &part, false, &options, shared);
+
+        tokio::select! {
+            result = transfer => match result {
+                Ok(_) => AUDIBLE_DL_COMPLETE,
+                Err(e) => fail(e),
+            },
+            _ = shared.cancel.notified() => AUDIBLE_DL_CANCELLED,
+        }
+    })
+}
+
+/// Start downloading `url` to `output`.
+///
+/// # Safety
+///
+/// `url` and `output` must be NULL or point to NUL-terminated strings.
+#[no_mangle]
+pub unsafe extern "C" fn audible_dl_start_download(
+    url: *const c_char,
+    output: *const c_char,
+) -> *mut AudibleDlDownload {
+    let (Some(url), Some(output)) = (string(url), string(output)) else {
+        return ptr::null_mut();
+    };
+
+    let shared = Arc::new(Shared::default());
+    let thread = {
+        let shared = shared.clone();
+
+        std::thread::Builder::new()
+            .name("audible-dl".to_owned())
+            .spawn(move || {
+                let state = run(url, PathBuf::from(output), &shared);
+                shared.state.store(state, Ordering::Release);
+            })
+    };
+
+    match thread {
+        Ok(thread) => Box::into_raw(Box::new(AudibleDlDownload {
+            shared,
+            thread: Some(thread),
+        })),
+        Err(_) => ptr::null_mut(),
+    }
+}
+
+/// Fill in `progress`, if not NULL, and return the state of `download`.
+///
+/// # Safety
+///
+/// `download` must come from [`audible_dl_start_download`] and not be freed, `progress` must be
+/// NULL or valid for writes.
+#[no_mangle]
+pub unsafe extern "C" fn audible_dl_poll_progress(
+    download: *const AudibleDlDownload,
+    progress: *mut AudibleDlProgress,
+) -> i32 {
+    let shared = shared(download);
+
+    if !progress.is_null() {
+        *progress = AudibleDlProgress {
+            position: shared.position.load(Ordering::Relaxed),
+            length: shared.length.load(Ordering::Relaxed),
+        };
+    }
+
+    shared.state.load(Ordering::Acquire)
+}
+
+/// Why `download` failed, or NULL.
+///
+/// # Safety
+///
+/// `download` must come from [`audible_dl_start_download`] and not be freed.
+#[no_mangle]
+pub unsafe extern "C" fn audible_dl_error(download: *const AudibleDlDownload) -> *const c_char {
+    match shared(download).error.get() {
+        Some(error) => error.as_ptr(),
+        None => ptr::null(),
+    }
+}
+
+/// Stop `download`, keeping the partial file.
+///
+/// # Safety
+///
+/// `download` must come from [`audible_dl_start_download`] and not be freed.
+#[no_mangle]
+pub unsafe extern "C" fn audible_dl_cancel(download: *const AudibleDlDownload) {
+    // Stores a permit if the download isn't waiting yet, so the cancel is never lost
+    shared(download).cancel.notify_one();
+}
+
+/// Cancel `download` if it's running, wait for it to stop and free it.
+///
+/// # Safety
+///
+/// `download` must be NULL or come from [`audible_dl_start_download`] and not be freed already.
+#[no_mangle]
+pub unsafe extern "C" fn audible_dl_free(download: *mut AudibleDlDownload) {
+    if download.is_null() {
+        return;
+    }
+
+    let mut download = Box::from_raw(download);
+    download.shared.cancel.notify_one();
+
+    if let Some(thread) = download.thread.take() {
+        let _ = thread.join();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn reports_failure() {
+        let url = CString::new("http://127.0.0.1:1/book.aax").unwrap();
+        let output = std::env::temp_dir().join("audible-dl-ffi-test.aax");
+        let output = CString::new(output.to_str().unwrap()).unwrap();
+
+        unsafe {
+            let download = audible_dl_start_download(url.as_ptr(), output.as_ptr());
+            assert!(!download.is_null());
+
+            let mut progress = AudibleDlProgress::default();
+
+            while audible_dl_poll_progress(download, &mut progress) == AUDIBLE_DL_RUNNING {
+                std::thread::sleep(std::time::Duration::from_millis(10));
+            }
+
+            assert_eq!(
+                audible_dl_poll_progress(download, ptr::null_mut()),
+                AUDIBLE_DL_FAILED
+            );
+            assert!(!audible_dl_error(download).is_null());
+
+            audible_dl_free(download);
+        }
+    }
+}
@@ -0,0 +1,144 @@
+//! Python bindings for audible-dl, built into the `audible_dl` module with maturin.
+//!
+//! Every function blocks until it's done, running the core on a single threaded runtime of its
+//! own with the GIL released.
+
+use std::future::Future;
+use std::path::{Path, PathBuf};
+
+use anyhow::Result;
+use audible_dl_core::api::{self, Marketplace};
+use audible_dl_core::rangedl::{self, Options};
+use audible_dl_core::Frontend;
+use pyo3::exceptions::PyValueError;
+use pyo3::prelude::*;
+
+/// Run `future` to completion on a new runtime
+fn block_on<F: Future>(future: F) -> Result<F::Output> {
+    let runtime = tokio::runtime::Builder::new_current_thread()
+        .enable_all()
+        .build()?;
+
+    Ok(runtime.block_on(future))
+}
+
+fn client(auth_file: &Path, marketplace: Option<&str>) -> PyResult<api::Client> {
+    let marketplace = match marketplace {
+        Some(code) => Some(
+            Marketplace::from_locale_code(code)
+                .ok_or_else(|| PyValueError::new_err(format!("Unknown marketplace {:?}", code)))?,
+        ),
+        None => None,
+    };
+
+    Ok(
+        api::Client::new(reqwest::Client::new(), auth_file, marketplace, false)?
+            .with_frontend(Warnings),
+    )
+}
+
+/// Passes the warnings of the API client on to the `warnings` module
+struct Warnings;
+
+impl Frontend for Warnings {
+    fn warn(&self, message: &str) {
+        Python::attach(|py| {
+            let warned = py
+                .import("warnings")
+                .and_then(|warnings| warnings.call_method1("warn", (message,)));
+
+            if let Err(e) = warned {
+                e.write_unraisable(py, None);
+            }
+        });
+    }
+}
+
+/// Calls the `progress` callback of [`download`] with the position and length every second
+struct Progress {
+    callback: Option<Py<PyAny>>,
+}
+
+impl Frontend for Progress {
+    fn rate(&self, position: u64, total: u64, _rate: u64) {
+        if let Some(callback) = &self.callback {
+            Python::attach(|py| {
+                if let Err(e) = callback.call1(py, (position, total)) {
+                    e.write_unraisable(py, Some(callback.bind(py)));
+                }
+            });
+        }
+    }
+}
+
+/// List the titles in the library, as dicts with the fields of `audible-dl library --format json`
+#[pyfunction]
+#[pyo3(signature = (auth_file, marketplace=None))]
+fn library<'py>(
+    py: Python<'py>,
+    auth_file: PathBuf,
+    marketplace: Option<&str>,
+) -> PyResult<Bound<'py, PyAny>> {
+    let api = client(&auth_file, marketplace)?;
+    let json = py.detach(|| -> Result<String> {
+        let items = block_on(api.library())??;
+        Ok(serde_json::to_string(&items)?)
+    })?;
+
+    py.import("json")?.call_method1("loads", (json,))
+}
+
+/// Signed URL to download the title `asin` from
+#[pyfunction]
+#[pyo3(signature = (auth_file, asin, marketplace=None))]
+fn download_url(
+    py: Python<'_>,
+    auth_file: PathBuf,
+    asin: &str,
+    marketplace: Option<&str>,
+) -> PyResult<String> {
+    let api = client(&auth_file, marketplace)?;
+    let url = py.detach(|| -> Result<String> {
+        let license = block_on(api.license(asin))??;
+        Ok(license.download_url()?.to_owned())
+    })?;
+
+    Ok(url)
+}
+
+/// Download `url` to `output`, resuming `<output>.part` if it's there, and return the path it
+/// was saved to. `progress` is called with the position and length every second.
+#[pyfunction]
+#[pyo3(signature = (url, output, progress=None))]
+fn download(
+    py: Python<'_>,
+    url: &str,
+    output: PathBuf,
+    progress: Option<Py<PyAny>>,
+) -> PyResult<PathBuf> {
+    let mut part = output.clone().into_os_string();
+    part.push(".part");
+    let part = PathBuf::from(part);
+
+    let frontend = Progress { callback: progress };
+    let completed = py.detach(|| {
+        let client = reqwest::Client::new();
+        let options = Options::default();
+
+        block_on(rangedl::transfer(
+            &client, url, &output, &part, false, &options, &frontend,
+        ))?
+    })?;
+
+    Ok(completed.path)
+}
+
+#[pymodule]
+#[pyo3(name = "audible_dl")]
+fn audible_dl(m: &Bound<'_, PyModule>) -> PyResult<()> {
+    m.add_function(wrap_pyfunction!(library, m)?)?;
+    m.add_function(wrap_pyfunction!(download_url, m)?)?;
+    m.add_function(wrap_pyfunction!(download, m)?)?;
+
+    Ok(())
+}
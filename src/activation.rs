@@ -0,0 +1,171 @@
+//! Sockets passed by systemd socket activation.
+//!
+//! systemd listens on the sockets of a `.socket` unit itself, and only starts the service once a
+//! connection comes in, handing the sockets over as file descriptors 3 and up. `LISTEN_PID` and
+//! `LISTEN_FDS` say whether there are any and how many, and `LISTEN_FDNAMES` names them after
+//! the `FileDescriptorName=` of their unit, which is how `watch` tells what each one is for.
+
+use std::net::SocketAddr;
+
+use anyhow::{bail, Context, Result};
+use tokio::net::TcpListener;
+
+/// Names of the sockets `watch` knows what to do with
+pub const NAMES: [&str; 3] = ["health", "cache", "web"];
+
+/// The sockets systemd passed to this process, by name
+pub struct Sockets(Vec<(String, std::net::TcpListener)>);
+
+impl Sockets {
+    /// Take over the sockets passed by systemd, if any
+    pub fn from_env() -> Result<Sockets> {
+        let sockets = passed().context("Failed to take over the sockets from systemd")?;
+
+        // Like `sd_listen_fds`, so that processes started from here don't pick them up too
+        for name in ["LISTEN_PID", "LISTEN_FDS", "LISTEN_FDNAMES"] {
+            std::env::remove_var(name);
+        }
+
+        Ok(Sockets(sockets))
+    }
+
+    /// The socket called `name` if systemd passed one, otherwise one bound to `addr`, if given
+    pub async fn listener(
+        &mut self,
+        name: &str,
+        addr: Option<SocketAddr>,
+    ) -> Result<Option<TcpListener>> {
+        if let Some(index) = self.0.iter().position(|(other, _)| other == name) {
+            let (_, listener) = self.0.remove(index);
+            listener.set_nonblocking(true)?;
+
+            return Ok(Some(TcpListener::from_std(listener)?));
+        }
+
+        match addr {
+            Some(addr) => {
+                Ok(Some(TcpListener::bind(addr).await.with_context(|| {
+                    format!("Failed to listen on {}", addr)
+                })?))
+            }
+            None => Ok(None),
+        }
+    }
+
+    /// Names of the sockets that weren't taken
+    pub fn unused(&self) -> impl Iterator<Item = &str> {
+        self.0.iter().map(|(name, _)| name.as_str())
+    }
+}
+
+/// The first file descriptor passed by systemd, after stdin, stdout and stderr
+const FIRST_FD: i32 = 3;
+
+/// More sockets than this can't have been passed, it's the usual limit of open files
+const MAX_FDS: i32 = 1024;
+
+/// The file descriptors passed to the process `pid` by name, from the values of `LISTEN_PID`,
+/// `LISTEN_FDS` and `LISTEN_FDNAMES`
+fn parse(
+    listen_pid: Option<&str>,
+    listen_fds: Option<&str>,
+    listen_fdnames: Option<&str>,
+    pid: u32,
+) -> Result<Vec<(String, i32)>> {
+    let for_us = listen_pid.and_then(|listen_pid| listen_pid.parse::<u32>().ok()) == Some(pid);
+
+    if !for_us {
+        return Ok(Vec::new());
+    }
+
+    let count = listen_fds
+        .context("LISTEN_PID is set but LISTEN_FDS isn't")?
+        .parse::<i32>()
+        .context("Invalid LISTEN_FDS")?;
+
+    if !(0..=MAX_FDS).contains(&count) {
+        bail!("Invalid LISTEN_FDS {}", count);
+    }
+
+    // systemd names sockets without a `FileDescriptorName=` after their unit, and leaves out
+    // LISTEN_FDNAMES only when it's older than the names
+    let names = match listen_fdnames {
+        Some("") => Vec::new(),
+        Some(names) => names.split(':').map(str::to_owned).collect::<Vec<_>>(),
+        None => vec!["unknown".to_owned(); count as usize],
+    };
+
+    if names.len() != count as usize {
+        bail!(
+            "LISTEN_FDNAMES has {} names for {} sockets in LISTEN_FDS",
+            names.len(),
+            count
+        );
+    }
+
+    Ok(names.into_iter().zip(FIRST_FD..).collect())
+}
+
+fn passed() -> Result<Vec<(String, std::net::TcpListener)>> {
+    let var = |name| std::env::var(name).ok();
+    let fds = parse(
+        var("LISTEN_PID").as_deref(),
+        var("LISTEN_FDS").as_deref(),
+        var("LISTEN_FDNAMES").as_deref(),
+        std::process::id(),
+    )?;
+
+    take_over(fds)
+}
+
+#[cfg(unix)]
+fn take_over(fds: Vec<(String, i32)>) -> Result<Vec<(String, std::net::TcpListener)>> {
+    use std::os::fd::FromRawFd;
+
+    Ok(fds
+        .into_iter()
+        .map(|(name, fd)| {
+            // SAFETY: systemd passes these to this process (`LISTEN_PID`) for it to own, and
+            // nothing else in it uses them
+            (name, unsafe { std::net::TcpListener::from_raw_fd(fd) })
+        })
+        .collect())
+}
+
+#[cfg(not(unix))]
+fn take_over(fds: Vec<(String, i32)>) -> Result<Vec<(String, std::net::TcpListener)>> {
+    match fds.is_empty() {
+        true => Ok(Vec::new()),
+        false => bail!("Socket activation is only supported on Unix"),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_env() {
+        let parse = |pid, fds, names| parse(pid, fds, names, 42);
+
+        assert!(parse(None, Some("2"), None).unwrap().is_empty());
+        assert!(parse(Some("41"), Some("2"), None).unwrap().is_empty());
+        assert_eq!(
+            parse(Some("42"), Some("2"), Some("web:health")).unwrap(),
+            [("web".to_owned(), 3), ("health".to_owned(), 4)]
+        );
+        assert_eq!(
+            parse(Some("42"), Some("1"), None).unwrap(),
+            [("unknown".to_owned(), 3)]
+        );
+        assert!(parse(Some("42"), Some("0"), Some("")).unwrap().is_empty());
+        assert!(parse(Some("42"), Some("0"), None).unwrap().is_empty());
+
+        assert!(parse(Some("42"), None, None).is_err());
+        assert!(parse(Some("42"), Some("-1"), None).is_err());
+        assert!(parse(Some("42"), Some("2147483647"), None).is_err());
+        assert!(parse(Some("42"), Some("1"), Some("")).is_err());
+        assert!(parse(Some("42"), Some("2"), Some("web")).is_err());
+        assert!(parse(Some("42"), Some("1"), Some("web:health")).is_err());
+    }
+}
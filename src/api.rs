@@ -0,0 +1,80 @@
+//! Options for commands that talk to the Audible API, see [`audible_dl_core::api`] for the client.
+
+use std::path::PathBuf;
+
+use anyhow::Result;
+
+pub use audible_dl_core::api::*;
+
+use audible_dl_core::Frontend;
+use auth::{Auth, AuthProvider};
+use cookies::Cookies;
+use external::ExternalCommand;
+
+use crate::{paths, style};
+
+/// Options for commands that talk to the Audible API
+#[derive(clap::Args, Debug)]
+#[command(group(clap::ArgGroup::new("credentials").required(true)))]
+pub struct ApiArgs {
+    /// Auth file with the Audible credentials, as created by `audible quickstart` from audible-cli
+    #[arg(long, env = "AUDIBLE_DL_AUTH_FILE", group = "credentials")]
+    auth_file: Option<PathBuf>,
+
+    /// Cookies of a browser logged in to Audible, as a `cookies.txt` file in the Netscape format
+    #[arg(
+        long,
+        env = "AUDIBLE_DL_AUTH_COOKIES",
+        value_name = "FILE",
+        group = "credentials"
+    )]
+    auth_cookies: Option<PathBuf>,
+
+    /// Command that prints the credentials as JSON with the fields of an auth file, run again
+    /// when the access token expires, e.g. `pass show audible`
+    #[arg(
+        long,
+        env = "AUDIBLE_DL_AUTH_COMMAND",
+        value_name = "COMMAND",
+        group = "credentials"
+    )]
+    auth_command: Option<String>,
+
+    /// Marketplace to use, defaults to the one in the auth file
+    #[arg(long, env = "AUDIBLE_DL_MARKETPLACE", value_enum)]
+    marketplace: Option<Marketplace>,
+
+    /// Sign requests with the time of the API servers when the local clock is off
+    #[arg(long, env = "AUDIBLE_DL_FIX_CLOCK_SKEW")]
+    fix_clock_skew: bool,
+}
+
+impl ApiArgs {
+    /// Client for the API with these options
+    pub fn client(&self, http: reqwest::Client) -> Result<Client> {
+        let auth: Box<dyn AuthProvider> =
+            match (&self.auth_file, &self.auth_cookies, &self.auth_command) {
+                (Some(path), _, _) => Box::new(Auth::load(path)?),
+                (_, Some(path), _) => Box::new(Cookies::load(path)?),
+                (_, _, Some(command)) => Box::new(ExternalCommand::run(command)?),
+                _ => unreachable!("clap requires one of the credentials"),
+            };
+
+        let client = Client::with_auth(http, auth, self.marketplace, self.fix_clock_skew)?
+            .with_frontend(Warnings);
+
+        Ok(match paths::ephemeral() {
+            true => client.ephemeral(),
+            false => client,
+        })
+    }
+}
+
+/// Prints the warnings of the API client
+struct Warnings;
+
+impl Frontend for Warnings {
+    fn warn(&self, message: &str) {
+        eprintln!("{} {}", style::warning("Warning:"), message);
+    }
+}
@@ -0,0 +1,106 @@
+//! Decoding the audio of converted books, to make sure it plays all the way through.
+
+use std::fs::File;
+use std::path::Path;
+
+use anyhow::{anyhow, bail, Context, Result};
+use indicatif::ProgressBar;
+use symphonia::core::codecs::{DecoderOptions, CODEC_TYPE_NULL};
+use symphonia::core::errors::Error;
+use symphonia::core::formats::{FormatOptions, FormatReader, Track};
+use symphonia::core::io::MediaSourceStream;
+use symphonia::core::meta::MetadataOptions;
+use symphonia::core::probe::Hint;
+use symphonia::core::units::{TimeBase, TimeStamp};
+
+/// Open the file at `path`, and find its audio track
+pub fn open(path: &Path) -> Result<(Box<dyn FormatReader>, Track)> {
+    let file = File::open(path).with_context(|| format!("Failed to open {}", path.display()))?;
+    let source = MediaSourceStream::new(Box::new(file), Default::default());
+
+    let reader = symphonia::default::get_probe()
+        .format(
+            &Hint::new(),
+            source,
+            &FormatOptions::default(),
+            &MetadataOptions::default(),
+        )
+        .with_context(|| format!("{} isn't a supported audio file", path.display()))?
+        .format;
+
+    let track = reader
+        .tracks()
+        .iter()
+        .find(|track| track.codec_params.codec != CODEC_TYPE_NULL)
+        .ok_or_else(|| anyhow!("{} has no audio track", path.display()))?
+        .clone();
+
+    Ok((reader, track))
+}
+
+/// Position `ts` in the track as `h:mm:ss.mmm`
+pub fn timestamp(time_base: Option<TimeBase>, ts: TimeStamp) -> String {
+    let Some(time_base) = time_base else {
+        return format!("sample {}", ts);
+    };
+
+    let time = time_base.calc_time(ts);
+    let millis = (time.frac * 1000.0) as u64;
+
+    format!(
+        "{}:{:02}:{:02}.{:03}",
+        time.seconds / 3600,
+        time.seconds / 60 % 60,
+        time.seconds % 60,
+        millis
+    )
+}
+
+/// Decode all audio of the file at `path`, failing with the position of every error
+pub fn verify(path: &Path, pb: &ProgressBar) -> Result<()> {
+    let (mut reader, track) = open(path)?;
+    let time_base = track.codec_params.time_base;
+
+    let mut decoder = symphonia::default::get_codecs()
+        .make(&track.codec_params, &DecoderOptions { verify: true })
+        .context("Unsupported audio codec")?;
+
+    pb.set_length(track.codec_params.n_frames.unwrap_or(0));
+
+    let mut errors = Vec::new();
+    let mut position = 0;
+
+    loop {
+        let packet = match reader.next_packet() {
+            Ok(packet) => packet,
+            Err(Error::IoError(e)) if e.kind() == std::io::ErrorKind::UnexpectedEof => break,
+            Err(e) => {
+                return Err(anyhow!(e).context(format!(
+                    "Unreadable audio after {}",
+                    timestamp(time_base, position)
+                )))
+            }
+        };
+
+        if packet.track_id() != track.id {
+            continue;
+        }
+
+        match decoder.decode(&packet) {
+            Ok(_) => {}
+            Err(Error::DecodeError(e)) => {
+                errors.push(format!("{} ({})", timestamp(time_base, packet.ts()), e))
+            }
+            Err(e) => return Err(e.into()),
+        }
+
+        position = packet.ts() + packet.dur();
+        pb.set_position(position);
+    }
+
+    if !errors.is_empty() {
+        bail!("The audio fails to decode at {}", errors.join(", "));
+    }
+
+    Ok(())
+}
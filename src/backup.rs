@@ -0,0 +1,545 @@
+//! `audible-dl backup`, carrying the config and the history of a watch folder to another machine.
+//!
+//! A backup is a `.tar.zst` archive of a `manifest.json`, the config file without secrets, the
+//! local tags, a snapshot of the state database, and the `reports/`, `done/` and `failed/`
+//! directories of the watch folder under `watch/`. The manifest records the version of this layout, so that restoring a backup made by
+//! an older audible-dl can upgrade it, and one made by a newer audible-dl is refused rather than
+//! half restored. Backups made as `.tar.gz` archives, before zstd, are still restored.
+
+use std::fs::File;
+use std::io::{Read, Seek, SeekFrom};
+use std::path::{Component, Path, PathBuf};
+use std::sync::Arc;
+
+use anyhow::{bail, Context, Result};
+use flate2::read::GzDecoder;
+use serde::{Deserialize, Serialize};
+
+use crate::report::Report;
+use crate::{config, conflict, db, tags};
+
+/// Version of the layout of backups, bump it along with a migration in [`migrate`]
+const FORMAT_VERSION: u32 = 2;
+
+const MANIFEST: &str = "manifest.json";
+const CONFIG: &str = "config.env";
+const TAGS: &str = "tags.json";
+const DB: &str = "state.db";
+const WATCH: &str = "watch";
+
+/// Directories of the watch folder that are backed up
+const HISTORY: [&str; 3] = ["reports", "done", "failed"];
+
+/// Start of every zstd frame, telling backups apart from the `.tar.gz` ones made before
+const ZSTD_MAGIC: [u8; 4] = [0x28, 0xb5, 0x2f, 0xfd];
+
+#[derive(clap::Args, Debug)]
+pub struct BackupArgs {
+    #[command(subcommand)]
+    command: BackupCommand,
+}
+
+#[derive(clap::Subcommand, Debug)]
+enum BackupCommand {
+    /// Save the config file, without secrets, the local tags, the state database and the history
+    /// of the watch folder in an archive
+    Create(CreateArgs),
+
+    /// Restore the config file, the local tags, the state database and the history of the watch
+    /// folder from an archive
+    Restore(RestoreArgs),
+}
+
+#[derive(clap::Args, Debug)]
+struct CreateArgs {
+    /// Archive to write, e.g. `audible-dl.tar.zst`
+    archive: PathBuf,
+
+    /// Watch folder to save the reports and the `done/` and `failed/` `.sku` files of
+    #[arg(long, env = "AUDIBLE_DL_WATCH_DIR")]
+    watch_dir: Option<PathBuf>,
+}
+
+#[derive(clap::Args, Debug)]
+struct RestoreArgs {
+    /// Archive written by `backup create`
+    archive: PathBuf,
+
+    /// Watch folder to restore the history into, it's skipped without one
+    #[arg(long, env = "AUDIBLE_DL_WATCH_DIR")]
+    watch_dir: Option<PathBuf>,
+
+    /// Replace an existing config file, tags and state database without asking
+    #[arg(short = 'y', long, env = "AUDIBLE_DL_RESTORE_ASSUME_YES")]
+    assume_yes: bool,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+struct Manifest {
+    version: u32,
+    created_at: String,
+    audible_dl: String,
+    /// Variables left out of the config file, which have to be set again after restoring
+    #[serde(default)]
+    secrets: Vec<String>,
+}
+
+pub fn run(args: BackupArgs) -> Result<()> {
+    match args.command {
+        BackupCommand::Create(args) => create(&args),
+        BackupCommand::Restore(args) => restore(&args),
+    }
+}
+
+fn append(
+    builder: &mut tar::Builder<zstd::Encoder<'static, File>>,
+    path: impl AsRef<Path>,
+    data: &[u8],
+) -> Result<()> {
+    let mut header = tar::Header::new_gnu();
+    header.set_size(data.len() as u64);
+    header.set_mode(0o644);
+    header.set_mtime(chrono::Utc::now().timestamp() as u64);
+    header.set_cksum();
+
+    Ok(builder.append_data(&mut header, path, data)?)
+}
+
+fn create(args: &CreateArgs) -> Result<()> {
+    let config_path = config::path()?;
+
+    let (config, secrets) = match std::fs::read_to_string(&config_path) {
+        Ok(contents) => {
+            let (config, secrets) = config::without_secrets(&contents);
+            (Some(config), secrets)
+        }
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => (None, Vec::new()),
+        Err(e) => {
+            return Err(e).with_context(|| format!("Failed to read {}", config_path.display()))
+        }
+    };
+
+    let tags_path = tags::path()?;
+
+    let tags = match std::fs::read(&tags_path) {
+        Ok(contents) => Some(contents),
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => None,
+        Err(e) => return Err(e).with_context(|| format!("Failed to read {}", tags_path.display())),
+    };
+
+    let db_path = db::path()?;
+
+    // A copy taken in one transaction, rather than the file as another instance writes it
+    let state = if db_path.exists() {
+        let snapshot = std::env::temp_dir().join(format!("audible-dl-{}.db", std::process::id()));
+        let _ = std::fs::remove_file(&snapshot);
+
+        let copied = db::Db::open(&db_path)
+            .and_then(|db| db.snapshot(&snapshot))
+            .and_then(|()| Ok(std::fs::read(&snapshot)?));
+        let _ = std::fs::remove_file(&snapshot);
+
+        Some(copied.with_context(|| format!("Failed to copy {}", db_path.display()))?)
+    } else {
+        None
+    };
+
+    if config.is_none() && tags.is_none() && state.is_none() && args.watch_dir.is_none() {
+        bail!(
+            "Nothing to back up, there's no {} and no --watch-dir",
+            config_path.display()
+        );
+    }
+
+    let file = File::create(&args.archive)
+        .with_context(|| format!("Failed to create {}", args.archive.display()))?;
+    let mut builder = tar::Builder::new(zstd::Encoder::new(file, 0)?);
+
+    // The manifest comes first, so that restoring can check it before anything else
+    let manifest = Manifest {
+        version: FORMAT_VERSION,
+        created_at: chrono::Utc::now().to_rfc3339(),
+        audible_dl: env!("CARGO_PKG_VERSION").to_owned(),
+        secrets,
+    };
+    append(
+        &mut builder,
+        MANIFEST,
+        &serde_json::to_vec_pretty(&manifest)?,
+    )?;
+
+    if let Some(config) = &config {
+        append(&mut builder, CONFIG, config.as_bytes())?;
+        eprintln!("Saved {}", config_path.display());
+    }
+
+    if let Some(tags) = &tags {
+        append(&mut builder, TAGS, tags)?;
+        eprintln!("Saved {}", tags_path.display());
+    }
+
+    if let Some(state) = &state {
+        append(&mut builder, DB, state)?;
+        eprintln!("Saved {}", db_path.display());
+    }
+
+    for name in manifest.secrets.iter() {
+        eprintln!("Left out {}, set it again after restoring", name);
+    }
+
+    if let Some(watch_dir) = &args.watch_dir {
+        for name in HISTORY {
+            let dir = watch_dir.join(name);
+
+            if dir.is_dir() {
+                builder
+                    .append_dir_all(Path::new(WATCH).join(name), &dir)
+                    .with_context(|| format!("Failed to save {}", dir.display()))?;
+                eprintln!("Saved {}", dir.display());
+            }
+        }
+    }
+
+    builder.into_inner()?.finish()?;
+
+    eprintln!("Wrote {}", args.archive.display());
+
+    Ok(())
+}
+
+/// Check that a backup made with the layout of `manifest` can be restored
+fn check_version(manifest: &Manifest) -> Result<()> {
+    match manifest.version {
+        1..=FORMAT_VERSION => Ok(()),
+        version if version > FORMAT_VERSION => bail!(
+            "The backup was made by audible-dl {} (format {}), update with `audible-dl self-update` to restore it",
+            manifest.audible_dl,
+            version
+        ),
+        version => bail!("Unknown backup format {}", version),
+    }
+}
+
+/// Upgrade what was restored from a backup made with an older layout to the current one, given
+/// the `reports` it added to the watch folder and a way to open the state database
+fn migrate(
+    manifest: &Manifest,
+    reports: &[PathBuf],
+    db: impl FnOnce() -> Option<Arc<db::Db>>,
+) -> Result<()> {
+    // Version 1 came before the state database, the history is only in the reports
+    if manifest.version < 2 && !reports.is_empty() {
+        let Some(db) = db() else {
+            return Ok(());
+        };
+
+        for path in reports {
+            let report: Report = serde_json::from_slice(&std::fs::read(path)?)
+                .with_context(|| format!("Invalid report {}", path.display()))?;
+            db.record(&report)?;
+        }
+
+        eprintln!(
+            "Added the books of {} reports to the history in the state database",
+            reports.len()
+        );
+    }
+
+    Ok(())
+}
+
+/// Path of an entry under `watch/` relative to the watch folder, refusing anything that would
+/// end up outside of it
+fn history_path(path: &Path) -> Option<PathBuf> {
+    let relative = path.strip_prefix(WATCH).ok()?;
+
+    relative
+        .components()
+        .all(|component| matches!(component, Component::Normal(_)))
+        .then(|| relative.to_path_buf())
+        .filter(|relative| !relative.as_os_str().is_empty())
+}
+
+/// Open a backup, whether it's compressed with zstd or, made before, with gzip
+fn open(path: &Path) -> Result<tar::Archive<Box<dyn Read>>> {
+    let mut file =
+        File::open(path).with_context(|| format!("Failed to open {}", path.display()))?;
+
+    let mut magic = [0; 4];
+    let zstd = file.read_exact(&mut magic).is_ok() && magic == ZSTD_MAGIC;
+    file.seek(SeekFrom::Start(0))?;
+
+    let reader: Box<dyn Read> = match zstd {
+        true => Box::new(zstd::Decoder::new(file)?),
+        false => Box::new(GzDecoder::new(file)),
+    };
+
+    Ok(tar::Archive::new(reader))
+}
+
+fn restore(args: &RestoreArgs) -> Result<()> {
+    let mut archive = open(&args.archive)?;
+    let mut entries = archive.entries()?;
+
+    let mut manifest = match entries.next() {
+        Some(entry) => entry?,
+        None => bail!("{} is empty", args.archive.display()),
+    };
+
+    if manifest.path()? != Path::new(MANIFEST) {
+        bail!(
+            "{} isn't a backup made by audible-dl",
+            args.archive.display()
+        );
+    }
+
+    let mut contents = Vec::new();
+    manifest.read_to_end(&mut contents)?;
+    let manifest: Manifest =
+        serde_json::from_slice(&contents).context("Invalid manifest in the backup")?;
+
+    check_version(&manifest)?;
+
+    // The history is unpacked here first, so that `unpack_in` checks every path, and then moved
+    // into place, next to it on the same file system
+    let staging = args
+        .watch_dir
+        .as_ref()
+        .map(|dir| dir.join(format!(".audible-dl-restore-{}", std::process::id())));
+
+    let result = restore_entries(args, entries, staging.as_deref());
+
+    if let Some(staging) = &staging {
+        let _ = std::fs::remove_dir_all(staging);
+    }
+
+    let reports = result?;
+
+    migrate(&manifest, &reports, db::shared)?;
+
+    for name in &manifest.secrets {
+        eprintln!("The backup doesn't include {}, set it again", name);
+    }
+
+    Ok(())
+}
+
+/// Restore the entries after the manifest, returning the reports added to the watch folder
+fn restore_entries(
+    args: &RestoreArgs,
+    entries: tar::Entries<'_, Box<dyn Read>>,
+    staging: Option<&Path>,
+) -> Result<Vec<PathBuf>> {
+    let mut reports = Vec::new();
+    let mut restored = 0;
+    let mut existing = 0;
+    let mut skipped_history = false;
+
+    for entry in entries {
+        let mut entry = entry?;
+        let path = entry.path()?.into_owned();
+        let kind = entry.header().entry_type();
+
+        // Links could point the entries after them anywhere
+        if !kind.is_file() && !kind.is_dir() {
+            bail!("Unexpected {:?} {} in the backup", kind, path.display());
+        }
+
+        let file = match path.to_str() {
+            Some(CONFIG) => Some(config::path()?),
+            Some(TAGS) => Some(tags::path()?),
+            Some(DB) => Some(db::path()?),
+            _ => None,
+        };
+
+        if let Some(file) = file {
+            if file.exists()
+                && !conflict::confirm(
+                    &format!("{} already exists, replace it?", file.display()),
+                    args.assume_yes,
+                )?
+            {
+                eprintln!("Kept {}", file.display());
+                continue;
+            }
+
+            if let Some(dir) = file.parent() {
+                std::fs::create_dir_all(dir)
+                    .with_context(|| format!("Failed to create {}", dir.display()))?;
+            }
+
+            let mut contents = Vec::new();
+            entry.read_to_end(&mut contents)?;
+            std::fs::write(&file, contents)
+                .with_context(|| format!("Failed to write {}", file.display()))?;
+            eprintln!("Restored {}", file.display());
+
+            continue;
+        }
+
+        let Some(relative) = history_path(&path) else {
+            bail!("Unexpected {} in the backup", path.display());
+        };
+
+        let (Some(watch_dir), Some(staging)) = (&args.watch_dir, staging) else {
+            skipped_history = true;
+            continue;
+        };
+
+        let target = watch_dir.join(&relative);
+
+        if kind.is_dir() {
+            std::fs::create_dir_all(&target)
+                .with_context(|| format!("Failed to create {}", target.display()))?;
+            continue;
+        }
+
+        // Reports and `.sku` files of this machine are never replaced
+        if target.exists() {
+            existing += 1;
+            continue;
+        }
+
+        std::fs::create_dir_all(staging)
+            .with_context(|| format!("Failed to create {}", staging.display()))?;
+
+        if !entry
+            .unpack_in(staging)
+            .with_context(|| format!("Failed to write {}", target.display()))?
+        {
+            bail!("Unexpected {} in the backup", path.display());
+        }
+
+        if let Some(dir) = target.parent() {
+            std::fs::create_dir_all(dir)
+                .with_context(|| format!("Failed to create {}", dir.display()))?;
+        }
+
+        std::fs::rename(staging.join(&path), &target)
+            .with_context(|| format!("Failed to write {}", target.display()))?;
+
+        restored += 1;
+
+        if relative.starts_with("reports") {
+            reports.push(target);
+        }
+    }
+
+    if let Some(watch_dir) = &args.watch_dir {
+        eprintln!(
+            "Restored {} files of history into {}, {} already existed",
+            restored,
+            watch_dir.display(),
+            existing
+        );
+    }
+
+    if skipped_history {
+        eprintln!("The backup has the history of a watch folder, restore it with --watch-dir");
+    }
+
+    Ok(reports)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn keeps_history_inside_watch_folder() {
+        assert_eq!(
+            history_path(Path::new("watch/reports/report-1.json")),
+            Some(PathBuf::from("reports/report-1.json"))
+        );
+        assert_eq!(history_path(Path::new("watch/../config.env")), None);
+        assert_eq!(history_path(Path::new("watch")), None);
+        assert_eq!(history_path(Path::new("other/done/a.sku")), None);
+    }
+
+    fn manifest(version: u32) -> Manifest {
+        Manifest {
+            version,
+            created_at: "2024-01-01T00:00:00Z".to_owned(),
+            audible_dl: "0.1.0".to_owned(),
+            secrets: Vec::new(),
+        }
+    }
+
+    #[test]
+    fn refuses_links() {
+        let dir = std::env::temp_dir().join(format!("audible-dl-backup-{}", std::process::id()));
+        let watch_dir = dir.join("watch");
+        std::fs::create_dir_all(&watch_dir).unwrap();
+
+        let archive = dir.join("backup.tar.zst");
+        let mut builder =
+            tar::Builder::new(zstd::Encoder::new(File::create(&archive).unwrap(), 0).unwrap());
+        append(
+            &mut builder,
+            MANIFEST,
+            &serde_json::to_vec(&manifest(FORMAT_VERSION)).unwrap(),
+        )
+        .unwrap();
+
+        // A link out of the watch folder, followed by a file written through it
+        let mut header = tar::Header::new_gnu();
+        header.set_entry_type(tar::EntryType::Symlink);
+        header.set_size(0);
+        builder
+            .append_link(&mut header, "watch/reports", &dir)
+            .unwrap();
+        append(&mut builder, "watch/reports/escaped.json", b"{}").unwrap();
+        builder.into_inner().unwrap().finish().unwrap();
+
+        let result = restore(&RestoreArgs {
+            archive: archive.clone(),
+            watch_dir: Some(watch_dir.clone()),
+            assume_yes: true,
+        });
+
+        let escaped = dir.join("escaped.json").exists();
+        let left = std::fs::read_dir(&watch_dir).unwrap().count();
+        std::fs::remove_dir_all(&dir).unwrap();
+
+        assert!(result.is_err());
+        assert!(!escaped);
+        assert_eq!(left, 0);
+    }
+
+    #[test]
+    fn checks_version() {
+        assert!(check_version(&manifest(1)).is_ok());
+        assert!(check_version(&manifest(FORMAT_VERSION)).is_ok());
+        assert!(check_version(&manifest(FORMAT_VERSION + 1)).is_err());
+        assert!(check_version(&manifest(0)).is_err());
+    }
+
+    #[test]
+    fn adds_reports_of_old_backups_to_history() {
+        let dir = std::env::temp_dir().join(format!("audible-dl-migrate-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let open = || Some(Arc::new(db::Db::open(&dir.join("state.db")).unwrap()));
+
+        let report = dir.join("report-20240101T000000.000Z.json");
+        std::fs::write(
+            &report,
+            r#"{"source": "/watch/a.sku", "started": "2024-01-01T00:00:00Z", "finished": "2024-01-01T01:00:00Z",
+                "downloaded": 1, "skipped": 0, "failed": 1, "items": [
+                {"sku": "BK_ADBL_000001_22", "status": "downloaded", "path": "/books/BK_ADBL_000001_22.aax"},
+                {"sku": "BK_ADBL_000002_22", "status": "failed", "error": "Gone"}
+            ]}"#,
+        )
+        .unwrap();
+
+        migrate(&manifest(2), std::slice::from_ref(&report), open).unwrap();
+        migrate(&manifest(1), std::slice::from_ref(&report), open).unwrap();
+
+        let conn = rusqlite::Connection::open(dir.join("state.db")).unwrap();
+        let count: u32 = conn
+            .query_row("SELECT count(*) FROM history", [], |row| row.get(0))
+            .unwrap();
+        std::fs::remove_dir_all(&dir).unwrap();
+
+        assert_eq!(count, 2);
+    }
+}
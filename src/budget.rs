@@ -0,0 +1,109 @@
+//! `--max-total-time`, a time limit for a whole batch of downloads.
+//!
+//! The clock starts with the batch. Once the time is up, the book being downloaded stops where
+//! it is and keeps its partial file, and the titles that weren't downloaded are written to a
+//! `-remaining.sku` file, which a watch folder picks up like any other to carry on.
+
+use std::path::{Path, PathBuf};
+use std::time::{Duration, Instant};
+
+use anyhow::{Context, Result};
+
+use audible_dl_core::rangedl::OutOfTime;
+
+/// Parse a duration like `6h`, `90m`, `1h30m` or `45s`, for use as a clap value parser
+pub fn parse_duration(s: &str) -> Result<Duration, String> {
+    let error = || format!("{:?} isn't a duration, e.g. 6h, 90m or 1h30m", s);
+
+    let mut total = 0;
+    let mut number = String::new();
+
+    for c in s.trim().chars() {
+        if c.is_ascii_digit() {
+            number.push(c);
+            continue;
+        }
+
+        let unit = match c {
+            'h' => 3600,
+            'm' => 60,
+            's' => 1,
+            _ => return Err(error()),
+        };
+
+        total = number
+            .parse::<u64>()
+            .ok()
+            .and_then(|number| number.checked_mul(unit))
+            .and_then(|seconds| seconds.checked_add(total))
+            .ok_or_else(error)?;
+        number.clear();
+    }
+
+    if !number.is_empty() || total == 0 {
+        return Err(error());
+    }
+
+    Ok(Duration::from_secs(total))
+}
+
+/// When a batch started now with the time limit `limit` has to stop, never for a limit beyond
+/// what the clock can count to
+pub fn stop_at(limit: Option<Duration>) -> Option<Instant> {
+    limit.and_then(|limit| Instant::now().checked_add(limit))
+}
+
+/// Whether the time of a batch that has to stop at `stop_at` is up
+pub fn is_up(stop_at: Option<Instant>) -> bool {
+    stop_at.is_some_and(|at| Instant::now() >= at)
+}
+
+/// Whether a download failed because the time of the batch was up
+pub fn ran_out(e: &anyhow::Error) -> bool {
+    e.downcast_ref::<OutOfTime>().is_some()
+}
+
+/// Write the SKUs left when the time of the batch `batch` was up to `<batch>-remaining.sku` in
+/// `dir`, returning its path
+pub async fn write_remaining(dir: &Path, batch: &Path, skus: &[String]) -> Result<PathBuf> {
+    let stem = batch.file_stem().unwrap_or_default().to_string_lossy();
+    let path = dir.join(format!("{}-remaining.sku", stem));
+
+    let mut contents = format!(
+        "# Left when --max-total-time was up while downloading {}\n",
+        batch.display()
+    );
+
+    for sku in skus {
+        contents += &format!("{}\n", sku);
+    }
+
+    tokio::fs::write(&path, contents)
+        .await
+        .with_context(|| format!("Failed to write {}", path.display()))?;
+
+    Ok(path)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_durations() {
+        assert_eq!(parse_duration("6h"), Ok(Duration::from_secs(6 * 3600)));
+        assert_eq!(parse_duration("90m"), Ok(Duration::from_secs(90 * 60)));
+        assert_eq!(parse_duration("1h30m"), Ok(Duration::from_secs(5400)));
+        assert_eq!(parse_duration("45s"), Ok(Duration::from_secs(45)));
+
+        for value in ["", "6", "h", "0h", "6d", "1.5h", "9999999999999999h"] {
+            assert!(parse_duration(value).is_err(), "{value}");
+        }
+
+        // Each part fits, their sum doesn't
+        assert!(parse_duration(&format!("{}s{}s", u64::MAX, 1)).is_err());
+
+        let longest = parse_duration(&format!("{}h", u64::MAX / 3600)).unwrap();
+        assert_eq!(stop_at(Some(longest)), None);
+    }
+}
@@ -0,0 +1,201 @@
+//! Sharing downloaded books between machines of the same Audible account.
+//!
+//! `watch --serve-cache` answers `GET /books/<SKU>` with the book from its output directory,
+//! honoring `Range` so that downloads from it resume like downloads from the CDS. Instances
+//! given `--cache` ask it for a book before going to Audible. Books are encrypted for the account
+//! that downloaded them, so the cache is only of use to machines logged in to that account.
+
+use std::io::SeekFrom;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+
+use anyhow::{bail, Result};
+use reqwest::{StatusCode, Url};
+use tokio::io::{AsyncBufReadExt, AsyncReadExt, AsyncSeekExt, AsyncWriteExt, BufReader};
+use tokio::net::{TcpListener, TcpStream};
+
+use crate::export;
+
+/// How long to wait for the cache to answer whether it has a book
+const LOOKUP_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Extensions a book downloaded by SKU can be saved with, and their content types
+const FORMATS: [(&str, &str); 3] = [
+    ("aax", "audio/vnd.audible.aax"),
+    ("aaxc", "audio/vnd.audible.aaxc"),
+    ("mp3", "audio/mpeg"),
+];
+
+/// Serve the books in `dir` to other instances of audible-dl
+pub async fn serve(listener: TcpListener, dir: PathBuf) {
+    loop {
+        match listener.accept().await {
+            Ok((stream, _)) => {
+                let dir = dir.clone();
+                tokio::spawn(async move {
+                    // Nothing useful to do if the client goes away mid-request
+                    let _ = respond(stream, &dir).await;
+                });
+            }
+            Err(e) => eprintln!("Cache failed to accept connection: {}", e),
+        }
+    }
+}
+
+/// Parse a `Range` header of a single range, `bytes=START-` or `bytes=START-END`
+pub fn parse_range(value: &str) -> Option<(u64, Option<u64>)> {
+    let (start, end) = value.trim().strip_prefix("bytes=")?.split_once('-')?;
+    let start = start.parse().ok()?;
+
+    match end {
+        "" => Some((start, None)),
+        end => Some((start, Some(end.parse().ok().filter(|end| *end >= start)?))),
+    }
+}
+
+/// The book with `sku` in `dir`, and its content type
+async fn find(dir: &Path, sku: &str) -> Option<(tokio::fs::File, &'static str)> {
+    for (extension, content_type) in FORMATS {
+        let path = dir.join(format!("{}.{}", sku, extension));
+
+        if let Ok(file) = tokio::fs::File::open(&path).await {
+            return Some((file, content_type));
+        }
+    }
+
+    None
+}
+
+async fn respond(stream: TcpStream, dir: &Path) -> std::io::Result<()> {
+    let mut stream = BufReader::new(stream);
+
+    let mut request_line = String::new();
+    stream.read_line(&mut request_line).await?;
+
+    let mut range = None;
+    let mut line = String::new();
+
+    while stream.read_line(&mut line).await? > 2 {
+        if let Some((name, value)) = line.split_once(':') {
+            if name.eq_ignore_ascii_case("range") {
+                range = parse_range(value);
+            }
+        }
+        line.clear();
+    }
+
+    let mut stream = stream.into_inner();
+
+    let mut parts = request_line.split_whitespace();
+    let sku = match (parts.next(), parts.next()) {
+        (Some("GET"), Some(path)) => path
+            .strip_prefix("/books/")
+            .filter(|sku| export::is_sku(sku)),
+        _ => None,
+    };
+
+    let Some((mut file, content_type)) = find(dir, sku.unwrap_or_default()).await else {
+        stream
+            .write_all(b"HTTP/1.1 404 Not Found\r\ncontent-length: 0\r\nconnection: close\r\n\r\n")
+            .await?;
+        return stream.shutdown().await;
+    };
+
+    let len = file.metadata().await?.len();
+
+    let (status, start, end) = match range {
+        None => ("200 OK", 0, len),
+        Some((start, _)) if start >= len => {
+            let head = format!(
+                "HTTP/1.1 416 Range Not Satisfiable\r\ncontent-range: bytes */{}\r\ncontent-length: 0\r\nconnection: close\r\n\r\n",
+                len
+            );
+            stream.write_all(head.as_bytes()).await?;
+            return stream.shutdown().await;
+        }
+        Some((start, end)) => (
+            "206 Partial Content",
+            start,
+            end.map_or(len, |end| (end + 1).min(len)),
+        ),
+    };
+
+    let mut head = format!(
+        "HTTP/1.1 {}\r\ncontent-type: {}\r\ncontent-length: {}\r\nconnection: close\r\n",
+        status,
+        content_type,
+        end - start
+    );
+
+    if range.is_some() {
+        head += &format!("content-range: bytes {}-{}/{}\r\n", start, end - 1, len);
+    }
+
+    stream.write_all(format!("{}\r\n", head).as_bytes()).await?;
+
+    file.seek(SeekFrom::Start(start)).await?;
+    tokio::io::copy(&mut file.take(end - start), &mut stream).await?;
+
+    stream.shutdown().await
+}
+
+/// The SKU a CDS download URL asks for, `None` for other URLs
+pub fn sku(url: &str) -> Option<String> {
+    let url = Url::parse(url).ok()?;
+
+    if url.host_str() != Some("cds.audible.com") {
+        return None;
+    }
+
+    url.query_pairs()
+        .find(|(name, _)| name == "product_id")
+        .map(|(_, value)| value.into_owned())
+}
+
+/// URL of the book that the CDS download `url` is for in the cache at `cache`, if it has it
+pub async fn lookup(client: &reqwest::Client, cache: &Url, url: &str) -> Result<Option<String>> {
+    let Some(sku) = sku(url) else {
+        return Ok(None);
+    };
+
+    let url = format!("{}/books/{}", cache.as_str().trim_end_matches('/'), sku);
+
+    let res = client
+        .get(&url)
+        .header("Range", "bytes=0-0")
+        .timeout(LOOKUP_TIMEOUT)
+        .send()
+        .await?;
+
+    match res.status() {
+        StatusCode::PARTIAL_CONTENT => Ok(Some(url)),
+        StatusCode::NOT_FOUND => Ok(None),
+        status => bail!("Invalid status code: {}", status),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_ranges() {
+        assert_eq!(parse_range(" bytes=0-\r\n"), Some((0, None)));
+        assert_eq!(parse_range("bytes=100-199"), Some((100, Some(199))));
+        assert_eq!(parse_range("bytes=-500"), None);
+        assert_eq!(parse_range("bytes=200-100"), None);
+        assert_eq!(parse_range("items=0-1"), None);
+    }
+
+    #[test]
+    fn reads_sku() {
+        assert_eq!(
+            sku("https://cds.audible.com/download?user_id=1&product_id=BK_ADBL_000123&codec=LC_128_44100_Stereo&awtype=AAX&cust_id=1").as_deref(),
+            Some("BK_ADBL_000123")
+        );
+        assert_eq!(
+            sku("https://example.com/book.aax?product_id=BK_ADBL_000123"),
+            None
+        );
+    }
+}
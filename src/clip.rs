@@ -0,0 +1,231 @@
+//! Cutting a short MP3 sample out of a converted book, to share when recommending it.
+//!
+//! The audio is decoded from the start of the sample and encoded again with LAME, at the
+//! original speed and sample rate.
+
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+
+use anyhow::{anyhow, bail, Context, Result};
+use mp3lame_encoder::{Bitrate, Builder, Encoder, FlushNoGap, InterleavedPcm, MonoPcm};
+use symphonia::core::audio::SampleBuffer;
+use symphonia::core::codecs::DecoderOptions;
+use symphonia::core::errors::Error;
+use symphonia::core::formats::{SeekMode, SeekTo};
+use symphonia::core::units::Time;
+
+use crate::{audio, part_path};
+
+#[derive(clap::Args, Debug)]
+pub struct ClipArgs {
+    /// Converted book, e.g. the M4B file from `audible-dl convert`
+    input: PathBuf,
+
+    /// Where the sample starts, as seconds, `m:ss` or `h:mm:ss`
+    #[arg(long, env = "AUDIBLE_DL_START", value_parser = parse_time)]
+    start: Duration,
+
+    /// Length of the sample, as seconds, `m:ss` or `h:mm:ss`
+    #[arg(long, env = "AUDIBLE_DL_LENGTH", value_parser = parse_time, default_value = "60")]
+    length: Duration,
+
+    /// Output file, defaults to the input file with a `.sample.mp3` extension
+    #[arg(short, long, env = "AUDIBLE_DL_CLIP_OUTPUT")]
+    output: Option<PathBuf>,
+}
+
+/// Parse a time given as seconds, `m:ss` or `h:mm:ss`, with optional fractions of a second
+fn parse_time(s: &str) -> Result<Duration, String> {
+    let error = || format!("{:?} isn't a time, use e.g. 90, 1:30 or 1:02:03", s);
+    let parts = s.trim().split(':').collect::<Vec<_>>();
+
+    if parts.len() > 3 {
+        return Err(error());
+    }
+
+    let (seconds, minutes) = parts.split_last().ok_or_else(error)?;
+    let seconds = seconds.parse::<f64>().map_err(|_| error())?;
+
+    if !seconds.is_finite() || seconds < 0.0 || (!minutes.is_empty() && seconds >= 60.0) {
+        return Err(error());
+    }
+
+    let mut total = 0;
+
+    for (i, part) in minutes.iter().enumerate() {
+        let value = part.parse::<u64>().map_err(|_| error())?;
+
+        // Minutes below hours must be less than an hour
+        if i > 0 && value >= 60 {
+            return Err(error());
+        }
+
+        total = total * 60 + value;
+    }
+
+    Ok(Duration::from_secs(total * 60) + Duration::from_secs_f64(seconds))
+}
+
+fn mp3_encoder(sample_rate: u32, channels: usize) -> Result<Encoder> {
+    if channels > 2 {
+        bail!("Only mono and stereo audio can be clipped, the book has {channels} channels");
+    }
+
+    let mut builder = Builder::new().ok_or_else(|| anyhow!("Failed to start the MP3 encoder"))?;
+    builder.set_sample_rate(sample_rate)?;
+    builder.set_num_channels(channels as u8)?;
+    builder.set_brate(Bitrate::Kbps128)?;
+
+    Ok(builder.build()?)
+}
+
+/// Decode `length` of the audio of `input` from `start`, and encode it as MP3
+fn encode(input: &Path, start: Duration, length: Duration) -> Result<Vec<u8>> {
+    let (mut reader, track) = audio::open(input)?;
+
+    let params = &track.codec_params;
+    let sample_rate = params
+        .sample_rate
+        .ok_or_else(|| anyhow!("{} has no sample rate", input.display()))?;
+
+    let mut decoder = symphonia::default::get_codecs()
+        .make(params, &DecoderOptions::default())
+        .context("Unsupported audio codec, decrypt the book with `audible-dl convert` first")?;
+
+    let time_base = params
+        .time_base
+        .ok_or_else(|| anyhow!("{} has no time base", input.display()))?;
+    let seconds = |ts| {
+        let time = time_base.calc_time(ts);
+        time.seconds as f64 + time.frac
+    };
+
+    if let Some(frames) = params.n_frames {
+        if start.as_secs_f64() >= seconds(frames) {
+            bail!(
+                "The book is only {} long",
+                audio::timestamp(Some(time_base), frames)
+            );
+        }
+    }
+
+    let seeked = reader
+        .seek(
+            SeekMode::Accurate,
+            SeekTo::Time {
+                time: Time::from(start.as_secs_f64()),
+                track_id: Some(track.id),
+            },
+        )
+        .with_context(|| format!("Failed to seek to {}s", start.as_secs()))?;
+
+    // The sample in frames from the first packet after the seek
+    let mut skip = ((start.as_secs_f64() - seconds(seeked.required_ts)).max(0.0)
+        * sample_rate as f64) as usize;
+    let mut left = (length.as_secs_f64() * sample_rate as f64) as usize;
+
+    let mut mp3 = Vec::new();
+    // Made for the first decoded packet, since the container doesn't always tell the channels
+    let mut encoder = None::<Encoder>;
+    let mut buffer = None::<SampleBuffer<f32>>;
+
+    while left > 0 {
+        let packet = match reader.next_packet() {
+            Ok(packet) => packet,
+            Err(Error::IoError(e)) if e.kind() == std::io::ErrorKind::UnexpectedEof => break,
+            Err(e) => return Err(e.into()),
+        };
+
+        if packet.track_id() != track.id || packet.ts() < seeked.required_ts {
+            continue;
+        }
+
+        let decoded = decoder.decode(&packet)?;
+        let spec = *decoded.spec();
+        let channels = spec.channels.count();
+
+        let encoder = match &mut encoder {
+            Some(encoder) => encoder,
+            None => encoder.insert(mp3_encoder(spec.rate, channels)?),
+        };
+
+        let buffer = buffer
+            .get_or_insert_with(|| SampleBuffer::new(decoded.capacity() as u64, *decoded.spec()));
+        buffer.copy_interleaved_ref(decoded);
+
+        let frames = buffer.samples().len() / channels;
+        let from = skip.min(frames);
+        let to = frames.min(from + left);
+        skip -= from;
+        left -= to - from;
+
+        let samples = &buffer.samples()[from * channels..to * channels];
+        mp3.reserve(mp3lame_encoder::max_required_buffer_size(to - from));
+
+        if channels == 2 {
+            encoder.encode_to_vec(InterleavedPcm(samples), &mut mp3)?;
+        } else {
+            encoder.encode_to_vec(MonoPcm(samples), &mut mp3)?;
+        }
+    }
+
+    if skip > 0 {
+        bail!("The book is shorter than {}s", start.as_secs());
+    }
+
+    let Some(mut encoder) = encoder else {
+        bail!("No audio after {}s", start.as_secs());
+    };
+
+    mp3.reserve(7200);
+    encoder.flush_to_vec::<FlushNoGap>(&mut mp3)?;
+
+    Ok(mp3)
+}
+
+/// Save a short MP3 sample of a converted book
+pub async fn run(args: ClipArgs) -> Result<()> {
+    let output = args
+        .output
+        .unwrap_or_else(|| args.input.with_extension("sample.mp3"));
+
+    if output.exists() {
+        bail!("{} already exists", output.display());
+    }
+
+    eprintln!("Encoding sample...");
+
+    let input = args.input.clone();
+    let mp3 =
+        tokio::task::spawn_blocking(move || encode(&input, args.start, args.length)).await??;
+
+    let part = part_path(&output, None);
+    tokio::fs::write(&part, mp3).await?;
+    tokio::fs::rename(&part, &output).await?;
+
+    eprintln!("Saved sample to {}", output.display());
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_times() {
+        assert_eq!(parse_time("90"), Ok(Duration::from_secs(90)));
+        assert_eq!(parse_time("10:00"), Ok(Duration::from_secs(600)));
+        assert_eq!(parse_time("1:02:03"), Ok(Duration::from_secs(3723)));
+        assert_eq!(parse_time("0:01.5"), Ok(Duration::from_millis(1500)));
+    }
+
+    #[test]
+    fn rejects_invalid_times() {
+        for time in [
+            "", "-1", "1:60", "1:60:00", "1::00", "1:2:3:4", "ten", "inf",
+        ] {
+            assert!(parse_time(time).is_err(), "{time}");
+        }
+    }
+}
@@ -0,0 +1,111 @@
+//! The config file written by `audible-dl init`.
+//!
+//! It holds `AUDIBLE_DL_*=value` lines, the same format as `docker run --env-file`, and is read
+//! before the command line is parsed. Variables that are already set in the environment win.
+
+use std::path::PathBuf;
+
+use anyhow::{Context, Result};
+
+use crate::paths;
+
+/// Variables holding secrets, which are left out of backups
+const SECRETS: [&str; 3] = [
+    "AUDIBLE_DL_ACTIVATION_BYTES",
+    "AUDIBLE_DL_HEADERS",
+    "AUDIBLE_DL_MQTT_URL",
+];
+
+/// Path of the config file, `AUDIBLE_DL_CONFIG` or `config.env` in the config directory
+pub fn path() -> Result<PathBuf> {
+    if let Some(path) = std::env::var_os("AUDIBLE_DL_CONFIG") {
+        return Ok(PathBuf::from(path));
+    }
+
+    Ok(paths::config_dir()?.join("config.env"))
+}
+
+/// `AUDIBLE_DL_*` variables in the contents of a config file
+fn parse(contents: &str) -> Vec<(&str, &str)> {
+    contents
+        .lines()
+        .map(str::trim)
+        .filter(|line| !line.is_empty() && !line.starts_with('#'))
+        .filter_map(|line| line.split_once('='))
+        .map(|(name, value)| (name.trim(), value.trim()))
+        .filter(|(name, _)| name.starts_with("AUDIBLE_DL_"))
+        .collect()
+}
+
+/// `contents` of a config file without the lines setting secrets, and the names of those
+pub fn without_secrets(contents: &str) -> (String, Vec<String>) {
+    let mut kept = String::new();
+    let mut removed = Vec::new();
+
+    for line in contents.lines() {
+        match parse(line).first() {
+            Some((name, _)) if SECRETS.contains(name) => removed.push(name.to_string()),
+            _ => {
+                kept += line;
+                kept.push('\n');
+            }
+        }
+    }
+
+    (kept, removed)
+}
+
+/// Set the variables of the config file that aren't set in the environment, if there is one
+pub fn load() -> Result<()> {
+    let path = match path() {
+        Ok(path) => path,
+        // Without a home there's no config, but the command might not need one
+        Err(_) => return Ok(()),
+    };
+
+    let contents = match std::fs::read_to_string(&path) {
+        Ok(contents) => contents,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(()),
+        Err(e) => return Err(e).with_context(|| format!("Failed to read {}", path.display())),
+    };
+
+    for (name, value) in parse(&contents) {
+        if std::env::var_os(name).is_none() {
+            std::env::set_var(name, value);
+        }
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_variables() {
+        let contents = "# Comment\n\nAUDIBLE_DL_CUSTOMER_ID=123\n AUDIBLE_DL_OUTPUT_DIR = /srv/My Books \nPATH=/tmp\nnonsense\n";
+
+        assert_eq!(
+            parse(contents),
+            [
+                ("AUDIBLE_DL_CUSTOMER_ID", "123"),
+                ("AUDIBLE_DL_OUTPUT_DIR", "/srv/My Books"),
+            ]
+        );
+    }
+
+    #[test]
+    fn removes_secrets() {
+        let contents =
+            "# Comment\nAUDIBLE_DL_CUSTOMER_ID=123\nAUDIBLE_DL_ACTIVATION_BYTES=1a2b3c4d\n";
+
+        assert_eq!(
+            without_secrets(contents),
+            (
+                "# Comment\nAUDIBLE_DL_CUSTOMER_ID=123\n".to_owned(),
+                vec!["AUDIBLE_DL_ACTIVATION_BYTES".to_owned()]
+            )
+        );
+    }
+}
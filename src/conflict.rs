@@ -0,0 +1,94 @@
+use std::io::{IsTerminal, Write};
+use std::path::{Path, PathBuf};
+
+use anyhow::Result;
+use indicatif::HumanBytes;
+
+/// What to do when the output file already exists
+#[derive(Debug)]
+pub enum Resolution {
+    /// Treat the existing file as a partial download and continue it
+    Resume,
+    /// Leave the existing file alone and don't download anything
+    Skip,
+    /// Throw away the existing file and download from scratch
+    Overwrite,
+    /// Download to this, previously unused, path instead
+    Rename(PathBuf),
+}
+
+/// Ask the user what to do about the existing `output`.
+///
+/// Without a terminal to ask on, or with `assume_yes`, the existing file is resumed.
+pub fn resolve(output: &Path, assume_yes: bool) -> Result<Resolution> {
+    let stdin = std::io::stdin();
+
+    if assume_yes || !stdin.is_terminal() {
+        return Ok(Resolution::Resume);
+    }
+
+    let size = std::fs::metadata(output)?.len();
+
+    loop {
+        eprint!(
+            "{} already exists ({}). [R]esume, [s]kip, [o]verwrite or re[n]ame? ",
+            output.display(),
+            HumanBytes(size)
+        );
+        std::io::stderr().flush()?;
+
+        let mut answer = String::new();
+
+        // Treat end of input the same as just pressing enter
+        if stdin.read_line(&mut answer)? == 0 {
+            return Ok(Resolution::Resume);
+        }
+
+        match answer.trim().to_lowercase().as_str() {
+            "" | "r" | "resume" => return Ok(Resolution::Resume),
+            "s" | "skip" => return Ok(Resolution::Skip),
+            "o" | "overwrite" => return Ok(Resolution::Overwrite),
+            "n" | "rename" => return Ok(Resolution::Rename(unused_path(output))),
+            _ => eprintln!("Please answer r, s, o or n"),
+        }
+    }
+}
+
+/// Ask a yes/no `question`, defaulting to no.
+///
+/// With `assume_yes` the answer is yes without asking, without a terminal to ask on it's no.
+pub fn confirm(question: &str, assume_yes: bool) -> Result<bool> {
+    let stdin = std::io::stdin();
+
+    if assume_yes {
+        return Ok(true);
+    }
+
+    if !stdin.is_terminal() {
+        return Ok(false);
+    }
+
+    eprint!("{} [y/N] ", question);
+    std::io::stderr().flush()?;
+
+    let mut answer = String::new();
+    stdin.read_line(&mut answer)?;
+
+    Ok(matches!(answer.trim().to_lowercase().as_str(), "y" | "yes"))
+}
+
+/// First of `name (1).ext`, `name (2).ext`, ... that doesn't exist yet
+fn unused_path(path: &Path) -> PathBuf {
+    let stem = path.file_stem().unwrap_or_default().to_string_lossy();
+    let extension = path.extension().map(|ext| ext.to_string_lossy());
+
+    (1..)
+        .map(|n| {
+            path.with_file_name(match &extension {
+                Some(ext) => format!("{} ({}).{}", stem, n, ext),
+                None => format!("{} ({})", stem, n),
+            })
+        })
+        .find(|candidate| !candidate.exists())
+        .expect("an unused file name")
+}
@@ -0,0 +1,112 @@
+use std::path::PathBuf;
+
+use anyhow::{bail, Context, Result};
+use indicatif::ProgressBar;
+
+use audible_dl_core::aax;
+
+use crate::{audio, part_path, progress};
+
+#[derive(clap::Args, Debug)]
+pub struct ConvertArgs {
+    /// Downloaded AAX or AAXC file
+    input: PathBuf,
+
+    /// Output file, defaults to the input file with an `.m4b` extension
+    #[arg(short, long, env = "AUDIBLE_DL_CONVERT_OUTPUT")]
+    output: Option<PathBuf>,
+
+    /// Activation bytes of your Audible account, as 8 hex digits, for AAX files
+    #[arg(
+        long,
+        env = "AUDIBLE_DL_ACTIVATION_BYTES",
+        value_parser = aax::parse_activation_bytes,
+        required_unless_present = "key"
+    )]
+    activation_bytes: Option<[u8; 4]>,
+
+    /// Key from the voucher of an AAXC file, as 32 hex digits
+    #[arg(long, env = "AUDIBLE_DL_KEY", value_parser = aax::parse_key, requires = "iv")]
+    key: Option<[u8; 16]>,
+
+    /// IV from the voucher of an AAXC file, as 32 hex digits
+    #[arg(long, env = "AUDIBLE_DL_IV", value_parser = aax::parse_key, requires = "key")]
+    iv: Option<[u8; 16]>,
+
+    /// Decode all audio of the converted file, to catch corruption that would only be heard
+    #[arg(long, env = "AUDIBLE_DL_VERIFY_AUDIO")]
+    verify_audio: bool,
+}
+
+#[derive(clap::Args, Debug)]
+pub struct ChecksumArgs {
+    /// Downloaded AAX file
+    input: PathBuf,
+}
+
+/// Print the checksum of an AAX file, for tools that look up the activation bytes from it
+pub fn checksum(args: ChecksumArgs) -> Result<()> {
+    println!("{}", aax::checksum(&args.input)?);
+
+    Ok(())
+}
+
+/// Decrypt a downloaded book into a DRM-free M4B file, without needing ffmpeg
+pub async fn run(args: ConvertArgs) -> Result<()> {
+    let output = args
+        .output
+        .unwrap_or_else(|| args.input.with_extension("m4b"));
+
+    if output.exists() {
+        bail!("{} already exists", output.display());
+    }
+
+    let key = match (args.key, args.iv, args.activation_bytes) {
+        (Some(key), Some(iv), _) => aax::Key::new(key, iv),
+        // Check the activation bytes before copying anything
+        (_, _, Some(activation_bytes)) => aax::key(&args.input, activation_bytes)?,
+        _ => unreachable!("clap requires either activation bytes or a key and IV"),
+    };
+
+    let part = part_path(&output, None);
+
+    let pb = ProgressBar::new_spinner();
+    pb.set_message("Copying...");
+    progress::set_style(&pb, progress::MESSAGE);
+    tokio::spawn(progress::tick(pb.clone()));
+
+    let result = async {
+        tokio::fs::copy(&args.input, &part).await?;
+
+        progress::set_style(&pb, progress::PROCESSING);
+
+        let (path, bar) = (part.clone(), progress::Bar::new(pb.clone()));
+        tokio::task::spawn_blocking(move || aax::decrypt(&path, &key, &bar)).await??;
+
+        tokio::fs::rename(&part, &output).await?;
+
+        if args.verify_audio {
+            pb.set_position(0);
+            progress::set_style(&pb, progress::VERIFYING);
+
+            let (path, bar) = (output.clone(), pb.clone());
+            tokio::task::spawn_blocking(move || audio::verify(&path, &bar))
+                .await?
+                .with_context(|| format!("{} is corrupt", output.display()))?;
+        }
+
+        Ok(())
+    }
+    .await;
+
+    if result.is_err() {
+        pb.abandon();
+        let _ = tokio::fs::remove_file(&part).await;
+        return result;
+    }
+
+    pb.finish();
+    eprintln!("Converted to {}", output.display());
+
+    Ok(())
+}
@@ -0,0 +1,575 @@
+//! The state database, `state.db` in the data directory, keeping what audible-dl needs to know
+//! across runs in one place:
+//!
+//! - `sidecars`: the total size, ETag and codec of partial downloads, which are otherwise kept in
+//!   `.json` files next to them, see `--sidecar-files`
+//! - `queue`: the SKUs of the `.sku` file `watch` is working through
+//! - `history`: the outcome of every book a batch or `watch` downloaded, skipped or failed, with
+//!   the size, ASIN and checksum of downloaded files for `reconcile` to find them after they moved
+//!
+//! The schema is upgraded by the migrations in [`MIGRATIONS`] when the database is opened, with
+//! the number of them applied kept in `PRAGMA user_version`.
+
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex, OnceLock};
+
+use anyhow::{bail, Context, Result};
+use chrono::{SecondsFormat, Utc};
+use indicatif::HumanBytes;
+use rusqlite::{params, Connection, OptionalExtension};
+use serde::Serialize;
+
+use audible_dl_core::sidecar::{self, Sidecar, Sidecars};
+
+use crate::reconcile::{Fingerprint, Manifest};
+use crate::report::{Report, Status};
+use crate::{paths, style};
+
+/// Upgrades of the schema, in order. Only ever add to the end, since databases record how many of
+/// them they already have.
+const MIGRATIONS: &[&str] = &[
+    "
+    CREATE TABLE sidecars (
+        part TEXT PRIMARY KEY,
+        total INTEGER,
+        etag TEXT,
+        codec TEXT
+    );
+
+    CREATE TABLE queue (
+        source TEXT NOT NULL,
+        position INTEGER NOT NULL,
+        sku TEXT NOT NULL,
+        queued_at TEXT NOT NULL,
+        PRIMARY KEY (source, position)
+    );
+
+    CREATE TABLE history (
+        id INTEGER PRIMARY KEY,
+        source TEXT NOT NULL,
+        sku TEXT NOT NULL,
+        status TEXT NOT NULL,
+        path TEXT,
+        remote TEXT,
+        reason TEXT,
+        finished_at TEXT NOT NULL
+    );
+",
+    "
+    ALTER TABLE history ADD COLUMN size INTEGER;
+    ALTER TABLE history ADD COLUMN asin TEXT;
+    ALTER TABLE history ADD COLUMN sha256 TEXT;
+",
+];
+
+#[derive(clap::Args, Debug)]
+pub struct DbArgs {
+    #[command(subcommand)]
+    command: DbCommand,
+}
+
+#[derive(clap::Subcommand, Debug)]
+enum DbCommand {
+    /// Print the sidecars, queue and history in the database as JSON
+    Export,
+
+    /// Rebuild the database file to give back the space of removed rows
+    Vacuum,
+}
+
+/// Path of the database, `AUDIBLE_DL_DB_FILE` or `state.db` in the data directory
+pub fn path() -> Result<PathBuf> {
+    if let Some(path) = std::env::var_os("AUDIBLE_DL_DB_FILE") {
+        return Ok(PathBuf::from(path));
+    }
+
+    Ok(paths::data_dir()?.join("state.db"))
+}
+
+/// The database shared by everything in this process, opened on first use.
+///
+/// `None` when it can't be opened, e.g. without a home directory or with a read-only data
+/// directory, after a warning the first time. Downloads then go on without it, as they did
+/// before there was a database.
+pub fn shared() -> Option<Arc<Db>> {
+    static SHARED: OnceLock<Option<Arc<Db>>> = OnceLock::new();
+
+    SHARED
+        .get_or_init(|| match open_shared() {
+            Ok(db) => Some(Arc::new(db)),
+            Err(e) => {
+                eprintln!(
+                    "{} {:#}, going on without the state database",
+                    style::warning("Warning:"),
+                    e
+                );
+                None
+            }
+        })
+        .clone()
+}
+
+/// The database in the data directory, or with `--ephemeral` one in memory
+fn open_shared() -> Result<Db> {
+    match paths::ephemeral() {
+        true => Db::in_memory(),
+        false => Db::open(&path()?),
+    }
+}
+
+/// Where `sku` was last downloaded to, if that file is still there, e.g. after `reconcile` found
+/// it moved
+pub fn downloaded(sku: &str) -> Option<PathBuf> {
+    match shared()?.last_download(sku) {
+        Ok(path) => path.filter(|path| path.exists()),
+        Err(e) => {
+            eprintln!(
+                "{} Failed to look up {} in the state database: {:#}",
+                style::warning("Warning:"),
+                sku,
+                e
+            );
+            None
+        }
+    }
+}
+
+/// Sidecars kept in the database, and with `files` in `.json` files as well. Without a database
+/// they're kept in `.json` files only.
+pub fn sidecars(files: bool) -> Sidecars {
+    Sidecars {
+        store: shared().map(|db| db as Arc<dyn sidecar::Store>),
+        files,
+    }
+}
+
+#[derive(Debug)]
+pub struct Db {
+    // A connection can't be used from two threads at once
+    conn: Mutex<Connection>,
+}
+
+impl Db {
+    pub fn open(path: &Path) -> Result<Db> {
+        if let Some(dir) = path.parent() {
+            std::fs::create_dir_all(dir)
+                .with_context(|| format!("Failed to create {}", dir.display()))?;
+        }
+
+        let conn = Connection::open(path)
+            .with_context(|| format!("Failed to open the database {}", path.display()))?;
+
+        Db::with_connection(conn)
+            .with_context(|| format!("Failed to upgrade the database {}", path.display()))
+    }
+
+    /// A database that's gone when it's dropped
+    pub fn in_memory() -> Result<Db> {
+        Db::with_connection(Connection::open_in_memory()?)
+    }
+
+    fn with_connection(mut conn: Connection) -> Result<Db> {
+        // Another instance may be writing, e.g. `watch` while `download` runs
+        conn.busy_timeout(std::time::Duration::from_secs(10))?;
+        migrate(&mut conn)?;
+
+        Ok(Db {
+            conn: Mutex::new(conn),
+        })
+    }
+
+    fn conn(&self) -> std::sync::MutexGuard<'_, Connection> {
+        self.conn.lock().unwrap()
+    }
+
+    /// Queue the SKUs of `source`, replacing what was queued from it before
+    pub fn enqueue(&self, source: &Path, skus: &[&str]) -> Result<()> {
+        let mut conn = self.conn();
+        let tx = conn.transaction()?;
+        let source = source.to_string_lossy();
+        let now = now();
+
+        tx.execute("DELETE FROM queue WHERE source = ?1", [&source])?;
+
+        for (position, sku) in skus.iter().enumerate() {
+            tx.execute(
+                "INSERT INTO queue (source, position, sku, queued_at) VALUES (?1, ?2, ?3, ?4)",
+                params![source, position, sku, now],
+            )?;
+        }
+
+        Ok(tx.commit()?)
+    }
+
+    /// Add the books of a finished batch to the history, and take its source off the queue
+    pub fn record(&self, report: &Report) -> Result<()> {
+        // Read before locking, files moved by `--rclone-remote` aren't here to read
+        let fingerprints = report
+            .items()
+            .iter()
+            .map(|item| match &item.status {
+                Status::Downloaded { path, remote: None } => Fingerprint::read(path),
+                _ => Fingerprint::default(),
+            })
+            .collect::<Vec<_>>();
+
+        let mut conn = self.conn();
+        let tx = conn.transaction()?;
+        let source = report.source().to_string_lossy();
+        let finished = report.finished().map_or_else(now, str::to_owned);
+
+        for (item, fingerprint) in report.items().iter().zip(fingerprints) {
+            let (status, path, remote, reason) = match &item.status {
+                Status::Downloaded { path, remote } => (
+                    "downloaded",
+                    Some(path.to_string_lossy()),
+                    remote.as_deref(),
+                    None,
+                ),
+                Status::Skipped { reason } => ("skipped", None, None, Some(reason.as_str())),
+                Status::Failed { error } => ("failed", None, None, Some(error.as_str())),
+            };
+
+            tx.execute(
+                "INSERT INTO history \
+                 (source, sku, status, path, remote, reason, finished_at, size, asin, sha256) \
+                 VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9, ?10)",
+                params![
+                    source,
+                    item.sku,
+                    status,
+                    path,
+                    remote,
+                    reason,
+                    finished,
+                    fingerprint.size,
+                    fingerprint.asin,
+                    fingerprint.sha256
+                ],
+            )?;
+        }
+
+        tx.execute("DELETE FROM queue WHERE source = ?1", [&source])?;
+
+        Ok(tx.commit()?)
+    }
+
+    /// Where `sku` was last downloaded to, whether or not the file is still there
+    pub fn last_download(&self, sku: &str) -> Result<Option<PathBuf>> {
+        let path = self
+            .conn()
+            .query_row(
+                "SELECT path FROM history WHERE sku = ?1 AND status = 'downloaded' \
+                 AND path IS NOT NULL ORDER BY id DESC LIMIT 1",
+                [sku],
+                |row| row.get::<_, String>(0),
+            )
+            .optional()?;
+
+        Ok(path.map(PathBuf::from))
+    }
+
+    /// The last download of every SKU that was downloaded to this machine
+    pub fn manifests(&self) -> Result<Vec<Manifest>> {
+        let conn = self.conn();
+
+        let manifests = conn
+            .prepare(
+                "SELECT id, sku, path, size, asin, sha256 FROM history WHERE id IN \
+                 (SELECT MAX(id) FROM history WHERE status = 'downloaded' AND path IS NOT NULL \
+                 AND remote IS NULL GROUP BY sku) ORDER BY sku",
+            )?
+            .query_map([], |row| {
+                Ok(Manifest {
+                    id: row.get(0)?,
+                    sku: row.get(1)?,
+                    path: PathBuf::from(row.get::<_, String>(2)?),
+                    fingerprint: Fingerprint {
+                        size: row.get(3)?,
+                        asin: row.get(4)?,
+                        sha256: row.get(5)?,
+                    },
+                })
+            })?
+            .collect::<rusqlite::Result<_>>()?;
+
+        Ok(manifests)
+    }
+
+    /// Point the download `id` of the history at `path`, where it was moved to
+    pub fn relocate(&self, id: i64, path: &Path) -> Result<()> {
+        self.conn().execute(
+            "UPDATE history SET path = ?1 WHERE id = ?2",
+            params![path.to_string_lossy(), id],
+        )?;
+
+        Ok(())
+    }
+
+    /// Write a consistent copy of the database to `path`, which mustn't exist yet
+    pub fn snapshot(&self, path: &Path) -> Result<()> {
+        self.conn()
+            .execute("VACUUM INTO ?1", [path.to_string_lossy()])?;
+
+        Ok(())
+    }
+
+    fn export(&self) -> Result<Export> {
+        let conn = self.conn();
+
+        let sidecars = conn
+            .prepare("SELECT part, total, etag, codec FROM sidecars ORDER BY part")?
+            .query_map([], |row| {
+                Ok(SidecarRow {
+                    part: row.get(0)?,
+                    total: row.get(1)?,
+                    etag: row.get(2)?,
+                    codec: row.get(3)?,
+                })
+            })?
+            .collect::<rusqlite::Result<_>>()?;
+
+        let queue = conn
+            .prepare("SELECT source, sku, queued_at FROM queue ORDER BY source, position")?
+            .query_map([], |row| {
+                Ok(QueueRow {
+                    source: row.get(0)?,
+                    sku: row.get(1)?,
+                    queued_at: row.get(2)?,
+                })
+            })?
+            .collect::<rusqlite::Result<_>>()?;
+
+        let history = conn
+            .prepare(
+                "SELECT source, sku, status, path, remote, reason, finished_at, size, asin, sha256 \
+                 FROM history ORDER BY id",
+            )?
+            .query_map([], |row| {
+                Ok(HistoryRow {
+                    source: row.get(0)?,
+                    sku: row.get(1)?,
+                    status: row.get(2)?,
+                    path: row.get(3)?,
+                    remote: row.get(4)?,
+                    reason: row.get(5)?,
+                    finished_at: row.get(6)?,
+                    size: row.get(7)?,
+                    asin: row.get(8)?,
+                    sha256: row.get(9)?,
+                })
+            })?
+            .collect::<rusqlite::Result<_>>()?;
+
+        Ok(Export {
+            sidecars,
+            queue,
+            history,
+        })
+    }
+}
+
+/// Key of the sidecar of `part`, the same however the path to it was given
+fn part_key(part: &Path) -> Result<String> {
+    Ok(std::path::absolute(part)?.to_string_lossy().into_owned())
+}
+
+impl sidecar::Store for Db {
+    fn load(&self, part: &Path) -> Result<Option<Sidecar>> {
+        let sidecar = self
+            .conn()
+            .query_row(
+                "SELECT total, etag, codec FROM sidecars WHERE part = ?1",
+                [part_key(part)?],
+                |row| {
+                    Ok(Sidecar {
+                        total: row.get(0)?,
+                        etag: row.get(1)?,
+                        codec: row.get(2)?,
+                    })
+                },
+            )
+            .optional()?;
+
+        Ok(sidecar)
+    }
+
+    fn save(&self, part: &Path, sidecar: &Sidecar) -> Result<()> {
+        self.conn().execute(
+            "INSERT OR REPLACE INTO sidecars (part, total, etag, codec) VALUES (?1, ?2, ?3, ?4)",
+            params![part_key(part)?, sidecar.total, sidecar.etag, sidecar.codec],
+        )?;
+
+        Ok(())
+    }
+
+    fn remove(&self, part: &Path) -> Result<()> {
+        self.conn()
+            .execute("DELETE FROM sidecars WHERE part = ?1", [part_key(part)?])?;
+
+        Ok(())
+    }
+}
+
+/// Apply the migrations `conn` doesn't have yet
+fn migrate(conn: &mut Connection) -> Result<()> {
+    let version: usize = conn.query_row("PRAGMA user_version", [], |row| row.get(0))?;
+
+    if version > MIGRATIONS.len() {
+        bail!(
+            "The database is of a newer audible-dl, version {} where this one knows up to {}",
+            version,
+            MIGRATIONS.len()
+        );
+    }
+
+    for (index, migration) in MIGRATIONS.iter().enumerate().skip(version) {
+        let tx = conn.transaction()?;
+        tx.execute_batch(migration)?;
+        tx.pragma_update(None, "user_version", index + 1)?;
+        tx.commit()?;
+    }
+
+    Ok(())
+}
+
+fn now() -> String {
+    Utc::now().to_rfc3339_opts(SecondsFormat::Secs, true)
+}
+
+#[derive(Serialize)]
+struct Export {
+    sidecars: Vec<SidecarRow>,
+    queue: Vec<QueueRow>,
+    history: Vec<HistoryRow>,
+}
+
+#[derive(Serialize)]
+struct SidecarRow {
+    part: String,
+    total: Option<u64>,
+    etag: Option<String>,
+    codec: Option<String>,
+}
+
+#[derive(Serialize)]
+struct QueueRow {
+    source: String,
+    sku: String,
+    queued_at: String,
+}
+
+#[derive(Serialize)]
+struct HistoryRow {
+    source: String,
+    sku: String,
+    status: String,
+    path: Option<String>,
+    remote: Option<String>,
+    reason: Option<String>,
+    finished_at: String,
+    size: Option<u64>,
+    asin: Option<String>,
+    sha256: Option<String>,
+}
+
+pub fn run(args: DbArgs) -> Result<()> {
+    let path = path()?;
+    let db = Db::open(&path)?;
+
+    match args.command {
+        DbCommand::Export => {
+            println!("{}", serde_json::to_string_pretty(&db.export()?)?);
+        }
+        DbCommand::Vacuum => {
+            let before = std::fs::metadata(&path)?.len();
+            db.conn().execute_batch("VACUUM")?;
+            let after = std::fs::metadata(&path)?.len();
+
+            eprintln!(
+                "Vacuumed {}: {} before, {} after",
+                path.display(),
+                HumanBytes(before),
+                HumanBytes(after)
+            );
+        }
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use audible_dl_core::sidecar::Store;
+
+    use super::*;
+    use crate::Outcome;
+
+    #[test]
+    fn keeps_state() {
+        let db = Db::in_memory().unwrap();
+
+        // Opening it again leaves the schema as it is
+        migrate(&mut db.conn()).unwrap();
+
+        let part = Path::new("BK_ADBL_000001_22.aax.part");
+        let sidecar = Sidecar {
+            total: Some(100),
+            etag: Some("\"a\"".to_owned()),
+            codec: None,
+        };
+
+        db.save(part, &sidecar).unwrap();
+        let loaded = db
+            .load(&std::path::absolute(part).unwrap())
+            .unwrap()
+            .unwrap();
+        assert_eq!((loaded.total, loaded.etag), (Some(100), sidecar.etag));
+
+        db.remove(part).unwrap();
+        assert!(db.load(part).unwrap().is_none());
+
+        let source = Path::new("batch.sku");
+        db.enqueue(source, &["BK_ADBL_000001_22", "BK_ADBL_000002_22"])
+            .unwrap();
+        assert_eq!(db.export().unwrap().queue.len(), 2);
+
+        let mut report = Report::new(source);
+        report.push("BK_ADBL_000001_22", &Ok(Outcome::Skipped));
+        report.push("BK_ADBL_000002_22", &Err(anyhow::anyhow!("Gone")));
+        db.record(&report).unwrap();
+
+        let export = db.export().unwrap();
+        assert!(export.queue.is_empty());
+        assert_eq!(export.history.len(), 2);
+        assert_eq!(export.history[1].status, "failed");
+        assert_eq!(export.history[1].reason.as_deref(), Some("Gone"));
+    }
+
+    #[test]
+    fn keeps_nothing_on_disk_when_ephemeral() {
+        let dir = std::env::temp_dir().join(format!("audible-dl-ephemeral-{}", std::process::id()));
+        std::env::set_var("AUDIBLE_DL_DB_FILE", dir.join("state.db"));
+        paths::set_ephemeral();
+
+        let db = shared().unwrap();
+        db.enqueue(Path::new("batch.sku"), &["BK_ADBL_000001_22"])
+            .unwrap();
+        db.save(&dir.join("BK_ADBL_000001_22.aax.part"), &Sidecar::default())
+            .unwrap();
+
+        let mut report = Report::new(Path::new("batch.sku"));
+        report.push("BK_ADBL_000001_22", &Ok(Outcome::Skipped));
+        db.record(&report).unwrap();
+
+        let written = tokio::runtime::Builder::new_current_thread()
+            .build()
+            .unwrap()
+            .block_on(report.write(&dir.join("reports")))
+            .unwrap();
+
+        assert!(written.is_none());
+        assert!(!dir.exists());
+        assert_eq!(db.export().unwrap().history.len(), 1);
+    }
+}
@@ -0,0 +1,194 @@
+//! Resolving host names for `--ip-version`, `--resolve` and `--doh`.
+//!
+//! `--resolve` overrides are handed to reqwest as they are. The rest goes through [`Resolver`],
+//! which asks the system resolver or a DNS-over-HTTPS server using the JSON API that Cloudflare,
+//! Google and others offer, and leaves out addresses of the IP version that isn't wanted.
+
+use std::net::{IpAddr, SocketAddr};
+
+use hyper::client::connect::dns::Name;
+use reqwest::dns::{Addrs, Resolve, Resolving};
+use reqwest::Url;
+use serde::Deserialize;
+
+/// DNS record types of IPv4 and IPv6 addresses
+const TYPE_A: u16 = 1;
+const TYPE_AAAA: u16 = 28;
+
+#[derive(clap::ValueEnum, Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum IpVersion {
+    /// Try IPv6 and IPv4 addresses side by side, using whichever connects first
+    #[default]
+    Auto,
+    /// Only IPv4
+    #[value(name = "4")]
+    V4,
+    /// Only IPv6
+    #[value(name = "6")]
+    V6,
+}
+
+impl IpVersion {
+    fn allows(self, ip: IpAddr) -> bool {
+        match self {
+            IpVersion::Auto => true,
+            IpVersion::V4 => ip.is_ipv4(),
+            IpVersion::V6 => ip.is_ipv6(),
+        }
+    }
+}
+
+/// Parse a curl style `HOST:PORT:ADDRESS` override, for use as a clap value parser
+pub fn parse_override(s: &str) -> Result<(String, IpAddr), String> {
+    let error = || {
+        format!(
+            "{:?} isn't HOST:PORT:ADDRESS, e.g. cds.audible.com:443:1.2.3.4",
+            s
+        )
+    };
+
+    let (host, rest) = s.split_once(':').ok_or_else(error)?;
+    let (port, address) = rest.split_once(':').ok_or_else(error)?;
+
+    if host.is_empty() || port.parse::<u16>().is_err() {
+        return Err(error());
+    }
+
+    let address = address.trim_start_matches('[').trim_end_matches(']');
+    let address = address.parse::<IpAddr>().map_err(|_| error())?;
+
+    Ok((host.to_owned(), address))
+}
+
+#[derive(Deserialize)]
+struct DohResponse {
+    #[serde(rename = "Status")]
+    status: u32,
+    #[serde(rename = "Answer", default)]
+    answer: Vec<DohAnswer>,
+}
+
+#[derive(Deserialize)]
+struct DohAnswer {
+    #[serde(rename = "type")]
+    kind: u16,
+    data: String,
+}
+
+/// Addresses of `host` of the DNS record type `kind`, from the DoH server at `url`
+async fn doh_query(
+    client: &reqwest::Client,
+    url: &Url,
+    host: &str,
+    kind: u16,
+) -> anyhow::Result<Vec<IpAddr>> {
+    let res: DohResponse = client
+        .get(url.clone())
+        .query(&[("name", host), ("type", &kind.to_string())])
+        .header("Accept", "application/dns-json")
+        .send()
+        .await?
+        .error_for_status()?
+        .json()
+        .await?;
+
+    // NXDOMAIN and the like, which a lookup of the other record type might still answer
+    if res.status != 0 {
+        return Ok(Vec::new());
+    }
+
+    // CNAME records come along with the addresses they point to
+    Ok(res
+        .answer
+        .iter()
+        .filter(|answer| answer.kind == kind)
+        .filter_map(|answer| answer.data.parse().ok())
+        .collect())
+}
+
+/// Looks up host names with the system resolver, or the DoH server if there is one
+#[derive(Clone)]
+pub struct Resolver {
+    pub ip_version: IpVersion,
+    /// Client without this resolver, and the URL of the DoH server
+    pub doh: Option<(reqwest::Client, Url)>,
+}
+
+impl Resolver {
+    async fn lookup(self, host: String) -> anyhow::Result<Vec<IpAddr>> {
+        let Some((client, url)) = &self.doh else {
+            let addrs = tokio::net::lookup_host((host.as_str(), 0)).await?;
+            return Ok(addrs.map(|addr| addr.ip()).collect());
+        };
+
+        let (v6, v4) = tokio::try_join!(
+            async {
+                match self.ip_version {
+                    IpVersion::V4 => Ok(Vec::new()),
+                    _ => doh_query(client, url, &host, TYPE_AAAA).await,
+                }
+            },
+            async {
+                match self.ip_version {
+                    IpVersion::V6 => Ok(Vec::new()),
+                    _ => doh_query(client, url, &host, TYPE_A).await,
+                }
+            },
+        )?;
+
+        Ok(v6.into_iter().chain(v4).collect())
+    }
+}
+
+impl Resolve for Resolver {
+    fn resolve(&self, name: Name) -> Resolving {
+        let resolver = self.clone();
+
+        Box::pin(async move {
+            let ip_version = resolver.ip_version;
+            let addrs = resolver
+                .lookup(name.as_str().to_owned())
+                .await?
+                .into_iter()
+                .filter(|ip| ip_version.allows(*ip))
+                .map(|ip| SocketAddr::new(ip, 0))
+                .collect::<Vec<_>>();
+
+            if addrs.is_empty() {
+                return Err(format!("Found no address of {} to connect to", name).into());
+            }
+
+            Ok(Box::new(addrs.into_iter()) as Addrs)
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_overrides() {
+        assert_eq!(
+            parse_override("cds.audible.com:443:1.2.3.4"),
+            Ok(("cds.audible.com".to_owned(), "1.2.3.4".parse().unwrap()))
+        );
+        assert_eq!(
+            parse_override("cds.audible.com:443:[2001:db8::1]"),
+            Ok(("cds.audible.com".to_owned(), "2001:db8::1".parse().unwrap()))
+        );
+    }
+
+    #[test]
+    fn rejects_invalid_overrides() {
+        for value in [
+            "cds.audible.com",
+            "cds.audible.com:1.2.3.4",
+            ":443:1.2.3.4",
+            "cds.audible.com:https:1.2.3.4",
+            "cds.audible.com:443:example.com",
+        ] {
+            assert!(parse_override(value).is_err(), "{value}");
+        }
+    }
+}
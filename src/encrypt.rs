@@ -0,0 +1,97 @@
+//! `--encrypt-output`, encrypting downloaded books for storage that isn't trusted.
+//!
+//! Books are encrypted with the `age` command once they're downloaded and verified, so that
+//! verification and checksums are of the plaintext, and the plaintext is removed afterwards.
+//! Only the holder of the identity of the recipient can decrypt them, audible-dl can't.
+
+use std::path::{Path, PathBuf};
+use std::process::Command;
+
+use anyhow::{bail, Context, Result};
+
+/// How downloaded books are encrypted
+#[derive(Clone, Debug)]
+pub enum Encryption {
+    /// With age, to this recipient
+    Age(String),
+}
+
+/// Parse `age:<recipient>`, for use as a clap value parser
+pub fn parse(s: &str) -> Result<Encryption, String> {
+    match s.split_once(':') {
+        Some(("age", recipient)) if !recipient.trim().is_empty() => {
+            Ok(Encryption::Age(recipient.trim().to_owned()))
+        }
+        _ => Err("expected `age:<recipient>`, e.g. `age:age1...`".to_owned()),
+    }
+}
+
+/// Where the book downloaded to `path` ends up once encrypted
+pub fn encrypted_path(path: &Path) -> PathBuf {
+    let mut name = path.file_name().unwrap_or_default().to_owned();
+    name.push(".age");
+    path.with_file_name(name)
+}
+
+/// Encrypt the book at `path`, replacing it with the encrypted file, and return its path
+pub async fn encrypt(path: &Path, encryption: &Encryption) -> Result<PathBuf> {
+    let Encryption::Age(recipient) = encryption;
+
+    let encrypted = encrypted_path(path);
+    let temp = encrypted.with_extension("age.tmp");
+
+    let output = {
+        let (recipient, path, temp) = (recipient.clone(), path.to_owned(), temp.clone());
+
+        tokio::task::spawn_blocking(move || {
+            Command::new("age")
+                .arg("--recipient")
+                .arg(recipient)
+                .arg("--output")
+                .arg(temp)
+                .arg(path)
+                .output()
+        })
+        .await?
+    };
+
+    let output = match output {
+        Ok(output) => output,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => {
+            bail!("--encrypt-output needs age, see https://age-encryption.org")
+        }
+        Err(e) => return Err(e).context("Failed to run age"),
+    };
+
+    if !output.status.success() {
+        let _ = tokio::fs::remove_file(&temp).await;
+        bail!(
+            "Failed to encrypt {}, it's left unencrypted: {}",
+            path.display(),
+            String::from_utf8_lossy(&output.stderr).trim()
+        );
+    }
+
+    tokio::fs::rename(&temp, &encrypted).await?;
+    tokio::fs::remove_file(path)
+        .await
+        .with_context(|| format!("Failed to remove the unencrypted {}", path.display()))?;
+
+    Ok(encrypted)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_recipients() {
+        assert!(matches!(parse("age:age1abc"), Ok(Encryption::Age(r)) if r == "age1abc"));
+        assert!(parse("age:").is_err());
+        assert!(parse("gpg:key").is_err());
+        assert_eq!(
+            encrypted_path(Path::new("books/BK_ADBL_000123.aax")),
+            Path::new("books/BK_ADBL_000123.aax.age")
+        );
+    }
+}
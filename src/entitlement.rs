@@ -0,0 +1,59 @@
+use anyhow::{bail, Result};
+
+use crate::api::library::Item;
+use crate::api::ApiArgs;
+
+/// The titles an account owns, to check SKUs against before downloading them.
+///
+/// The CDS answers a download of a title that isn't in the library with an error that doesn't
+/// say so, this turns it into a clear one before anything is downloaded.
+pub struct Entitlements {
+    items: Vec<Item>,
+    domain: &'static str,
+}
+
+impl Entitlements {
+    pub async fn load(client: &reqwest::Client, args: &ApiArgs) -> Result<Entitlements> {
+        let api = args.client(client.clone())?;
+
+        Ok(Entitlements {
+            items: api.library().await?,
+            domain: api.marketplace().domain(),
+        })
+    }
+
+    /// Domain of the marketplace the library is from, e.g. `com`
+    pub fn domain(&self) -> &'static str {
+        self.domain
+    }
+
+    /// The title in the library that is downloaded as `sku`
+    fn find(&self, sku: &str) -> Option<&Item> {
+        self.items.iter().find(|item| {
+            item.product.sku.as_deref() == Some(sku)
+                || item.product.sku_lite.as_deref() == Some(sku)
+        })
+    }
+
+    /// Whether the library has a title that is downloaded as `sku`
+    pub fn owns(&self, sku: &str) -> bool {
+        self.find(sku).is_some()
+    }
+
+    /// Fail unless the library has a title that is downloaded as `sku`, and it's released
+    pub fn check(&self, sku: &str) -> Result<()> {
+        let Some(item) = self.find(sku) else {
+            bail!("{} is not in your library on audible.{}", sku, self.domain);
+        };
+
+        if item.product.is_preorder() {
+            bail!(
+                "{} is a preorder that isn't released until {}",
+                sku,
+                item.product.release_date.as_deref().unwrap_or("later")
+            );
+        }
+
+        Ok(())
+    }
+}
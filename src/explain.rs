@@ -0,0 +1,115 @@
+//! `--explain-url`, printing the request a download would make instead of making it.
+//!
+//! When Audible changes the download endpoint, this shows what audible-dl sends and where each
+//! part comes from, so that it can be adapted with `--url` and `--header` until a new release.
+
+use std::path::Path;
+
+use anyhow::Result;
+use audible_dl_core::rangedl::{self, Strategy};
+use reqwest::Url;
+
+use crate::{http, style, DownloadOptions};
+
+/// What the query parameters of a CDS download URL are
+const PARAMETERS: [(&str, &str); 5] = [
+    (
+        "user_id",
+        "Audible user id, `--user-id` or else the customer id",
+    ),
+    ("product_id", "SKU of the book"),
+    (
+        "codec",
+        "Quality of the book, see `--fallback-quality` for the others",
+    ),
+    (
+        "awtype",
+        "Format of the book, AAX is the DRM protected Audible format",
+    ),
+    ("cust_id", "Audible customer id, `--customer-id`"),
+];
+
+fn print_row(name: &str, value: &str, meaning: &str) {
+    println!("  {:<12} {:<48} {}", name, style::highlight(value), meaning);
+}
+
+/// Print the request for downloading `url` into `part`, and what each part of it means
+pub async fn print(url: &str, part: &Path, options: &DownloadOptions) -> Result<()> {
+    let parsed = Url::parse(url)?;
+    let cds = parsed.host_str() == Some("cds.audible.com");
+
+    // Highlight the values in the URL, as they're written in it
+    let line = match url.split_once('?') {
+        Some((base, query)) => {
+            let pairs = query
+                .split('&')
+                .map(|pair| match pair.split_once('=') {
+                    Some((name, value)) => format!("{}={}", name, style::highlight(value)),
+                    None => pair.to_owned(),
+                })
+                .collect::<Vec<_>>();
+
+            format!("{}?{}", base, pairs.join("&"))
+        }
+        None => url.to_owned(),
+    };
+
+    println!("GET {}", line);
+
+    if parsed.query().is_some() {
+        println!();
+        println!("Query parameters:");
+
+        for (name, value) in parsed.query_pairs() {
+            let meaning = PARAMETERS
+                .iter()
+                .find(|(parameter, _)| cds && *parameter == name)
+                .map_or("Unknown, passed on as is", |(_, meaning)| meaning);
+
+            print_row(&name, &value, meaning);
+        }
+    }
+
+    let start = match tokio::fs::metadata(part).await {
+        Ok(metadata) => metadata.len(),
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => 0,
+        Err(e) => return Err(e.into()),
+    };
+
+    let (range, meaning) = match (options.strategy, start) {
+        (Strategy::Pipelined, _) => (
+            format!("bytes={}-{}", start, start + rangedl::PIECE_SIZE - 1),
+            "First of the 4 MiB pieces requested by `--strategy pipelined`".to_owned(),
+        ),
+        (Strategy::Single, 0) => (
+            "bytes=0-".to_owned(),
+            "From the start, there's no partial download yet".to_owned(),
+        ),
+        (Strategy::Single, start) => (
+            format!("bytes={}-", start),
+            format!("Resumes the partial download at {}", part.display()),
+        ),
+    };
+
+    println!();
+    println!("Headers:");
+    print_row("Range", &range, &meaning);
+
+    if !http::overrides("User-Agent") {
+        print_row(
+            "User-Agent",
+            rangedl::USER_AGENT,
+            "The Audible download manager, which the CDS expects",
+        );
+    }
+
+    for (name, value) in http::headers() {
+        print_row(
+            name.as_str(),
+            value.to_str().unwrap_or("<binary>"),
+            "From `--header`",
+        );
+    }
+
+    Ok(())
+}
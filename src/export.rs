@@ -0,0 +1,187 @@
+//! Reading the titles from the CSV files of Audible's data export.
+//!
+//! The column names differ between marketplaces and versions of the export, so the SKU column is
+//! found by name when possible and otherwise by what its values look like.
+
+use anyhow::{bail, Result};
+
+/// Names of the SKU column, lowercase without punctuation
+const SKU_COLUMNS: [&str; 5] = [
+    "sku",
+    "productsku",
+    "skulite",
+    "productskulite",
+    "produktsku",
+];
+
+/// Names of the ASIN column, lowercase without punctuation
+const ASIN_COLUMNS: [&str; 3] = ["asin", "productasin", "produktasin"];
+
+/// A title listed in the export
+#[derive(Debug, PartialEq, Eq)]
+pub enum Title {
+    Sku(String),
+    /// A row without a SKU, which can't be downloaded from the CDS
+    AsinOnly(String),
+}
+
+/// Split CSV `contents` into rows of fields, with `delimiter` between them
+fn parse(contents: &str, delimiter: char) -> Vec<Vec<String>> {
+    let mut rows = Vec::new();
+    let mut row = Vec::new();
+    let mut field = String::new();
+    let mut quoted = false;
+    let mut chars = contents.chars().peekable();
+
+    while let Some(c) = chars.next() {
+        match c {
+            '"' if quoted && chars.peek() == Some(&'"') => {
+                field.push('"');
+                chars.next();
+            }
+            '"' => quoted = !quoted,
+            c if quoted => field.push(c),
+            c if c == delimiter => row.push(std::mem::take(&mut field)),
+            '\r' => {}
+            '\n' => {
+                row.push(std::mem::take(&mut field));
+                rows.push(std::mem::take(&mut row));
+            }
+            c => field.push(c),
+        }
+    }
+
+    if !field.is_empty() || !row.is_empty() {
+        row.push(field);
+        rows.push(row);
+    }
+
+    rows.retain(|row| row.iter().any(|field| !field.trim().is_empty()));
+    rows
+}
+
+fn normalize(name: &str) -> String {
+    name.chars()
+        .filter(char::is_ascii_alphanumeric)
+        .map(|c| c.to_ascii_lowercase())
+        .collect()
+}
+
+/// Whether `value` looks like an Audible SKU, e.g. `BK_ADBL_012345`
+pub fn is_sku(value: &str) -> bool {
+    let parts = value.split('_').collect::<Vec<_>>();
+
+    parts.len() >= 3
+        && parts.iter().all(|part| {
+            !part.is_empty()
+                && part
+                    .chars()
+                    .all(|c| c.is_ascii_uppercase() || c.is_ascii_digit())
+        })
+}
+
+/// Index of the first column named like one of `names`
+fn column(header: &[String], names: &[&str]) -> Option<usize> {
+    header
+        .iter()
+        .position(|name| names.contains(&normalize(name).as_str()))
+}
+
+/// Titles listed in the contents of an export CSV file, without duplicates
+pub fn titles(contents: &str) -> Result<Vec<Title>> {
+    let contents = contents.trim_start_matches('\u{feff}');
+    let first_line = contents.lines().next().unwrap_or_default();
+
+    // Exports opened and saved in a spreadsheet in some locales use `;` between fields
+    let delimiter = if first_line.matches(';').count() > first_line.matches(',').count() {
+        ';'
+    } else {
+        ','
+    };
+
+    let mut rows = parse(contents, delimiter).into_iter();
+
+    let Some(header) = rows.next() else {
+        bail!("The file is empty");
+    };
+
+    let rows = rows.collect::<Vec<_>>();
+    let value = |row: &Vec<String>, index: usize| {
+        row.get(index)
+            .map(|value| value.trim().to_owned())
+            .filter(|value| !value.is_empty())
+    };
+
+    let sku = column(&header, &SKU_COLUMNS).or_else(|| {
+        (0..header.len()).find(|&index| {
+            let values = rows.iter().filter_map(|row| value(row, index));
+            let (skus, total) = values.fold((0, 0), |(skus, total), value| {
+                (skus + usize::from(is_sku(&value)), total + 1)
+            });
+
+            total > 0 && skus * 2 > total
+        })
+    });
+    let asin = column(&header, &ASIN_COLUMNS);
+
+    if sku.is_none() && asin.is_none() {
+        bail!("Found no SKU or ASIN column, expected one named e.g. \"Product SKU\" or \"ASIN\"");
+    }
+
+    let mut result = Vec::new();
+
+    for row in &rows {
+        let title = match (sku.and_then(|index| value(row, index)), asin) {
+            (Some(sku), _) => Title::Sku(sku),
+            (None, Some(index)) => match value(row, index) {
+                Some(asin) => Title::AsinOnly(asin),
+                None => continue,
+            },
+            (None, None) => continue,
+        };
+
+        if !result.contains(&title) {
+            result.push(title);
+        }
+    }
+
+    Ok(result)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn sku(s: &str) -> Title {
+        Title::Sku(s.to_owned())
+    }
+
+    #[test]
+    fn reads_named_columns() {
+        let csv = "\u{feff}ASIN,Title,Product SKU\r\nB002V0QK4C,\"Dune, Book 1\",BK_ADBL_000123\r\nB00XYZ1234,Other,\r\nB002V0QK4C,\"Dune, Book 1\",BK_ADBL_000123\r\n";
+
+        assert_eq!(
+            titles(csv).unwrap(),
+            [
+                sku("BK_ADBL_000123"),
+                Title::AsinOnly("B00XYZ1234".to_owned())
+            ]
+        );
+    }
+
+    #[test]
+    fn finds_sku_column_by_values() {
+        let csv = "Titel;Autor;Kennung\n\"Er ist \"\"wieder\"\" da\";Timur Vermes;BK_RHDE_004321DE\nDer Schwarm;Frank Schätzing;BK_HOER_001234\n";
+
+        assert_eq!(
+            titles(csv).unwrap(),
+            [sku("BK_RHDE_004321DE"), sku("BK_HOER_001234")]
+        );
+    }
+
+    #[test]
+    fn requires_an_identifier() {
+        assert!(titles("Title,Author\nDune,Frank Herbert\n").is_err());
+        assert!(titles("").is_err());
+    }
+}
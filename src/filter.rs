@@ -0,0 +1,477 @@
+//! Filter expressions for selecting titles from the library, e.g.
+//! `length_min > 600 && purchased_after:2023-01-01`.
+//!
+//! A term compares a field with a value using one of `==`, `!=`, `<`, `<=`, `>`, `>=` or `:`
+//! (contains, ignoring case). Terms are combined with `&&`, `||`, `!` and parentheses. Values
+//! containing spaces or operator characters are quoted, e.g. `author:"Terry Pratchett"`.
+
+use std::cmp::Ordering;
+
+use crate::api::catalog::Person;
+use crate::api::library::Item;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Field {
+    Asin,
+    Sku,
+    Title,
+    Author,
+    Narrator,
+    Series,
+    Publisher,
+    LengthMin,
+    Purchased,
+    Released,
+}
+
+impl Field {
+    const NAMES: &'static [(&'static str, Field)] = &[
+        ("asin", Field::Asin),
+        ("sku", Field::Sku),
+        ("title", Field::Title),
+        ("author", Field::Author),
+        ("narrator", Field::Narrator),
+        ("series", Field::Series),
+        ("publisher", Field::Publisher),
+        ("length_min", Field::LengthMin),
+        ("purchased", Field::Purchased),
+        ("released", Field::Released),
+    ];
+
+    fn from_name(name: &str) -> Option<Field> {
+        Field::NAMES
+            .iter()
+            .find(|(candidate, _)| *candidate == name)
+            .map(|(_, field)| *field)
+    }
+
+    /// Values of the field for `item`, a term matches if any of them does
+    fn values(self, item: &Item) -> Vec<&str> {
+        let product = &item.product;
+
+        match self {
+            Field::Asin => vec![product.asin.as_str()],
+            Field::Sku => product.download_sku().into_iter().collect(),
+            Field::Title => vec![product.title.as_str()],
+            Field::Author => people(&product.authors),
+            Field::Narrator => people(&product.narrators),
+            Field::Series => product
+                .series
+                .iter()
+                .flatten()
+                .map(|series| series.title.as_str())
+                .collect(),
+            Field::Publisher => product.publisher_name.as_deref().into_iter().collect(),
+            // Compared as a number by `Filter::Length`
+            Field::LengthMin => Vec::new(),
+            // Only compare the date part of timestamps
+            Field::Purchased => item
+                .purchase_date
+                .as_deref()
+                .map(|date| date.get(..10).unwrap_or(date))
+                .into_iter()
+                .collect(),
+            Field::Released => product.release_date.as_deref().into_iter().collect(),
+        }
+    }
+}
+
+fn people(people: &Option<Vec<Person>>) -> Vec<&str> {
+    people
+        .iter()
+        .flatten()
+        .map(|person| person.name.as_str())
+        .collect()
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Op {
+    Eq,
+    Ne,
+    Lt,
+    Le,
+    Gt,
+    Ge,
+    Contains,
+}
+
+impl Op {
+    fn symbol(self) -> &'static str {
+        match self {
+            Op::Eq => "==",
+            Op::Ne => "!=",
+            Op::Lt => "<",
+            Op::Le => "<=",
+            Op::Gt => ">",
+            Op::Ge => ">=",
+            Op::Contains => ":",
+        }
+    }
+
+    fn holds(self, ordering: Ordering) -> bool {
+        match self {
+            Op::Eq => ordering == Ordering::Equal,
+            Op::Ne => ordering != Ordering::Equal,
+            Op::Lt => ordering == Ordering::Less,
+            Op::Le => ordering != Ordering::Greater,
+            Op::Gt => ordering == Ordering::Greater,
+            Op::Ge => ordering != Ordering::Less,
+            Op::Contains => unreachable!("contains isn't an ordering"),
+        }
+    }
+}
+
+#[derive(Clone, Debug)]
+pub enum Filter {
+    And(Box<Filter>, Box<Filter>),
+    Or(Box<Filter>, Box<Filter>),
+    Not(Box<Filter>),
+    Text(Field, Op, String),
+    Length(Op, u64),
+}
+
+impl Filter {
+    /// Parse a filter expression, for use as a clap value parser
+    pub fn parse(s: &str) -> Result<Filter, String> {
+        let tokens = tokenize(s)?;
+        let mut parser = Parser { tokens, pos: 0 };
+
+        let filter = parser.or()?;
+
+        match parser.next() {
+            None => Ok(filter),
+            Some(token) => Err(format!("unexpected {}", token)),
+        }
+    }
+
+    pub fn matches(&self, item: &Item) -> bool {
+        match self {
+            Filter::And(a, b) => a.matches(item) && b.matches(item),
+            Filter::Or(a, b) => a.matches(item) || b.matches(item),
+            Filter::Not(filter) => !filter.matches(item),
+            Filter::Length(op, value) => item
+                .product
+                .runtime_length_min
+                .is_some_and(|length| op.holds(u64::from(length).cmp(value))),
+            Filter::Text(field, op, value) => {
+                let value = value.to_lowercase();
+
+                field.values(item).iter().any(|candidate| {
+                    let candidate = candidate.to_lowercase();
+
+                    match op {
+                        Op::Contains => candidate.contains(&value),
+                        op => op.holds(candidate.as_str().cmp(value.as_str())),
+                    }
+                })
+            }
+        }
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+enum Token {
+    LParen,
+    RParen,
+    And,
+    Or,
+    Not,
+    Op(Op),
+    Word(String),
+}
+
+impl std::fmt::Display for Token {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Token::LParen => write!(f, "`(`"),
+            Token::RParen => write!(f, "`)`"),
+            Token::And => write!(f, "`&&`"),
+            Token::Or => write!(f, "`||`"),
+            Token::Not => write!(f, "`!`"),
+            Token::Op(op) => write!(f, "`{}`", op.symbol()),
+            Token::Word(word) => write!(f, "`{}`", word),
+        }
+    }
+}
+
+fn tokenize(s: &str) -> Result<Vec<Token>, String> {
+    let mut tokens = Vec::new();
+    let mut chars = s.chars().peekable();
+
+    while let Some(c) = chars.next() {
+        let token = match c {
+            c if c.is_whitespace() => continue,
+            '(' => Token::LParen,
+            ')' => Token::RParen,
+            ':' => Token::Op(Op::Contains),
+            '&' | '|' | '=' => {
+                if chars.next_if_eq(&c).is_none() {
+                    return Err(format!("expected `{}{}`", c, c));
+                }
+
+                match c {
+                    '&' => Token::And,
+                    '|' => Token::Or,
+                    _ => Token::Op(Op::Eq),
+                }
+            }
+            '!' | '<' | '>' => {
+                let or_equal = chars.next_if_eq(&'=').is_some();
+
+                match (c, or_equal) {
+                    ('!', false) => Token::Not,
+                    ('!', true) => Token::Op(Op::Ne),
+                    ('<', false) => Token::Op(Op::Lt),
+                    ('<', true) => Token::Op(Op::Le),
+                    ('>', false) => Token::Op(Op::Gt),
+                    (_, _) => Token::Op(Op::Ge),
+                }
+            }
+            '"' => {
+                let mut word = String::new();
+
+                loop {
+                    match chars.next() {
+                        Some('"') => break,
+                        Some(c) => word.push(c),
+                        None => return Err("unterminated quote".to_owned()),
+                    }
+                }
+
+                Token::Word(word)
+            }
+            c => {
+                let mut word = c.to_string();
+
+                while let Some(c) =
+                    chars.next_if(|c| !c.is_whitespace() && !"()&|=!<>:\"".contains(*c))
+                {
+                    word.push(c);
+                }
+
+                Token::Word(word)
+            }
+        };
+
+        tokens.push(token);
+    }
+
+    Ok(tokens)
+}
+
+struct Parser {
+    tokens: Vec<Token>,
+    pos: usize,
+}
+
+impl Parser {
+    fn next(&mut self) -> Option<Token> {
+        let token = self.tokens.get(self.pos).cloned();
+        self.pos += 1;
+        token
+    }
+
+    fn eat(&mut self, token: &Token) -> bool {
+        let matches = self.tokens.get(self.pos) == Some(token);
+
+        if matches {
+            self.pos += 1;
+        }
+
+        matches
+    }
+
+    fn or(&mut self) -> Result<Filter, String> {
+        let mut filter = self.and()?;
+
+        while self.eat(&Token::Or) {
+            filter = Filter::Or(Box::new(filter), Box::new(self.and()?));
+        }
+
+        Ok(filter)
+    }
+
+    fn and(&mut self) -> Result<Filter, String> {
+        let mut filter = self.unary()?;
+
+        while self.eat(&Token::And) {
+            filter = Filter::And(Box::new(filter), Box::new(self.unary()?));
+        }
+
+        Ok(filter)
+    }
+
+    fn unary(&mut self) -> Result<Filter, String> {
+        match self.next() {
+            Some(Token::Not) => Ok(Filter::Not(Box::new(self.unary()?))),
+            Some(Token::LParen) => {
+                let filter = self.or()?;
+
+                if !self.eat(&Token::RParen) {
+                    return Err("missing `)`".to_owned());
+                }
+
+                Ok(filter)
+            }
+            Some(Token::Word(name)) => self.term(&name),
+            Some(token) => Err(format!("expected a field, found {}", token)),
+            None => Err("unexpected end of filter".to_owned()),
+        }
+    }
+
+    fn term(&mut self, name: &str) -> Result<Filter, String> {
+        let op = match self.next() {
+            Some(Token::Op(op)) => op,
+            _ => return Err(format!("expected an operator after `{}`", name)),
+        };
+
+        let value = match self.next() {
+            Some(Token::Word(value)) => value,
+            _ => return Err(format!("expected a value after `{}`", name)),
+        };
+
+        // `purchased_after:2023-01-01` is short for `purchased > 2023-01-01`
+        let shorthand = [("_after", Op::Gt), ("_before", Op::Lt)]
+            .into_iter()
+            .find_map(|(suffix, op)| Some((name.strip_suffix(suffix)?, op)));
+
+        let (field, op) = match shorthand {
+            Some((field, shorthand_op)) if matches!(field, "purchased" | "released") => {
+                if op != Op::Contains {
+                    return Err(format!("use `{}:<date>`", name));
+                }
+
+                (Field::from_name(field).expect("known field"), shorthand_op)
+            }
+            _ => {
+                let field = Field::from_name(name).ok_or_else(|| {
+                    let names: Vec<_> = Field::NAMES.iter().map(|(name, _)| *name).collect();
+                    format!(
+                        "unknown field `{}`, expected one of {}",
+                        name,
+                        names.join(", ")
+                    )
+                })?;
+
+                (field, op)
+            }
+        };
+
+        if field != Field::LengthMin {
+            return Ok(Filter::Text(field, op, value));
+        }
+
+        if op == Op::Contains {
+            return Err(format!("`{}` can only be compared", name));
+        }
+
+        let value = value
+            .parse()
+            .map_err(|_| format!("`{}` isn't a number of minutes", value))?;
+
+        Ok(Filter::Length(op, value))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn item() -> Item {
+        serde_json::from_value(serde_json::json!({
+            "asin": "B002V0QK4C",
+            "sku_lite": "BK_ADBL_000123",
+            "title": "Guards! Guards!",
+            "authors": [{ "name": "Terry Pratchett" }],
+            "narrators": [{ "name": "Nigel Planer" }],
+            "series": [{ "title": "Discworld", "sequence": "8" }],
+            "publisher_name": "Isis Audio Books",
+            "release_date": "2008-06-01",
+            "runtime_length_min": 660,
+            "purchase_date": "2023-03-14T09:26:53.000Z",
+        }))
+        .unwrap()
+    }
+
+    fn matches(s: &str) -> bool {
+        Filter::parse(s).unwrap().matches(&item())
+    }
+
+    fn error(s: &str) -> String {
+        Filter::parse(s).unwrap_err()
+    }
+
+    #[test]
+    fn matches_example() {
+        assert!(matches("length_min > 600 && purchased_after:2023-01-01"));
+        assert!(!matches("length_min > 700 && purchased_after:2023-01-01"));
+        assert!(!matches("length_min > 600 && purchased_after:2023-06-01"));
+    }
+
+    #[test]
+    fn compares_with_each_operator() {
+        assert!(matches("length_min == 660"));
+        assert!(matches("length_min != 600"));
+        assert!(matches("length_min < 700"));
+        assert!(matches("length_min <= 660"));
+        assert!(!matches("length_min < 660"));
+        assert!(matches("length_min >= 660"));
+        assert!(!matches("length_min > 660"));
+
+        assert!(matches("asin == b002v0qk4c"));
+        assert!(matches("sku == BK_ADBL_000123"));
+        assert!(matches("author:pratchett"));
+        assert!(matches("narrator:\"nigel planer\""));
+        assert!(matches("series != Cosmere"));
+        assert!(matches("publisher >= Isis"));
+        assert!(matches("title:\"guards!\""));
+        assert!(matches("purchased == 2023-03-14"));
+        assert!(matches("purchased_before:2023-03-15"));
+        assert!(matches("released_before:2010-01-01"));
+        assert!(!matches("released_after:2010-01-01"));
+    }
+
+    #[test]
+    fn binds_not_then_and_then_or() {
+        // `a || b && c` is `a || (b && c)`
+        assert!(matches(
+            "author:pratchett || author:sanderson && length_min > 1000"
+        ));
+        assert!(!matches(
+            "(author:pratchett || author:sanderson) && length_min > 1000"
+        ));
+        // `!a && b` is `(!a) && b`
+        assert!(!matches("!author:pratchett && length_min > 600"));
+        assert!(matches("!(author:pratchett && length_min > 1000)"));
+        assert!(matches("!!series:discworld"));
+    }
+
+    #[test]
+    fn rejects_unknown_fields() {
+        assert!(error("genre:fantasy").starts_with("unknown field `genre`"));
+        assert!(error("length_min_after:600").starts_with("unknown field `length_min_after`"));
+        assert_eq!(
+            error("purchased_after > 2023-01-01"),
+            "use `purchased_after:<date>`"
+        );
+    }
+
+    #[test]
+    fn rejects_malformed_filters() {
+        assert_eq!(error(""), "unexpected end of filter");
+        assert_eq!(error("author"), "expected an operator after `author`");
+        assert_eq!(error("author:"), "expected a value after `author`");
+        assert_eq!(error("author = x"), "expected `==`");
+        assert_eq!(error("a:b & c:d"), "expected `&&`");
+        assert_eq!(error("title:\"Mort"), "unterminated quote");
+        assert_eq!(error("(author:x"), "missing `)`");
+        assert_eq!(error("author:x)"), "unexpected `)`");
+        assert_eq!(error("author:x author:y"), "unexpected `author`");
+        assert_eq!(error("&& author:x"), "expected a field, found `&&`");
+        assert_eq!(error("length_min:6"), "`length_min` can only be compared");
+        assert_eq!(
+            error("length_min > long"),
+            "`long` isn't a number of minutes"
+        );
+    }
+}
@@ -0,0 +1,43 @@
+use tokio::io::{AsyncBufReadExt, AsyncWriteExt, BufReader};
+use tokio::net::{TcpListener, TcpStream};
+
+/// Answer `GET /healthz` with `200 OK` for as long as the process is running.
+///
+/// This is deliberately tiny, it only exists so that container orchestrators can tell
+/// whether the daemon is still alive.
+pub async fn serve(listener: TcpListener) {
+    loop {
+        match listener.accept().await {
+            Ok((stream, _)) => {
+                tokio::spawn(async move {
+                    // Nothing useful to do if the client goes away mid-request
+                    let _ = respond(stream).await;
+                });
+            }
+            Err(e) => eprintln!("Health endpoint failed to accept connection: {}", e),
+        }
+    }
+}
+
+async fn respond(stream: TcpStream) -> std::io::Result<()> {
+    let mut stream = BufReader::new(stream);
+
+    let mut request_line = String::new();
+    stream.read_line(&mut request_line).await?;
+
+    // Skip the remaining request headers
+    let mut line = String::new();
+    while stream.read_line(&mut line).await? > 2 {
+        line.clear();
+    }
+
+    let mut parts = request_line.split_whitespace();
+    let response = match (parts.next(), parts.next()) {
+        (Some("GET"), Some("/healthz")) => "HTTP/1.1 200 OK\r\ncontent-length: 3\r\n\r\nok\n",
+        _ => "HTTP/1.1 404 Not Found\r\ncontent-length: 0\r\n\r\n",
+    };
+
+    let mut stream = stream.into_inner();
+    stream.write_all(response.as_bytes()).await?;
+    stream.shutdown().await
+}
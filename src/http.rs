@@ -0,0 +1,322 @@
+//! The HTTP client, configured by the options shared by all commands.
+//!
+//! The options are set once from the command line by `main`, so that clients built later on,
+//! e.g. when reconnecting, get the same setup.
+
+use std::net::{IpAddr, Ipv4Addr, SocketAddr, SocketAddrV4};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, OnceLock};
+use std::time::Duration;
+
+use anyhow::Result;
+use reqwest::header::{HeaderMap, HeaderName, HeaderValue};
+use reqwest::Url;
+
+use crate::budget;
+use crate::dns::{self, IpVersion};
+
+static ARGS: OnceLock<HttpArgs> = OnceLock::new();
+
+/// How long to wait for a connection without `--connect-timeout` or `--timeout-profile`
+const DEFAULT_CONNECT_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// How long to wait for the first byte of a response without `--first-byte-timeout` or
+/// `--timeout-profile`
+pub const DEFAULT_FIRST_BYTE_TIMEOUT: Duration = Duration::from_secs(60);
+
+/// How long to wait for more data without `--chunk-timeout` or `--timeout-profile`
+pub const DEFAULT_CHUNK_TIMEOUT: Duration = Duration::from_secs(120);
+
+/// Where the Tor SOCKS proxy listens without `--tor-proxy`
+const DEFAULT_TOR_PROXY: SocketAddr = SocketAddr::V4(SocketAddrV4::new(Ipv4Addr::LOCALHOST, 9050));
+
+/// Tor circuit used by clients built from now on, see [`new_circuit`]
+static CIRCUIT: AtomicU64 = AtomicU64::new(0);
+
+/// Options for all HTTP requests
+#[derive(clap::Args, Clone, Debug)]
+pub struct HttpArgs {
+    /// Extra header to send with every request, e.g. `--header "X-Foo: bar"`, can be repeated.
+    /// A User-Agent given this way replaces the one sent for downloads.
+    #[arg(
+        long = "header",
+        env = "AUDIBLE_DL_HEADERS",
+        value_name = "NAME: VALUE",
+        value_parser = parse_header,
+        value_delimiter = '\n',
+        global = true
+    )]
+    headers: Vec<(HeaderName, HeaderValue)>,
+
+    /// Send all requests through Tor, using a separate circuit for each title
+    #[arg(long, env = "AUDIBLE_DL_TOR", global = true)]
+    tor: bool,
+
+    /// Address of the Tor SOCKS proxy used by `--tor`
+    #[arg(
+        long,
+        env = "AUDIBLE_DL_TOR_PROXY",
+        default_value_t = DEFAULT_TOR_PROXY,
+        global = true
+    )]
+    tor_proxy: SocketAddr,
+
+    /// Timeouts and retries suited for a kind of connection, instead of the default timeouts and
+    /// retrying forever
+    #[arg(long, env = "AUDIBLE_DL_TIMEOUT_PROFILE", value_enum, global = true)]
+    timeout_profile: Option<TimeoutProfile>,
+
+    /// How long to wait for a connection to the server, e.g. `30s`, instead of what the
+    /// `--timeout-profile` says
+    #[arg(
+        long,
+        env = "AUDIBLE_DL_CONNECT_TIMEOUT",
+        value_name = "DURATION",
+        value_parser = budget::parse_duration,
+        global = true
+    )]
+    connect_timeout: Option<Duration>,
+
+    /// How long to wait for the first byte of a response, including the TLS handshake, e.g.
+    /// `1m`, before trying again
+    #[arg(
+        long,
+        env = "AUDIBLE_DL_FIRST_BYTE_TIMEOUT",
+        value_name = "DURATION",
+        value_parser = budget::parse_duration,
+        global = true
+    )]
+    first_byte_timeout: Option<Duration>,
+
+    /// How long to wait for more data while downloading, e.g. `2m`, before reconnecting
+    #[arg(
+        long,
+        env = "AUDIBLE_DL_CHUNK_TIMEOUT",
+        value_name = "DURATION",
+        value_parser = budget::parse_duration,
+        global = true
+    )]
+    chunk_timeout: Option<Duration>,
+
+    /// Only connect over this IP version, e.g. `4` when IPv6 is broken and downloads stall
+    #[arg(
+        long,
+        env = "AUDIBLE_DL_IP_VERSION",
+        value_enum,
+        default_value_t = IpVersion::Auto,
+        conflicts_with = "tor",
+        global = true
+    )]
+    ip_version: IpVersion,
+
+    /// Use this address for a host instead of looking it up, like curl's `--resolve`, e.g.
+    /// `cds.audible.com:443:1.2.3.4`. The address is used for every port. Can be repeated.
+    #[arg(
+        long,
+        env = "AUDIBLE_DL_RESOLVE",
+        value_name = "HOST:PORT:ADDRESS",
+        value_parser = dns::parse_override,
+        value_delimiter = ',',
+        conflicts_with = "tor",
+        global = true
+    )]
+    resolve: Vec<(String, IpAddr)>,
+
+    /// Look up host names with this DNS-over-HTTPS server, using its JSON API, e.g.
+    /// `https://1.1.1.1/dns-query`
+    #[arg(
+        long,
+        env = "AUDIBLE_DL_DOH",
+        value_name = "URL",
+        conflicts_with = "tor",
+        global = true
+    )]
+    doh: Option<Url>,
+}
+
+/// The options when none are given, for clients built before [`configure`]
+impl Default for HttpArgs {
+    fn default() -> HttpArgs {
+        HttpArgs {
+            headers: Vec::new(),
+            tor: false,
+            tor_proxy: DEFAULT_TOR_PROXY,
+            timeout_profile: None,
+            connect_timeout: None,
+            first_byte_timeout: None,
+            chunk_timeout: None,
+            ip_version: IpVersion::Auto,
+            resolve: Vec::new(),
+            doh: None,
+        }
+    }
+}
+
+#[derive(clap::ValueEnum, Clone, Copy, Debug, PartialEq, Eq)]
+pub enum TimeoutProfile {
+    /// Fast and stable connections, give up quickly
+    Fast,
+    /// Slow or congested connections
+    Patient,
+    /// High latency connections that drop out now and then, e.g. satellite or in-flight wifi
+    Satellite,
+}
+
+impl TimeoutProfile {
+    /// How long to wait for a connection to be established
+    pub fn connect(self) -> Duration {
+        match self {
+            TimeoutProfile::Fast => Duration::from_secs(5),
+            TimeoutProfile::Patient => Duration::from_secs(30),
+            TimeoutProfile::Satellite => Duration::from_secs(60),
+        }
+    }
+
+    /// How long to wait for the response to a request
+    pub fn response(self) -> Duration {
+        match self {
+            TimeoutProfile::Fast => Duration::from_secs(10),
+            TimeoutProfile::Patient => Duration::from_secs(60),
+            TimeoutProfile::Satellite => Duration::from_secs(120),
+        }
+    }
+
+    /// How long to wait for more data before reconnecting
+    pub fn stall(self) -> Duration {
+        match self {
+            TimeoutProfile::Fast => Duration::from_secs(10),
+            TimeoutProfile::Patient => Duration::from_secs(60),
+            TimeoutProfile::Satellite => Duration::from_secs(90),
+        }
+    }
+
+    /// How many times in a row a download may fail before giving up
+    pub fn retries(self) -> u32 {
+        match self {
+            TimeoutProfile::Fast => 3,
+            TimeoutProfile::Patient => 20,
+            TimeoutProfile::Satellite => 50,
+        }
+    }
+}
+
+fn parse_header(s: &str) -> Result<(HeaderName, HeaderValue), String> {
+    let (name, value) = s
+        .split_once(':')
+        .ok_or_else(|| "expected `Name: value`".to_owned())?;
+
+    let name = HeaderName::try_from(name.trim()).map_err(|e| e.to_string())?;
+    let value = HeaderValue::try_from(value.trim()).map_err(|e| e.to_string())?;
+
+    Ok((name, value))
+}
+
+/// Use `args` for all clients built from now on
+pub fn configure(args: HttpArgs) {
+    ARGS.set(args)
+        .expect("HTTP options are only configured once");
+}
+
+fn args() -> &'static HttpArgs {
+    ARGS.get_or_init(HttpArgs::default)
+}
+
+/// The extra headers sent with every request
+pub fn headers() -> &'static [(HeaderName, HeaderValue)] {
+    &args().headers
+}
+
+/// Whether the user replaced the header `name`
+pub fn overrides(name: &str) -> bool {
+    args()
+        .headers
+        .iter()
+        .any(|(header, _)| header.as_str().eq_ignore_ascii_case(name))
+}
+
+/// The chosen `--timeout-profile`, if any
+pub fn timeout_profile() -> Option<TimeoutProfile> {
+    args().timeout_profile
+}
+
+/// The `--first-byte-timeout`, or the one of the `--timeout-profile`
+pub fn first_byte_timeout() -> Option<Duration> {
+    let args = args();
+    args.first_byte_timeout
+        .or(args.timeout_profile.map(|profile| profile.response()))
+}
+
+/// The `--chunk-timeout`, or the one of the `--timeout-profile`
+pub fn chunk_timeout() -> Option<Duration> {
+    let args = args();
+    args.chunk_timeout
+        .or(args.timeout_profile.map(|profile| profile.stall()))
+}
+
+/// Whether requests go through Tor
+pub fn tor() -> bool {
+    args().tor
+}
+
+/// Let clients built from now on use a new Tor circuit
+pub fn new_circuit() {
+    CIRCUIT.fetch_add(1, Ordering::Relaxed);
+}
+
+/// Build an HTTP client
+pub fn client() -> Result<reqwest::Client> {
+    let args = args();
+    let mut headers = HeaderMap::new();
+
+    // A repeated header replaces the earlier one
+    for (name, value) in &args.headers {
+        headers.insert(name, value.clone());
+    }
+
+    let mut builder = reqwest::Client::builder().default_headers(headers);
+
+    let connect_timeout = args
+        .connect_timeout
+        .or(args.timeout_profile.map(|profile| profile.connect()))
+        .unwrap_or(DEFAULT_CONNECT_TIMEOUT);
+    builder = builder.connect_timeout(connect_timeout);
+
+    // Through Tor the exit resolves host names and picks the IP version, clap keeps these
+    // options from being combined with `--tor`
+    if args.ip_version != IpVersion::Auto || args.doh.is_some() {
+        let doh = match &args.doh {
+            // The DoH server itself is looked up with the system resolver, unless it's an IP
+            Some(url) => Some((reqwest::Client::builder().build()?, url.clone())),
+            None => None,
+        };
+
+        builder = builder.dns_resolver(Arc::new(dns::Resolver {
+            ip_version: args.ip_version,
+            doh,
+        }));
+    }
+
+    for (host, _) in &args.resolve {
+        let addrs = args
+            .resolve
+            .iter()
+            .filter(|(other, _)| other == host)
+            .map(|(_, ip)| SocketAddr::new(*ip, 0))
+            .collect::<Vec<_>>();
+
+        builder = builder.resolve_to_addrs(host, &addrs);
+    }
+
+    if args.tor {
+        // Tor puts streams with different SOCKS credentials on different circuits, and with
+        // `socks5h` the host names are resolved by the exit instead of leaking to the local DNS
+        builder = builder.proxy(reqwest::Proxy::all(format!(
+            "socks5h://audible-dl-{}:circuit-{}@{}",
+            std::process::id(),
+            CIRCUIT.load(Ordering::Relaxed),
+            args.tor_proxy
+        ))?);
+    }
+
+    Ok(builder.build()?)
+}
@@ -0,0 +1,91 @@
+use anyhow::Result;
+
+use crate::api::ApiArgs;
+
+#[derive(clap::Args, Debug)]
+pub struct InfoArgs {
+    /// ASIN of the title
+    asin: String,
+
+    /// How to print the details
+    #[arg(long, env = "AUDIBLE_DL_INFO_FORMAT", value_enum, default_value_t = Format::Text)]
+    format: Format,
+
+    #[command(flatten)]
+    api: ApiArgs,
+}
+
+#[derive(clap::Args, Debug)]
+pub struct LicenseArgs {
+    /// ASIN of the title
+    asin: String,
+
+    #[command(flatten)]
+    api: ApiArgs,
+}
+
+#[derive(clap::ValueEnum, Clone, Copy, Debug)]
+pub enum Format {
+    /// One detail per line
+    Text,
+    /// JSON, see `audible-dl schema <command>`
+    Json,
+}
+
+/// Print the catalog details of a title
+pub async fn run(client: &reqwest::Client, args: InfoArgs) -> Result<()> {
+    let api = args.api.client(client.clone())?;
+    let product = api.product(&args.asin).await?;
+
+    if let Format::Json = args.format {
+        println!("{}", serde_json::to_string_pretty(&product)?);
+
+        return Ok(());
+    }
+
+    let series = product
+        .series
+        .iter()
+        .flatten()
+        .map(|series| match &series.sequence {
+            Some(sequence) => format!("{} #{}", series.title, sequence),
+            None => series.title.clone(),
+        })
+        .collect::<Vec<_>>()
+        .join(", ");
+
+    let length = product
+        .runtime_length_min
+        .map(|min| format!("{}h {}m", min / 60, min % 60));
+
+    let fields = [
+        ("Title", Some(product.title.clone())),
+        ("Subtitle", product.subtitle.clone()),
+        ("Authors", Some(product.author_names())),
+        ("Narrators", Some(product.narrator_names())),
+        ("Series", Some(series)),
+        ("Publisher", product.publisher_name.clone()),
+        ("Released", product.release_date.clone()),
+        ("Length", length),
+        ("ASIN", Some(product.asin.clone())),
+        ("SKU", product.download_sku().map(str::to_owned)),
+    ];
+
+    for (name, value) in fields {
+        if let Some(value) = value.filter(|value| !value.is_empty()) {
+            println!("{:<10} {}", format!("{}:", name), value);
+        }
+    }
+
+    Ok(())
+}
+
+/// Request a download license and print the signed download URL, for use with `download --url`
+pub async fn license(client: &reqwest::Client, args: LicenseArgs) -> Result<()> {
+    let api = args.api.client(client.clone())?;
+    let license = api.license(&args.asin).await?;
+
+    println!("{}", license.download_url()?);
+
+    Ok(())
+}
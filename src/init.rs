@@ -0,0 +1,205 @@
+//! `audible-dl init`, asking for the usual options and writing them to the config file.
+
+use std::io::{IsTerminal, Write};
+use std::path::PathBuf;
+
+use anyhow::{bail, Context, Result};
+use clap::ValueEnum;
+
+use audible_dl_core::aax;
+use audible_dl_core::api::{Client, Marketplace};
+
+use crate::{config, conflict, quality};
+
+/// Ask `question`, returning `default` if the answer is empty
+fn ask(question: &str, default: Option<&str>) -> Result<String> {
+    match default {
+        Some(default) if !default.is_empty() => eprint!("{} [{}] ", question, default),
+        _ => eprint!("{} ", question),
+    }
+    std::io::stderr().flush()?;
+
+    let mut answer = String::new();
+
+    if std::io::stdin().read_line(&mut answer)? == 0 {
+        bail!("No answer, giving up");
+    }
+
+    match answer.trim() {
+        "" => Ok(default.unwrap_or_default().to_owned()),
+        answer => Ok(answer.to_owned()),
+    }
+}
+
+/// Ask `question` until the answer passes `parse`, which returns why it didn't otherwise
+fn ask_valid<T>(
+    question: &str,
+    default: Option<&str>,
+    parse: impl Fn(&str) -> Result<T, String>,
+) -> Result<T> {
+    loop {
+        match parse(&ask(question, default)?) {
+            Ok(value) => return Ok(value),
+            Err(e) => eprintln!("{}", e),
+        }
+    }
+}
+
+fn marketplace_name(marketplace: Marketplace) -> String {
+    marketplace
+        .to_possible_value()
+        .expect("marketplaces have names")
+        .get_name()
+        .to_owned()
+}
+
+/// Ask for the auth file until one works, returning it with the marketplace it's for
+async fn login(client: &reqwest::Client) -> Result<(PathBuf, Marketplace)> {
+    eprintln!("audible-dl uses the auth file of audible-cli to log in, create one with `audible quickstart`.");
+
+    loop {
+        let path = PathBuf::from(ask("Path to your auth file:", None)?);
+
+        // Uses the marketplace of the auth file, when it has one
+        let api = match Client::new(client.clone(), &path, None, false) {
+            Ok(api) => api,
+            Err(e) if path.exists() => {
+                eprintln!("{:#}", e);
+                let names = Marketplace::value_variants()
+                    .iter()
+                    .map(|marketplace| marketplace_name(*marketplace))
+                    .collect::<Vec<_>>();
+                let marketplace = ask_valid(
+                    &format!("Marketplace ({}):", names.join(", ")),
+                    Some("us"),
+                    |name| Marketplace::from_str(name, true),
+                )?;
+
+                match Client::new(client.clone(), &path, Some(marketplace), false) {
+                    Ok(api) => api,
+                    Err(e) => {
+                        eprintln!("{:#}", e);
+                        continue;
+                    }
+                }
+            }
+            Err(e) => {
+                eprintln!("{:#}", e);
+                continue;
+            }
+        };
+
+        eprintln!("Checking your library...");
+
+        match api.library().await {
+            Ok(items) => {
+                eprintln!(
+                    "Logged in to audible.{}, found {} titles in your library",
+                    api.marketplace().domain(),
+                    items.len()
+                );
+
+                return Ok((path.canonicalize()?, api.marketplace()));
+            }
+            Err(e) => eprintln!("That auth file doesn't work: {:#}", e),
+        }
+    }
+}
+
+/// Ask for the common options and write them to the config file
+pub async fn run(client: &reqwest::Client) -> Result<()> {
+    if !std::io::stdin().is_terminal() {
+        bail!("`audible-dl init` asks questions, run it in a terminal");
+    }
+
+    let path = config::path()?;
+
+    if path.exists()
+        && !conflict::confirm(
+            &format!("{} already exists, replace it?", path.display()),
+            false,
+        )?
+    {
+        return Ok(());
+    }
+
+    let mut config = vec![(
+        "AUDIBLE_DL_CUSTOMER_ID",
+        ask_valid("Your Audible customer id:", None, |id| match id {
+            "" => Err("The customer id is needed to download by SKU".to_owned()),
+            id => Ok(id.to_owned()),
+        })?,
+    )];
+
+    if conflict::confirm(
+        "Log in to the Audible API, to list your library and check titles before downloading them?",
+        false,
+    )? {
+        let (auth_file, marketplace) = login(client).await?;
+        config.push(("AUDIBLE_DL_AUTH_FILE", auth_file.display().to_string()));
+        config.push(("AUDIBLE_DL_MARKETPLACE", marketplace_name(marketplace)));
+    }
+
+    let output_dir = ask(
+        "Directory to save books in, when watching a folder:",
+        Some("."),
+    )?;
+    config.push(("AUDIBLE_DL_OUTPUT_DIR", output_dir));
+
+    eprintln!(
+        "Books are downloaded in the best quality, {}.",
+        quality::QUALITIES[0]
+    );
+    let failures = ask_valid(
+        "Fall back to a lower quality after how many failures in a row? (0 for never)",
+        Some("0"),
+        |answer| {
+            answer
+                .parse::<u32>()
+                .map_err(|_| "Please answer with a number".to_owned())
+        },
+    )?;
+
+    if failures > 0 {
+        config.push(("AUDIBLE_DL_FALLBACK_QUALITY", failures.to_string()));
+    }
+
+    if conflict::confirm(
+        "Convert books to M4B? This needs the activation bytes of your account",
+        false,
+    )? {
+        let activation_bytes = ask_valid("Activation bytes, as 8 hex digits:", None, |hex| {
+            aax::parse_activation_bytes(hex).map(|_| hex.to_lowercase())
+        })?;
+        config.push(("AUDIBLE_DL_ACTIVATION_BYTES", activation_bytes));
+    }
+
+    let mut contents = String::from(
+        "# Written by `audible-dl init`, variables set in the environment take precedence\n",
+    );
+
+    for (name, value) in &config {
+        contents += &format!("{}={}\n", name, value);
+    }
+
+    if let Some(dir) = path.parent() {
+        tokio::fs::create_dir_all(dir)
+            .await
+            .with_context(|| format!("Failed to create {}", dir.display()))?;
+    }
+
+    tokio::fs::write(&path, contents)
+        .await
+        .with_context(|| format!("Failed to write {}", path.display()))?;
+
+    eprintln!("Saved the configuration to {}", path.display());
+
+    if config
+        .iter()
+        .any(|(name, _)| *name == "AUDIBLE_DL_ACTIVATION_BYTES")
+    {
+        eprintln!("Convert downloaded books with `audible-dl convert <book>.aax`");
+    }
+
+    Ok(())
+}
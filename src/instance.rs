@@ -0,0 +1,106 @@
+//! Making sure only one watcher runs for a directory, and handing over to a new one.
+//!
+//! The running watcher holds a lock on `.audible-dl.lock` in the watched directory. A new one
+//! started with `--takeover` creates `.audible-dl.takeover` next to it, which makes the running
+//! one stop after the chunk it's writing and exit without moving the `.sku` file it was working
+//! on. The new watcher then gets the lock and picks up the same files, resuming the partial
+//! download where it was left.
+
+use std::fs::{File, OpenOptions, TryLockError};
+use std::path::{Path, PathBuf};
+use std::time::{Duration, Instant};
+
+use anyhow::{anyhow, bail, Context, Result};
+
+use audible_dl_core::rangedl;
+
+const LOCK_NAME: &str = ".audible-dl.lock";
+const TAKEOVER_NAME: &str = ".audible-dl.takeover";
+
+/// How long to wait for the running watcher to hand over
+const TAKEOVER_TIMEOUT: Duration = Duration::from_secs(120);
+
+/// How often to check for a takeover, and for the lock when taking over
+const POLL_INTERVAL: Duration = Duration::from_millis(500);
+
+/// Lock on a watched directory, held for as long as the watcher runs
+#[derive(Debug)]
+pub struct InstanceLock {
+    _file: File,
+}
+
+fn try_lock(file: &File, path: &Path) -> Result<bool> {
+    match file.try_lock() {
+        Ok(()) => Ok(true),
+        Err(TryLockError::WouldBlock) => Ok(false),
+        Err(TryLockError::Error(e)) => {
+            Err(e).with_context(|| format!("Failed to lock {}", path.display()))
+        }
+    }
+}
+
+/// Lock `dir`, asking the watcher that holds the lock to hand over if `takeover` is set
+pub async fn acquire(dir: &Path, takeover: bool) -> Result<InstanceLock> {
+    let path = dir.join(LOCK_NAME);
+    let request = dir.join(TAKEOVER_NAME);
+
+    let file = OpenOptions::new()
+        .create(true)
+        .truncate(false)
+        .write(true)
+        .open(&path)
+        .with_context(|| format!("Failed to open {}", path.display()))?;
+
+    if try_lock(&file, &path)? {
+        // Left behind by a takeover that gave up
+        match tokio::fs::remove_file(&request).await {
+            Err(e) if e.kind() != std::io::ErrorKind::NotFound => return Err(e.into()),
+            _ => {}
+        }
+
+        return Ok(InstanceLock { _file: file });
+    }
+
+    if !takeover {
+        bail!(
+            "Another audible-dl is already watching {}, use --takeover to replace it",
+            dir.display()
+        );
+    }
+
+    eprintln!("Asking the running audible-dl to hand over...");
+    tokio::fs::write(&request, std::process::id().to_string()).await?;
+
+    let started = Instant::now();
+
+    let result = loop {
+        if try_lock(&file, &path)? {
+            break Ok(InstanceLock { _file: file });
+        }
+
+        if started.elapsed() > TAKEOVER_TIMEOUT {
+            break Err(anyhow!(
+                "The running audible-dl didn't hand over within {} seconds",
+                TAKEOVER_TIMEOUT.as_secs()
+            ));
+        }
+
+        tokio::time::sleep(POLL_INTERVAL).await;
+    };
+
+    tokio::fs::remove_file(&request).await?;
+
+    result
+}
+
+/// Stop all downloads once another watcher asks to take over `dir`
+pub async fn watch_takeover(dir: PathBuf) {
+    let request = dir.join(TAKEOVER_NAME);
+
+    while !request.exists() {
+        tokio::time::sleep(POLL_INTERVAL).await;
+    }
+
+    eprintln!("Another audible-dl is taking over, stopping");
+    rangedl::stop();
+}
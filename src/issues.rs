@@ -0,0 +1,54 @@
+use anyhow::Result;
+
+use crate::api::issues;
+use crate::api::ApiArgs;
+
+#[derive(clap::Args, Debug)]
+pub struct IssuesArgs {
+    /// ASIN of the periodical, as listed by `audible-dl library`
+    asin: String,
+
+    /// Only list the N most recent issues
+    #[arg(long, env = "AUDIBLE_DL_ISSUES_LATEST_ISSUES", value_name = "N")]
+    latest_issues: Option<usize>,
+
+    /// How to print the issues
+    #[arg(long, env = "AUDIBLE_DL_ISSUES_FORMAT", value_enum, default_value_t = Format::Table)]
+    format: Format,
+
+    #[command(flatten)]
+    api: ApiArgs,
+}
+
+#[derive(clap::ValueEnum, Clone, Copy, Debug)]
+enum Format {
+    /// Tab separated ASIN, SKU, issue number and title, the oldest first
+    Table,
+    /// Just the SKUs, one per line, as read by `watch`
+    Ids,
+}
+
+/// Print the issues of a periodical, one per line
+pub async fn run(client: &reqwest::Client, args: IssuesArgs) -> Result<()> {
+    let api = args.api.client(client.clone())?;
+    let issues = issues::latest(api.issues(&args.asin).await?, args.latest_issues);
+
+    for issue in issues {
+        match args.format {
+            Format::Table => println!(
+                "{}\t{}\t{}\t{}",
+                issue.asin,
+                issue.download_sku().unwrap_or("-"),
+                issue.sequence.as_deref().unwrap_or("-"),
+                issue.title.as_deref().unwrap_or("-")
+            ),
+            Format::Ids => {
+                if let Some(sku) = issue.download_sku() {
+                    println!("{}", sku);
+                }
+            }
+        }
+    }
+
+    Ok(())
+}
@@ -0,0 +1,176 @@
+use anyhow::Result;
+use chrono::NaiveDate;
+
+use crate::api::library::Item;
+use crate::api::ApiArgs;
+use crate::filter::Filter;
+use crate::tags::Tags;
+
+#[derive(clap::Args, Debug)]
+pub struct LibraryArgs {
+    /// Only list titles matching this expression, e.g. `length_min > 600 && author:pratchett`
+    #[arg(long, env = "AUDIBLE_DL_FILTER", value_parser = Filter::parse)]
+    filter: Option<Filter>,
+
+    /// Only list titles in the collection with this name, see `audible-dl collections`
+    #[arg(long, env = "AUDIBLE_DL_COLLECTION")]
+    collection: Option<String>,
+
+    /// Only list titles with this tag, set with `audible-dl tag-local`, repeat for several
+    #[arg(long, env = "AUDIBLE_DL_TAG", value_delimiter = ',')]
+    tag: Vec<String>,
+
+    /// Only list titles purchased on or after this date, e.g. `2024-01-01`
+    #[arg(
+        long,
+        env = "AUDIBLE_DL_PURCHASED_AFTER",
+        value_name = "DATE",
+        value_parser = parse_date
+    )]
+    purchased_after: Option<NaiveDate>,
+
+    /// Only list titles purchased on or before this date, e.g. `2024-12-31`
+    #[arg(
+        long,
+        env = "AUDIBLE_DL_PURCHASED_BEFORE",
+        value_name = "DATE",
+        value_parser = parse_date
+    )]
+    purchased_before: Option<NaiveDate>,
+
+    /// How to print the titles
+    #[arg(long, env = "AUDIBLE_DL_LIBRARY_FORMAT", value_enum, default_value_t = Format::Table)]
+    format: Format,
+
+    #[command(flatten)]
+    api: ApiArgs,
+}
+
+fn parse_date(s: &str) -> Result<NaiveDate, String> {
+    NaiveDate::parse_from_str(s, "%Y-%m-%d")
+        .map_err(|_| "expected a date like 2024-01-01".to_owned())
+}
+
+/// Whether `item` was purchased between `after` and `before`, both included. Titles without a
+/// purchase date only match when neither is given.
+fn purchased_within(item: &Item, after: Option<NaiveDate>, before: Option<NaiveDate>) -> bool {
+    if after.is_none() && before.is_none() {
+        return true;
+    }
+
+    let Some(date) = item
+        .purchase_date
+        .as_deref()
+        .and_then(|date| NaiveDate::parse_from_str(date.get(..10)?, "%Y-%m-%d").ok())
+    else {
+        return false;
+    };
+
+    after.is_none_or(|after| date >= after) && before.is_none_or(|before| date <= before)
+}
+
+#[derive(clap::Args, Debug)]
+pub struct CollectionsArgs {
+    #[command(flatten)]
+    api: ApiArgs,
+}
+
+#[derive(clap::ValueEnum, Clone, Copy, Debug)]
+enum Format {
+    /// Tab separated ASIN, SKU, purchase date, title and authors, with the release date of
+    /// preorders after the title
+    Table,
+    /// Just the SKUs, one per line, as read by `watch`, leaving out preorders
+    Ids,
+    /// A JSON array of the titles, see `audible-dl schema library`
+    Json,
+}
+
+/// Print the titles in the library, one per line
+pub async fn run(client: &reqwest::Client, args: LibraryArgs) -> Result<()> {
+    let api = args.api.client(client.clone())?;
+
+    let asins = match &args.collection {
+        Some(name) => {
+            let collection = api.collection(name).await?;
+            Some(api.collection_items(&collection.collection_id).await?)
+        }
+        None => None,
+    };
+
+    let tags = match args.tag.is_empty() {
+        true => None,
+        false => Some(Tags::load()?),
+    };
+
+    let items = api.library().await?.into_iter().filter(|item| {
+        asins
+            .as_ref()
+            .is_none_or(|asins| asins.contains(&item.product.asin))
+            && tags.as_ref().is_none_or(|tags| {
+                item.product
+                    .download_sku()
+                    .is_some_and(|sku| tags.has_all(sku, &args.tag))
+            })
+            && purchased_within(item, args.purchased_after, args.purchased_before)
+            && args
+                .filter
+                .as_ref()
+                .is_none_or(|filter| filter.matches(item))
+    });
+
+    if let Format::Json = args.format {
+        let items = items.collect::<Vec<_>>();
+        println!("{}", serde_json::to_string_pretty(&items)?);
+
+        return Ok(());
+    }
+
+    for item in items {
+        if let Format::Ids = args.format {
+            // Titles that can't be downloaded have no SKU, and preorders can't be downloaded yet
+            if let Some(sku) = item
+                .product
+                .download_sku()
+                .filter(|_| !item.product.is_preorder())
+            {
+                println!("{}", sku);
+            }
+
+            continue;
+        }
+
+        let mut title = item.product.title.clone();
+
+        if item.product.is_preorder() {
+            let released = item.product.release_date.as_deref().unwrap_or("soon");
+            title += &format!(" (preorder, released {})", released);
+        }
+
+        println!(
+            "{}\t{}\t{}\t{}\t{}",
+            item.product.asin,
+            item.product.download_sku().unwrap_or("-"),
+            item.purchase_date.as_deref().unwrap_or("-"),
+            title,
+            item.product.author_names()
+        );
+    }
+
+    Ok(())
+}
+
+/// Print the collections in the library, one per line
+pub async fn collections(client: &reqwest::Client, args: CollectionsArgs) -> Result<()> {
+    let api = args.api.client(client.clone())?;
+
+    for collection in api.collections().await? {
+        println!(
+            "{}\t{}",
+            collection.name,
+            collection.description.as_deref().unwrap_or("-")
+        );
+    }
+
+    Ok(())
+}
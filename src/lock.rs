@@ -0,0 +1,127 @@
+use std::fs::{File, OpenOptions, TryLockError};
+use std::path::{Path, PathBuf};
+
+use anyhow::{bail, Context, Result};
+
+/// Exclusive lock on a partial download, so that two instances downloading the same book don't
+/// write to the same file.
+///
+/// The lock is taken on a `.lock` file next to the partial download rather than on the partial
+/// download itself, since Windows doesn't allow writing to a locked file through another handle,
+/// and the partial download is reopened, replaced and renamed during the transfer.
+///
+/// The lock file is removed while the lock is still held, so an instance that opened it just
+/// before may still get the lock on the removed file. It then finds another file at the path, or
+/// none, and starts over. Where that can't be told, outside of Unix, the lock file is left.
+#[derive(Debug)]
+pub struct PartLock {
+    file: File,
+    path: PathBuf,
+}
+
+impl PartLock {
+    /// Lock `part`, failing right away if another instance holds the lock
+    pub fn acquire(part: &Path) -> Result<PartLock> {
+        let mut path = part.as_os_str().to_owned();
+        path.push(".lock");
+        let path = PathBuf::from(path);
+
+        loop {
+            let file = OpenOptions::new()
+                .create(true)
+                .truncate(false)
+                .write(true)
+                .open(&path)
+                .with_context(|| format!("Failed to open {}", path.display()))?;
+
+            match file.try_lock() {
+                Ok(()) => {}
+                Err(TryLockError::WouldBlock) => bail!(
+                    "Another audible-dl is already downloading to {}",
+                    part.display()
+                ),
+                Err(TryLockError::Error(e)) => {
+                    return Err(e).with_context(|| format!("Failed to lock {}", path.display()))
+                }
+            }
+
+            if is_at(&file, &path) {
+                return Ok(PartLock { file, path });
+            }
+        }
+    }
+}
+
+/// Whether `file` is still the file at `path`, and not one that was removed from there
+#[cfg(unix)]
+fn is_at(file: &File, path: &Path) -> bool {
+    use std::os::unix::fs::MetadataExt;
+
+    match (file.metadata(), std::fs::metadata(path)) {
+        (Ok(file), Ok(path)) => file.dev() == path.dev() && file.ino() == path.ino(),
+        _ => false,
+    }
+}
+
+#[cfg(not(unix))]
+fn is_at(_file: &File, _path: &Path) -> bool {
+    true
+}
+
+impl Drop for PartLock {
+    fn drop(&mut self) {
+        // Removed before the file is closed and unlocked, so that no one locks it once it's gone
+        if cfg!(unix) {
+            let _ = std::fs::remove_file(&self.path);
+        }
+
+        let _ = self.file.unlock();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::sync::Arc;
+
+    use super::*;
+
+    #[test]
+    fn one_holder_at_a_time() {
+        let part =
+            std::env::temp_dir().join(format!("audible-dl-lock-{}.part", std::process::id()));
+        let holders = Arc::new(AtomicUsize::new(0));
+        let acquired = Arc::new(AtomicUsize::new(0));
+
+        let threads = (0..8)
+            .map(|_| {
+                let (part, holders, acquired) = (part.clone(), holders.clone(), acquired.clone());
+
+                std::thread::spawn(move || {
+                    for _ in 0..2000 {
+                        let Ok(lock) = PartLock::acquire(&part) else {
+                            continue;
+                        };
+
+                        assert_eq!(holders.fetch_add(1, Ordering::SeqCst), 0);
+                        acquired.fetch_add(1, Ordering::SeqCst);
+                        std::thread::yield_now();
+                        holders.fetch_sub(1, Ordering::SeqCst);
+
+                        drop(lock);
+                    }
+                })
+            })
+            .collect::<Vec<_>>();
+
+        for thread in threads {
+            thread.join().unwrap();
+        }
+
+        assert!(acquired.load(Ordering::SeqCst) > 0);
+
+        if cfg!(unix) {
+            assert!(!Path::new(&format!("{}.lock", part.display())).exists());
+        }
+    }
+}
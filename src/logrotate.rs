@@ -0,0 +1,166 @@
+//! `--log-file`, rotating the log of `watch` so that a long running service doesn't fill the disk.
+//!
+//! The log is written by systemd, or whatever else runs `watch` with its output appended to the
+//! file, so it can't be moved away. It's copied to `<file>.<timestamp>.gz` and truncated instead,
+//! which the writer doesn't notice since it appends. Lines written in between the two are lost.
+
+use std::fs::{File, OpenOptions};
+use std::io::BufReader;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+
+use anyhow::{Context, Result};
+use chrono::{DateTime, Local, NaiveDate};
+use flate2::write::GzEncoder;
+use flate2::Compression;
+
+use crate::style;
+
+/// How often the size of the log is checked
+const INTERVAL: Duration = Duration::from_secs(60);
+
+/// Options for rotating the log of `watch`
+#[derive(clap::Args, Clone, Debug)]
+pub struct LogArgs {
+    /// Log that the output of `watch` is appended to, to rotate when it grows too big and every
+    /// day. Set by `service install --log-file`
+    #[arg(long, env = "AUDIBLE_DL_LOG_FILE", value_name = "FILE")]
+    log_file: Option<PathBuf>,
+
+    /// Size in MiB to rotate the log at
+    #[arg(
+        long,
+        env = "AUDIBLE_DL_LOG_MAX_SIZE",
+        value_name = "MIB",
+        default_value_t = 10
+    )]
+    log_max_size: u64,
+
+    /// Number of rotated, compressed logs to keep
+    #[arg(
+        long,
+        env = "AUDIBLE_DL_LOG_RETENTION",
+        value_name = "COUNT",
+        default_value_t = 7
+    )]
+    log_retention: usize,
+}
+
+/// Keep rotating the log of `args`, if one is given
+pub async fn watch(args: LogArgs) {
+    let Some(path) = args.log_file else {
+        return;
+    };
+
+    // The day the lines in the log are from, taken from the last one when starting
+    let mut day = match tokio::fs::metadata(&path).await.and_then(|m| m.modified()) {
+        Ok(modified) => DateTime::<Local>::from(modified).date_naive(),
+        Err(_) => today(),
+    };
+
+    loop {
+        if let Ok(metadata) = tokio::fs::metadata(&path).await {
+            let len = metadata.len();
+
+            if len > 0 && (len >= args.log_max_size * 1024 * 1024 || day != today()) {
+                let (path, keep) = (path.clone(), args.log_retention);
+                let result = tokio::task::spawn_blocking(move || rotate(&path, keep)).await;
+
+                if let Ok(Err(e)) = result {
+                    eprintln!(
+                        "{} Failed to rotate the log: {:#}",
+                        style::warning("Warning:"),
+                        e
+                    );
+                }
+            }
+        }
+
+        day = today();
+        tokio::time::sleep(INTERVAL).await;
+    }
+}
+
+fn today() -> NaiveDate {
+    Local::now().date_naive()
+}
+
+/// Compress the log at `path` next to it and empty it, keeping the last `keep` rotated logs
+fn rotate(path: &Path, keep: usize) -> Result<()> {
+    let mut name = path
+        .file_name()
+        .context("The log has no file name")?
+        .to_owned();
+    name.push(Local::now().format(".%Y%m%d-%H%M%S.gz").to_string());
+    let rotated = path.with_file_name(name);
+    let temp = rotated.with_extension("gz.tmp");
+
+    let mut log = BufReader::new(File::open(path)?);
+    let mut encoder = GzEncoder::new(File::create(&temp)?, Compression::default());
+    std::io::copy(&mut log, &mut encoder)?;
+    encoder.finish()?.sync_all()?;
+
+    std::fs::rename(&temp, &rotated)?;
+    OpenOptions::new().write(true).open(path)?.set_len(0)?;
+
+    let dir = match path.parent() {
+        Some(dir) if !dir.as_os_str().is_empty() => dir,
+        _ => Path::new("."),
+    };
+    let prefix = format!(
+        "{}.",
+        path.file_name().unwrap_or_default().to_string_lossy()
+    );
+
+    let names = std::fs::read_dir(dir)?
+        .filter_map(|entry| entry.ok()?.file_name().into_string().ok())
+        .collect::<Vec<_>>();
+
+    for name in expired(&prefix, names, keep) {
+        std::fs::remove_file(dir.join(&name))
+            .with_context(|| format!("Failed to remove {}", name))?;
+    }
+
+    Ok(())
+}
+
+/// The rotated logs in `names` beyond the newest `keep`
+fn expired(prefix: &str, names: Vec<String>, keep: usize) -> Vec<String> {
+    let mut rotated = names
+        .into_iter()
+        .filter(|name| name.starts_with(prefix) && name.ends_with(".gz"))
+        .collect::<Vec<_>>();
+
+    // The timestamps sort by name, newest last
+    rotated.sort();
+    rotated.truncate(rotated.len().saturating_sub(keep));
+    rotated
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn expires_oldest_logs() {
+        let names = [
+            "audible-dl.log",
+            "audible-dl.log.20260103-000000.gz",
+            "audible-dl.log.20260101-000000.gz",
+            "audible-dl.log.20260102-120000.gz",
+            "audible-dl.log.20260104-000000.gz.tmp",
+            "other.log.20250101-000000.gz",
+        ]
+        .map(str::to_owned)
+        .to_vec();
+
+        assert_eq!(
+            expired("audible-dl.log.", names.clone(), 1),
+            [
+                "audible-dl.log.20260101-000000.gz",
+                "audible-dl.log.20260102-120000.gz"
+            ]
+        );
+        assert!(expired("audible-dl.log.", names, 3).is_empty());
+    }
+}
@@ -1,191 +1,1065 @@
-use std::str::FromStr;
+use std::path::{Path, PathBuf};
+use std::process::ExitCode;
+use std::time::{Duration, Instant};
 
-use anyhow::{anyhow, Result};
-use clap::Parser;
-use indicatif::{ProgressBar, ProgressStyle};
-use reqwest::StatusCode;
-use tokio::io::AsyncWriteExt;
+use anyhow::{anyhow, bail, Context, Result};
+use audible_dl_core::rangedl::{self, Strategy};
+use audible_dl_core::sidecar::Sidecar;
+use audible_dl_core::Frontend;
+use clap::{Parser, Subcommand};
+use indicatif::{HumanBytes, ProgressBar, ProgressDrawTarget};
 
-fn style(s: &'static str) -> ProgressStyle {
-    ProgressStyle::with_template(s)
-        .unwrap()
-        .progress_chars("#>-")
-}
+use crate::api::ApiArgs;
+use crate::conflict::Resolution;
+use crate::entitlement::Entitlements;
+use crate::plan::Plan;
+use crate::report::Report;
+use crate::webhook::Notifier;
+
+mod activation;
+mod api;
+mod audio;
+mod backup;
+mod budget;
+mod cache;
+mod clip;
+mod config;
+mod conflict;
+mod convert;
+mod db;
+mod dns;
+mod encrypt;
+mod entitlement;
+mod explain;
+mod export;
+mod filter;
+mod health;
+mod http;
+mod info;
+mod init;
+mod instance;
+mod issues;
+mod library;
+mod lock;
+mod logrotate;
+mod mqtt;
+mod paths;
+mod plan;
+mod preorder;
+mod probe;
+mod progress;
+mod quality;
+mod rclone;
+mod reconcile;
+mod repair;
+mod report;
+mod schema;
+mod selftest;
+mod service;
+mod speedtest;
+mod stats;
+mod status;
+mod style;
+mod tags;
+mod unlisted;
+mod update;
+mod watch;
+mod web;
+mod webhook;
+
+/// How long `--aggressive-resume` waits for a response, or for more data, before reconnecting
+const AGGRESSIVE_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// How long `--fallback-quality` waits for more data before counting a stall as a failure
+const FALLBACK_STALL_TIMEOUT: Duration = Duration::from_secs(60);
 
-/// Simple program to greet a person
+/// Download Audible books on slow or unstable internet connections
 #[derive(Parser, Debug)]
-#[command(author, version, about, long_about = None)]
-struct Args {
-    /// Audible customer id
-    #[arg(long)]
-    customer_id: String,
+#[command(
+    author,
+    version,
+    about,
+    long_about = None,
+    subcommand_negates_reqs = true
+)]
+struct Cli {
+    #[command(subcommand)]
+    command: Option<Command>,
 
-    /// SKU of the book to download
-    sku: String,
+    #[command(flatten)]
+    download: DownloadArgs,
 
-    /// Output file
-    #[arg(short, long)]
-    output: Option<String>,
+    #[command(flatten)]
+    http: http::HttpArgs,
+
+    #[command(flatten)]
+    mqtt: mqtt::MqttArgs,
+
+    /// When to color the output, `auto` colors it on terminals unless `NO_COLOR` is set
+    #[arg(
+        long,
+        env = "AUDIBLE_DL_COLOR",
+        value_enum,
+        default_value_t = style::Color::Auto,
+        global = true
+    )]
+    color: style::Color,
+
+    /// Threads to run on. With more than one, the servers of `watch` keep answering while a
+    /// book is verified or hashed.
+    #[arg(
+        long,
+        env = "AUDIBLE_DL_THREADS",
+        default_value_t = 1,
+        value_parser = clap::value_parser!(u16).range(1..),
+        global = true
+    )]
+    threads: u16,
+
+    /// Keep nothing on disk but the books: refreshed tokens aren't saved to the auth file, and
+    /// the state database and the reports of batches only live in memory, e.g. on a shared
+    /// machine
+    #[arg(
+        long,
+        alias = "no-write-credentials",
+        env = "AUDIBLE_DL_EPHEMERAL",
+        global = true
+    )]
+    ephemeral: bool,
+
+    /// Directory to keep the config file, the local tags and other files of audible-dl in,
+    /// instead of the usual places of the platform
+    #[arg(
+        id = "config_dir",
+        long = "config-dir",
+        env = "AUDIBLE_DL_CONFIG_DIR",
+        value_name = "DIR",
+        global = true
+    )]
+    // Picked out by `paths::pick_config_dir`, before the command line is parsed
+    _config_dir: Option<PathBuf>,
+}
+
+#[derive(Subcommand, Debug)]
+enum Command {
+    /// Download a single book (the default when no command is given)
+    Download(DownloadArgs),
+
+    /// Ask for your customer id, auth file and other options, and save them in the config file
+    Init,
+
+    /// Watch a directory for `*.sku` files and download the books they list
+    Watch(watch::WatchArgs),
+
+    /// List the titles in your library
+    Library(library::LibraryArgs),
+
+    /// List the collections in your library, by name and description
+    Collections(library::CollectionsArgs),
+
+    /// Tag a title or write a note about it, kept on this machine, e.g. `--add favorite`
+    TagLocal(tags::TagLocalArgs),
+
+    /// Show catalog details of a title
+    Info(info::InfoArgs),
+
+    /// Print a signed download URL for a title, for use with `download --url`
+    License(info::LicenseArgs),
+
+    /// List the issues of a periodical, e.g. a podcast or newspaper of a subscription
+    Issues(issues::IssuesArgs),
+
+    /// Export statistics of your account
+    Stats(stats::StatsArgs),
+
+    /// Check for, and install, a newer release of audible-dl
+    SelfUpdate(update::SelfUpdateArgs),
+
+    /// Decrypt a downloaded AAX or AAXC file into an M4B file
+    Convert(convert::ConvertArgs),
+
+    /// Print the activation checksum stored in an AAX file
+    Checksum(convert::ChecksumArgs),
+
+    /// Save a short MP3 sample of a converted book, e.g. to recommend it
+    Clip(clip::ClipArgs),
+
+    /// Show the length, bitrate, sample rate and chapters of a downloaded or converted book
+    Probe(probe::ProbeArgs),
+
+    /// Measure the latency and throughput to the CDS, and whether more connections would help
+    Speedtest(speedtest::SpeedtestArgs),
+
+    /// Remove HTML or XML error pages that were written into partial downloads
+    Repair(repair::RepairArgs),
+
+    /// List downloaded books that are no longer in your library, e.g. returned ones
+    Unlisted(unlisted::UnlistedArgs),
+
+    /// Find downloaded books that were moved or renamed, so that batches don't download them again
+    Reconcile(reconcile::ReconcileArgs),
+
+    /// Count the downloaded and converted books in a directory, and find the ones kept in both
+    /// formats
+    Status(status::StatusArgs),
+
+    /// Print the JSON Schema of the `--format json` output of a command
+    Schema(schema::SchemaArgs),
+
+    /// Check this build by downloading, resuming, verifying and converting a synthetic book from
+    /// a local fixture server
+    Selftest(selftest::SelftestArgs),
+
+    /// Run the watch folder as a systemd user service
+    Service(service::ServiceArgs),
+
+    /// Back up the config and the history of a watch folder, e.g. to move to another machine
+    Backup(backup::BackupArgs),
+
+    /// Export or vacuum the database of partial downloads, the watch queue and its history
+    Db(db::DbArgs),
+}
+
+/// Options shared by all commands that download books
+#[derive(clap::Args, Debug)]
+pub struct DownloadOptions {
+    /// Directory to keep the partial download in, defaults to next to the output file
+    #[arg(long, env = "AUDIBLE_DL_PART_DIR")]
+    part_dir: Option<PathBuf>,
+
+    /// Also keep the size, ETag and codec of a partial download in a `.json` file next to it,
+    /// besides the state database
+    #[arg(long, env = "AUDIBLE_DL_SIDECAR_FILES")]
+    sidecar_files: bool,
+
+    /// Check that the partial download is a well formed MP4 file every N MiB, 0 to disable
+    #[arg(
+        long,
+        env = "AUDIBLE_DL_VERIFY_EVERY",
+        default_value_t = 64,
+        value_name = "MIB"
+    )]
+    verify_every: u64,
+
+    /// Don't ask, resume existing output files and start over when the book changed on the server
+    #[arg(short = 'y', long, env = "AUDIBLE_DL_ASSUME_YES")]
+    assume_yes: bool,
+
+    /// Reconnect as soon as the connection stalls, for mobile connections that switch address
+    #[arg(long, env = "AUDIBLE_DL_AGGRESSIVE_RESUME")]
+    aggressive_resume: bool,
+
+    /// How to show the progress, `json` prints the transfer rate every second as JSON lines on
+    /// stdout instead of drawing a progress bar
+    #[arg(long, env = "AUDIBLE_DL_PROGRESS", value_enum, default_value_t = Progress::Bar)]
+    progress: Progress,
+
+    /// How to request the data, `pipelined` keeps several requests for parts of the book in
+    /// flight at once, which is faster when a single connection is throttled
+    #[arg(long, env = "AUDIBLE_DL_STRATEGY", value_enum, default_value_t = Strategy::Single)]
+    strategy: Strategy,
+
+    /// Download at a steady rate of this many KiB per second, e.g. to stay below the radar of
+    /// traffic shaping over many hours
+    #[arg(
+        long,
+        env = "AUDIBLE_DL_TRICKLE",
+        value_name = "KIB_PER_SEC",
+        value_parser = clap::value_parser!(u64).range(1..),
+        conflicts_with = "strategy"
+    )]
+    trickle: Option<u64>,
+
+    /// Compute the SHA-256 of the book while downloading it, and save it as `<output>.sha256`
+    #[arg(long, env = "AUDIBLE_DL_CHECKSUM_ON_THE_FLY")]
+    checksum_on_the_fly: bool,
+
+    /// Collect writes to the partial download in a buffer of this many KiB, larger buffers are
+    /// faster on SMB and NFS mounts, 0 to write every chunk as it comes in
+    #[arg(
+        long,
+        env = "AUDIBLE_DL_WRITE_BUFFER",
+        value_name = "KIB",
+        default_value_t = 1024
+    )]
+    write_buffer: usize,
+
+    /// Fall back to the next lower quality after this many failed attempts in a row, e.g. 403
+    /// Forbidden or stalls, when downloading by SKU
+    #[arg(
+        long,
+        env = "AUDIBLE_DL_FALLBACK_QUALITY",
+        value_name = "FAILURES",
+        value_parser = clap::value_parser!(u32).range(1..)
+    )]
+    fallback_quality: Option<u32>,
+
+    /// Before downloading a batch, e.g. with `--from-audible-csv` or a `.sku` file, ask for the
+    /// size of every book and estimate how long the batch takes
+    #[arg(long, env = "AUDIBLE_DL_ESTIMATE")]
+    estimate: bool,
+
+    /// Stop after this long, e.g. `6h`, counted from the start of a batch like
+    /// `--from-audible-csv` or a `.sku` file. The titles that weren't downloaded are written to a
+    /// `-remaining.sku` file.
+    #[arg(
+        long,
+        env = "AUDIBLE_DL_MAX_TOTAL_TIME",
+        value_name = "DURATION",
+        value_parser = budget::parse_duration
+    )]
+    max_total_time: Option<Duration>,
+
+    /// Ask the audible-dl at this URL, running `watch --serve-cache`, for books before
+    /// downloading them from Audible, e.g. `http://nas.local:8081`
+    #[arg(long, env = "AUDIBLE_DL_CACHE", value_name = "URL")]
+    cache: Option<reqwest::Url>,
+
+    /// JSON file of webhooks to call when a download starts, passes 25, 50 and 75%, is retried,
+    /// fails or completes
+    #[arg(long, env = "AUDIBLE_DL_WEBHOOKS", value_name = "FILE", value_parser = webhook::parse)]
+    webhooks: Option<webhook::Webhooks>,
+
+    /// Encrypt downloaded books with age to this recipient, e.g. `age:age1...`, removing the
+    /// unencrypted file once it's verified
+    #[arg(
+        long,
+        env = "AUDIBLE_DL_ENCRYPT_OUTPUT",
+        value_name = "age:RECIPIENT",
+        value_parser = encrypt::parse
+    )]
+    encrypt_output: Option<encrypt::Encryption>,
+
+    /// Move downloaded books to this rclone remote, e.g. `gdrive:Audiobooks`, and record where
+    /// they went in the report of the batch
+    #[arg(
+        long,
+        env = "AUDIBLE_DL_RCLONE_REMOTE",
+        value_name = "REMOTE:PATH",
+        value_parser = rclone::parse
+    )]
+    rclone_remote: Option<String>,
 
     /// Verbose output
-    #[arg(short, long)]
+    #[arg(short, long, env = "AUDIBLE_DL_VERBOSE")]
     verbose: bool,
 }
 
-struct ContentRange {
-    start: u64,
-    end: u64,
-    total: u64,
+impl DownloadOptions {
+    /// Options for [`rangedl::transfer`], taking `--aggressive-resume` and the
+    /// `--timeout-profile` into account
+    fn transfer_options(&self) -> rangedl::Options {
+        let profile = http::timeout_profile();
+        let aggressive = self.aggressive_resume.then_some(AGGRESSIVE_TIMEOUT);
+        // A stall has to end for the fallback to count it
+        let fallback = self.fallback_quality.map(|_| FALLBACK_STALL_TIMEOUT);
+
+        rangedl::Options {
+            verify_every: self.verify_every * 1024 * 1024,
+            strategy: self.strategy,
+            trickle: self.trickle.map(|rate| rate * 1024),
+            checksum: self.checksum_on_the_fly,
+            write_buffer: self.write_buffer * 1024,
+            response_timeout: Some(
+                http::first_byte_timeout()
+                    .or(aggressive)
+                    .unwrap_or(http::DEFAULT_FIRST_BYTE_TIMEOUT),
+            ),
+            stall_timeout: Some(
+                http::chunk_timeout()
+                    .or(aggressive)
+                    .or(fallback)
+                    .unwrap_or(http::DEFAULT_CHUNK_TIMEOUT),
+            ),
+            retry_requests: self.aggressive_resume || profile.is_some(),
+            max_retries: [
+                profile.map(|profile| profile.retries()),
+                self.fallback_quality.map(|failures| failures - 1),
+            ]
+            .into_iter()
+            .flatten()
+            .min(),
+            retry_forbidden: self.fallback_quality.is_some(),
+            new_client: self.aggressive_resume.then_some(http::client as fn() -> _),
+            user_agent: !http::overrides("User-Agent"),
+            // Set by `download`, for the batch it's part of
+            stop_at: None,
+            // Set by `download`, which opens the database
+            sidecars: Default::default(),
+        }
+    }
 }
 
-impl FromStr for ContentRange {
-    type Err = anyhow::Error;
+#[derive(clap::ValueEnum, Clone, Copy, Debug, PartialEq, Eq)]
+enum Progress {
+    Bar,
+    Json,
+}
+
+#[derive(clap::Args, Debug)]
+#[command(mut_group("credentials", |group| group.required(false)))]
+struct DownloadArgs {
+    /// SKU of the book to download
+    #[arg(env = "AUDIBLE_DL_SKU", required_unless_present_any = ["url", "from_audible_csv", "issues_of"])]
+    sku: Option<String>,
 
-    fn from_str(s: &str) -> Result<Self, Self::Err> {
-        let s = s
-            .strip_prefix("bytes ")
-            .ok_or_else(|| anyhow!("Invalid Content-Range header"))?;
+    /// Audible customer id
+    #[arg(long, env = "AUDIBLE_DL_CUSTOMER_ID", required_unless_present = "url")]
+    customer_id: Option<String>,
 
-        let parts = s
-            .split(['-', '/'])
-            .map(|s| s.parse::<u64>())
-            .collect::<Result<Vec<_>, _>>()?;
+    /// Audible user id, for accounts where it differs from the customer id
+    #[arg(long, env = "AUDIBLE_DL_USER_ID")]
+    user_id: Option<String>,
 
-        Ok(Self {
-            start: parts[0],
-            end: parts[1],
-            total: parts[2],
-        })
+    /// Download from this (signed) URL instead of building one from the SKU and customer id
+    #[arg(long, env = "AUDIBLE_DL_URL", conflicts_with = "sku")]
+    url: Option<reqwest::Url>,
+
+    /// Download all titles in a CSV file from Audible's data export, as `<SKU>.aax`
+    #[arg(
+        long,
+        env = "AUDIBLE_DL_FROM_AUDIBLE_CSV",
+        value_name = "FILE",
+        conflicts_with_all = ["sku", "url", "output"]
+    )]
+    from_audible_csv: Option<PathBuf>,
+
+    /// Download the issues of the periodical with this ASIN, as `<SKU>.aax`, needs credentials
+    /// for the API, e.g. `--auth-file`
+    #[arg(
+        long,
+        env = "AUDIBLE_DL_ISSUES_OF",
+        value_name = "ASIN",
+        requires = "credentials",
+        conflicts_with_all = ["sku", "url", "output", "from_audible_csv"]
+    )]
+    issues_of: Option<String>,
+
+    /// Only download the N most recent issues of `--issues-of`
+    #[arg(
+        long,
+        env = "AUDIBLE_DL_LATEST_ISSUES",
+        value_name = "N",
+        requires = "issues_of"
+    )]
+    latest_issues: Option<usize>,
+
+    /// Directory to write a `report-<timestamp>.json` to after `--from-audible-csv` or
+    /// `--issues-of`, defaults to `reports/` next to the CSV file, or in the current directory
+    #[arg(long, env = "AUDIBLE_DL_REPORT_DIR")]
+    report_dir: Option<PathBuf>,
+
+    /// Output file
+    #[arg(short, long, env = "AUDIBLE_DL_OUTPUT")]
+    output: Option<PathBuf>,
+
+    /// Print the request for the book and what each part of it means, without downloading it
+    #[arg(
+        long,
+        env = "AUDIBLE_DL_EXPLAIN_URL",
+        conflicts_with_all = ["from_audible_csv", "issues_of"]
+    )]
+    explain_url: bool,
+
+    #[command(flatten)]
+    options: DownloadOptions,
+
+    /// With `--auth-file` or other credentials, check that the book is in your library before
+    /// downloading it
+    #[command(flatten)]
+    api: Option<ApiArgs>,
+}
+
+/// Path of the partial download for `output`, optionally placed in `part_dir`
+fn part_path(output: &Path, part_dir: Option<&Path>) -> PathBuf {
+    let mut name = output.file_name().unwrap_or_default().to_os_string();
+    name.push(".part");
+
+    match part_dir {
+        Some(dir) => dir.join(name),
+        None => output.with_file_name(name),
     }
 }
 
-async fn update_progress_bar(pb: ProgressBar) {
-    while !pb.is_finished() {
-        pb.tick();
-        tokio::time::sleep(std::time::Duration::from_secs(1)).await;
+/// URL for downloading `sku` from the Audible CDS, the user id defaults to the customer id
+pub fn cds_url(customer_id: &str, user_id: Option<&str>, sku: &str) -> String {
+    format!(
+        "https://cds.audible.com/download?user_id={}&product_id={}&codec={}&awtype=AAX&cust_id={}",
+        user_id.unwrap_or(customer_id),
+        sku,
+        quality::QUALITIES[0],
+        customer_id,
+    )
+}
+
+/// Download every title listed in an export CSV file from Audible
+async fn download_export(
+    client: &reqwest::Client,
+    csv: &Path,
+    args: &DownloadArgs,
+    entitlements: Option<&Entitlements>,
+) -> Result<()> {
+    let contents = tokio::fs::read_to_string(csv)
+        .await
+        .with_context(|| format!("Failed to read {}", csv.display()))?;
+
+    let titles = export::titles(&contents).with_context(|| format!("In {}", csv.display()))?;
+
+    download_batch(client, csv, titles, args, entitlements).await
+}
+
+/// Download the issues of the periodical with `asin`, only the latest ones with
+/// `--latest-issues`
+async fn download_issues(client: &reqwest::Client, asin: &str, args: &DownloadArgs) -> Result<()> {
+    let api = args
+        .api
+        .as_ref()
+        .expect("clap requires credentials with --issues-of")
+        .client(client.clone())?;
+
+    let issues = api::issues::latest(api.issues(asin).await?, args.latest_issues);
+
+    let titles = issues
+        .into_iter()
+        .map(|issue| match issue.download_sku() {
+            Some(sku) => export::Title::Sku(sku.to_owned()),
+            None => export::Title::AsinOnly(issue.asin),
+        })
+        .collect();
+
+    // The library lists the periodical, not its issues, so there's nothing to check them against
+    download_batch(client, Path::new(asin), titles, args, None).await
+}
+
+/// Download `titles` as `<SKU>.aax`, reporting them as a batch read from `source`
+async fn download_batch(
+    client: &reqwest::Client,
+    source: &Path,
+    titles: Vec<export::Title>,
+    args: &DownloadArgs,
+    entitlements: Option<&Entitlements>,
+) -> Result<()> {
+    let customer_id = args
+        .customer_id
+        .as_deref()
+        .expect("clap requires a customer id without --url");
+
+    let mut report = Report::new(source);
+
+    let url = |sku: &str| cds_url(customer_id, args.user_id.as_deref(), sku);
+    let output = |sku: &str| PathBuf::from(format!("{}.aax", sku));
+    let owned = |sku: &str| entitlements.is_none_or(|entitlements| entitlements.check(sku).is_ok());
+
+    let mut plan = None;
+
+    if args.options.estimate {
+        let batch = titles
+            .iter()
+            .filter_map(|title| match title {
+                export::Title::Sku(sku) if owned(sku) => Some((url(sku), output(sku))),
+                _ => None,
+            })
+            .collect::<Vec<_>>();
+
+        plan = Some(Plan::probe(client, &batch, &args.options).await);
+    }
+
+    let mut planned = 0;
+    let stop_at = budget::stop_at(args.options.max_total_time);
+    let mut remaining = Vec::new();
+
+    for title in titles {
+        match title {
+            export::Title::Sku(sku) => {
+                if let Some(Err(e)) = entitlements.map(|entitlements| entitlements.check(&sku)) {
+                    report.push(&sku, &Err(e));
+                    continue;
+                }
+
+                if let Some(path) = db::downloaded(&sku).filter(|path| *path != output(&sku)) {
+                    eprintln!("Skipping download, {} is in {}", sku, path.display());
+                    report.push(&sku, &Ok(Outcome::Skipped));
+                    continue;
+                }
+
+                if !remaining.is_empty() || budget::is_up(stop_at) {
+                    remaining.push(sku);
+                    continue;
+                }
+
+                let result = download(
+                    client,
+                    &url(&sku),
+                    &output(&sku),
+                    true,
+                    &args.options,
+                    stop_at,
+                )
+                .await;
+
+                if result.as_ref().is_err_and(budget::ran_out) {
+                    remaining.push(sku);
+                    continue;
+                }
+
+                if let Some(plan) = &mut plan {
+                    plan.finish(planned, matches!(result, Ok(Outcome::Downloaded { .. })));
+                    planned += 1;
+                }
+
+                report.push(&sku, &result);
+            }
+            export::Title::AsinOnly(asin) => {
+                let result = Err(anyhow!(
+                    "There is no SKU for this title, look it up with `audible-dl info {}`",
+                    asin
+                ));
+                report.push(&asin, &result);
+            }
+        }
+    }
+
+    let dir = source.parent().unwrap_or(Path::new("."));
+    let report_dir = match &args.report_dir {
+        Some(report_dir) => report_dir.clone(),
+        None => dir.join("reports"),
+    };
+
+    report.print_summary();
+    let path = report.write(&report_dir).await?;
+
+    if let Some(db) = db::shared() {
+        db.record(&report)?;
+    }
+
+    if !remaining.is_empty() {
+        let path = budget::write_remaining(dir, source, &remaining).await?;
+        bail!(
+            "The time was up with {} titles left, they're listed in {}",
+            remaining.len(),
+            path.display()
+        );
     }
+
+    if report.has_failures() {
+        match path {
+            Some(path) => bail!("Not all books were downloaded, see {}", path.display()),
+            None => bail!("Not all books were downloaded"),
+        }
+    }
+
+    Ok(())
 }
 
-#[tokio::main(flavor = "current_thread")]
-async fn main() -> Result<()> {
-    let args = Args::parse();
+/// What [`download`] did
+#[derive(Debug)]
+pub enum Outcome {
+    /// The book was downloaded and saved to `path`, and moved to `remote` by `--rclone-remote`
+    Downloaded {
+        path: PathBuf,
+        remote: Option<String>,
+    },
+    /// The output file already existed and was left alone
+    Skipped,
+}
 
-    let url = format!(
-        "https://cds.audible.com/download?user_id={}&product_id={}&codec=LC_128_44100_Stereo&awtype=AAX&cust_id={}",
-        args.customer_id,
-        args.sku,
-        args.customer_id,
-    );
+/// Download `url` to `output`, resuming any earlier partial download.
+///
+/// With `detect_extension` the extension of `output` is replaced to match the delivered format,
+/// otherwise a warning is printed if they don't match. The download gives up at `stop_at`, when
+/// the time of its batch is up.
+pub async fn download(
+    client: &reqwest::Client,
+    url: &str,
+    output: &Path,
+    detect_extension: bool,
+    options: &DownloadOptions,
+    stop_at: Option<Instant>,
+) -> Result<Outcome> {
+    if options.encrypt_output.is_some() {
+        let encrypted = encrypt::encrypted_path(output);
+
+        if encrypted.exists() {
+            eprintln!("Skipping download, {} already exists", encrypted.display());
+            return Ok(Outcome::Skipped);
+        }
+    }
+
+    let renamed;
+    let output = if output.exists() {
+        match conflict::resolve(output, options.assume_yes)? {
+            Resolution::Resume => output,
+            Resolution::Skip => {
+                eprintln!("Skipping download, {} already exists", output.display());
+                return Ok(Outcome::Skipped);
+            }
+            Resolution::Overwrite => {
+                tokio::fs::remove_file(output).await?;
+                output
+            }
+            Resolution::Rename(path) => {
+                renamed = path;
+                &renamed
+            }
+        }
+    } else {
+        output
+    };
 
-    let output = args.output.unwrap_or_else(|| format!("{}.aax", args.sku));
+    let part = part_path(output, options.part_dir.as_deref());
+    let _lock = lock::PartLock::acquire(&part)?;
 
-    let style_downloading =
-        style("[{elapsed_precise}] [{bar:35.cyan/blue}] {bytes}/{total_bytes} ({eta})");
-    let style_init = style("[{elapsed_precise}] [{bar:35.cyan/blue}] {msg}");
+    // Over Tor each title gets its own circuit, so that the downloads can't be linked
+    let isolated;
+    let client = if http::tor() {
+        http::new_circuit();
+        isolated = http::client()?;
+        &isolated
+    } else {
+        client
+    };
 
     // Initialize progress bar
     let pb = ProgressBar::new_spinner();
     pb.set_message("Initiating download...");
-    pb.set_style(style_init.clone());
-    tokio::spawn(update_progress_bar(pb.clone()));
+    progress::set_style(&pb, progress::MESSAGE);
+    tokio::spawn(progress::tick(pb.clone()));
 
-    // Create reqwest client
-    let client = reqwest::Client::builder().build()?;
-
-    loop {
-        // Get file size of existing file
-        let start = match tokio::fs::metadata(&output).await {
-            Ok(metadata) => metadata.len(),
-            // Ignore if file doesn't exist
-            Err(e) if e.kind() == std::io::ErrorKind::NotFound => 0,
-            // Propagate all other errors
-            Err(e) => return Err(e.into()),
-        };
-
-        if args.verbose {
-            pb.println(format!("Downloading from offset {}", start));
+    let json = options.progress == Progress::Json;
+
+    if json {
+        pb.set_draw_target(ProgressDrawTarget::hidden());
+    }
+
+    let bar = progress::Bar {
+        pb: pb.clone(),
+        assume_yes: options.assume_yes,
+        verbose: options.verbose,
+        json,
+    };
+    let frontend = Notifier::new(bar, options.webhooks.as_ref(), url, output);
+    frontend.started().await;
+
+    let transfer = rangedl::Options {
+        stop_at,
+        sidecars: db::sidecars(options.sidecar_files),
+        ..options.transfer_options()
+    };
+
+    // Resume in the quality the partial download was started in
+    let requested = quality::codec(url);
+    let mut url = match transfer.sidecars.load(&part).await?.codec {
+        Some(codec) if requested.is_some() => quality::with_codec(url, &codec),
+        _ => url.to_owned(),
+    };
+
+    // Books already downloaded on another machine come from the cache, unless a download from
+    // Audible was started before
+    let cached = match &options.cache {
+        Some(cache) if !part.exists() => match cache::lookup(client, cache, &url).await {
+            Ok(cached) => cached,
+            Err(e) => {
+                frontend.warn(&format!("Failed to ask the cache: {:#}", e));
+                None
+            }
+        },
+        _ => None,
+    };
+
+    let mut from_cache = None;
+
+    if let Some(cached) = cached {
+        frontend.log(&format!("Downloading from the cache: {}", cached));
+
+        let result = rangedl::transfer(
+            client,
+            &cached,
+            output,
+            &part,
+            detect_extension,
+            &transfer,
+            &frontend,
+        )
+        .await;
+
+        match result {
+            Ok(completed) => from_cache = Some(completed),
+            Err(e) if rangedl::stopped() || budget::ran_out(&e) => return Err(e),
+            Err(e) => {
+                frontend.warn(&format!("{:#}, downloading from Audible instead", e));
+
+                match tokio::fs::remove_file(&part).await {
+                    Err(e) if e.kind() != std::io::ErrorKind::NotFound => return Err(e.into()),
+                    _ => {}
+                }
+
+                transfer.sidecars.remove(&part).await?;
+            }
         }
+    }
 
-        // Send the request with the range header
-        let mut res = client
-            .get(&url)
-            .header("Range", format!("bytes={}-", start))
-            .header(
-                "User-Agent",
-                "Audible ADM 6.6.0.19;Windows Vista  Build 9200",
+    let result = match from_cache {
+        Some(completed) => Ok(completed),
+        None => loop {
+            let result = rangedl::transfer(
+                client,
+                &url,
+                output,
+                &part,
+                detect_extension,
+                &transfer,
+                &frontend,
             )
-            .send()
-            .await?;
-
-        match res.status() {
-            StatusCode::PARTIAL_CONTENT => {}
-            StatusCode::RANGE_NOT_SATISFIABLE => {
-                pb.finish();
-                eprintln!("Download complete: {}", output);
-                return Ok(());
+            .await;
+
+            let lower = quality::codec(&url).and_then(|codec| quality::lower(&codec));
+
+            match (result, lower) {
+                (Err(e), Some(lower))
+                    if options.fallback_quality.is_some()
+                        && e.downcast_ref::<rangedl::OutOfRetries>().is_some() =>
+                {
+                    frontend.warn(&format!("{:#}, falling back to {}", e, lower));
+
+                    match tokio::fs::remove_file(&part).await {
+                        Err(e) if e.kind() != std::io::ErrorKind::NotFound => return Err(e.into()),
+                        _ => {}
+                    }
+
+                    let sidecar = Sidecar {
+                        total: None,
+                        etag: None,
+                        codec: Some(lower.to_owned()),
+                    };
+                    transfer.sidecars.save(&sidecar, &part).await?;
+
+                    url = quality::with_codec(&url, lower);
+                }
+                (result, _) => break result,
             }
-            code => return Err(anyhow!("Invalid status code: {code}")),
+        },
+    };
+
+    // Encrypted only once verified, so that verification and the checksum are of the plaintext
+    let result = match (result, &options.encrypt_output) {
+        (Ok(completed), Some(encryption)) => {
+            progress::set_style(&pb, progress::MESSAGE);
+            pb.set_message("Encrypting...");
+
+            encrypt::encrypt(&completed.path, encryption)
+                .await
+                .map(|path| (path, completed))
         }
+        (result, _) => result.map(|completed| (completed.path.clone(), completed)),
+    };
 
-        // Parse Content-Range header
-        let content_range: ContentRange = res
-            .headers()
-            .get("Content-Range")
-            .ok_or_else(|| anyhow!("Missing Content-Range header"))?
-            .to_str()?
-            .parse()?;
+    // Moved last, once the book is in its final form
+    let result = match (result, &options.rclone_remote) {
+        (Ok((path, completed)), Some(remote)) => {
+            progress::set_style(&pb, progress::MESSAGE);
+            pb.set_message("Uploading...");
 
-        if content_range.start != start {
-            return Err(anyhow!("Server returned invalid start offset"));
+            rclone::upload(&path, remote)
+                .await
+                .map(|remote| (path, Some(remote), completed))
         }
+        (result, _) => result.map(|(path, completed)| (path, None, completed)),
+    };
+
+    let (path, remote, completed) = match result {
+        Ok(result) => result,
+        Err(e) => {
+            // Stop the ticker task
+            pb.abandon();
+
+            // Stopping for `--takeover` isn't a failure, the next watcher carries on
+            if !rangedl::stopped() {
+                frontend.finished(Err(&e)).await;
+            }
 
-        if content_range.end != content_range.total - 1 {
-            return Err(anyhow!("Server returned invalid end offset"));
+            return Err(e);
         }
+    };
 
-        pb.set_style(style_downloading.clone());
-        pb.set_length(content_range.total);
-        pb.set_position(start);
-        pb.reset_eta();
-
-        // Open file for appending
-        let mut file = tokio::fs::OpenOptions::new()
-            .create(true)
-            .append(true)
-            .open(&output)
-            .await?;
-
-        // Download data
-        loop {
-            match res.chunk().await {
-                Ok(Some(chunk)) => {
-                    file.write_all(&chunk).await?;
-                    pb.inc(chunk.len() as u64);
-                }
-                // The entire file has been downloaded
-                Ok(None) => {
-                    pb.finish();
-                    eprintln!("Download complete: {}", output);
-                    return Ok(());
-                }
-                // Retry on error
-                Err(e) => {
-                    if args.verbose {
-                        pb.println(format!("Error: {}", e));
-                    }
+    frontend.finished(Ok(&path)).await;
 
-                    pb.set_message("Restarting download...");
-                    pb.set_style(style_init.clone());
+    pb.finish();
+    eprintln!(
+        "{} {}",
+        style::success("Download complete:"),
+        path.display()
+    );
 
-                    // Close and flush file
-                    file.shutdown().await?;
+    if let Some(remote) = &remote {
+        eprintln!("Moved to: {}", remote);
+    }
 
-                    // Wait a bit before retrying
-                    tokio::time::sleep(std::time::Duration::from_secs(1)).await;
+    let obtained = quality::codec(&url);
 
-                    break;
-                }
-            }
+    if obtained != requested {
+        eprintln!(
+            "Quality: {} instead of {}",
+            obtained.unwrap_or_default(),
+            requested.unwrap_or_default()
+        );
+    }
+
+    if json {
+        let rates = completed.rates;
+        let line = serde_json::json!({
+            "event": "complete",
+            "path": path,
+            "remote": remote,
+            "min_rate": rates.map(|rates| rates.min),
+            "avg_rate": rates.map(|rates| rates.avg),
+            "max_rate": rates.map(|rates| rates.max),
+        });
+        println!("{}", line);
+    }
+
+    if let Some(rates) = completed.rates {
+        eprintln!(
+            "Speed: min {}/s, avg {}/s, max {}/s",
+            HumanBytes(rates.min),
+            HumanBytes(rates.avg),
+            HumanBytes(rates.max)
+        );
+    }
+
+    if let Some(sha256) = completed.sha256 {
+        eprintln!("SHA-256: {}", sha256);
+    }
+
+    Ok(Outcome::Downloaded { path, remote })
+}
+
+fn main() -> ExitCode {
+    match start() {
+        Ok(()) => ExitCode::SUCCESS,
+        Err(e) => {
+            eprintln!("{} {:?}", style::error("Error:"), e);
+            ExitCode::FAILURE
         }
     }
 }
+
+/// Parse the command line and run the command, on as many threads as `--threads` asks for
+fn start() -> Result<()> {
+    paths::pick_config_dir(std::env::args_os());
+    config::load()?;
+
+    let cli = Cli::parse();
+
+    let mut builder = match cli.threads {
+        1 => tokio::runtime::Builder::new_current_thread(),
+        threads => {
+            let mut builder = tokio::runtime::Builder::new_multi_thread();
+            builder.worker_threads(threads.into());
+            builder
+        }
+    };
+
+    builder.enable_all().build()?.block_on(run(cli))
+}
+
+async fn run(cli: Cli) -> Result<()> {
+    style::configure(cli.color);
+    http::configure(cli.http);
+    mqtt::configure(cli.mqtt);
+
+    if cli.ephemeral {
+        paths::set_ephemeral();
+    }
+
+    // Create reqwest client
+    let client = http::client()?;
+
+    match cli.command {
+        Some(Command::Download(args)) => run_download(&client, args).await,
+        Some(Command::Init) => init::run(&client).await,
+        Some(Command::Watch(args)) => watch::run(&client, args).await,
+        Some(Command::Library(args)) => library::run(&client, args).await,
+        Some(Command::Collections(args)) => library::collections(&client, args).await,
+        Some(Command::TagLocal(args)) => tags::run(args),
+        Some(Command::Info(args)) => info::run(&client, args).await,
+        Some(Command::License(args)) => info::license(&client, args).await,
+        Some(Command::Issues(args)) => issues::run(&client, args).await,
+        Some(Command::Stats(args)) => stats::run(&client, args).await,
+        Some(Command::SelfUpdate(args)) => update::run(args).await,
+        Some(Command::Service(args)) => service::run(args),
+        Some(Command::Backup(args)) => backup::run(args),
+        Some(Command::Db(args)) => db::run(args),
+        Some(Command::Convert(args)) => convert::run(args).await,
+        Some(Command::Checksum(args)) => convert::checksum(args),
+        Some(Command::Clip(args)) => clip::run(args).await,
+        Some(Command::Probe(args)) => probe::run(&client, args).await,
+        Some(Command::Speedtest(args)) => speedtest::run(&client, args).await,
+        Some(Command::Repair(args)) => repair::run(args),
+        Some(Command::Unlisted(args)) => unlisted::run(&client, args).await,
+        Some(Command::Reconcile(args)) => reconcile::run(args).await,
+        Some(Command::Status(args)) => status::run(args),
+        Some(Command::Schema(args)) => schema::run(args),
+        Some(Command::Selftest(args)) => selftest::run(args).await,
+        None => run_download(&client, cli.download).await,
+    }
+}
+
+async fn run_download(client: &reqwest::Client, args: DownloadArgs) -> Result<()> {
+    if let Some(asin) = &args.issues_of {
+        return download_issues(client, asin, &args).await;
+    }
+
+    let entitlements = match &args.api {
+        Some(api) if args.url.is_none() => Some(Entitlements::load(client, api).await?),
+        _ => None,
+    };
+
+    if let Some(csv) = &args.from_audible_csv {
+        return download_export(client, csv, &args, entitlements.as_ref()).await;
+    }
+
+    if let (Some(entitlements), Some(sku)) = (&entitlements, &args.sku) {
+        entitlements.check(sku)?;
+    }
+
+    let (url, default_name) = match (args.url, args.sku, args.customer_id) {
+        (Some(url), _, _) => {
+            let name = url
+                .path_segments()
+                .and_then(|mut segments| segments.next_back())
+                .filter(|name| !name.is_empty())
+                .map(str::to_owned);
+
+            (url.to_string(), name)
+        }
+        (None, Some(sku), Some(customer_id)) => (
+            cds_url(&customer_id, args.user_id.as_deref(), &sku),
+            Some(format!("{}.aax", sku)),
+        ),
+        _ => unreachable!("clap requires either --url or a SKU and customer id"),
+    };
+
+    let (output, detect_extension) = match (args.output, default_name) {
+        (Some(output), _) => (output, false),
+        (None, Some(name)) => (PathBuf::from(name), true),
+        (None, None) => return Err(anyhow!("Use --output to choose where to save the download")),
+    };
+
+    if args.explain_url {
+        let part = part_path(&output, args.options.part_dir.as_deref());
+        return explain::print(&url, &part, &args.options).await;
+    }
+
+    let stop_at = budget::stop_at(args.options.max_total_time);
+    download(
+        client,
+        &url,
+        &output,
+        detect_extension,
+        &args.options,
+        stop_at,
+    )
+    .await?;
+
+    Ok(())
+}
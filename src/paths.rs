@@ -0,0 +1,141 @@
+//! Where audible-dl keeps its own files, such as the config file and the local tags.
+//!
+//! Each platform has its own place for them, as the `directories` crate knows:
+//!
+//! - Linux and other Unixes: `$XDG_CONFIG_HOME/audible-dl` (`~/.config/audible-dl`) for config
+//!   and `$XDG_DATA_HOME/audible-dl` (`~/.local/share/audible-dl`) for data
+//! - macOS: `~/Library/Application Support/audible-dl` for both
+//! - Windows: `%APPDATA%\audible-dl\config` for config and `%LOCALAPPDATA%\audible-dl\data` for
+//!   data, which shouldn't roam with the profile
+//!
+//! Outside Linux the Unix directories are still used if an earlier version created them.
+//!
+//! `--config-dir` or `AUDIBLE_DL_CONFIG_DIR` keeps all of them in one directory instead, and
+//! `--ephemeral` keeps what would be written there in memory.
+
+use std::ffi::OsString;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, Ordering};
+
+use anyhow::{anyhow, Result};
+use directories::ProjectDirs;
+
+const APP: &str = "audible-dl";
+
+static EPHEMERAL: AtomicBool = AtomicBool::new(false);
+
+/// Keep refreshed tokens, the state database and reports in memory from now on, see
+/// [`ephemeral`]
+pub fn set_ephemeral() {
+    EPHEMERAL.store(true, Ordering::Relaxed);
+}
+
+/// Whether `--ephemeral` was given, so that nothing but the books is written to disk
+pub fn ephemeral() -> bool {
+    EPHEMERAL.load(Ordering::Relaxed)
+}
+
+/// Set `AUDIBLE_DL_CONFIG_DIR` from a `--config-dir` in `args`.
+///
+/// The config file is read before the command line is parsed, since it sets the defaults of
+/// the options, so the directory it's in has to be found before clap runs.
+pub fn pick_config_dir(args: impl IntoIterator<Item = OsString>) {
+    if let Some(dir) = find_config_dir(args) {
+        std::env::set_var("AUDIBLE_DL_CONFIG_DIR", dir);
+    }
+}
+
+/// The last `--config-dir` in `args`
+fn find_config_dir(args: impl IntoIterator<Item = OsString>) -> Option<OsString> {
+    let mut args = args.into_iter();
+    let mut found = None;
+
+    while let Some(arg) = args.next() {
+        let dir = match arg.to_str() {
+            // The rest are for another program, e.g. `watch` under `service install`
+            Some("--") => break,
+            Some("--config-dir") => args.next(),
+            Some(arg) => arg.strip_prefix("--config-dir=").map(OsString::from),
+            None => None,
+        };
+
+        found = dir.or(found);
+    }
+
+    found
+}
+
+fn env_dir(name: &str) -> Option<PathBuf> {
+    std::env::var_os(name)
+        .filter(|dir| !dir.is_empty())
+        .map(PathBuf::from)
+}
+
+fn project() -> Result<ProjectDirs> {
+    ProjectDirs::from("", "", APP)
+        .ok_or_else(|| anyhow!("Found no home directory, pass --config-dir"))
+}
+
+/// `dir`, or the Unix directory `xdg` (defaulting to `default` in the home directory) where
+/// earlier versions kept the files on every platform, if only that one exists
+fn or_legacy(dir: &Path, xdg: &str, default: &str) -> PathBuf {
+    let legacy = match env_dir(xdg) {
+        Some(xdg) => Some(xdg.join(APP)),
+        None => env_dir("HOME").map(|home| home.join(default).join(APP)),
+    };
+
+    match legacy {
+        Some(legacy) if !cfg!(target_os = "linux") && !dir.exists() && legacy.exists() => legacy,
+        _ => dir.to_owned(),
+    }
+}
+
+/// Directory of the config file
+pub fn config_dir() -> Result<PathBuf> {
+    if let Some(dir) = env_dir("AUDIBLE_DL_CONFIG_DIR") {
+        return Ok(dir);
+    }
+
+    Ok(or_legacy(
+        project()?.config_dir(),
+        "XDG_CONFIG_HOME",
+        ".config",
+    ))
+}
+
+/// Directory of the data audible-dl keeps, such as the local tags
+pub fn data_dir() -> Result<PathBuf> {
+    if let Some(dir) = env_dir("AUDIBLE_DL_CONFIG_DIR") {
+        return Ok(dir);
+    }
+
+    Ok(or_legacy(
+        project()?.data_local_dir(),
+        "XDG_DATA_HOME",
+        ".local/share",
+    ))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn finds_config_dir() {
+        let find = |args: &[&str]| find_config_dir(args.iter().map(OsString::from));
+
+        assert_eq!(
+            find(&["audible-dl", "--config-dir", "/a", "library"]),
+            Some("/a".into())
+        );
+        assert_eq!(
+            find(&["audible-dl", "stats", "--config-dir=/b"]),
+            Some("/b".into())
+        );
+        assert_eq!(
+            find(&["audible-dl", "service", "install", "--", "--config-dir=/c"]),
+            None
+        );
+        assert_eq!(find(&["audible-dl", "--config-dir"]), None);
+    }
+}
@@ -0,0 +1,146 @@
+//! Estimating how long a batch of downloads will take, for `--estimate`.
+//!
+//! The size of every book is asked for before the batch starts, and the bandwidth is measured by
+//! downloading from the first one for a few seconds. Once books have been downloaded, the rate
+//! of the batch so far is used instead, which includes the time spent between them.
+
+use std::path::PathBuf;
+use std::time::{Duration, Instant};
+
+use audible_dl_core::rangedl;
+use indicatif::{HumanBytes, HumanDuration};
+
+use crate::{part_path, DownloadOptions};
+
+/// How long to download for when measuring the bandwidth
+const MEASURE_DURATION: Duration = Duration::from_secs(5);
+
+/// Sizes of the books in a batch, and the rate they're expected to download at
+#[derive(Debug)]
+pub struct Plan {
+    /// Bytes left to download of each book, `None` if the size couldn't be found
+    left: Vec<Option<u64>>,
+    /// Measured rate in bytes per second, if the measurement worked
+    measured: Option<u64>,
+    /// Index of the first book that isn't done
+    next: usize,
+    started: Instant,
+    downloaded: u64,
+}
+
+impl Plan {
+    /// Ask for the size of every book in `batch` of URLs and outputs, and print the estimate
+    pub async fn probe(
+        client: &reqwest::Client,
+        batch: &[(String, PathBuf)],
+        options: &DownloadOptions,
+    ) -> Plan {
+        let transfer = options.transfer_options();
+        let mut left = Vec::new();
+
+        eprintln!("Estimating the download of {} books...", batch.len());
+
+        for (url, output) in batch {
+            let size = match rangedl::size(client, url, &transfer).await {
+                Ok(size) => size,
+                Err(e) => {
+                    if options.verbose {
+                        eprintln!("Failed to get the size of {}: {:#}", output.display(), e);
+                    }
+
+                    left.push(None);
+                    continue;
+                }
+            };
+
+            // Resumed downloads only need the rest
+            let part = part_path(output, options.part_dir.as_deref());
+            let done = [output, &part]
+                .iter()
+                .filter_map(|path| path.metadata().ok())
+                .map(|metadata| metadata.len())
+                .max()
+                .unwrap_or(0);
+
+            left.push(Some(size.saturating_sub(done)));
+        }
+
+        let measured = match batch.first() {
+            Some((url, _)) => rangedl::measure(client, url, &transfer, MEASURE_DURATION)
+                .await
+                .ok()
+                .filter(|&rate| rate > 0),
+            None => None,
+        };
+
+        // The measurement doesn't hold back like the downloads do
+        let measured = match transfer.trickle {
+            Some(trickle) => measured.map(|rate| rate.min(trickle)),
+            None => measured,
+        };
+
+        let plan = Plan {
+            left,
+            measured,
+            next: 0,
+            started: Instant::now(),
+            downloaded: 0,
+        };
+
+        plan.print("Plan");
+        plan
+    }
+
+    /// Expected bytes per second, from the batch so far or else the measurement
+    fn rate(&self) -> Option<u64> {
+        let elapsed = self.started.elapsed().as_secs_f64();
+
+        if self.downloaded > 0 && elapsed > 0.0 {
+            return Some((self.downloaded as f64 / elapsed) as u64);
+        }
+
+        self.measured
+    }
+
+    fn print(&self, label: &str) {
+        let left = &self.left[self.next.min(self.left.len())..];
+        let books = left.iter().flatten().count();
+        let bytes = left.iter().flatten().sum::<u64>();
+        let unknown = left.iter().filter(|size| size.is_none()).count();
+
+        let mut line = format!(
+            "{}: {} books, {} to download",
+            label,
+            books,
+            HumanBytes(bytes)
+        );
+
+        if let Some(rate) = self.rate() {
+            let secs = bytes / rate.max(1);
+            line += &format!(
+                ", about {} at {}/s",
+                HumanDuration(Duration::from_secs(secs)),
+                HumanBytes(rate)
+            );
+        }
+
+        if unknown > 0 {
+            line += &format!(" ({} of unknown size)", unknown);
+        }
+
+        eprintln!("{}", line);
+    }
+
+    /// Count book `index` as done, downloaded or not, and print the estimate for the rest
+    pub fn finish(&mut self, index: usize, downloaded: bool) {
+        if downloaded {
+            self.downloaded += self.left.get(index).copied().flatten().unwrap_or(0);
+        }
+
+        self.next = index + 1;
+
+        if self.next < self.left.len() {
+            self.print("Left");
+        }
+    }
+}
@@ -0,0 +1,90 @@
+//! Downloading preorders as soon as they're released, for `watch --preorders`.
+//!
+//! The library is checked for preorders every hour. Once one of them is released a
+//! `preorder-<sku>.sku` file is dropped into the watched directory, which the watcher then picks
+//! up like any other.
+
+use std::collections::BTreeMap;
+use std::path::PathBuf;
+use std::time::{Duration, Instant};
+
+use anyhow::Result;
+use chrono::{DateTime, Utc};
+
+use crate::api;
+
+/// How often to check the library for new preorders
+const REFRESH_INTERVAL: Duration = Duration::from_secs(60 * 60);
+
+/// How often to check whether a preorder was released
+const CHECK_INTERVAL: Duration = Duration::from_secs(60);
+
+/// Preorders in the library, as SKU and when they're released
+async fn preorders(api: &api::Client) -> Result<BTreeMap<String, DateTime<Utc>>> {
+    let items = api.library().await?;
+
+    Ok(items
+        .iter()
+        .filter(|item| item.product.is_preorder())
+        .filter_map(|item| {
+            let sku = item.product.download_sku()?;
+            Some((sku.to_owned(), item.product.published_at()?))
+        })
+        .collect())
+}
+
+/// Drop a `.sku` file into `dir` for each preorder in the library once it's released
+pub async fn watch(api: api::Client, dir: PathBuf) {
+    let mut scheduled = BTreeMap::<String, DateTime<Utc>>::new();
+    let mut refreshed = None::<Instant>;
+
+    loop {
+        let now = Utc::now();
+        let released = scheduled
+            .iter()
+            .filter(|(_, at)| **at <= now)
+            .map(|(sku, _)| sku.clone())
+            .collect::<Vec<_>>();
+
+        for sku in released {
+            let trigger = dir.join(format!("preorder-{}.sku", sku));
+            eprintln!("Preorder {} was released, downloading it", sku);
+
+            // Written under another name first, so that the watcher doesn't see half of it
+            let temp = trigger.with_extension("sku.tmp");
+            let result = match tokio::fs::write(&temp, format!("{}\n", sku)).await {
+                Ok(()) => tokio::fs::rename(&temp, &trigger).await,
+                Err(e) => Err(e),
+            };
+
+            if let Err(e) = result {
+                eprintln!("Failed to write {}: {}", trigger.display(), e);
+                continue;
+            }
+
+            scheduled.remove(&sku);
+        }
+
+        if refreshed.is_none_or(|at| at.elapsed() >= REFRESH_INTERVAL) {
+            refreshed = Some(Instant::now());
+
+            match preorders(&api).await {
+                Ok(preorders) => {
+                    for (sku, at) in &preorders {
+                        if !scheduled.contains_key(sku) {
+                            eprintln!(
+                                "Preorder {} will be downloaded when released at {}",
+                                sku, at
+                            );
+                        }
+                    }
+
+                    scheduled = preorders;
+                }
+                Err(e) => eprintln!("Failed to check the library for preorders: {:#}", e),
+            }
+        }
+
+        tokio::time::sleep(CHECK_INTERVAL).await;
+    }
+}
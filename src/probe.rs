@@ -0,0 +1,151 @@
+use std::fs::File;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+
+use anyhow::{anyhow, bail, Context, Result};
+use serde_json::json;
+
+use crate::api::ApiArgs;
+use crate::info::Format;
+use audible_dl_core::mp4;
+
+/// Allowed difference between the length of the file and the one in the catalog, which is
+/// rounded to whole minutes
+const TOLERANCE_MIN: u64 = 1;
+
+#[derive(clap::Args, Debug)]
+#[command(mut_group("credentials", |group| group.required(false)))]
+pub struct ProbeArgs {
+    /// Downloaded AAX or AAXC file, or converted M4B file
+    input: PathBuf,
+
+    /// ASIN of the book, to compare the length of the file with the one in the Audible catalog
+    #[arg(long, env = "AUDIBLE_DL_ASIN", requires = "credentials")]
+    asin: Option<String>,
+
+    /// How to print the properties
+    #[arg(long, env = "AUDIBLE_DL_PROBE_FORMAT", value_enum, default_value_t = Format::Text)]
+    format: Format,
+
+    #[command(flatten)]
+    api: Option<ApiArgs>,
+}
+
+/// Properties of the audio in a file
+struct Audio {
+    duration: Duration,
+    /// Average bitrate in bits per second
+    bitrate: u64,
+    sample_rate: u32,
+    chapters: usize,
+}
+
+fn read(path: &Path) -> Result<Audio> {
+    let mut file =
+        File::open(path).with_context(|| format!("Failed to open {}", path.display()))?;
+    let tracks = mp4::tracks(&mut file)?;
+
+    let kind = |track: &mp4::Track| track.entry.as_ref().map(|entry| entry.kind);
+
+    let audio = tracks
+        .iter()
+        .find(|track| matches!(kind(track), Some(kind) if &kind == b"mp4a" || &kind == b"aavd"))
+        .ok_or_else(|| anyhow!("{} has no AAC audio track", path.display()))?;
+
+    let duration = mp4::duration(&mut file, &audio.mdia)?;
+    let sample_rate = mp4::sample_rate(&mut file, audio.entry.as_ref().expect("audio entry"))?;
+
+    let bytes: u64 = mp4::chunks(&mut file, &audio.stbl)?
+        .iter()
+        .flat_map(|chunk| &chunk.sample_sizes)
+        .map(|&size| u64::from(size))
+        .sum();
+
+    // Audible books have one sample per chapter in a text track
+    let chapters = match tracks.iter().find(|track| kind(track) == Some(*b"text")) {
+        Some(track) => mp4::chunks(&mut file, &track.stbl)?
+            .iter()
+            .map(|chunk| chunk.sample_sizes.len())
+            .sum(),
+        None => 0,
+    };
+
+    Ok(Audio {
+        duration,
+        bitrate: (bytes as f64 * 8.0 / duration.as_secs_f64().max(1.0)) as u64,
+        sample_rate,
+        chapters,
+    })
+}
+
+fn format_duration(duration: Duration) -> String {
+    let secs = duration.as_secs();
+
+    format!("{}h {:02}m {:02}s", secs / 3600, secs / 60 % 60, secs % 60)
+}
+
+/// Print the length, bitrate, sample rate and number of chapters of a book, and check the
+/// length against the catalog
+pub async fn run(client: &reqwest::Client, args: ProbeArgs) -> Result<()> {
+    let audio = read(&args.input)?;
+
+    let expected = match (&args.asin, &args.api) {
+        (Some(asin), Some(api)) => Some(
+            api.client(client.clone())?
+                .product(asin)
+                .await?
+                .runtime_length_min
+                .ok_or_else(|| anyhow!("The catalog has no length for {}", asin))?,
+        ),
+        _ => None,
+    };
+
+    match args.format {
+        Format::Text => {
+            let fields = [
+                ("Length", Some(format_duration(audio.duration))),
+                ("Bitrate", Some(format!("{} kbit/s", audio.bitrate / 1000))),
+                ("Sample rate", Some(format!("{} Hz", audio.sample_rate))),
+                ("Chapters", Some(audio.chapters.to_string())),
+                (
+                    "Catalog",
+                    expected.map(|min| format_duration(Duration::from_secs(u64::from(min) * 60))),
+                ),
+            ];
+
+            for (name, value) in fields {
+                if let Some(value) = value {
+                    println!("{:<12} {}", format!("{}:", name), value);
+                }
+            }
+        }
+        Format::Json => {
+            let output = json!({
+                "length_secs": audio.duration.as_secs_f64(),
+                "bitrate": audio.bitrate,
+                "sample_rate": audio.sample_rate,
+                "chapters": audio.chapters,
+                "catalog_length_min": expected,
+            });
+
+            println!("{}", serde_json::to_string_pretty(&output)?);
+        }
+    }
+
+    let Some(expected) = expected else {
+        return Ok(());
+    };
+
+    let actual = (audio.duration.as_secs_f64() / 60.0).round() as u64;
+
+    if actual.abs_diff(u64::from(expected)) > TOLERANCE_MIN {
+        bail!(
+            "{} is {} minutes long, but the book is {} minutes according to the catalog",
+            args.input.display(),
+            actual,
+            expected
+        );
+    }
+
+    Ok(())
+}
@@ -0,0 +1,218 @@
+//! Progress bar styles that fit the width of the terminal.
+//!
+//! Each template has a compact variant with just the percentage and speed, used when the
+//! terminal is too narrow for the full one, which would wrap on every redraw. Only one progress
+//! bar is shown at a time, its template is remembered so that it can be switched over when the
+//! terminal is resized.
+
+use std::sync::Mutex;
+use std::time::Duration;
+
+use anyhow::Result;
+use audible_dl_core::{Frontend, Stage};
+use indicatif::{ProgressBar, ProgressStyle};
+use serde_json::json;
+
+use crate::{conflict, style};
+
+/// Terminals narrower than this get the compact templates
+const COMPACT_BELOW: u16 = 90;
+
+/// Template of the current progress bar, and whether its compact variant is shown
+static CURRENT: Mutex<Option<(Template, bool)>> = Mutex::new(None);
+
+#[derive(Clone, Copy, Debug)]
+pub struct Template {
+    full: &'static str,
+    compact: &'static str,
+}
+
+/// Just the message
+pub const MESSAGE: Template = Template {
+    full: "[{elapsed_precise}] [{bar:35.cyan/blue}] {msg}",
+    compact: "{msg}",
+};
+
+/// Data being downloaded
+pub const DOWNLOADING: Template = Template {
+    full: "[{elapsed_precise}] [{bar:35.cyan/blue}] {bytes}/{total_bytes} {binary_bytes_per_sec} ({eta})",
+    compact: "{percent:>3}% {binary_bytes_per_sec}",
+};
+
+/// A file being copied or processed, with a message saying what's going on
+pub const WORKING: Template = Template {
+    full: "[{elapsed_precise}] [{bar:35.cyan/blue}] {bytes}/{total_bytes} {msg}",
+    compact: "{percent:>3}% {msg}",
+};
+
+/// A file being processed, without a message
+pub const PROCESSING: Template = Template {
+    full: "[{elapsed_precise}] [{bar:35.cyan/blue}] {bytes}/{total_bytes} ({eta})",
+    compact: "{percent:>3}% ({eta})",
+};
+
+/// Audio being decoded
+pub const VERIFYING: Template = Template {
+    full: "[{elapsed_precise}] [{bar:35.cyan/blue}] Verifying audio... ({eta})",
+    compact: "{percent:>3}% Verifying audio...",
+};
+
+fn is_narrow() -> bool {
+    console::Term::stderr()
+        .size_checked()
+        .is_some_and(|(_, width)| width < COMPACT_BELOW)
+}
+
+fn style(template: Template, compact: bool) -> ProgressStyle {
+    let template = if compact {
+        template.compact
+    } else {
+        template.full
+    };
+
+    ProgressStyle::with_template(template)
+        .unwrap()
+        .progress_chars("#>-")
+}
+
+/// Show `pb` with `template`, or its compact variant on narrow terminals
+pub fn set_style(pb: &ProgressBar, template: Template) {
+    let compact = is_narrow();
+
+    pb.set_style(style(template, compact));
+    *CURRENT.lock().unwrap() = Some((template, compact));
+}
+
+/// Switch to the other variant of the template if the terminal was resized across the limit
+fn refit(pb: &ProgressBar) {
+    let mut current = CURRENT.lock().unwrap();
+
+    if let Some((template, compact)) = current.as_mut() {
+        let narrow = is_narrow();
+
+        if *compact != narrow {
+            pb.set_style(style(*template, narrow));
+            *compact = narrow;
+        }
+    }
+}
+
+/// Shows the progress of the core operations on a progress bar
+#[derive(Clone, Debug)]
+pub struct Bar {
+    pub pb: ProgressBar,
+    /// Answer yes to every question, see `--assume-yes`
+    pub assume_yes: bool,
+    /// Print the log lines above the bar
+    pub verbose: bool,
+    /// Print the transfer rate every second as a JSON line on stdout
+    pub json: bool,
+}
+
+impl Bar {
+    /// A bar that only shows the progress
+    pub fn new(pb: ProgressBar) -> Bar {
+        Bar {
+            pb,
+            assume_yes: false,
+            verbose: false,
+            json: false,
+        }
+    }
+}
+
+impl Frontend for Bar {
+    fn set_stage(&self, stage: Stage) {
+        let template = match stage {
+            Stage::Waiting => MESSAGE,
+            Stage::Downloading => DOWNLOADING,
+            Stage::Working => WORKING,
+        };
+
+        set_style(&self.pb, template);
+    }
+
+    fn set_message(&self, message: &str) {
+        self.pb.set_message(message.to_owned());
+    }
+
+    fn set_length(&self, len: u64) {
+        self.pb.set_length(len);
+    }
+
+    fn set_position(&self, position: u64) {
+        self.pb.set_position(position);
+        self.pb.reset_eta();
+    }
+
+    fn inc(&self, delta: u64) {
+        self.pb.inc(delta);
+    }
+
+    fn log(&self, line: &str) {
+        if self.verbose {
+            self.pb.println(line);
+        }
+    }
+
+    fn warn(&self, message: &str) {
+        self.pb
+            .suspend(|| eprintln!("{} {}", style::warning("Warning:"), message));
+    }
+
+    fn rate(&self, position: u64, total: u64, rate: u64) {
+        if self.json {
+            let line = json!({
+                "event": "progress",
+                "position": position,
+                "total": total,
+                "rate": rate,
+            });
+            println!("{}", line);
+        }
+    }
+
+    fn confirm(&self, question: &str) -> Result<bool> {
+        self.pb
+            .suspend(|| conflict::confirm(question, self.assume_yes))
+    }
+}
+
+/// Redraw `pb` every second, and right away when the terminal is resized, until it's finished
+pub async fn tick(pb: ProgressBar) {
+    #[cfg(unix)]
+    let mut resized =
+        tokio::signal::unix::signal(tokio::signal::unix::SignalKind::window_change()).ok();
+
+    while !pb.is_finished() {
+        refit(&pb);
+        pb.tick();
+
+        let sleep = tokio::time::sleep(Duration::from_secs(1));
+
+        #[cfg(unix)]
+        if let Some(resized) = &mut resized {
+            tokio::select! {
+                _ = sleep => {}
+                _ = resized.recv() => {}
+            }
+
+            continue;
+        }
+
+        sleep.await;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn templates_are_valid() {
+        for template in [MESSAGE, DOWNLOADING, WORKING, PROCESSING, VERIFYING] {
+            style(template, false);
+            style(template, true);
+        }
+    }
+}
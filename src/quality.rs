@@ -0,0 +1,82 @@
+//! Falling back to a lower quality when the CDS keeps failing to deliver a book.
+//!
+//! The quality is picked with the `codec` parameter of the CDS download URL, so falling back
+//! means asking for the next codec in [`QUALITIES`]. A partial download can't be continued in
+//! another codec, so it's started over, and the codec is recorded in the sidecar to resume with
+//! the same one later.
+
+use reqwest::Url;
+
+/// Codecs of the CDS, from the best quality
+pub const QUALITIES: [&str; 4] = [
+    "LC_128_44100_Stereo",
+    "LC_64_44100_Stereo",
+    "LC_64_22050_Stereo",
+    "LC_32_22050_Stereo",
+];
+
+/// The codec a CDS download URL asks for, `None` for other URLs
+pub fn codec(url: &str) -> Option<String> {
+    let url = Url::parse(url).ok()?;
+
+    if url.host_str() != Some("cds.audible.com") {
+        return None;
+    }
+
+    url.query_pairs()
+        .find(|(name, _)| name == "codec")
+        .map(|(_, value)| value.into_owned())
+}
+
+/// The next lower quality after `codec`, if any
+pub fn lower(codec: &str) -> Option<&'static str> {
+    let index = QUALITIES.iter().position(|quality| *quality == codec)?;
+
+    QUALITIES.get(index + 1).copied()
+}
+
+/// `url` asking for `codec` instead
+pub fn with_codec(url: &str, codec: &str) -> String {
+    let Ok(mut url) = Url::parse(url) else {
+        return url.to_owned();
+    };
+
+    let pairs = url
+        .query_pairs()
+        .map(|(name, value)| match name.as_ref() {
+            "codec" => (name.into_owned(), codec.to_owned()),
+            _ => (name.into_owned(), value.into_owned()),
+        })
+        .collect::<Vec<_>>();
+
+    url.query_pairs_mut().clear().extend_pairs(pairs);
+    url.into()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const URL: &str = "https://cds.audible.com/download?user_id=1&product_id=BK_ADBL_000123&codec=LC_128_44100_Stereo&awtype=AAX&cust_id=1";
+
+    #[test]
+    fn reads_codec() {
+        assert_eq!(codec(URL).as_deref(), Some("LC_128_44100_Stereo"));
+        assert_eq!(
+            codec("https://example.com/book.aax?codec=LC_128_44100_Stereo"),
+            None
+        );
+    }
+
+    #[test]
+    fn falls_back() {
+        assert_eq!(lower("LC_128_44100_Stereo"), Some("LC_64_44100_Stereo"));
+        assert_eq!(lower("LC_32_22050_Stereo"), None);
+        assert_eq!(lower("mp42_22_22050_Mono"), None);
+
+        assert_eq!(
+            with_codec(URL, "LC_64_44100_Stereo"),
+            URL.replace("LC_128_44100_Stereo", "LC_64_44100_Stereo")
+        );
+    }
+}
@@ -0,0 +1,82 @@
+//! `--rclone-remote`, moving downloaded books to cloud storage with rclone.
+//!
+//! rclone does the talking to the storage, with the remotes set up in its own config by
+//! `rclone config`. Books are moved once they're complete, so the local disk only has to hold
+//! the one being downloaded.
+
+use std::path::Path;
+use std::process::Command;
+
+use anyhow::{bail, Context, Result};
+
+/// Parse a `remote:path` destination, for use as a clap value parser
+pub fn parse(s: &str) -> Result<String, String> {
+    match s.split_once(':') {
+        Some((remote, _)) if !remote.is_empty() => Ok(s.to_owned()),
+        _ => Err("expected `remote:path`, e.g. `gdrive:Audiobooks`".to_owned()),
+    }
+}
+
+/// Where the file `name` goes in the rclone destination `remote`
+fn destination(remote: &str, name: &str) -> String {
+    if remote.ends_with(':') || remote.ends_with('/') {
+        format!("{}{}", remote, name)
+    } else {
+        format!("{}/{}", remote, name)
+    }
+}
+
+/// Move the book at `path` to `remote`, returning where it ended up
+pub async fn upload(path: &Path, remote: &str) -> Result<String> {
+    let name = path
+        .file_name()
+        .context("The book has no file name")?
+        .to_string_lossy();
+    let target = destination(remote, &name);
+
+    let output = {
+        let (path, target) = (path.to_owned(), target.clone());
+
+        tokio::task::spawn_blocking(move || {
+            Command::new("rclone")
+                .arg("moveto")
+                .arg(path)
+                .arg(target)
+                .output()
+        })
+        .await?
+    };
+
+    let output = match output {
+        Ok(output) => output,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => {
+            bail!("--rclone-remote needs rclone, see https://rclone.org")
+        }
+        Err(e) => return Err(e).context("Failed to run rclone"),
+    };
+
+    if !output.status.success() {
+        bail!(
+            "Failed to move {} to {}, it's left where it is: {}",
+            path.display(),
+            target,
+            String::from_utf8_lossy(&output.stderr).trim()
+        );
+    }
+
+    Ok(target)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn joins_destinations() {
+        assert_eq!(destination("gdrive:", "a.aax"), "gdrive:a.aax");
+        assert_eq!(destination("gdrive:Books", "a.aax"), "gdrive:Books/a.aax");
+        assert_eq!(destination("gdrive:Books/", "a.aax"), "gdrive:Books/a.aax");
+        assert!(parse("Books").is_err());
+        assert!(parse(":Books").is_err());
+    }
+}
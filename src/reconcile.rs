@@ -0,0 +1,358 @@
+//! Finding downloaded books again after the library was reorganized, so that batches and `watch`
+//! don't download them again.
+//!
+//! The history of the state database has the path, size, ASIN and checksum of every download, a
+//! manifest of it. A book whose file is no longer at its path is matched to a file of the same
+//! size and extension, with the same ASIN in its `CDEK` tag and the same SHA-256 where the
+//! manifest has them, however the file was renamed or moved.
+
+use std::collections::{BTreeSet, HashSet};
+use std::fs::File;
+use std::path::{Path, PathBuf};
+
+use anyhow::{Context, Result};
+
+use audible_dl_core::checksum::Checksum;
+use audible_dl_core::mp4;
+
+use crate::db::{self, Db};
+use crate::report::Report;
+use crate::{export, Outcome};
+
+/// Files that are a book, rather than what's kept next to one
+const EXTENSIONS: [&str; 5] = ["aax", "aaxc", "m4b", "m4a", "mp3"];
+
+#[derive(clap::Args, Debug)]
+pub struct ReconcileArgs {
+    /// Directory with the downloaded books, searched with its subdirectories
+    #[arg(long, env = "AUDIBLE_DL_DIR", default_value = ".")]
+    dir: PathBuf,
+
+    /// Only report what was found, don't update the database
+    #[arg(long, env = "AUDIBLE_DL_DRY_RUN")]
+    dry_run: bool,
+}
+
+/// What's known about a downloaded file to recognize it by
+#[derive(Debug, Default, PartialEq, Eq)]
+pub struct Fingerprint {
+    pub size: Option<u64>,
+    /// From the `CDEK` tag of AAX and AAXC files
+    pub asin: Option<String>,
+    /// From the `<file>.sha256` of `--checksum`
+    pub sha256: Option<String>,
+}
+
+impl Fingerprint {
+    /// Fingerprint of the file at `path`, leaving out what can't be read
+    pub fn read(path: &Path) -> Fingerprint {
+        let mut checksum = path.as_os_str().to_owned();
+        checksum.push(".sha256");
+
+        let sha256 = std::fs::read_to_string(checksum)
+            .ok()
+            .and_then(|line| line.split_whitespace().next().map(str::to_owned));
+
+        Fingerprint {
+            size: std::fs::metadata(path).ok().map(|metadata| metadata.len()),
+            asin: asin(path),
+            sha256,
+        }
+    }
+}
+
+/// A download in the history of the state database
+#[derive(Debug)]
+pub struct Manifest {
+    pub id: i64,
+    pub sku: String,
+    pub path: PathBuf,
+    pub fingerprint: Fingerprint,
+}
+
+fn asin(path: &Path) -> Option<String> {
+    let mut file = File::open(path).ok()?;
+    mp4::tag(&mut file, b"CDEK").ok().flatten()
+}
+
+/// A book file found in the directory
+struct Found {
+    path: PathBuf,
+    size: u64,
+    asin: Option<String>,
+}
+
+fn extension(path: &Path) -> Option<String> {
+    path.extension()
+        .and_then(|extension| extension.to_str())
+        .map(str::to_ascii_lowercase)
+}
+
+/// Book files in `dir` and its subdirectories
+fn scan(dir: &Path, found: &mut Vec<Found>) -> Result<()> {
+    let entries =
+        std::fs::read_dir(dir).with_context(|| format!("Failed to read {}", dir.display()))?;
+
+    for entry in entries {
+        let entry = entry?;
+        let file_type = entry.file_type()?;
+        let path = entry.path();
+
+        if file_type.is_dir() {
+            scan(&path, found)?;
+        } else if file_type.is_file()
+            && extension(&path).is_some_and(|extension| EXTENSIONS.contains(&extension.as_str()))
+        {
+            found.push(Found {
+                size: entry.metadata()?.len(),
+                asin: asin(&path),
+                path,
+            });
+        }
+    }
+
+    Ok(())
+}
+
+async fn sha256(path: &Path, size: u64) -> Result<String> {
+    let mut checksum = Checksum::new();
+    checksum.catch_up(path, size).await?;
+    Ok(checksum.finish())
+}
+
+/// Files that match `manifest` and weren't matched to another one yet.
+///
+/// Manifests from before sizes were recorded only match a file with the same name.
+async fn candidates<'a>(
+    manifest: &Manifest,
+    found: &'a [Found],
+    claimed: &HashSet<PathBuf>,
+) -> Result<Vec<&'a Found>> {
+    let fingerprint = &manifest.fingerprint;
+    let mut candidates = Vec::new();
+
+    for file in found {
+        if claimed.contains(&file.path) {
+            continue;
+        }
+
+        let Some(size) = fingerprint.size else {
+            if file.path.file_name() == manifest.path.file_name() {
+                candidates.push(file);
+            }
+            continue;
+        };
+
+        if size != file.size || extension(&file.path) != extension(&manifest.path) {
+            continue;
+        }
+
+        if fingerprint.asin.is_some() && fingerprint.asin != file.asin {
+            continue;
+        }
+
+        if let Some(expected) = &fingerprint.sha256 {
+            if sha256(&file.path, file.size).await? != *expected {
+                continue;
+            }
+        }
+
+        candidates.push(file);
+    }
+
+    Ok(candidates)
+}
+
+#[derive(Debug, Default, PartialEq, Eq)]
+struct Summary {
+    moved: usize,
+    missing: usize,
+    added: usize,
+}
+
+/// Update the history in `db` with where the books in `dir` are now
+async fn reconcile(db: &Db, dir: &Path, dry_run: bool) -> Result<Summary> {
+    let mut found = Vec::new();
+    scan(dir, &mut found)?;
+
+    let manifests = db.manifests()?;
+    let mut summary = Summary::default();
+
+    // Files still where the history has them are taken
+    let mut claimed = manifests
+        .iter()
+        .filter(|manifest| manifest.path.exists())
+        .filter_map(|manifest| std::path::absolute(&manifest.path).ok())
+        .collect::<HashSet<_>>();
+
+    for file in &mut found {
+        file.path = std::path::absolute(&file.path)?;
+    }
+
+    for manifest in manifests.iter().filter(|manifest| !manifest.path.exists()) {
+        let candidates = candidates(manifest, &found, &claimed).await?;
+
+        match candidates.as_slice() {
+            [file] => {
+                println!(
+                    "{}: moved from {} to {}",
+                    manifest.sku,
+                    manifest.path.display(),
+                    file.path.display()
+                );
+
+                if !dry_run {
+                    db.relocate(manifest.id, &file.path)?;
+                }
+
+                claimed.insert(file.path.clone());
+                summary.moved += 1;
+            }
+            [] => {
+                println!(
+                    "{}: {} is gone, and nothing in {} matches it",
+                    manifest.sku,
+                    manifest.path.display(),
+                    dir.display()
+                );
+                summary.missing += 1;
+            }
+            candidates => {
+                println!(
+                    "{}: {} is gone, and {} files match it, leaving it as it is",
+                    manifest.sku,
+                    manifest.path.display(),
+                    candidates.len()
+                );
+                summary.missing += 1;
+            }
+        }
+    }
+
+    // Books named after their SKU, downloaded before there was a database or on another machine
+    let known = manifests
+        .iter()
+        .map(|manifest| manifest.sku.as_str())
+        .collect::<BTreeSet<_>>();
+    let mut report = Report::new(dir);
+
+    for file in found.iter().filter(|file| !claimed.contains(&file.path)) {
+        let Some(sku) = file
+            .path
+            .file_name()
+            .and_then(|name| name.to_str())
+            .and_then(|name| name.split('.').next())
+        else {
+            continue;
+        };
+
+        if !export::is_sku(sku) || known.contains(sku) {
+            continue;
+        }
+
+        println!("{}: found {}", sku, file.path.display());
+
+        let outcome = Outcome::Downloaded {
+            path: file.path.clone(),
+            remote: None,
+        };
+        report.push(sku, &Ok(outcome));
+        summary.added += 1;
+    }
+
+    if !dry_run && !report.items().is_empty() {
+        db.record(&report)?;
+    }
+
+    Ok(summary)
+}
+
+/// Match the books in a directory to the downloads in the state database
+pub async fn run(args: ReconcileArgs) -> Result<()> {
+    let db = Db::open(&db::path()?)?;
+    let summary = reconcile(&db, &args.dir, args.dry_run).await?;
+
+    eprintln!(
+        "{} moved, {} missing, {} found that weren't known{}",
+        summary.moved,
+        summary.missing,
+        summary.added,
+        if args.dry_run {
+            ", nothing was changed"
+        } else {
+            ""
+        }
+    );
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn finds_moved_books() {
+        let dir = std::env::temp_dir().join(format!("audible-dl-reconcile-{}", std::process::id()));
+        let old = dir.join("BK_ADBL_000001_22.aax");
+        std::fs::create_dir_all(&dir).unwrap();
+        std::fs::write(&old, b"first book").unwrap();
+        std::fs::write(
+            dir.join("BK_ADBL_000001_22.aax.sha256"),
+            format!("{}  x\n", {
+                let mut checksum = Checksum::new();
+                checksum.update(0, b"first book");
+                checksum.finish()
+            }),
+        )
+        .unwrap();
+
+        let db = Db::in_memory().unwrap();
+        let mut report = Report::new(&dir.join("batch.sku"));
+        let outcome = Outcome::Downloaded {
+            path: old.clone(),
+            remote: None,
+        };
+        report.push("BK_ADBL_000001_22", &Ok(outcome));
+        db.record(&report).unwrap();
+
+        // Renamed into an author directory, next to a file of the same size and one named after
+        // a SKU that was never recorded
+        let new = dir.join("Terry Pratchett").join("Guards! Guards!.aax");
+        std::fs::create_dir_all(new.parent().unwrap()).unwrap();
+        std::fs::rename(&old, &new).unwrap();
+        std::fs::write(dir.join("other book.aax"), b"other book").unwrap();
+        std::fs::write(dir.join("BK_ADBL_000002_22.m4b"), b"second").unwrap();
+
+        let block_on = |future| {
+            tokio::runtime::Builder::new_current_thread()
+                .build()
+                .unwrap()
+                .block_on(future)
+        };
+
+        let dry_run = block_on(reconcile(&db, &dir, true));
+        let last_after_dry_run = db.last_download("BK_ADBL_000001_22").unwrap();
+        let summary = block_on(reconcile(&db, &dir, false));
+        let last = db.last_download("BK_ADBL_000001_22").unwrap();
+        let added = db.last_download("BK_ADBL_000002_22").unwrap();
+        let again = block_on(reconcile(&db, &dir, false));
+
+        std::fs::remove_dir_all(&dir).unwrap();
+
+        let expected = Summary {
+            moved: 1,
+            missing: 0,
+            added: 1,
+        };
+        assert_eq!(dry_run.unwrap(), expected);
+        assert_eq!(last_after_dry_run, Some(old));
+        assert_eq!(summary.unwrap(), expected);
+        assert_eq!(last, Some(std::path::absolute(&new).unwrap()));
+        assert_eq!(
+            added,
+            Some(std::path::absolute(dir.join("BK_ADBL_000002_22.m4b")).unwrap())
+        );
+        assert_eq!(again.unwrap(), Summary::default());
+    }
+}
@@ -0,0 +1,84 @@
+use std::fs::{File, OpenOptions};
+use std::io::Read;
+use std::path::{Path, PathBuf};
+
+use anyhow::{Context, Result};
+
+use audible_dl_core::rangedl::{self, ERROR_TEXT_LEN};
+
+const BLOCK_SIZE: usize = 1024 * 1024;
+
+#[derive(clap::Args, Debug)]
+pub struct RepairArgs {
+    /// Partial downloads to check
+    #[arg(required = true)]
+    parts: Vec<PathBuf>,
+
+    /// Only report error pages, don't remove them
+    #[arg(long, env = "AUDIBLE_DL_DRY_RUN")]
+    dry_run: bool,
+}
+
+/// Offset of the first HTML or XML error page in the file at `path`
+fn find(path: &Path) -> Result<Option<u64>> {
+    let mut file =
+        File::open(path).with_context(|| format!("Failed to open {}", path.display()))?;
+
+    let mut block = vec![0; BLOCK_SIZE];
+    let mut data = Vec::new();
+    let mut offset = 0;
+
+    loop {
+        let read = file.read(&mut block)?;
+        data.extend_from_slice(&block[..read]);
+
+        // Keep the end of the block for the next one, in case a page starts there
+        let limit = match read {
+            0 => data.len(),
+            _ => data.len().saturating_sub(ERROR_TEXT_LEN),
+        };
+
+        let found = (0..limit).find(|&i| data[i] == b'<' && rangedl::is_error_document(&data[i..]));
+
+        if let Some(i) = found {
+            return Ok(Some(offset + i as u64));
+        }
+
+        if read == 0 {
+            return Ok(None);
+        }
+
+        data.drain(..limit);
+        offset += limit as u64;
+    }
+}
+
+/// Cut partial downloads off where an error page was written into them, so that resuming
+/// downloads the rest of the book again
+pub fn run(args: RepairArgs) -> Result<()> {
+    for part in &args.parts {
+        let Some(offset) = find(part)? else {
+            println!("{}: no error pages found", part.display());
+            continue;
+        };
+
+        if args.dry_run {
+            println!("{}: error page at offset {}", part.display(), offset);
+            continue;
+        }
+
+        OpenOptions::new()
+            .write(true)
+            .open(part)
+            .and_then(|file| file.set_len(offset))
+            .with_context(|| format!("Failed to truncate {}", part.display()))?;
+
+        println!(
+            "{}: removed error page at offset {}, resume the download to fetch the rest",
+            part.display(),
+            offset
+        );
+    }
+
+    Ok(())
+}
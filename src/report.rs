@@ -0,0 +1,164 @@
+use std::path::{Path, PathBuf};
+
+use anyhow::{Context, Result};
+use chrono::{DateTime, SecondsFormat, Utc};
+use console::StyledObject;
+use serde::{Deserialize, Serialize};
+
+use crate::{paths, style, Outcome};
+
+/// Summary of a batch of downloads, written as `report-<timestamp>.json`
+#[derive(Serialize, Deserialize, Debug)]
+pub struct Report {
+    /// File the SKUs were read from
+    source: PathBuf,
+    started: String,
+    finished: Option<String>,
+    downloaded: usize,
+    skipped: usize,
+    failed: usize,
+    items: Vec<Item>,
+    #[serde(skip)]
+    started_at: DateTime<Utc>,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct Item {
+    pub sku: String,
+    #[serde(flatten)]
+    pub status: Status,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+#[serde(tag = "status", rename_all = "lowercase")]
+pub enum Status {
+    Downloaded {
+        path: PathBuf,
+        /// Where `--rclone-remote` moved the book
+        #[serde(skip_serializing_if = "Option::is_none")]
+        remote: Option<String>,
+    },
+    Skipped {
+        reason: String,
+    },
+    Failed {
+        error: String,
+    },
+}
+
+impl Report {
+    pub fn new(source: &Path) -> Report {
+        let started_at = Utc::now();
+
+        Report {
+            source: source.to_owned(),
+            started: started_at.to_rfc3339_opts(SecondsFormat::Secs, true),
+            finished: None,
+            downloaded: 0,
+            skipped: 0,
+            failed: 0,
+            items: Vec::new(),
+            started_at,
+        }
+    }
+
+    /// Record the result of downloading `sku`
+    pub fn push(&mut self, sku: &str, result: &Result<Outcome>) {
+        let status = match result {
+            Ok(Outcome::Downloaded { path, remote }) => {
+                self.downloaded += 1;
+                Status::Downloaded {
+                    path: path.clone(),
+                    remote: remote.clone(),
+                }
+            }
+            Ok(Outcome::Skipped) => {
+                self.skipped += 1;
+                Status::Skipped {
+                    reason: "already exists".to_owned(),
+                }
+            }
+            Err(e) => {
+                self.failed += 1;
+                Status::Failed {
+                    error: format!("{:#}", e),
+                }
+            }
+        };
+
+        self.items.push(Item {
+            sku: sku.to_owned(),
+            status,
+        });
+    }
+
+    /// File the SKUs were read from
+    pub fn source(&self) -> &Path {
+        &self.source
+    }
+
+    /// When the batch finished, once [`Report::write`] marked it so
+    pub fn finished(&self) -> Option<&str> {
+        self.finished.as_deref()
+    }
+
+    pub fn items(&self) -> &[Item] {
+        &self.items
+    }
+
+    pub fn has_failures(&self) -> bool {
+        self.failed > 0
+    }
+
+    /// Print a summary of the batch, including the reason every failed or skipped book has
+    pub fn print_summary(&self) {
+        // Only counts of something that happened stand out
+        let count = |count: usize, color: fn(usize) -> StyledObject<usize>| match count {
+            0 => count.to_string(),
+            _ => color(count).to_string(),
+        };
+
+        eprintln!(
+            "{}: {} downloaded, {} skipped, {} failed",
+            self.source.display(),
+            count(self.downloaded, style::success),
+            count(self.skipped, style::warning),
+            count(self.failed, style::error)
+        );
+
+        for item in &self.items {
+            match &item.status {
+                Status::Downloaded { .. } => {}
+                Status::Skipped { reason } => {
+                    eprintln!("  {} {}: {}", style::warning("skipped"), item.sku, reason)
+                }
+                Status::Failed { error } => {
+                    eprintln!("  {} {}: {}", style::error("failed"), item.sku, error)
+                }
+            }
+        }
+    }
+
+    /// Mark the batch as finished and write the report to `dir`, returning its path. With
+    /// `--ephemeral` nothing is written.
+    pub async fn write(&mut self, dir: &Path) -> Result<Option<PathBuf>> {
+        self.finished = Some(Utc::now().to_rfc3339_opts(SecondsFormat::Secs, true));
+
+        if paths::ephemeral() {
+            return Ok(None);
+        }
+
+        let name = format!(
+            "report-{}.json",
+            self.started_at.format("%Y%m%dT%H%M%S%.3fZ")
+        );
+        let path = dir.join(name);
+
+        tokio::fs::create_dir_all(dir).await?;
+        tokio::fs::write(&path, serde_json::to_vec_pretty(self)?)
+            .await
+            .with_context(|| format!("Failed to write report {}", path.display()))?;
+
+        Ok(Some(path))
+    }
+}
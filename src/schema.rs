@@ -0,0 +1,172 @@
+use anyhow::Result;
+use serde_json::{json, Value};
+
+#[derive(clap::Args, Debug)]
+pub struct SchemaArgs {
+    /// Command to print the JSON output schema of
+    #[arg(value_enum)]
+    command: Output,
+}
+
+#[derive(clap::ValueEnum, Clone, Copy, Debug)]
+enum Output {
+    /// `library --format json`
+    Library,
+    /// `info --format json`
+    Info,
+    /// `probe --format json`
+    Probe,
+    /// `stats listening --format json`
+    Stats,
+}
+
+fn nullable(kind: &str) -> Value {
+    json!({ "type": [kind, "null"] })
+}
+
+/// Properties of a title in the catalog, shared by `info` and `library`
+fn product_properties() -> Value {
+    let people = json!({
+        "type": ["array", "null"],
+        "items": {
+            "type": "object",
+            "required": ["name"],
+            "properties": { "name": { "type": "string" } },
+        },
+    });
+
+    json!({
+        "asin": { "type": "string" },
+        "sku": nullable("string"),
+        "sku_lite": nullable("string"),
+        "title": { "type": "string" },
+        "subtitle": nullable("string"),
+        "authors": people,
+        "narrators": people,
+        "series": {
+            "type": ["array", "null"],
+            "items": {
+                "type": "object",
+                "required": ["title", "sequence"],
+                "properties": {
+                    "title": { "type": "string" },
+                    "sequence": nullable("string"),
+                },
+            },
+        },
+        "publisher_name": nullable("string"),
+        "release_date": { "type": ["string", "null"], "description": "YYYY-MM-DD" },
+        "publication_datetime": {
+            "type": ["string", "null"],
+            "description": "When the title becomes available, as RFC 3339, in the future for preorders",
+        },
+        "runtime_length_min": nullable("integer"),
+    })
+}
+
+fn product() -> Value {
+    json!({
+        "type": "object",
+        "required": ["asin", "title"],
+        "properties": product_properties(),
+    })
+}
+
+fn library() -> Value {
+    let mut properties = product_properties();
+    properties["purchase_date"] = json!({
+        "type": ["string", "null"],
+        "description": "ISO 8601 date and time",
+    });
+
+    json!({
+        "type": "array",
+        "items": {
+            "type": "object",
+            "required": ["asin", "title", "purchase_date"],
+            "properties": properties,
+        },
+    })
+}
+
+fn probe() -> Value {
+    json!({
+        "type": "object",
+        "required": ["length_secs", "bitrate", "sample_rate", "chapters", "catalog_length_min"],
+        "properties": {
+            "length_secs": { "type": "number" },
+            "bitrate": { "type": "integer", "description": "Average bits per second" },
+            "sample_rate": { "type": "integer", "description": "Hz" },
+            "chapters": { "type": "integer" },
+            "catalog_length_min": {
+                "type": ["integer", "null"],
+                "description": "Length in the Audible catalog, only with --asin",
+            },
+        },
+    })
+}
+
+fn stats() -> Value {
+    json!({
+        "type": "object",
+        "required": ["year", "total_minutes", "months", "badges"],
+        "properties": {
+            "year": { "type": "integer" },
+            "total_minutes": { "type": "integer" },
+            "months": {
+                "type": "array",
+                "items": {
+                    "type": "object",
+                    "required": ["month", "minutes"],
+                    "properties": {
+                        "month": { "type": "string", "description": "YYYY-MM" },
+                        "minutes": { "type": "integer" },
+                    },
+                },
+            },
+            "badges": { "description": "Badges as returned by the Audible API" },
+        },
+    })
+}
+
+/// Print the JSON Schema of the JSON output of a command
+pub fn run(args: SchemaArgs) -> Result<()> {
+    let (title, mut schema) = match args.command {
+        Output::Library => ("audible-dl library", library()),
+        Output::Info => ("audible-dl info", product()),
+        Output::Probe => ("audible-dl probe", probe()),
+        Output::Stats => ("audible-dl stats listening", stats()),
+    };
+
+    schema["$schema"] = json!("https://json-schema.org/draft/2020-12/schema");
+    schema["title"] = json!(title);
+
+    println!("{}", serde_json::to_string_pretty(&schema)?);
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::api::catalog::Product;
+    use crate::api::library::Item;
+
+    fn keys(value: &Value) -> Vec<&String> {
+        let mut keys = value.as_object().unwrap().keys().collect::<Vec<_>>();
+        keys.sort();
+        keys
+    }
+
+    #[test]
+    fn schemas_match_output() {
+        let product: Product =
+            serde_json::from_value(json!({ "asin": "A", "title": "T" })).unwrap();
+        let product = serde_json::to_value(product).unwrap();
+        assert_eq!(keys(&product), keys(&product_properties()));
+
+        let item: Item = serde_json::from_value(json!({ "asin": "A", "title": "T" })).unwrap();
+        let item = serde_json::to_value(item).unwrap();
+        assert_eq!(keys(&item), keys(&library()["items"]["properties"]));
+    }
+}
@@ -0,0 +1,312 @@
+//! `selftest`, running the whole pipeline against a fixture server, to check a build on a new
+//! platform without an Audible account.
+//!
+//! A synthetic AAX file, with the structure of a real one but noise for audio, is served from
+//! memory on a local port. The first response is cut off part way through, so the download has
+//! to resume. The downloaded file is then compared with the original and converted with the
+//! activation bytes it was made for.
+
+use std::fs::File;
+use std::path::Path;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+
+use anyhow::{bail, ensure, Context, Result};
+use indicatif::HumanBytes;
+use tokio::io::{AsyncBufReadExt, AsyncWriteExt, BufReader};
+use tokio::net::{TcpListener, TcpStream};
+
+use audible_dl_core::{aax, mp4, Hidden};
+
+use crate::{cache, download, style, DownloadOptions, Outcome};
+
+/// Activation bytes the fixture is locked with
+const ACTIVATION_BYTES: [u8; 4] = [0x1c, 0xeb, 0x00, 0xda];
+
+/// Body of an adrm box locking a file key with `ACTIVATION_BYTES`
+const ADRM: &str = "00000000000000000145e1ca5db7e1e436111570be4b1ac6d6dfb8f4a5b7ebe1129c656b86f2d6fbbf40cb20579e6acc0abcd9b95223547c0000000000000000000000007b19e237cd6eef8770b30a93fe165070ab199e54";
+
+/// Size of each audio sample of the fixture
+const SAMPLE_SIZE: u32 = 4096;
+
+/// Samples in each chunk, and chunks in the fixture, making it 8 MiB of audio
+const SAMPLES_PER_CHUNK: u32 = 64;
+const CHUNKS: u32 = 32;
+
+/// How far into the first response the connection is cut, as a fraction of the file
+const CUT_AT: f64 = 0.4;
+
+#[derive(clap::Args, Debug)]
+pub struct SelftestArgs {
+    /// Keep the directory with the downloaded and converted fixture, instead of removing it
+    /// once the test passes
+    #[arg(long, env = "AUDIBLE_DL_KEEP")]
+    keep: bool,
+
+    /// Options to download the fixture with, e.g. `--strategy pipelined`
+    #[command(flatten)]
+    options: DownloadOptions,
+}
+
+/// Download, resume, verify and convert a synthetic book served from a local port
+pub async fn run(mut args: SelftestArgs) -> Result<()> {
+    // Nothing of the fixture should leave the machine, or end up anywhere but the test directory
+    args.options.encrypt_output = None;
+    args.options.rclone_remote = None;
+    args.options.webhooks = None;
+    args.options.cache = None;
+    args.options.part_dir = None;
+    args.options.assume_yes = true;
+
+    let dir = std::env::temp_dir().join(format!("audible-dl-selftest-{}", std::process::id()));
+    tokio::fs::create_dir_all(&dir).await?;
+
+    let result = steps(&dir, &args.options).await;
+
+    match &result {
+        Ok(()) if !args.keep => tokio::fs::remove_dir_all(&dir).await?,
+        _ => eprintln!("The files of the test are in {}", dir.display()),
+    }
+
+    result?;
+
+    eprintln!("{}", style::success("Self-test passed"));
+
+    Ok(())
+}
+
+async fn steps(dir: &Path, options: &DownloadOptions) -> Result<()> {
+    let fixture = Arc::new(fixture());
+    let requests = Arc::new(AtomicUsize::new(0));
+
+    let listener = TcpListener::bind(("127.0.0.1", 0)).await?;
+    let url = format!("http://{}/fixture.aax", listener.local_addr()?);
+    tokio::spawn(serve(listener, fixture.clone(), requests.clone()));
+
+    eprintln!(
+        "Serving a synthetic AAX file of {} on {}",
+        HumanBytes(fixture.len() as u64),
+        url
+    );
+
+    let output = dir.join("fixture.aax");
+    let client = reqwest::Client::new();
+
+    let path = match download(&client, &url, &output, false, options, None).await? {
+        Outcome::Downloaded { path, .. } => path,
+        Outcome::Skipped => bail!("The download of the fixture was skipped"),
+    };
+
+    ensure!(
+        requests.load(Ordering::SeqCst) > 1,
+        "The download didn't resume after the connection was cut"
+    );
+    eprintln!(
+        "{} resumed after the connection was cut",
+        style::highlight("Download:")
+    );
+
+    let downloaded = tokio::fs::read(&path).await?;
+    ensure!(
+        downloaded == *fixture,
+        "The downloaded file differs from the fixture"
+    );
+    eprintln!("{} identical to the fixture", style::highlight("Verify:"));
+
+    tokio::task::spawn_blocking(move || convert(&path)).await??;
+    eprintln!(
+        "{} decrypted into an M4B file",
+        style::highlight("Convert:")
+    );
+
+    Ok(())
+}
+
+/// Decrypt the downloaded fixture into an M4B file next to it, and check that it's one
+fn convert(input: &Path) -> Result<()> {
+    let output = input.with_extension("m4b");
+    std::fs::copy(input, &output)?;
+
+    let key = aax::key(&output, ACTIVATION_BYTES).context("Failed to unlock the fixture")?;
+    aax::decrypt(&output, &key, &Hidden)?;
+
+    let len = std::fs::metadata(&output)?.len();
+    mp4::Verifier::new().finish(&output, len)?;
+
+    let entries = mp4::sample_entries(&mut File::open(&output)?)?;
+    ensure!(
+        entries == [*b"mp4a"],
+        "The converted file has {:?} audio",
+        entries
+    );
+
+    Ok(())
+}
+
+fn boxed(kind: &[u8; 4], body: &[u8]) -> Vec<u8> {
+    let mut result = (body.len() as u32 + 8).to_be_bytes().to_vec();
+    result.extend(kind);
+    result.extend(body);
+    result
+}
+
+/// A box with version and flags
+fn full(kind: &[u8; 4], body: &[u8]) -> Vec<u8> {
+    boxed(kind, &[&[0; 4], body].concat())
+}
+
+fn hex(s: &str) -> Vec<u8> {
+    (0..s.len())
+        .step_by(2)
+        .map(|i| u8::from_str_radix(&s[i..i + 2], 16).expect("hex"))
+        .collect()
+}
+
+/// An AAX file of one encrypted track, with noise for samples
+fn fixture() -> Vec<u8> {
+    let ftyp = boxed(b"ftyp", b"aax \0\0\0\x01aax M4B mp42isom");
+    let adrm = boxed(b"adrm", &hex(ADRM));
+    let entry = boxed(b"aavd", &[&[0; 28][..], &adrm].concat());
+
+    let moov = |mdat: u32| {
+        let chunk_size = SAMPLE_SIZE * SAMPLES_PER_CHUNK;
+        let offsets = (0..CHUNKS).map(|chunk| mdat + 8 + chunk * chunk_size);
+
+        let stsd = full(b"stsd", &[&1u32.to_be_bytes()[..], &entry].concat());
+        let stsc = full(
+            b"stsc",
+            &[1u32, 1, SAMPLES_PER_CHUNK, 1]
+                .map(u32::to_be_bytes)
+                .concat(),
+        );
+        let stsz = full(
+            b"stsz",
+            &[SAMPLE_SIZE, SAMPLES_PER_CHUNK * CHUNKS]
+                .map(u32::to_be_bytes)
+                .concat(),
+        );
+        let stco = full(
+            b"stco",
+            &std::iter::once(CHUNKS)
+                .chain(offsets)
+                .flat_map(u32::to_be_bytes)
+                .collect::<Vec<_>>(),
+        );
+        let stbl = boxed(b"stbl", &[stsd, stsc, stsz, stco].concat());
+        let trak = boxed(b"trak", &boxed(b"mdia", &boxed(b"minf", &stbl)));
+        boxed(b"moov", &trak)
+    };
+
+    let mdat = (ftyp.len() + moov(0).len()) as u32;
+
+    // Noise from xorshift, so that no two parts of the file look alike
+    let mut state = 0x2545_f491_4f6c_dd1du64;
+    let samples = (0..SAMPLE_SIZE * SAMPLES_PER_CHUNK * CHUNKS)
+        .map(|_| {
+            state ^= state << 13;
+            state ^= state >> 7;
+            state ^= state << 17;
+            state as u8
+        })
+        .collect::<Vec<_>>();
+
+    [ftyp, moov(mdat), boxed(b"mdat", &samples)].concat()
+}
+
+/// Serve `fixture` with `Range` support, cutting the first response off part way through
+async fn serve(listener: TcpListener, fixture: Arc<Vec<u8>>, requests: Arc<AtomicUsize>) {
+    loop {
+        let Ok((stream, _)) = listener.accept().await else {
+            continue;
+        };
+
+        let (fixture, requests) = (fixture.clone(), requests.clone());
+        let first = requests.fetch_add(1, Ordering::SeqCst) == 0;
+
+        tokio::spawn(async move {
+            // Nothing useful to do if the client goes away mid-request
+            let _ = respond(stream, &fixture, first).await;
+        });
+    }
+}
+
+async fn respond(stream: TcpStream, fixture: &[u8], cut: bool) -> std::io::Result<()> {
+    let mut stream = BufReader::new(stream);
+
+    let mut request_line = String::new();
+    stream.read_line(&mut request_line).await?;
+
+    let mut range = None;
+    let mut line = String::new();
+
+    while stream.read_line(&mut line).await? > 2 {
+        if let Some((name, value)) = line.split_once(':') {
+            if name.eq_ignore_ascii_case("range") {
+                range = cache::parse_range(value);
+            }
+        }
+        line.clear();
+    }
+
+    let mut stream = stream.into_inner();
+    let len = fixture.len();
+
+    let Some((start, end)) = range.filter(|(start, _)| (*start as usize) < len) else {
+        let head = format!(
+            "HTTP/1.1 416 Range Not Satisfiable\r\ncontent-range: bytes */{}\r\ncontent-length: 0\r\nconnection: close\r\n\r\n",
+            len
+        );
+        stream.write_all(head.as_bytes()).await?;
+        return stream.shutdown().await;
+    };
+
+    let start = start as usize;
+    let end = end.map_or(len, |end| (end as usize + 1).min(len));
+
+    let head = format!(
+        "HTTP/1.1 206 Partial Content\r\ncontent-type: audio/vnd.audible.aax\r\ncontent-length: {}\r\ncontent-range: bytes {}-{}/{}\r\nconnection: close\r\n\r\n",
+        end - start,
+        start,
+        end - 1,
+        len
+    );
+    stream.write_all(head.as_bytes()).await?;
+
+    let body = &fixture[start..end];
+
+    if cut {
+        let cut_at = ((len as f64 * CUT_AT) as usize).min(body.len() / 2);
+        stream.write_all(&body[..cut_at]).await?;
+        stream.flush().await?;
+        // Dropped without a shutdown, like a connection that's lost
+        return Ok(());
+    }
+
+    stream.write_all(body).await?;
+    stream.shutdown().await
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn builds_valid_fixture() {
+        let path =
+            std::env::temp_dir().join(format!("audible-dl-fixture-{}.aax", std::process::id()));
+        std::fs::write(&path, fixture()).unwrap();
+
+        let checksum = aax::checksum(&path);
+        let tracks = mp4::tracks(&mut File::open(&path).unwrap());
+        let converted = convert(&path);
+        std::fs::remove_file(&path).unwrap();
+        let _ = std::fs::remove_file(path.with_extension("m4b"));
+
+        assert_eq!(
+            checksum.unwrap(),
+            "7b19e237cd6eef8770b30a93fe165070ab199e54"
+        );
+        assert_eq!(tracks.unwrap().len(), 1);
+        converted.unwrap();
+    }
+}
@@ -0,0 +1,297 @@
+use std::net::SocketAddr;
+use std::path::PathBuf;
+use std::process::Command;
+
+use anyhow::{anyhow, bail, Context, Result};
+use clap::{Parser, Subcommand};
+
+use crate::{activation, Cli};
+
+const UNIT_NAME: &str = "audible-dl.service";
+
+#[derive(clap::Args, Debug)]
+pub struct ServiceArgs {
+    #[command(subcommand)]
+    command: ServiceCommand,
+}
+
+#[derive(Subcommand, Debug)]
+enum ServiceCommand {
+    /// Install and start a systemd user service that runs `watch` with the given arguments
+    Install(InstallArgs),
+
+    /// Print the unit file `install` would write, without installing it
+    Print(InstallArgs),
+
+    /// Stop and remove the installed service
+    Uninstall,
+}
+
+#[derive(clap::Args, Debug)]
+struct InstallArgs {
+    /// Append the output of the service to this file instead of the journal, rotated by `watch`
+    #[arg(long)]
+    log_file: Option<PathBuf>,
+
+    /// Only write the unit file, don't enable or start the service
+    #[arg(long)]
+    no_start: bool,
+
+    /// Let systemd listen for `/healthz` on this address, and start the service on the first
+    /// request instead of right away, e.g. `0.0.0.0:8080`
+    #[arg(long, value_name = "ADDR")]
+    health_socket: Option<SocketAddr>,
+
+    /// Let systemd listen for the cache of `--serve-cache` on this address, and start the service
+    /// on the first request instead of right away, e.g. `0.0.0.0:8081`
+    #[arg(long, value_name = "ADDR")]
+    cache_socket: Option<SocketAddr>,
+
+    /// Let systemd listen for the web UI of `--web-listen` on this address, and start the
+    /// service on the first request instead of right away, e.g. `0.0.0.0:8082`
+    #[arg(long, value_name = "ADDR")]
+    web_socket: Option<SocketAddr>,
+
+    /// Arguments for `watch`, e.g. `--customer-id <id> ~/Dropbox/audible`
+    #[arg(trailing_var_arg = true, allow_hyphen_values = true, required = true)]
+    watch_args: Vec<String>,
+}
+
+pub fn run(args: ServiceArgs) -> Result<()> {
+    match args.command {
+        ServiceCommand::Install(args) => install(&args),
+        ServiceCommand::Print(args) => {
+            let units = units(&args)?;
+
+            for (index, (name, unit)) in units.iter().enumerate() {
+                // Tell the units apart when there's more than one
+                if units.len() > 1 {
+                    if index > 0 {
+                        println!();
+                    }
+                    println!("# {}", name);
+                }
+                print!("{}", unit);
+            }
+
+            Ok(())
+        }
+        ServiceCommand::Uninstall => uninstall(),
+    }
+}
+
+fn unit_dir() -> Result<PathBuf> {
+    let config = match std::env::var_os("XDG_CONFIG_HOME") {
+        Some(dir) => PathBuf::from(dir),
+        None => std::env::var_os("HOME")
+            .map(|home| PathBuf::from(home).join(".config"))
+            .ok_or_else(|| anyhow!("Neither XDG_CONFIG_HOME nor HOME is set"))?,
+    };
+
+    Ok(config.join("systemd/user"))
+}
+
+/// Name of the socket unit for the socket `name` of `watch`
+fn socket_name(name: &str) -> String {
+    format!("audible-dl-{}.socket", name)
+}
+
+/// The socket unit that makes systemd listen on `addr`, and pass the socket as `name`
+fn socket_unit(name: &str, description: &str, addr: SocketAddr) -> String {
+    format!(
+        "[Unit]\n\
+         Description=audible-dl {}\n\
+         \n\
+         [Socket]\n\
+         ListenStream={}\n\
+         FileDescriptorName={}\n\
+         Service={}\n\
+         \n\
+         [Install]\n\
+         WantedBy=sockets.target\n",
+        description, addr, name, UNIT_NAME
+    )
+}
+
+/// The sockets systemd should listen on for `watch`, by name
+fn sockets(args: &InstallArgs) -> Vec<(&'static str, &'static str, SocketAddr)> {
+    [
+        ("health", "health endpoint", args.health_socket),
+        ("cache", "download cache", args.cache_socket),
+        ("web", "web UI", args.web_socket),
+    ]
+    .into_iter()
+    .filter_map(|(name, description, addr)| Some((name, description, addr?)))
+    .collect()
+}
+
+/// The service unit followed by the socket units, by file name
+fn units(args: &InstallArgs) -> Result<Vec<(String, String)>> {
+    let mut units = vec![(UNIT_NAME.to_owned(), unit(args)?)];
+
+    for (name, description, addr) in sockets(args) {
+        units.push((socket_name(name), socket_unit(name, description, addr)));
+    }
+
+    Ok(units)
+}
+
+/// The systemd unit running `watch` with `args`.
+///
+/// The configuration of the current invocation is kept, so `AUDIBLE_DL_*` environment variables
+/// are copied into the unit and relative paths are resolved from the current directory.
+fn unit(args: &InstallArgs) -> Result<String> {
+    // Catch mistakes now rather than in a restart loop of the service
+    let mut argv = vec!["audible-dl".to_owned(), "watch".to_owned()];
+    argv.extend(args.watch_args.iter().cloned());
+
+    if let Err(e) = Cli::try_parse_from(&argv) {
+        e.exit();
+    }
+
+    let exe = std::env::current_exe().context("Failed to find the audible-dl executable")?;
+    let cwd = std::env::current_dir()?;
+
+    let mut exec_start = vec![quote(&exe.to_string_lossy()), "watch".to_owned()];
+
+    // Let `watch` rotate the log
+    if let Some(log_file) = &args.log_file {
+        exec_start.push("--log-file".to_owned());
+        exec_start.push(quote(&cwd.join(log_file).to_string_lossy()));
+    }
+
+    exec_start.extend(args.watch_args.iter().map(|arg| quote(arg)));
+
+    let mut unit = format!(
+        "[Unit]\n\
+         Description=audible-dl watch folder\n\
+         \n\
+         [Service]\n\
+         ExecStart={}\n\
+         WorkingDirectory={}\n",
+        exec_start.join(" "),
+        escape(&cwd.to_string_lossy())
+    );
+
+    let mut vars: Vec<_> = std::env::vars()
+        .filter(|(name, _)| name.starts_with("AUDIBLE_DL_"))
+        .collect();
+    vars.sort();
+
+    for (name, value) in vars {
+        unit += &format!("Environment={}\n", quote(&format!("{}={}", name, value)));
+    }
+
+    if let Some(log_file) = &args.log_file {
+        let log_file = escape(&cwd.join(log_file).to_string_lossy());
+        unit += &format!("StandardOutput=append:{}\n", log_file);
+        unit += &format!("StandardError=append:{}\n", log_file);
+    }
+
+    unit += "Restart=on-failure\n\
+             RestartSec=30\n\
+             \n\
+             [Install]\n\
+             WantedBy=default.target\n";
+
+    Ok(unit)
+}
+
+/// Escape systemd specifiers in `s`
+fn escape(s: &str) -> String {
+    s.replace('%', "%%")
+}
+
+/// Quote `s` for use as a single word in a systemd command line
+fn quote(s: &str) -> String {
+    let escaped = escape(s).replace('$', "$$");
+
+    if !escaped.is_empty() && !escaped.contains(|c: char| c.is_whitespace() || "\"'\\;".contains(c))
+    {
+        return escaped;
+    }
+
+    format!("\"{}\"", escaped.replace('\\', "\\\\").replace('"', "\\\""))
+}
+
+fn systemctl(args: &[&str]) -> Result<()> {
+    let status = Command::new("systemctl")
+        .arg("--user")
+        .args(args)
+        .status()
+        .context("Failed to run systemctl")?;
+
+    if !status.success() {
+        bail!("systemctl --user {} failed ({})", args.join(" "), status);
+    }
+
+    Ok(())
+}
+
+fn install(args: &InstallArgs) -> Result<()> {
+    if !cfg!(target_os = "linux") {
+        bail!("Installing a service is only supported with systemd on Linux, run `watch` with your platform's service manager instead");
+    }
+
+    let dir = unit_dir()?;
+    std::fs::create_dir_all(&dir)?;
+
+    for (name, unit) in units(args)? {
+        let path = dir.join(name);
+        std::fs::write(&path, unit)
+            .with_context(|| format!("Failed to write {}", path.display()))?;
+        eprintln!("Wrote {}", path.display());
+    }
+
+    systemctl(&["daemon-reload"])?;
+
+    let sockets = sockets(args);
+
+    if !args.no_start && !sockets.is_empty() {
+        // The sockets start the service, on the first connection
+        for (name, _, addr) in sockets {
+            systemctl(&["enable", "--now", &socket_name(name)])?;
+            eprintln!(
+                "Listening on {}, audible-dl starts on the first connection",
+                addr
+            );
+        }
+    } else if !args.no_start {
+        systemctl(&["enable", "--now", UNIT_NAME])?;
+        eprintln!("Started {}", UNIT_NAME);
+
+        if args.log_file.is_none() {
+            eprintln!("Follow its output with `journalctl --user -u audible-dl`");
+        }
+    }
+
+    Ok(())
+}
+
+fn uninstall() -> Result<()> {
+    let dir = unit_dir()?;
+    let path = dir.join(UNIT_NAME);
+
+    if !path.exists() {
+        bail!("{} isn't installed", UNIT_NAME);
+    }
+
+    // Stop the sockets first, so that they don't start the service again
+    for name in activation::NAMES {
+        let socket = dir.join(socket_name(name));
+
+        if socket.exists() {
+            systemctl(&["disable", "--now", &socket_name(name)])?;
+            std::fs::remove_file(&socket)?;
+            eprintln!("Removed {}", socket.display());
+        }
+    }
+
+    systemctl(&["disable", "--now", UNIT_NAME])?;
+    std::fs::remove_file(&path)?;
+    systemctl(&["daemon-reload"])?;
+
+    eprintln!("Removed {}", path.display());
+
+    Ok(())
+}
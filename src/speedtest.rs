@@ -0,0 +1,135 @@
+//! Measuring the latency and throughput to the CDS, to tell whether `--strategy pipelined`
+//! would help.
+//!
+//! The start of the book is downloaded over one connection and then over several at once. When
+//! the connections together are much faster than one alone, each connection is throttled, which
+//! is what pipelining gets around. Nothing is written to disk.
+
+use std::time::{Duration, Instant};
+
+use anyhow::Result;
+use indicatif::{HumanBytes, HumanDuration};
+use tokio::task::JoinSet;
+
+use audible_dl_core::rangedl;
+
+use crate::{cds_url, http};
+
+/// How many requests to time for the latency
+const LATENCY_SAMPLES: u32 = 5;
+
+/// How long to wait for any response
+const RESPONSE_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// Parallel connections that are this much faster than one mean each connection is throttled
+const THROTTLED_RATIO: f64 = 1.5;
+
+#[derive(clap::Args, Debug)]
+pub struct SpeedtestArgs {
+    /// SKU of a book in your library to download from
+    #[arg(env = "AUDIBLE_DL_SPEEDTEST_SKU", required_unless_present = "url")]
+    sku: Option<String>,
+
+    /// Audible customer id
+    #[arg(long, env = "AUDIBLE_DL_CUSTOMER_ID", required_unless_present = "url")]
+    customer_id: Option<String>,
+
+    /// Audible user id, for accounts where it differs from the customer id
+    #[arg(long, env = "AUDIBLE_DL_USER_ID")]
+    user_id: Option<String>,
+
+    /// Download from this (signed) URL instead of building one from the SKU and customer id
+    #[arg(long, env = "AUDIBLE_DL_SPEEDTEST_URL", conflicts_with = "sku")]
+    url: Option<reqwest::Url>,
+
+    /// Seconds to download for, over one connection and then over several
+    #[arg(long, env = "AUDIBLE_DL_DURATION", default_value_t = 10)]
+    duration: u64,
+
+    /// How many connections to download over at once
+    #[arg(
+        long,
+        env = "AUDIBLE_DL_CONNECTIONS",
+        default_value_t = 4,
+        value_parser = clap::value_parser!(u32).range(2..=16)
+    )]
+    connections: u32,
+}
+
+fn print_row(name: &str, value: impl std::fmt::Display) {
+    println!("{:<15} {}", format!("{}:", name), value);
+}
+
+/// Measure the latency and throughput of downloading from the CDS
+pub async fn run(client: &reqwest::Client, args: SpeedtestArgs) -> Result<()> {
+    let url = match (&args.url, &args.sku, &args.customer_id) {
+        (Some(url), _, _) => url.to_string(),
+        (None, Some(sku), Some(customer_id)) => cds_url(customer_id, args.user_id.as_deref(), sku),
+        _ => unreachable!("clap requires either --url or a SKU and customer id"),
+    };
+
+    let options = rangedl::Options {
+        response_timeout: Some(RESPONSE_TIMEOUT),
+        user_agent: !http::overrides("User-Agent"),
+        ..rangedl::Options::default()
+    };
+    let duration = Duration::from_secs(args.duration);
+
+    eprintln!("Measuring latency...");
+
+    let mut latencies = Vec::new();
+    let mut size = 0;
+
+    for _ in 0..LATENCY_SAMPLES {
+        let started = Instant::now();
+        size = rangedl::size(client, &url, &options).await?;
+        latencies.push(started.elapsed());
+    }
+
+    let min = latencies.iter().min().copied().unwrap_or_default();
+    let avg = latencies.iter().sum::<Duration>() / LATENCY_SAMPLES;
+
+    print_row("Book size", HumanBytes(size));
+    print_row(
+        "Latency",
+        format!("min {} ms, avg {} ms", min.as_millis(), avg.as_millis()),
+    );
+
+    eprintln!(
+        "Downloading over 1 connection for {}...",
+        HumanDuration(duration)
+    );
+    let single = rangedl::measure(client, &url, &options, duration).await?;
+    print_row("1 connection", format!("{}/s", HumanBytes(single)));
+
+    eprintln!(
+        "Downloading over {} connections for {}...",
+        args.connections,
+        HumanDuration(duration)
+    );
+    let mut tasks = JoinSet::new();
+
+    for _ in 0..args.connections {
+        let (client, url, options) = (client.clone(), url.clone(), options.clone());
+        tasks.spawn(async move { rangedl::measure(&client, &url, &options, duration).await });
+    }
+
+    let mut parallel = 0;
+
+    while let Some(rate) = tasks.join_next().await {
+        parallel += rate??;
+    }
+
+    print_row(
+        &format!("{} connections", args.connections),
+        format!("{}/s", HumanBytes(parallel)),
+    );
+
+    if parallel as f64 > single as f64 * THROTTLED_RATIO {
+        println!("Each connection seems to be throttled, `--strategy pipelined` should be faster");
+    } else {
+        println!("More connections don't help, the default `--strategy single` is fine");
+    }
+
+    Ok(())
+}
@@ -0,0 +1,87 @@
+use anyhow::Result;
+use chrono::Datelike;
+use serde_json::json;
+
+use crate::api::ApiArgs;
+
+#[derive(clap::Args, Debug)]
+pub struct StatsArgs {
+    #[command(subcommand)]
+    command: StatsCommand,
+}
+
+#[derive(clap::Subcommand, Debug)]
+enum StatsCommand {
+    /// Export the listening time of each month, and the badges earned
+    Listening(ListeningArgs),
+}
+
+#[derive(clap::Args, Debug)]
+struct ListeningArgs {
+    /// Year to export, defaults to the current year
+    #[arg(long, env = "AUDIBLE_DL_YEAR")]
+    year: Option<i32>,
+
+    /// Output format, badges are only included in JSON
+    #[arg(long, env = "AUDIBLE_DL_STATS_FORMAT", value_enum, default_value_t = Format::Json)]
+    format: Format,
+
+    #[command(flatten)]
+    api: ApiArgs,
+}
+
+#[derive(clap::ValueEnum, Clone, Copy, Debug)]
+enum Format {
+    Json,
+    /// `month,minutes` lines
+    Csv,
+}
+
+pub async fn run(client: &reqwest::Client, args: StatsArgs) -> Result<()> {
+    match args.command {
+        StatsCommand::Listening(args) => listening(client, args).await,
+    }
+}
+
+/// Print the listening time of each month of the year
+async fn listening(client: &reqwest::Client, args: ListeningArgs) -> Result<()> {
+    let api = args.api.client(client.clone())?;
+    let year = args.year.unwrap_or_else(|| chrono::Utc::now().year());
+
+    let months = api.monthly_listening(year).await?;
+    let minutes = |ms: u64| ms / 60_000;
+
+    match args.format {
+        Format::Csv => {
+            println!("month,minutes");
+
+            for month in months {
+                println!(
+                    "{},{}",
+                    month.interval_identifier,
+                    minutes(month.aggregated_sum)
+                );
+            }
+        }
+        Format::Json => {
+            let total: u64 = months.iter().map(|month| month.aggregated_sum).sum();
+
+            let output = json!({
+                "year": year,
+                "total_minutes": minutes(total),
+                "months": months
+                    .iter()
+                    .map(|month| json!({
+                        "month": month.interval_identifier,
+                        "minutes": minutes(month.aggregated_sum),
+                    }))
+                    .collect::<Vec<_>>(),
+                "badges": api.badges().await?,
+            });
+
+            println!("{}", serde_json::to_string_pretty(&output)?);
+        }
+    }
+
+    Ok(())
+}
@@ -0,0 +1,220 @@
+//! `status`, an overview of a directory of downloaded books, and which of them are kept twice.
+//!
+//! A book that was converted usually still has its encrypted original next to the M4B file. The
+//! two are recognized as the same book by their content rather than their names: decrypting
+//! leaves the size of every audio sample as it was, so the same encode has the same sample
+//! sizes in either format.
+
+use std::collections::BTreeMap;
+use std::fs::File;
+use std::path::{Path, PathBuf};
+
+use anyhow::{Context, Result};
+use indicatif::HumanBytes;
+
+use audible_dl_core::mp4;
+
+use crate::export;
+
+/// Extensions of the files that are looked at
+const EXTENSIONS: [&str; 3] = ["aax", "aaxc", "m4b"];
+
+#[derive(clap::Args, Debug)]
+pub struct StatusArgs {
+    /// Directory with the downloaded books
+    #[arg(env = "AUDIBLE_DL_DIR", default_value = ".")]
+    dir: PathBuf,
+
+    /// Print the encrypted originals of books that are also there as complete M4B files, one
+    /// per line, e.g. to pass to `xargs rm`. Nothing is deleted.
+    #[arg(long, env = "AUDIBLE_DL_RECLAIM")]
+    reclaim: bool,
+}
+
+/// A downloaded or converted book
+#[derive(Debug)]
+struct Book {
+    path: PathBuf,
+    size: u64,
+    /// Whether the audio is still encrypted, as in AAX and AAXC files
+    encrypted: bool,
+    /// Size of each audio sample of each track, the same before and after decrypting
+    samples: Vec<Vec<u32>>,
+}
+
+impl Book {
+    fn read(path: &Path) -> Result<Book> {
+        let size = std::fs::metadata(path)?.len();
+
+        // A conversion that was cut short doesn't replace its original
+        mp4::Verifier::new().finish(path, size)?;
+
+        let mut file = File::open(path)?;
+        let mut encrypted = false;
+        let mut samples = Vec::new();
+
+        for track in mp4::tracks(&mut file)? {
+            let Some(entry) = &track.entry else {
+                continue;
+            };
+
+            encrypted |= &entry.kind == b"aavd";
+            samples.push(
+                mp4::chunks(&mut file, &track.stbl)?
+                    .into_iter()
+                    .flat_map(|chunk| chunk.sample_sizes)
+                    .collect(),
+            );
+        }
+
+        Ok(Book {
+            path: path.to_owned(),
+            size,
+            encrypted,
+            samples,
+        })
+    }
+
+    /// SKU the book was downloaded as, or its file name when it isn't named after one
+    fn name(&self) -> String {
+        let name = self.path.file_name().unwrap_or_default().to_string_lossy();
+
+        match name.split('.').next() {
+            Some(sku) if export::is_sku(sku) => sku.to_owned(),
+            _ => name.into_owned(),
+        }
+    }
+}
+
+/// Books in `dir`, skipping files that can't be read as one
+fn books(dir: &Path) -> Result<Vec<Book>> {
+    let mut books = Vec::new();
+
+    let entries =
+        std::fs::read_dir(dir).with_context(|| format!("Failed to read {}", dir.display()))?;
+
+    for entry in entries {
+        let path = entry?.path();
+
+        let is_book = path
+            .extension()
+            .and_then(|extension| extension.to_str())
+            .is_some_and(|extension| EXTENSIONS.contains(&extension));
+
+        if !is_book || !path.is_file() {
+            continue;
+        }
+
+        match Book::read(&path) {
+            Ok(book) => books.push(book),
+            Err(e) => eprintln!("Skipping {}: {:#}", path.display(), e),
+        }
+    }
+
+    books.sort_by(|a, b| a.path.cmp(&b.path));
+
+    Ok(books)
+}
+
+/// Books with the same audio, in more than one file, with at least one of them decrypted
+fn duplicates(books: &[Book]) -> Vec<Vec<&Book>> {
+    let mut groups = BTreeMap::<&[Vec<u32>], Vec<&Book>>::new();
+
+    for book in books.iter().filter(|book| !book.samples.is_empty()) {
+        groups.entry(&book.samples).or_default().push(book);
+    }
+
+    groups
+        .into_values()
+        .filter(|group| group.len() > 1 && group.iter().any(|book| !book.encrypted))
+        .collect()
+}
+
+/// Report the books in a directory, and the ones kept both encrypted and converted
+pub fn run(args: StatusArgs) -> Result<()> {
+    let books = books(&args.dir)?;
+    let duplicates = duplicates(&books);
+
+    let redundant = duplicates
+        .iter()
+        .flatten()
+        .filter(|book| book.encrypted)
+        .collect::<Vec<_>>();
+    let reclaimable = redundant.iter().map(|book| book.size).sum::<u64>();
+
+    if args.reclaim {
+        for book in &redundant {
+            println!("{}", book.path.display());
+        }
+
+        eprintln!(
+            "Deleting these {} files would free {}, their M4B files stay",
+            redundant.len(),
+            HumanBytes(reclaimable)
+        );
+
+        return Ok(());
+    }
+
+    for group in &duplicates {
+        let paths = group
+            .iter()
+            .map(|book| book.path.display().to_string())
+            .collect::<Vec<_>>();
+
+        println!("{}\t{}", group[0].name(), paths.join("\t"));
+    }
+
+    let encrypted = books.iter().filter(|book| book.encrypted).count();
+
+    eprintln!(
+        "{} files in {}: {} encrypted, {} converted",
+        books.len(),
+        args.dir.display(),
+        encrypted,
+        books.len() - encrypted
+    );
+
+    if !duplicates.is_empty() {
+        eprintln!(
+            "{} books are there both encrypted and converted, `--reclaim` lists the encrypted \
+             files, {} in all",
+            duplicates.len(),
+            HumanBytes(reclaimable)
+        );
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn book(path: &str, encrypted: bool, samples: &[u32]) -> Book {
+        Book {
+            path: path.into(),
+            size: 1,
+            encrypted,
+            samples: vec![samples.to_vec()],
+        }
+    }
+
+    #[test]
+    fn groups_by_content() {
+        let books = [
+            book("BK_ADBL_000001_22.aax", true, &[1, 2, 3]),
+            book("renamed.m4b", false, &[1, 2, 3]),
+            book("BK_ADBL_000002_22.aax", true, &[4, 5]),
+            book("BK_ADBL_000002_22 (1).aax", true, &[4, 5]),
+            book("BK_ADBL_000003_22.m4b", false, &[6]),
+        ];
+
+        let duplicates = duplicates(&books);
+
+        assert_eq!(duplicates.len(), 1);
+        assert_eq!(duplicates[0].len(), 2);
+        assert_eq!(duplicates[0][0].name(), "BK_ADBL_000001_22");
+        assert_eq!(books[1].name(), "renamed.m4b");
+    }
+}
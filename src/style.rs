@@ -0,0 +1,54 @@
+//! Colors of the output, following `--color` and the `NO_COLOR` convention.
+//!
+//! Everything is colored through `console`, like the progress bars, so turning colors off here
+//! turns them off there too. Messages for the user go to stderr, and are styled for it, except
+//! for [`highlight`].
+
+use console::{Style, StyledObject};
+
+#[derive(clap::ValueEnum, Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Color {
+    /// Color output on terminals, unless `NO_COLOR` is set
+    Auto,
+    Always,
+    Never,
+}
+
+/// Turn colors on or off for the rest of the program
+pub fn configure(color: Color) {
+    let no_color = std::env::var_os("NO_COLOR").is_some_and(|value| !value.is_empty());
+
+    let enabled = match color {
+        // `console` already checks whether stdout and stderr are terminals
+        Color::Auto if !no_color => return,
+        Color::Auto | Color::Never => false,
+        Color::Always => true,
+    };
+
+    console::set_colors_enabled(enabled);
+    console::set_colors_enabled_stderr(enabled);
+}
+
+fn apply<D>(style: Style, value: D) -> StyledObject<D> {
+    style.for_stderr().apply_to(value)
+}
+
+/// Errors, and counts of things that failed
+pub fn error<D>(value: D) -> StyledObject<D> {
+    apply(Style::new().red().bold(), value)
+}
+
+/// Warnings, and counts of things that were skipped
+pub fn warning<D>(value: D) -> StyledObject<D> {
+    apply(Style::new().yellow().bold(), value)
+}
+
+/// Things that went well, e.g. a completed download
+pub fn success<D>(value: D) -> StyledObject<D> {
+    apply(Style::new().green().bold(), value)
+}
+
+/// Values filled in from the options, in output on stdout
+pub fn highlight<D>(value: D) -> StyledObject<D> {
+    Style::new().cyan().bold().apply_to(value)
+}
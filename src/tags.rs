@@ -0,0 +1,189 @@
+//! Tags and notes of titles, kept on this machine with `audible-dl tag-local`.
+//!
+//! They're stored by SKU in a JSON file in the data directory of the user, and nothing about them
+//! is sent to Audible. `library --tag` lists the titles with a tag.
+
+use std::collections::{BTreeMap, BTreeSet};
+use std::path::PathBuf;
+
+use anyhow::{bail, Context, Result};
+use serde::{Deserialize, Serialize};
+
+use crate::{export, paths};
+
+#[derive(clap::Args, Debug)]
+pub struct TagLocalArgs {
+    /// SKU of the title to tag, lists all tagged titles when left out
+    #[arg(value_parser = parse_sku)]
+    sku: Option<String>,
+
+    /// Add a tag, e.g. `favorite`
+    #[arg(long, value_name = "TAG", requires = "sku")]
+    add: Vec<String>,
+
+    /// Remove a tag
+    #[arg(long, value_name = "TAG", requires = "sku")]
+    remove: Vec<String>,
+
+    /// Set the note of the title
+    #[arg(long, requires = "sku")]
+    note: Option<String>,
+
+    /// Remove the note of the title
+    #[arg(long, requires = "sku", conflicts_with = "note")]
+    clear_note: bool,
+}
+
+fn parse_sku(s: &str) -> Result<String, String> {
+    match export::is_sku(s) {
+        true => Ok(s.to_owned()),
+        false => Err(format!("{:?} isn't a SKU, e.g. BK_ADBL_000123", s)),
+    }
+}
+
+#[derive(Serialize, Deserialize, Default, Debug)]
+struct Entry {
+    #[serde(default, skip_serializing_if = "BTreeSet::is_empty")]
+    tags: BTreeSet<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    note: Option<String>,
+}
+
+impl Entry {
+    fn is_empty(&self) -> bool {
+        self.tags.is_empty() && self.note.is_none()
+    }
+}
+
+/// Tags and notes of all titles, by SKU
+#[derive(Serialize, Deserialize, Default, Debug)]
+pub struct Tags(BTreeMap<String, Entry>);
+
+/// Path of the tags file, `AUDIBLE_DL_TAGS_FILE` or `tags.json` in the data directory
+pub fn path() -> Result<PathBuf> {
+    if let Some(path) = std::env::var_os("AUDIBLE_DL_TAGS_FILE") {
+        return Ok(PathBuf::from(path));
+    }
+
+    Ok(paths::data_dir()?.join("tags.json"))
+}
+
+impl Tags {
+    /// Load the tags file, or no tags if there is none yet
+    pub fn load() -> Result<Tags> {
+        let path = path()?;
+
+        match std::fs::read(&path) {
+            Ok(contents) => serde_json::from_slice(&contents)
+                .with_context(|| format!("Invalid tags in {}", path.display())),
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(Tags::default()),
+            Err(e) => Err(e).with_context(|| format!("Failed to read {}", path.display())),
+        }
+    }
+
+    fn save(&self) -> Result<()> {
+        let path = path()?;
+
+        if let Some(dir) = path.parent() {
+            std::fs::create_dir_all(dir)
+                .with_context(|| format!("Failed to create {}", dir.display()))?;
+        }
+
+        std::fs::write(&path, serde_json::to_vec_pretty(self)?)
+            .with_context(|| format!("Failed to write {}", path.display()))
+    }
+
+    /// Whether the title with `sku` has every one of `tags`
+    pub fn has_all(&self, sku: &str, tags: &[String]) -> bool {
+        let Some(entry) = self.0.get(sku) else {
+            return tags.is_empty();
+        };
+
+        tags.iter().all(|tag| entry.tags.contains(tag))
+    }
+}
+
+fn print(sku: &str, entry: &Entry) {
+    let tags = entry.tags.iter().cloned().collect::<Vec<_>>();
+
+    println!(
+        "{}\t{}\t{}",
+        sku,
+        match tags.is_empty() {
+            true => "-".to_owned(),
+            false => tags.join(","),
+        },
+        entry.note.as_deref().unwrap_or("-")
+    );
+}
+
+/// Change the tags and the note of a title, and print them
+pub fn run(args: TagLocalArgs) -> Result<()> {
+    let mut tags = Tags::load()?;
+
+    let Some(sku) = args.sku else {
+        for (sku, entry) in &tags.0 {
+            print(sku, entry);
+        }
+
+        return Ok(());
+    };
+
+    if let Some(tag) = args
+        .add
+        .iter()
+        .find(|tag| tag.is_empty() || tag.contains(','))
+    {
+        bail!(
+            "{:?} can't be a tag, tags can't be empty or contain commas",
+            tag
+        );
+    }
+
+    let entry = tags.0.entry(sku.clone()).or_default();
+    let changed =
+        !args.add.is_empty() || !args.remove.is_empty() || args.note.is_some() || args.clear_note;
+
+    entry.tags.extend(args.add);
+
+    for tag in &args.remove {
+        entry.tags.remove(tag);
+    }
+
+    if let Some(note) = args.note {
+        entry.note = Some(note);
+    }
+
+    if args.clear_note {
+        entry.note = None;
+    }
+
+    print(&sku, entry);
+
+    if entry.is_empty() {
+        tags.0.remove(&sku);
+    }
+
+    if changed {
+        tags.save()?;
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn matches_tags() {
+        let tags: Tags =
+            serde_json::from_str(r#"{"BK_ADBL_000123": {"tags": ["favorite", "road-trip"]}}"#)
+                .unwrap();
+
+        assert!(tags.has_all("BK_ADBL_000123", &["favorite".to_owned()]));
+        assert!(!tags.has_all("BK_ADBL_000123", &["sleep".to_owned()]));
+        assert!(!tags.has_all("BK_ADBL_000456", &["favorite".to_owned()]));
+        assert!(tags.has_all("BK_ADBL_000456", &[]));
+    }
+}
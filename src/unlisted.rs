@@ -0,0 +1,108 @@
+//! Finding downloaded books that are no longer in the library, because they were returned or
+//! taken off Audible.
+//!
+//! Books are recognized by their file names, which start with the SKU they were downloaded as,
+//! e.g. `<sku>.aax`, `<sku>.m4b` or `<sku>.aax.part`.
+
+use std::collections::BTreeMap;
+use std::path::{Path, PathBuf};
+
+use anyhow::{Context, Result};
+
+use crate::api::ApiArgs;
+use crate::entitlement::Entitlements;
+use crate::export;
+
+const UNLISTED_DIR: &str = "unlisted";
+
+#[derive(clap::Args, Debug)]
+pub struct UnlistedArgs {
+    /// Directory with the downloaded books
+    #[arg(env = "AUDIBLE_DL_DIR", default_value = ".")]
+    dir: PathBuf,
+
+    /// Move the files of unlisted books into an `unlisted` directory next to them
+    #[arg(long = "move", env = "AUDIBLE_DL_MOVE")]
+    move_files: bool,
+
+    #[command(flatten)]
+    api: ApiArgs,
+}
+
+/// Files in `dir` that are named after a SKU, by SKU
+fn files_by_sku(dir: &Path) -> Result<BTreeMap<String, Vec<PathBuf>>> {
+    let mut files = BTreeMap::<String, Vec<PathBuf>>::new();
+
+    let entries =
+        std::fs::read_dir(dir).with_context(|| format!("Failed to read {}", dir.display()))?;
+
+    for entry in entries {
+        let entry = entry?;
+
+        if !entry.file_type()?.is_file() {
+            continue;
+        }
+
+        let name = entry.file_name();
+        let Some(sku) = name.to_str().and_then(|name| name.split('.').next()) else {
+            continue;
+        };
+
+        if export::is_sku(sku) {
+            files.entry(sku.to_owned()).or_default().push(entry.path());
+        }
+    }
+
+    for paths in files.values_mut() {
+        paths.sort();
+    }
+
+    Ok(files)
+}
+
+/// Report downloaded books that aren't in the library anymore, and optionally move them away
+pub async fn run(client: &reqwest::Client, args: UnlistedArgs) -> Result<()> {
+    let files = files_by_sku(&args.dir)?;
+    let entitlements = Entitlements::load(client, &args.api).await?;
+
+    let unlisted = files
+        .iter()
+        .filter(|(sku, _)| !entitlements.owns(sku))
+        .collect::<Vec<_>>();
+
+    let target = args.dir.join(UNLISTED_DIR);
+
+    if args.move_files && !unlisted.is_empty() {
+        tokio::fs::create_dir_all(&target)
+            .await
+            .with_context(|| format!("Failed to create {}", target.display()))?;
+    }
+
+    for (sku, paths) in &unlisted {
+        for path in *paths {
+            println!("{}\t{}", sku, path.display());
+
+            if args.move_files {
+                let moved = target.join(path.file_name().unwrap_or_default());
+
+                tokio::fs::rename(path, &moved).await.with_context(|| {
+                    format!("Failed to move {} to {}", path.display(), moved.display())
+                })?;
+            }
+        }
+    }
+
+    eprintln!(
+        "{} of {} books in {} are not in your library on audible.{}",
+        unlisted.len(),
+        files.len(),
+        args.dir.display(),
+        entitlements.domain()
+    );
+
+    if args.move_files && !unlisted.is_empty() {
+        eprintln!("Moved their files to {}", target.display());
+    }
+
+    Ok(())
+}
@@ -0,0 +1,57 @@
+use anyhow::{Context, Result};
+use self_update::backends::github::Update;
+use self_update::{UpdateConfig, VersionStatus};
+
+#[derive(clap::Args, Debug)]
+pub struct SelfUpdateArgs {
+    /// Only check whether a newer release is available, don't install it
+    #[arg(long, env = "AUDIBLE_DL_CHECK")]
+    check: bool,
+
+    /// Replace the binary without asking for confirmation
+    #[arg(short = 'y', long, env = "AUDIBLE_DL_UPDATE_ASSUME_YES")]
+    yes: bool,
+}
+
+/// Check GitHub releases for a newer version, and optionally replace the running binary with it.
+///
+/// Nothing is sent anywhere except the request for the release list.
+pub async fn run(args: SelfUpdateArgs) -> Result<()> {
+    // self_update uses a blocking HTTP client and may prompt on stdin
+    tokio::task::spawn_blocking(move || run_blocking(&args)).await?
+}
+
+fn run_blocking(args: &SelfUpdateArgs) -> Result<()> {
+    let updater = Update::configure()
+        .repo_owner("LinusU")
+        .repo_name("audible-dl")
+        .bin_name("audible-dl")
+        .current_version(self_update::cargo_crate_version!())
+        .show_download_progress(true)
+        .no_confirm(args.yes)
+        .build()?;
+
+    if args.check {
+        let releases = updater
+            .get_latest_release()
+            .context("Failed to fetch the latest release")?;
+
+        match releases.latest() {
+            Some(latest) if releases.is_update_available()? => eprintln!(
+                "Update available: {} -> {}, run `audible-dl self-update` to install it",
+                updater.current_version(),
+                latest.version()
+            ),
+            _ => eprintln!("audible-dl {} is up to date", updater.current_version()),
+        }
+
+        return Ok(());
+    }
+
+    match updater.update()? {
+        VersionStatus::Updated(version) => eprintln!("Updated audible-dl to {}", version),
+        status => eprintln!("audible-dl {} is up to date", status.version()),
+    }
+
+    Ok(())
+}
@@ -0,0 +1,302 @@
+use std::net::SocketAddr;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+
+use anyhow::{anyhow, Context, Result};
+
+use crate::activation::{self, Sockets};
+use crate::api::ApiArgs;
+use crate::plan::Plan;
+use crate::report::Report;
+use audible_dl_core::rangedl;
+
+use crate::{
+    budget, cache, cds_url, db, download, health, instance, logrotate, part_path, preorder, style,
+    web, DownloadOptions, Outcome,
+};
+
+#[derive(clap::Args, Debug)]
+#[command(mut_group("credentials", |group| group.required(false)))]
+pub struct WatchArgs {
+    /// Directory to watch for `*.sku` files
+    #[arg(env = "AUDIBLE_DL_WATCH_DIR")]
+    dir: PathBuf,
+
+    /// Audible customer id
+    #[arg(long, env = "AUDIBLE_DL_CUSTOMER_ID")]
+    customer_id: String,
+
+    /// Audible user id, for accounts where it differs from the customer id
+    #[arg(long, env = "AUDIBLE_DL_USER_ID")]
+    user_id: Option<String>,
+
+    /// Directory to save the downloaded books in
+    #[arg(long, env = "AUDIBLE_DL_OUTPUT_DIR", default_value = ".")]
+    output_dir: PathBuf,
+
+    /// Seconds to wait between scans of the watched directory
+    #[arg(long, env = "AUDIBLE_DL_INTERVAL", default_value_t = 10)]
+    interval: u64,
+
+    /// Address to serve the `/healthz` endpoint on, e.g. `0.0.0.0:8080`
+    #[arg(long, env = "AUDIBLE_DL_HEALTH_LISTEN")]
+    health_listen: Option<SocketAddr>,
+
+    /// Address to serve the books in the output directory on, to other machines of the same
+    /// Audible account running with `--cache`, e.g. `0.0.0.0:8081`
+    #[arg(long, env = "AUDIBLE_DL_SERVE_CACHE")]
+    serve_cache: Option<SocketAddr>,
+
+    /// Address to serve a web UI on, showing the queue and progress and taking titles to
+    /// download, e.g. `0.0.0.0:8082`
+    #[arg(long, env = "AUDIBLE_DL_WEB_LISTEN")]
+    web_listen: Option<SocketAddr>,
+
+    /// Directory to write a `report-<timestamp>.json` to after each `.sku` file, defaults to
+    /// `reports/` in the watched directory
+    #[arg(long, env = "AUDIBLE_DL_REPORT_DIR")]
+    report_dir: Option<PathBuf>,
+
+    /// Make an audible-dl already watching the directory stop and hand over to this one
+    #[arg(long, env = "AUDIBLE_DL_TAKEOVER")]
+    takeover: bool,
+
+    /// Download the preorders in your library as soon as they're released, needs credentials for
+    /// the API, e.g. `--auth-file`
+    #[arg(long, env = "AUDIBLE_DL_PREORDERS", requires = "credentials")]
+    preorders: bool,
+
+    #[command(flatten)]
+    log: logrotate::LogArgs,
+
+    #[command(flatten)]
+    options: DownloadOptions,
+
+    #[command(flatten)]
+    api: Option<ApiArgs>,
+}
+
+/// Keep downloading the books listed in `*.sku` files dropped into the watched directory.
+///
+/// Each file lists one SKU per line, blank lines and lines starting with `#` are ignored.
+/// Once every book in a file has been downloaded it's moved to `done/`, if any of them
+/// fails it's moved to `failed/` instead. Either way a report of the batch is written.
+pub async fn run(client: &reqwest::Client, args: WatchArgs) -> Result<()> {
+    let _lock = instance::acquire(&args.dir, args.takeover).await?;
+    tokio::spawn(instance::watch_takeover(args.dir.clone()));
+    tokio::spawn(logrotate::watch(args.log.clone()));
+
+    let done = args.dir.join("done");
+    let failed = args.dir.join("failed");
+
+    tokio::fs::create_dir_all(&done).await?;
+    tokio::fs::create_dir_all(&failed).await?;
+
+    // Sockets from systemd socket activation take the place of the addresses
+    let mut sockets = Sockets::from_env()?;
+
+    if let Some(listener) = sockets.listener("health", args.health_listen).await? {
+        tokio::spawn(health::serve(listener));
+    }
+
+    if let Some(listener) = sockets.listener("cache", args.serve_cache).await? {
+        tokio::spawn(cache::serve(listener, args.output_dir.clone()));
+    }
+
+    if let Some(listener) = sockets.listener("web", args.web_listen).await? {
+        let watcher = web::Watcher {
+            dir: args.dir.clone(),
+            report_dir: report_dir(&args),
+            api: match &args.api {
+                Some(api) => Some(api.client(client.clone())?),
+                None => None,
+            },
+        };
+        tokio::spawn(web::serve(listener, watcher));
+    }
+
+    for name in sockets.unused() {
+        eprintln!(
+            "{} ignoring socket {:?} from systemd, set `FileDescriptorName=` to one of {}",
+            style::warning("Warning:"),
+            name,
+            activation::NAMES.join(", ")
+        );
+    }
+
+    if let (true, Some(api)) = (args.preorders, &args.api) {
+        let api = api.client(client.clone())?;
+        tokio::spawn(preorder::watch(api, args.dir.clone()));
+    }
+
+    eprintln!("Watching {} for .sku files", args.dir.display());
+
+    loop {
+        for trigger in pending(&args.dir).await? {
+            let result = process(client, &trigger, &args).await;
+
+            // Leave the file for the watcher taking over
+            if rangedl::stopped() {
+                return Ok(());
+            }
+
+            let target = match result {
+                Ok(()) => &done,
+                Err(e) => {
+                    eprintln!(
+                        "{} {}: {:#}",
+                        style::error("Failed to process"),
+                        trigger.display(),
+                        e
+                    );
+                    &failed
+                }
+            };
+
+            let name = trigger.file_name().expect("trigger file name");
+            tokio::fs::rename(&trigger, target.join(name)).await?;
+        }
+
+        tokio::time::sleep(Duration::from_secs(args.interval)).await;
+
+        if rangedl::stopped() {
+            return Ok(());
+        }
+    }
+}
+
+/// List all `*.sku` files in `dir`, sorted by name
+pub async fn pending(dir: &Path) -> Result<Vec<PathBuf>> {
+    let mut result = Vec::new();
+    let mut entries = tokio::fs::read_dir(dir).await?;
+
+    while let Some(entry) = entries.next_entry().await? {
+        let path = entry.path();
+
+        if path.extension().is_some_and(|ext| ext == "sku") && entry.file_type().await?.is_file() {
+            result.push(path);
+        }
+    }
+
+    result.sort();
+
+    Ok(result)
+}
+
+/// The SKUs listed in the contents of a `.sku` file
+pub fn skus(contents: &str) -> impl Iterator<Item = &str> {
+    contents
+        .lines()
+        .map(str::trim)
+        .filter(|line| !line.is_empty() && !line.starts_with('#'))
+}
+
+/// Where the reports of batches are written
+fn report_dir(args: &WatchArgs) -> PathBuf {
+    match &args.report_dir {
+        Some(dir) => dir.clone(),
+        None => args.dir.join("reports"),
+    }
+}
+
+async fn process(client: &reqwest::Client, trigger: &Path, args: &WatchArgs) -> Result<()> {
+    let contents = tokio::fs::read_to_string(trigger)
+        .await
+        .with_context(|| format!("Failed to read {}", trigger.display()))?;
+
+    let skus = skus(&contents).collect::<Vec<_>>();
+    let db = db::shared();
+
+    if let Some(db) = &db {
+        db.enqueue(trigger, &skus)?;
+    }
+
+    let mut report = Report::new(trigger);
+
+    let batch = skus
+        .into_iter()
+        .map(|sku| {
+            let output = args.output_dir.join(format!("{}.aax", sku));
+            let url = cds_url(&args.customer_id, args.user_id.as_deref(), sku);
+            (sku, url, output)
+        })
+        .collect::<Vec<_>>();
+
+    let mut plan = None;
+
+    if args.options.estimate {
+        let urls = batch
+            .iter()
+            .map(|(_, url, output)| (url.clone(), output.clone()))
+            .collect::<Vec<_>>();
+
+        plan = Some(Plan::probe(client, &urls, &args.options).await);
+    }
+
+    let stop_at = budget::stop_at(args.options.max_total_time);
+    let mut remaining = Vec::new();
+
+    for (index, (sku, url, output)) in batch.iter().enumerate() {
+        if let Some(path) = db::downloaded(sku).filter(|path| path != output) {
+            eprintln!("Skipping download, {} is in {}", sku, path.display());
+            report.push(sku, &Ok(Outcome::Skipped));
+            continue;
+        }
+
+        if !remaining.is_empty() || budget::is_up(stop_at) {
+            remaining.push(sku.to_string());
+            continue;
+        }
+
+        let part = part_path(output, args.options.part_dir.as_deref());
+        web::set_current(Some((sku.to_string(), part)));
+
+        let result = download(client, url, output, true, &args.options, stop_at).await;
+        web::set_current(None);
+
+        if rangedl::stopped() {
+            return Ok(());
+        }
+
+        if result.as_ref().is_err_and(budget::ran_out) {
+            remaining.push(sku.to_string());
+            continue;
+        }
+
+        if let Some(plan) = &mut plan {
+            plan.finish(index, matches!(result, Ok(Outcome::Downloaded { .. })));
+        }
+
+        report.push(sku, &result);
+    }
+
+    let report_dir = report_dir(args);
+
+    report.print_summary();
+    let path = report.write(&report_dir).await?;
+
+    if let Some(db) = &db {
+        db.record(&report)?;
+    }
+
+    // Written next to where the `.sku` file ends up, to be moved back into the watched directory
+    if !remaining.is_empty() {
+        let failed = args.dir.join("failed");
+        let remaining_path = budget::write_remaining(&failed, trigger, &remaining).await?;
+
+        return Err(anyhow!(
+            "The time was up with {} titles left, move {} back to continue",
+            remaining.len(),
+            remaining_path.display()
+        ));
+    }
+
+    if report.has_failures() {
+        return Err(match path {
+            Some(path) => anyhow!("Not all books were downloaded, see {}", path.display()),
+            None => anyhow!("Not all books were downloaded"),
+        });
+    }
+
+    Ok(())
+}
@@ -0,0 +1,313 @@
+//! A small web UI for the watch folder, for `watch --web-listen`.
+//!
+//! It shows the `.sku` files waiting in the watched directory, the book being downloaded and the
+//! reports of earlier batches, and takes SKUs or ASINs to download. A requested title is dropped
+//! into the watched directory as a `web-<sku>.sku` file, like anyone with access to the folder
+//! would. There's no authentication, it's meant for the home network.
+
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+
+use anyhow::{anyhow, bail, Result};
+use serde_json::{json, Value};
+use tokio::io::{AsyncBufReadExt, AsyncReadExt, AsyncWriteExt, BufReader};
+use tokio::net::{TcpListener, TcpStream};
+
+use crate::{api, db, export, watch};
+
+const INDEX: &str = include_str!("web/index.html");
+
+/// How many of the latest reports to show
+const HISTORY_LEN: usize = 20;
+
+/// Largest request body that's read, enough for a form with one field
+const MAX_BODY: u64 = 4096;
+
+/// The book being downloaded by the watcher, see [`set_current`]
+static CURRENT: Mutex<Option<(String, PathBuf)>> = Mutex::new(None);
+
+/// Tell the web UI that the book `sku` is being downloaded into `part`, or that nothing is
+pub fn set_current(current: Option<(String, PathBuf)>) {
+    *CURRENT.lock().unwrap() = current;
+}
+
+/// What the web UI needs to know about the watcher
+pub struct Watcher {
+    /// The watched directory
+    pub dir: PathBuf,
+    /// Where the reports of batches are written
+    pub report_dir: PathBuf,
+    /// Client for looking up ASINs in the library, with `--auth-file`
+    pub api: Option<api::Client>,
+}
+
+/// Serve the web UI of `watcher`
+pub async fn serve(listener: TcpListener, watcher: Watcher) {
+    let watcher = Arc::new(watcher);
+
+    loop {
+        match listener.accept().await {
+            Ok((stream, _)) => {
+                let watcher = watcher.clone();
+                tokio::spawn(async move {
+                    // Nothing useful to do if the client goes away mid-request
+                    let _ = respond(stream, &watcher).await;
+                });
+            }
+            Err(e) => eprintln!("Web UI failed to accept connection: {}", e),
+        }
+    }
+}
+
+struct Request {
+    method: String,
+    path: String,
+    body: String,
+}
+
+async fn read_request(stream: &mut BufReader<TcpStream>) -> std::io::Result<Request> {
+    let mut request_line = String::new();
+    stream.read_line(&mut request_line).await?;
+
+    let mut parts = request_line.split_whitespace();
+    let method = parts.next().unwrap_or_default().to_owned();
+    let path = parts.next().unwrap_or_default().to_owned();
+
+    let mut headers = HashMap::new();
+    let mut line = String::new();
+
+    while stream.read_line(&mut line).await? > 2 {
+        if let Some((name, value)) = line.split_once(':') {
+            headers.insert(name.trim().to_ascii_lowercase(), value.trim().to_owned());
+        }
+        line.clear();
+    }
+
+    let len = headers
+        .get("content-length")
+        .and_then(|len| len.parse::<u64>().ok())
+        .unwrap_or(0);
+
+    let mut body = String::new();
+    stream
+        .take(len.min(MAX_BODY))
+        .read_to_string(&mut body)
+        .await?;
+
+    Ok(Request { method, path, body })
+}
+
+async fn respond(stream: TcpStream, watcher: &Watcher) -> std::io::Result<()> {
+    let mut stream = BufReader::new(stream);
+    let request = read_request(&mut stream).await?;
+
+    let (status, content_type, body) = match (request.method.as_str(), request.path.as_str()) {
+        ("GET", "/") => ("200 OK", "text/html; charset=utf-8", INDEX.to_owned()),
+        ("GET", "/api/status") => match status(watcher).await {
+            Ok(status) => ("200 OK", "application/json", status.to_string()),
+            Err(e) => error("500 Internal Server Error", e),
+        },
+        ("POST", "/api/enqueue") => match enqueue(watcher, &request.body).await {
+            Ok(sku) => (
+                "200 OK",
+                "application/json",
+                json!({ "sku": sku }).to_string(),
+            ),
+            Err(e) => error("400 Bad Request", e),
+        },
+        _ => ("404 Not Found", "text/plain", "Not found\n".to_owned()),
+    };
+
+    let response = format!(
+        "HTTP/1.1 {}\r\ncontent-type: {}\r\ncontent-length: {}\r\ncache-control: no-store\r\nconnection: close\r\n\r\n{}",
+        status,
+        content_type,
+        body.len(),
+        body
+    );
+
+    let mut stream = stream.into_inner();
+    stream.write_all(response.as_bytes()).await?;
+    stream.shutdown().await
+}
+
+fn error(status: &'static str, e: anyhow::Error) -> (&'static str, &'static str, String) {
+    let body = json!({ "error": format!("{:#}", e) }).to_string();
+    (status, "application/json", body)
+}
+
+/// The queue, the book being downloaded and the latest reports, as JSON
+async fn status(watcher: &Watcher) -> Result<Value> {
+    let mut queue = Vec::new();
+
+    for path in watch::pending(&watcher.dir).await? {
+        let contents = tokio::fs::read_to_string(&path).await.unwrap_or_default();
+        let skus = watch::skus(&contents).collect::<Vec<_>>();
+        let name = path.file_name().unwrap_or_default().to_string_lossy();
+
+        queue.push(json!({ "file": name, "skus": skus }));
+    }
+
+    let current = CURRENT.lock().unwrap().clone();
+    let current = match current {
+        Some((sku, part)) => {
+            let position = match tokio::fs::metadata(&part).await {
+                Ok(metadata) => metadata.len(),
+                Err(_) => 0,
+            };
+            let total = db::sidecars(false)
+                .load(&part)
+                .await
+                .ok()
+                .and_then(|sidecar| sidecar.total);
+
+            json!({ "sku": sku, "position": position, "total": total })
+        }
+        None => Value::Null,
+    };
+
+    Ok(json!({
+        "queue": queue,
+        "current": current,
+        "history": history(&watcher.report_dir).await,
+    }))
+}
+
+/// The latest reports in `dir`, newest first
+async fn history(dir: &Path) -> Vec<Value> {
+    let Ok(mut entries) = tokio::fs::read_dir(dir).await else {
+        return Vec::new();
+    };
+
+    let mut paths = Vec::new();
+
+    while let Ok(Some(entry)) = entries.next_entry().await {
+        let path = entry.path();
+        let name = path.file_name().unwrap_or_default().to_string_lossy();
+
+        if name.starts_with("report-") && name.ends_with(".json") {
+            paths.push(path);
+        }
+    }
+
+    // The timestamps in the names sort like the times
+    paths.sort();
+    paths.reverse();
+
+    let mut reports = Vec::new();
+
+    for path in paths.into_iter().take(HISTORY_LEN) {
+        if let Ok(contents) = tokio::fs::read(&path).await {
+            if let Ok(report) = serde_json::from_slice::<Value>(&contents) {
+                reports.push(report);
+            }
+        }
+    }
+
+    reports
+}
+
+/// The value of the field `name` of a form, as sent by browsers
+fn form_value(body: &str, name: &str) -> Option<String> {
+    body.split('&').find_map(|pair| {
+        let (key, value) = pair.split_once('=')?;
+        (key == name).then(|| decode(value))
+    })
+}
+
+/// Decode a URL encoded form value
+fn decode(value: &str) -> String {
+    let bytes = value.as_bytes();
+    let mut decoded = Vec::with_capacity(bytes.len());
+    let mut i = 0;
+
+    while i < bytes.len() {
+        let hex = bytes
+            .get(i + 1..i + 3)
+            .and_then(|hex| std::str::from_utf8(hex).ok())
+            .and_then(|hex| u8::from_str_radix(hex, 16).ok());
+
+        match (bytes[i], hex) {
+            (b'+', _) => decoded.push(b' '),
+            (b'%', Some(byte)) => {
+                decoded.push(byte);
+                i += 2;
+            }
+            (byte, _) => decoded.push(byte),
+        }
+        i += 1;
+    }
+
+    String::from_utf8_lossy(&decoded).into_owned()
+}
+
+/// The SKU of `title`, a SKU or the ASIN of a title in the library
+async fn resolve(watcher: &Watcher, title: &str) -> Result<String> {
+    if export::is_sku(title) {
+        return Ok(title.to_owned());
+    }
+
+    let Some(api) = &watcher.api else {
+        bail!(
+            "{:?} isn't a SKU, looking up ASINs needs the watcher to run with credentials for the API, e.g. --auth-file",
+            title
+        );
+    };
+
+    let items = api.library().await?;
+    let item = items
+        .iter()
+        .find(|item| item.product.asin.eq_ignore_ascii_case(title))
+        .ok_or_else(|| {
+            anyhow!(
+                "{} isn't a SKU, or the ASIN of a title in your library",
+                title
+            )
+        })?;
+
+    item.product
+        .download_sku()
+        .map(str::to_owned)
+        .ok_or_else(|| anyhow!("{} can't be downloaded", item.product.title))
+}
+
+/// Drop a `.sku` file for the title in the form `body` into the watched directory
+async fn enqueue(watcher: &Watcher, body: &str) -> Result<String> {
+    let title = form_value(body, "title").unwrap_or_default();
+    let title = title.trim();
+
+    if title.is_empty() {
+        bail!("Enter a SKU or an ASIN");
+    }
+
+    let sku = resolve(watcher, title).await?;
+    let trigger = watcher.dir.join(format!("web-{}.sku", sku));
+
+    // Written under another name first, so that the watcher doesn't see half of it
+    let temp = trigger.with_extension("sku.tmp");
+    tokio::fs::write(&temp, format!("{}\n", sku)).await?;
+    tokio::fs::rename(&temp, &trigger).await?;
+
+    eprintln!("Queued {} from the web UI", sku);
+
+    Ok(sku)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn reads_form_values() {
+        assert_eq!(
+            form_value("title=BK_ADBL_000123", "title").as_deref(),
+            Some("BK_ADBL_000123")
+        );
+        assert_eq!(
+            form_value("other=1&title=+B0%2B9%", "title").as_deref(),
+            Some(" B0+9%")
+        );
+        assert_eq!(form_value("other=1", "title"), None);
+    }
+}
@@ -0,0 +1,394 @@
+//! `--webhooks`, HTTP requests to other services when something happens to a download.
+//!
+//! The hooks are listed in a JSON file. Each one has a `url` to POST to, optionally the `events`
+//! it wants (all of them by default), `headers` to send and a `body` template. Strings in the
+//! template can contain `{{name}}` placeholders for the fields of the event, a string that's
+//! just a placeholder is replaced with the value as is, so that numbers stay numbers. Without a
+//! template the fields are sent as a JSON object.
+//!
+//! ```json
+//! [
+//!   {
+//!     "url": "http://homeassistant.local:8123/api/webhook/audible",
+//!     "events": ["completed", "failed"],
+//!     "body": { "message": "{{sku}} {{event}}", "error": "{{error}}" }
+//!   }
+//! ]
+//! ```
+
+use std::cell::Cell;
+use std::collections::BTreeMap;
+use std::path::Path;
+use std::sync::Arc;
+use std::time::Duration;
+
+use anyhow::{Context, Result};
+use audible_dl_core::{Frontend, Stage};
+use serde::Deserialize;
+use serde_json::{json, Map, Value};
+
+use crate::{cache, mqtt, progress};
+
+/// How long to wait for a hook to answer
+const TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Percentages of the download that send a progress event
+const MILESTONES: [(u64, Event); 3] = [
+    (25, Event::Progress25),
+    (50, Event::Progress50),
+    (75, Event::Progress75),
+];
+
+#[derive(Deserialize, Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Event {
+    #[serde(rename = "started")]
+    Started,
+    #[serde(rename = "progress-25")]
+    Progress25,
+    #[serde(rename = "progress-50")]
+    Progress50,
+    #[serde(rename = "progress-75")]
+    Progress75,
+    #[serde(rename = "retried")]
+    Retried,
+    #[serde(rename = "failed")]
+    Failed,
+    #[serde(rename = "completed")]
+    Completed,
+}
+
+impl Event {
+    fn name(self) -> &'static str {
+        match self {
+            Event::Started => "started",
+            Event::Progress25 => "progress-25",
+            Event::Progress50 => "progress-50",
+            Event::Progress75 => "progress-75",
+            Event::Retried => "retried",
+            Event::Failed => "failed",
+            Event::Completed => "completed",
+        }
+    }
+}
+
+#[derive(Deserialize, Debug)]
+#[serde(deny_unknown_fields)]
+struct Hook {
+    url: String,
+    /// Events to send, all of them if empty
+    #[serde(default)]
+    events: Vec<Event>,
+    #[serde(default)]
+    headers: BTreeMap<String, String>,
+    body: Option<Value>,
+}
+
+/// The hooks of a `--webhooks` file
+#[derive(Clone, Debug, Default)]
+pub struct Webhooks {
+    hooks: Arc<Vec<Hook>>,
+    /// Without the `--header` headers and Tor, which are meant for Audible
+    client: reqwest::Client,
+}
+
+/// Read the hooks in the file at `path`, for use as a clap value parser
+pub fn parse(path: &str) -> Result<Webhooks, String> {
+    let contents =
+        std::fs::read(path).map_err(|e| format!("Failed to read webhooks {}: {}", path, e))?;
+    let hooks = serde_json::from_slice::<Vec<Hook>>(&contents)
+        .map_err(|e| format!("Invalid webhooks {}: {}", path, e))?;
+
+    for hook in &hooks {
+        reqwest::Url::parse(&hook.url)
+            .map_err(|e| format!("Invalid webhook URL {:?} in {}: {}", hook.url, path, e))?;
+    }
+
+    Ok(Webhooks {
+        hooks: Arc::new(hooks),
+        client: reqwest::Client::new(),
+    })
+}
+
+/// Replace the `{{name}}` placeholders in the strings of `template` with the fields of `fields`
+fn render(template: &Value, fields: &Map<String, Value>) -> Value {
+    match template {
+        Value::String(s) => {
+            let whole = s
+                .strip_prefix("{{")
+                .and_then(|s| s.strip_suffix("}}"))
+                .and_then(|name| fields.get(name));
+
+            if let Some(value) = whole {
+                return value.clone();
+            }
+
+            let mut rendered = s.clone();
+
+            for (name, value) in fields {
+                let text = match value {
+                    Value::String(s) => s.clone(),
+                    Value::Null => String::new(),
+                    value => value.to_string(),
+                };
+                rendered = rendered.replace(&format!("{{{{{}}}}}", name), &text);
+            }
+
+            Value::String(rendered)
+        }
+        Value::Array(items) => Value::Array(items.iter().map(|v| render(v, fields)).collect()),
+        Value::Object(object) => Value::Object(
+            object
+                .iter()
+                .map(|(key, value)| (key.clone(), render(value, fields)))
+                .collect(),
+        ),
+        value => value.clone(),
+    }
+}
+
+impl Webhooks {
+    /// Send `event` with `fields` to every hook that wants it, warning on `frontend` about those
+    /// that fail
+    async fn send(&self, event: Event, mut fields: Map<String, Value>, frontend: &progress::Bar) {
+        fields.insert("event".to_owned(), json!(event.name()));
+
+        for hook in self.hooks.iter() {
+            if !hook.events.is_empty() && !hook.events.contains(&event) {
+                continue;
+            }
+
+            let body = match &hook.body {
+                Some(template) => render(template, &fields),
+                None => Value::Object(fields.clone()),
+            };
+
+            if let Err(e) = self.post(hook, &body).await {
+                frontend.warn(&format!("Webhook {} failed: {:#}", hook.url, e));
+            }
+        }
+    }
+
+    async fn post(&self, hook: &Hook, body: &Value) -> Result<()> {
+        let mut request = self.client.post(&hook.url).json(body).timeout(TIMEOUT);
+
+        for (name, value) in &hook.headers {
+            request = request.header(name, value);
+        }
+
+        request
+            .send()
+            .await?
+            .error_for_status()
+            .context("The hook answered with an error")?;
+
+        Ok(())
+    }
+}
+
+/// Shows the progress of a download on `frontend`, and sends its events to the webhooks and to
+/// MQTT
+pub struct Notifier {
+    frontend: progress::Bar,
+    webhooks: Webhooks,
+    /// Fields sent with every event
+    fields: Map<String, Value>,
+    downloading: Cell<bool>,
+    length: Cell<u64>,
+    /// Highest percentage reached, from where the download started
+    percent: Cell<Option<u64>>,
+}
+
+impl Notifier {
+    /// Send the events of downloading `url` to `output` to `webhooks`, if any
+    pub fn new(
+        frontend: progress::Bar,
+        webhooks: Option<&Webhooks>,
+        url: &str,
+        output: &Path,
+    ) -> Notifier {
+        let mut fields = Map::new();
+        fields.insert("sku".to_owned(), json!(cache::sku(url)));
+        fields.insert("output".to_owned(), json!(output));
+
+        // Fields of other events are empty, rather than left as placeholders
+        for name in ["percent", "failures", "error", "path"] {
+            fields.insert(name.to_owned(), Value::Null);
+        }
+
+        Notifier {
+            frontend,
+            webhooks: webhooks.cloned().unwrap_or_default(),
+            fields,
+            downloading: Cell::new(false),
+            length: Cell::new(0),
+            percent: Cell::new(None),
+        }
+    }
+
+    fn fields(&self, extra: Value) -> Map<String, Value> {
+        let mut fields = self.fields.clone();
+
+        if let Value::Object(extra) = extra {
+            fields.extend(extra);
+        }
+
+        fields
+    }
+
+    /// Send `event` in the background, so that the download doesn't wait for it
+    fn spawn(&self, event: Event, extra: Value) {
+        if self.webhooks.hooks.is_empty() {
+            return;
+        }
+
+        let webhooks = self.webhooks.clone();
+        let fields = self.fields(extra);
+        let frontend = self.frontend.clone();
+
+        tokio::spawn(async move { webhooks.send(event, fields, &frontend).await });
+    }
+
+    /// The download started
+    pub async fn started(&self) {
+        let state = json!({ "state": "downloading", "sku": self.fields["sku"], "output": self.fields["output"] });
+        mqtt::state(state).await;
+
+        let fields = self.fields(json!({}));
+        self.webhooks
+            .send(Event::Started, fields, &self.frontend)
+            .await;
+    }
+
+    /// The download finished with `result`, the path of the book or the error
+    pub async fn finished(&self, result: Result<&Path, &anyhow::Error>) {
+        let (event, extra) = match result {
+            Ok(path) => (Event::Completed, json!({ "path": path })),
+            Err(e) => (Event::Failed, json!({ "error": format!("{:#}", e) })),
+        };
+
+        let fields = self.fields(extra);
+
+        let mut state = json!({ "state": event.name() });
+        for name in ["sku", "output", "path", "error"] {
+            state[name] = fields[name].clone();
+        }
+        mqtt::state(state).await;
+
+        self.webhooks.send(event, fields, &self.frontend).await;
+    }
+
+    /// Send the progress events of the milestones passed on the way to `position` of `total`
+    fn reach(&self, position: u64, total: u64) {
+        if total == 0 {
+            return;
+        }
+
+        let percent = position * 100 / total;
+
+        // Resumed downloads start where they are, without sending what they passed before
+        let Some(reached) = self.percent.get() else {
+            self.percent.set(Some(percent));
+            return;
+        };
+
+        for (milestone, event) in MILESTONES {
+            if reached < milestone && percent >= milestone {
+                self.spawn(event, json!({ "percent": milestone }));
+            }
+        }
+
+        self.percent.set(Some(reached.max(percent)));
+    }
+}
+
+impl Frontend for Notifier {
+    fn set_stage(&self, stage: Stage) {
+        self.downloading.set(stage == Stage::Downloading);
+        self.frontend.set_stage(stage);
+    }
+
+    fn set_message(&self, message: &str) {
+        self.frontend.set_message(message);
+    }
+
+    fn set_length(&self, len: u64) {
+        self.length.set(len);
+        self.frontend.set_length(len);
+    }
+
+    fn set_position(&self, position: u64) {
+        if self.downloading.get() {
+            self.reach(position, self.length.get());
+        }
+
+        self.frontend.set_position(position);
+    }
+
+    fn inc(&self, delta: u64) {
+        self.frontend.inc(delta);
+    }
+
+    fn log(&self, line: &str) {
+        self.frontend.log(line);
+    }
+
+    fn warn(&self, message: &str) {
+        self.frontend.warn(message);
+    }
+
+    fn retry(&self, failures: u32, error: &str) {
+        self.spawn(
+            Event::Retried,
+            json!({ "failures": failures, "error": error }),
+        );
+        self.frontend.retry(failures, error);
+    }
+
+    fn rate(&self, position: u64, total: u64, rate: u64) {
+        self.reach(position, total);
+
+        mqtt::progress(json!({
+            "sku": self.fields["sku"],
+            "position": position,
+            "total": total,
+            "percent": (total > 0).then(|| position * 100 / total),
+            "rate": rate,
+        }));
+
+        self.frontend.rate(position, total, rate);
+    }
+
+    fn confirm(&self, question: &str) -> Result<bool> {
+        self.frontend.confirm(question)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn renders_templates() {
+        let fields = json!({ "sku": "BK_ADBL_000123", "percent": 50, "error": null });
+        let Value::Object(fields) = fields else {
+            unreachable!()
+        };
+
+        let template = json!({
+            "message": "{{sku}} is {{percent}}% done{{error}}",
+            "percent": "{{percent}}",
+            "tags": ["{{sku}}", "{{unknown}}"],
+            "count": 1,
+        });
+
+        assert_eq!(
+            render(&template, &fields),
+            json!({
+                "message": "BK_ADBL_000123 is 50% done",
+                "percent": 50,
+                "tags": ["BK_ADBL_000123", "{{unknown}}"],
+                "count": 1,
+            })
+        );
+    }
+}